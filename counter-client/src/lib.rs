@@ -0,0 +1,215 @@
+//! A client for Counter as a Service.
+//!
+//! ```no_run
+//! use counter_client::{CounterClient, NewCounter};
+//!
+//! let client = CounterClient::new("http://localhost:8000").with_api_key("s3cret");
+//! let counter = client.create(&NewCounter::named("signups"))?;
+//!
+//! client.increment(counter.id)?;
+//! # Ok::<(), counter_client::Error>(())
+//! ```
+
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::Duration;
+use uuid::Uuid;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A counter as the API returns it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Counter {
+    pub id: Uuid,
+    pub value: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    /// Milliseconds since the Unix epoch.
+    pub updated_at: u64,
+    pub archived: bool,
+    pub frozen: bool,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    /// Secret that changing the counter takes, only returned when it is created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_token: Option<String>,
+    /// Every other field, such as `step`, `bounds` and `cycle`, as sent.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+/// What to create a counter with.
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct NewCounter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl NewCounter {
+    pub fn named(name: &str) -> NewCounter {
+        NewCounter {
+            name: Some(name.to_string()),
+            ..NewCounter::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent, or the response could not be read.
+    Http(reqwest::Error),
+    /// The API answered with an error.
+    Api { status: u16, reason: String },
+    /// The change was accepted but is still running; `location` is where to poll for it.
+    Pending { location: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(error) => error.fmt(f),
+            Error::Api { status, reason } => write!(f, "{}: {}", status, reason),
+            Error::Pending { location } => write!(
+                f,
+                "The change was accepted but has not finished; see {}",
+                location
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Error {
+        Error::Http(error)
+    }
+}
+
+pub struct CounterClient {
+    base_url: String,
+    api_key: Option<String>,
+    http: reqwest::Client,
+}
+
+impl CounterClient {
+    /// A client for the instance at `base_url`, such as `http://localhost:8000`.
+    pub fn new(base_url: &str) -> CounterClient {
+        CounterClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: None,
+            http: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("Failed to build the HTTP client"),
+        }
+    }
+
+    /// Sends `api_key` as `X-Api-Key` with every request.
+    pub fn with_api_key(mut self, api_key: &str) -> CounterClient {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Every counter that is not archived.
+    pub fn list(&self) -> Result<Vec<Counter>, Error> {
+        self.send(Method::GET, "", None)
+    }
+
+    pub fn get(&self, id: Uuid) -> Result<Counter, Error> {
+        self.send(Method::GET, &format!("/{}", id), None)
+    }
+
+    pub fn create(&self, new_counter: &NewCounter) -> Result<Counter, Error> {
+        let body = serde_json::to_string(new_counter).expect("NewCounter serializes to JSON");
+
+        self.send(Method::POST, "", Some(body))
+    }
+
+    /// Adds the counter's step, returning the counter as it now is.
+    pub fn increment(&self, id: Uuid) -> Result<Counter, Error> {
+        self.send(Method::PUT, &format!("/{}/increment", id), None)
+    }
+
+    /// Removes the counter's step, returning the counter as it now is.
+    pub fn decrement(&self, id: Uuid) -> Result<Counter, Error> {
+        self.send(Method::PUT, &format!("/{}/decrement", id), None)
+    }
+
+    /// Adds the counter's step `steps` times in one request.
+    pub fn increment_by(&self, id: Uuid, steps: u32) -> Result<Counter, Error> {
+        self.send(
+            Method::PUT,
+            &format!("/{}/increment?steps={}", id, steps),
+            None,
+        )
+    }
+
+    /// Removes the counter's step `steps` times in one request.
+    pub fn decrement_by(&self, id: Uuid, steps: u32) -> Result<Counter, Error> {
+        self.send(
+            Method::PUT,
+            &format!("/{}/decrement?steps={}", id, steps),
+            None,
+        )
+    }
+
+    fn send<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<T, Error> {
+        let mut request = self
+            .http
+            .request(method, &format!("{}/v1/counter{}", self.base_url, path))
+            .header("Accept", "application/json");
+
+        if let Some(api_key) = &self.api_key {
+            request = request.header("X-Api-Key", api_key.as_str());
+        }
+
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body);
+        }
+
+        let mut response = request.send()?;
+        let status = response.status();
+
+        if status == StatusCode::ACCEPTED {
+            let body: Value = response.json()?;
+
+            return Err(Error::Pending {
+                location: body["location"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+
+        if !status.is_success() {
+            let body: Value = response.json().unwrap_or_default();
+
+            return Err(Error::Api {
+                status: status.as_u16(),
+                reason: body["reason"]
+                    .as_str()
+                    .unwrap_or("no reason given")
+                    .to_string(),
+            });
+        }
+
+        Ok(response.json()?)
+    }
+}
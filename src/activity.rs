@@ -0,0 +1,213 @@
+//! How busy each counter has been lately, for `/counter/<id>/activity`: the number of changes
+//! to its value and their net delta over the last minute, five minutes and hour. Kept in rings
+//! of buckets rather than read from the history, so that recording a change takes constant
+//! time and the counts do not depend on how much history is kept.
+//!
+//! Buckets are a second wide for the last minute, ten seconds for five minutes and a minute for
+//! the hour. A window counts its buckets in full, so the oldest one may reach up to a bucket
+//! further back than the window.
+
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::history;
+use crate::lock;
+use crate::tenancy::TenantStore;
+use crate::{not_found_error, parse_id, ApiError};
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    changes: u32,
+    delta: i64,
+}
+
+/// `length` buckets of `width` milliseconds each, the newest at the index of the current time.
+#[derive(Clone)]
+struct Ring {
+    width: u64,
+    buckets: Vec<Bucket>,
+    /// Number of the newest bucket since the Unix epoch, in widths.
+    newest: u64,
+}
+
+impl Ring {
+    fn new(width: u64, length: usize) -> Ring {
+        Ring {
+            width,
+            buckets: vec![Bucket::default(); length],
+            newest: 0,
+        }
+    }
+
+    /// Empties the buckets that have fallen out of the ring by `now`. At most every bucket is
+    /// emptied, however long it has been.
+    fn advance(&mut self, now: u64) {
+        let current = now / self.width;
+        let length = self.buckets.len() as u64;
+
+        if current <= self.newest {
+            return;
+        }
+
+        for number in (self.newest + 1)..=current.min(self.newest + length) {
+            self.buckets[(number % length) as usize] = Bucket::default();
+        }
+
+        self.newest = current;
+    }
+
+    fn record(&mut self, delta: i64, now: u64) {
+        self.advance(now);
+
+        let length = self.buckets.len() as u64;
+        let bucket = &mut self.buckets[(self.newest % length) as usize];
+
+        bucket.changes += 1;
+        bucket.delta += delta;
+    }
+
+    /// Changes in every bucket that is still current at `now`.
+    fn total(&self, now: u64) -> Window {
+        let current = now / self.width;
+        let length = self.buckets.len() as u64;
+
+        (current.saturating_sub(length - 1)..=current)
+            .filter(|number| *number <= self.newest && self.newest - number < length)
+            .map(|number| self.buckets[(number % length) as usize])
+            .fold(Window::default(), |window, bucket| Window {
+                changes: window.changes + u64::from(bucket.changes),
+                delta: window.delta + bucket.delta,
+            })
+    }
+}
+
+#[derive(Clone)]
+struct Rings {
+    minute: Ring,
+    five_minutes: Ring,
+    hour: Ring,
+    /// Milliseconds since the Unix epoch.
+    last_change_at: u64,
+}
+
+impl Rings {
+    fn new() -> Rings {
+        Rings {
+            minute: Ring::new(1000, 60),
+            five_minutes: Ring::new(10_000, 30),
+            hour: Ring::new(60_000, 60),
+            last_change_at: 0,
+        }
+    }
+}
+
+/// Recent changes of every counter whose value has changed since the service started.
+#[derive(Default)]
+pub struct Activity(Mutex<HashMap<Uuid, Rings>>);
+
+impl Activity {
+    /// Separate activity with the same changes recorded.
+    pub fn copy(&self) -> Activity {
+        Activity(Mutex::new(lock(&self.0).clone()))
+    }
+
+    pub fn record(&self, id: Uuid, delta: i64, now: u64) {
+        let mut activity = lock(&self.0);
+        let rings = activity.entry(id).or_insert_with(Rings::new);
+
+        rings.minute.record(delta, now);
+        rings.five_minutes.record(delta, now);
+        rings.hour.record(delta, now);
+        rings.last_change_at = now;
+    }
+
+    pub fn forget(&self, id: &Uuid) {
+        lock(&self.0).remove(id);
+    }
+
+    fn windows(&self, id: &Uuid, now: u64) -> Windows {
+        match lock(&self.0).get(id) {
+            Some(rings) => Windows {
+                last_minute: rings.minute.total(now),
+                last_five_minutes: rings.five_minutes.total(now),
+                last_hour: rings.hour.total(now),
+                last_change_at: Some(rings.last_change_at),
+            },
+            None => Windows::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Window {
+    changes: u64,
+    delta: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct Windows {
+    #[serde(rename = "1m")]
+    last_minute: Window,
+    #[serde(rename = "5m")]
+    last_five_minutes: Window,
+    #[serde(rename = "1h")]
+    last_hour: Window,
+    /// Milliseconds since the Unix epoch, if the value changed since the service started.
+    last_change_at: Option<u64>,
+}
+
+/// `GET /counter/<id>/activity`, how many times the value changed and by how much in all over
+/// the last minute, five minutes and hour.
+#[get("/<id>/activity")]
+pub fn get_activity(id: String, store: TenantStore) -> Result<Json<Windows>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    Ok(Json(
+        store.activity().windows(&parsed_uuid, history::timestamp()),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn track_recent_activity() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for path in &["increment", "increment", "increment", "decrement"] {
+            client
+                .put(format!("/counter/{}/{}", counter.id, path))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let mut response = client
+            .get(format!("/counter/{}/activity", counter.id))
+            .dispatch();
+        let activity: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(activity["1m"]["changes"], 4);
+        assert_eq!(activity["1m"]["delta"], 2);
+        assert_eq!(activity["1h"]["changes"], 4);
+        assert!(activity["last_change_at"].as_u64().unwrap() >= counter.created_at);
+
+        let unknown_response = client
+            .get(format!("/counter/{}/activity", uuid::Uuid::new_v4()))
+            .dispatch();
+
+        assert_eq!(unknown_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,629 @@
+//! Email alerts, for people who cannot receive webhooks. Sent through the SMTP server
+//! configured as `smtp = { host = "smtp.example.com", from = "alerts@example.com" }`, with
+//! optional `username`, `password`, `tls` (on by default) and `port` (only without TLS).
+
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, SmtpClient, Transport};
+use lettre_email::Email;
+use rocket::http::Status;
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::history;
+use crate::jobs::{Jobs, Run};
+use crate::lock;
+use crate::negotiation::Body;
+use crate::notify::{label, Condition, Event, Notifier, Notifiers};
+use crate::pool::BlockingPool;
+use crate::simulate::{self, Fired};
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::validation::{Validate, Violations};
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+const MAX_ALERTS: usize = 20;
+const MAX_RECIPIENTS: usize = 10;
+/// Seconds an alert stays quiet after sending, unless `smtp.min_interval` says otherwise.
+const DEFAULT_MIN_INTERVAL: u64 = 3600;
+/// How often idle alerts are checked.
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+const HOUR: u64 = 3_600_000;
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Trigger {
+    /// When the value comes to satisfy the condition.
+    Condition(Condition),
+    /// When the counter has not changed for this many hours.
+    IdleHours(u32),
+}
+
+#[derive(Serialize, Clone)]
+pub struct Alert {
+    id: Uuid,
+    recipients: Vec<String>,
+    #[serde(flatten)]
+    trigger: Trigger,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    /// Whether the trigger held at the last check, so that the alert fires only on becoming
+    /// true.
+    #[serde(skip)]
+    holds: bool,
+    /// Milliseconds since the Unix epoch.
+    #[serde(skip)]
+    last_change: u64,
+    /// Milliseconds since the Unix epoch.
+    #[serde(skip)]
+    last_sent: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct NewAlert {
+    recipients: Vec<String>,
+    condition: Option<String>,
+    idle_hours: Option<u32>,
+}
+
+impl Validate for NewAlert {
+    fn validate(&self, violations: &mut Violations) {
+        violations.positive("idle_hours", self.idle_hours);
+    }
+}
+
+struct Smtp {
+    host: String,
+    port: u16,
+    tls: bool,
+    credentials: Option<Credentials>,
+    from: String,
+    /// Milliseconds.
+    min_interval: u64,
+}
+
+impl Smtp {
+    fn from_config(config: &Config) -> Option<Smtp> {
+        let table = config.get_table("smtp").ok()?;
+        let text = |key: &str| table.get(key).and_then(|value| value.as_str());
+        let (host, from) = match (text("host"), text("from")) {
+            (Some(host), Some(from)) => (host, from),
+            _ => {
+                eprintln!("Ignoring smtp: host and from are required");
+                return None;
+            }
+        };
+
+        Some(Smtp {
+            host: host.to_string(),
+            port: table
+                .get("port")
+                .and_then(|port| port.as_integer())
+                .map_or(25, |port| port as u16),
+            tls: table
+                .get("tls")
+                .and_then(|tls| tls.as_bool())
+                .unwrap_or(true),
+            credentials: match (text("username"), text("password")) {
+                (Some(username), Some(password)) => {
+                    Some(Credentials::new(username.to_string(), password.to_string()))
+                }
+                _ => None,
+            },
+            from: from.to_string(),
+            min_interval: table
+                .get("min_interval")
+                .and_then(|seconds| seconds.as_integer())
+                .filter(|seconds| *seconds >= 0)
+                .map_or(DEFAULT_MIN_INTERVAL, |seconds| seconds as u64)
+                * 1000,
+        })
+    }
+
+    fn send(&self, to: &[String], subject: &str, text: &str) -> Result<(), String> {
+        let mut email = Email::builder()
+            .from(self.from.as_str())
+            .subject(subject)
+            .text(text);
+
+        for recipient in to {
+            email = email.to(recipient.as_str());
+        }
+
+        let email = email.build().map_err(|error| error.to_string())?;
+        let client = if self.tls {
+            SmtpClient::new_simple(&self.host)
+        } else {
+            SmtpClient::new((self.host.as_str(), self.port), ClientSecurity::None)
+        }
+        .map_err(|error| error.to_string())?;
+        let client = match &self.credentials {
+            Some(credentials) => client.credentials(credentials.clone()),
+            None => client,
+        };
+
+        client
+            .transport()
+            .send(email.into())
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Alerts per counter, checked on every change like webhooks, and for idleness once a minute.
+/// Each alert sends at most once per `smtp.min_interval`; what it would send in between is
+/// dropped.
+#[derive(Clone)]
+pub struct Alerts {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    alerts: Mutex<HashMap<Uuid, Vec<Alert>>>,
+    smtp: Option<Smtp>,
+    pool: BlockingPool,
+}
+
+impl Alerts {
+    pub fn from_config(config: &Config) -> Alerts {
+        Alerts {
+            shared: Arc::new(Shared {
+                alerts: Mutex::default(),
+                smtp: Smtp::from_config(config),
+                pool: BlockingPool::new(1),
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.shared.smtp.is_some()
+    }
+
+    /// Makes email notification channels available, sent through the same SMTP server.
+    pub fn register_notifier(&self, notifiers: &Notifiers) {
+        notifiers.register(EmailNotifier(Arc::clone(&self.shared)));
+    }
+
+    /// Starts checking changes to the counters in `store`. Checking stops with the store.
+    pub fn watch(&self, store: &Store) {
+        let changes = store.subscribe();
+        let alerts = self.clone();
+
+        thread::spawn(move || {
+            for counter in changes {
+                alerts.check(&counter);
+            }
+        });
+    }
+
+    /// Checks for idle counters once a minute, until the alerts are dropped.
+    pub fn schedule_idle_checks(&self, jobs: &Jobs) {
+        let shared = Arc::downgrade(&self.shared);
+
+        jobs.every("idle_alerts", IDLE_CHECK, move || match shared.upgrade() {
+            Some(shared) => {
+                check_idle(&shared);
+                Run::Done
+            }
+            None => Run::Finished,
+        });
+    }
+
+    /// Which of the alerts of `id` would fire if the counter took `values` in turn, without
+    /// sending anything.
+    pub fn simulate(&self, id: Uuid, values: &[u32]) -> Vec<Fired> {
+        let mut fired = Vec::new();
+
+        for alert in lock(&self.shared.alerts).get(&id).into_iter().flatten() {
+            if let Trigger::Condition(condition) = alert.trigger {
+                let mut holds = alert.holds;
+
+                fired.extend(simulate::walk("alert", alert.id, values, |value| {
+                    condition.became_true(value, &mut holds)
+                }));
+            }
+        }
+        fired
+    }
+
+    fn check(&self, counter: &Counter) {
+        let now = history::timestamp();
+        let mut alerts = lock(&self.shared.alerts);
+        let alerts = match alerts.get_mut(&counter.id) {
+            Some(alerts) => alerts,
+            None => return,
+        };
+
+        for alert in alerts.iter_mut() {
+            alert.last_change = now;
+
+            let condition = match alert.trigger {
+                Trigger::Condition(condition) => condition,
+                Trigger::IdleHours(_) => {
+                    alert.holds = false;
+                    continue;
+                }
+            };
+
+            if condition.became_true(counter.value, &mut alert.holds) {
+                let subject = format!("{} crossed {}", label(counter), condition);
+                let text = format!(
+                    "{} is now at {}.\n\nCounter: {}\n",
+                    label(counter),
+                    counter.value,
+                    counter.id
+                );
+
+                send(&self.shared, alert, now, subject, text);
+            }
+        }
+    }
+}
+
+/// Sends `alert` unless it has sent within the minimum interval.
+fn send(shared: &Arc<Shared>, alert: &mut Alert, now: u64, subject: String, text: String) {
+    let min_interval = shared.smtp.as_ref().map_or(0, |smtp| smtp.min_interval);
+
+    if alert
+        .last_sent
+        .map_or(false, |sent| now < sent + min_interval)
+    {
+        eprintln!("Alert {} is rate limited, not sending", alert.id);
+        return;
+    }
+
+    alert.last_sent = Some(now);
+
+    let job_shared = Arc::clone(shared);
+    let recipients = alert.recipients.clone();
+
+    shared.pool.spawn(move || {
+        if let Some(smtp) = &job_shared.smtp {
+            if let Err(reason) = smtp.send(&recipients, &subject, &text) {
+                eprintln!("Sending an alert failed: {}", reason);
+            }
+        }
+    });
+}
+
+fn check_idle(shared: &Arc<Shared>) {
+    let now = history::timestamp();
+    let mut alerts = lock(&shared.alerts);
+
+    for (id, alerts) in alerts.iter_mut() {
+        for alert in alerts.iter_mut() {
+            let hours = match alert.trigger {
+                Trigger::IdleHours(hours) => hours,
+                Trigger::Condition(_) => continue,
+            };
+            let holds = now >= alert.last_change + u64::from(hours) * HOUR;
+
+            if holds && !alert.holds {
+                let subject = format!("Counter {} has been idle", id);
+                let text = format!("Counter {} has not changed for {} hours.\n", id, hours);
+
+                send(shared, alert, now, subject, text);
+            }
+
+            alert.holds = holds;
+        }
+    }
+}
+
+/// Channels that mail the event to one address. Unlike alerts, they are not rate limited.
+struct EmailNotifier(Arc<Shared>);
+
+impl Notifier for EmailNotifier {
+    fn kind(&self) -> &'static str {
+        "email"
+    }
+
+    fn check_target(&self, target: &str) -> Result<(), String> {
+        if valid_address(target) {
+            Ok(())
+        } else {
+            Err(format!("{} is not a valid email address.", target))
+        }
+    }
+
+    fn send(&self, target: &str, event: &Event) -> Result<(), String> {
+        let smtp = self.0.smtp.as_ref().ok_or("Email is not configured")?;
+        let subject = format!("{} crossed {}", label(&event.counter), event.condition);
+        let text = format!("{}\n\nCounter: {}\n", event.summary(), event.counter.id);
+
+        smtp.send(&[target.to_string()], &subject, &text)
+    }
+}
+
+/// Plain addresses only; anything that could add mail headers is refused.
+fn valid_address(address: &str) -> bool {
+    let mut parts = address.splitn(2, '@');
+
+    match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !address
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || "<>,;\"".contains(c))
+        }
+        _ => false,
+    }
+}
+
+/// `{ "recipients": ["ops@example.com"], "condition": "value >= 1000" }` to be told when the
+/// value crosses a threshold, or `"idle_hours": 24` instead of the condition to be told when
+/// the counter has not changed for a day.
+#[post("/<id>/alerts", data = "<new_alert>")]
+pub fn create_alert(
+    id: String,
+    new_alert: Body<NewAlert>,
+    alerts: State<Alerts>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Alert>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+
+    if !alerts.enabled() {
+        return Err(error(Status::NotFound, "Email alerts are not configured."));
+    }
+
+    writer.check(parsed_uuid, &store)?;
+
+    let NewAlert {
+        recipients,
+        condition,
+        idle_hours,
+    } = new_alert.into_inner();
+    let trigger = match (condition, idle_hours) {
+        (Some(condition), None) => {
+            Trigger::Condition(Condition::parse(&condition).ok_or_else(|| {
+                error(
+                    Status::BadRequest,
+                    "Condition must look like `value >= 1000`.",
+                )
+            })?)
+        }
+        (None, Some(hours)) if hours > 0 => Trigger::IdleHours(hours),
+        _ => {
+            return Err(error(
+                Status::BadRequest,
+                "An alert takes either a condition or idle_hours of at least 1.",
+            ))
+        }
+    };
+
+    if recipients.is_empty() || recipients.len() > MAX_RECIPIENTS {
+        return Err(error(
+            Status::BadRequest,
+            &format!(
+                "An alert takes between 1 and {} recipients.",
+                MAX_RECIPIENTS
+            ),
+        ));
+    }
+
+    if let Some(invalid) = recipients.iter().find(|address| !valid_address(address)) {
+        return Err(error(
+            Status::BadRequest,
+            &format!("{} is not a valid email address.", invalid),
+        ));
+    }
+
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+    let mut registered = lock(&alerts.shared.alerts);
+    let registered = registered.entry(parsed_uuid).or_default();
+
+    if registered.len() >= MAX_ALERTS {
+        return Err(error(
+            Status::Conflict,
+            &format!("A counter can have at most {} alerts.", MAX_ALERTS),
+        ));
+    }
+
+    let alert = Alert {
+        id: Uuid::new_v4(),
+        recipients,
+        trigger,
+        created_at: history::timestamp(),
+        holds: match trigger {
+            Trigger::Condition(condition) => condition.matches(counter.value),
+            Trigger::IdleHours(_) => false,
+        },
+        last_change: counter.updated_at,
+        last_sent: None,
+    };
+
+    registered.push(alert.clone());
+    Ok(Json(alert))
+}
+
+#[get("/<id>/alerts")]
+pub fn list_alerts(
+    id: String,
+    alerts: State<Alerts>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Vec<Alert>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    // Recipients are personal data, so only those who may change the counter see them.
+    writer.check(parsed_uuid, &store)?;
+
+    let registered = lock(&alerts.shared.alerts);
+
+    Ok(Json(
+        registered.get(&parsed_uuid).cloned().unwrap_or_default(),
+    ))
+}
+
+#[delete("/<id>/alerts/<alert_id>")]
+pub fn delete_alert(
+    id: String,
+    alert_id: String,
+    alerts: State<Alerts>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Alert>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let alert_id = parse_id(&alert_id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let mut registered = lock(&alerts.shared.alerts);
+    let registered = registered
+        .get_mut(&parsed_uuid)
+        .ok_or_else(not_found_error)?;
+    let index = registered
+        .iter()
+        .position(|alert| alert.id == alert_id)
+        .ok_or_else(not_found_error)?;
+
+    Ok(Json(registered.remove(index)))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn send_email_alerts() {
+        use std::io::{BufRead, BufReader};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut smtp = BTreeMap::new();
+
+        smtp.insert("host".to_string(), Value::String("127.0.0.1".to_string()));
+        smtp.insert(
+            "port".to_string(),
+            Value::Integer(i64::from(listener.local_addr().unwrap().port())),
+        );
+        smtp.insert("tls".to_string(), Value::Boolean(false));
+        smtp.insert(
+            "from".to_string(),
+            Value::String("alerts@example.com".to_string()),
+        );
+
+        let config = Config::build(Environment::Development)
+            .extra("smtp", Value::Table(smtp))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let (sender, messages) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut message = String::new();
+                let mut line = String::new();
+                let mut in_data = false;
+
+                stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+                while reader.read_line(&mut line).unwrap() > 0 {
+                    if in_data {
+                        if line == ".\r\n" {
+                            in_data = false;
+                            stream.write_all(b"250 Queued\r\n").unwrap();
+                        } else {
+                            message.push_str(&line);
+                        }
+                    } else if line.starts_with("DATA") {
+                        in_data = true;
+                        stream.write_all(b"354 Go ahead\r\n").unwrap();
+                    } else if line.starts_with("QUIT") {
+                        stream.write_all(b"221 Bye\r\n").unwrap();
+                        break;
+                    } else {
+                        stream.write_all(b"250 OK\r\n").unwrap();
+                    }
+
+                    line.clear();
+                }
+
+                sender.send(message).unwrap();
+            }
+        });
+
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Signups" }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut alert_response = client
+            .post(format!("/counter/{}/alerts", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "recipients": ["ops@example.com"], "condition": "value >= 2" }"#)
+            .dispatch();
+
+        assert_eq!(alert_response.status(), Status::Ok);
+        assert!(alert_response
+            .body_string()
+            .unwrap()
+            .contains(r#""condition":"value >= 2""#));
+
+        let change = |operation: &str, times: usize| {
+            for _ in 0..times {
+                client
+                    .put(format!("/counter/{}/{}", counter.id, operation))
+                    .header(ContentType::JSON)
+                    .dispatch();
+            }
+        };
+
+        change("increment", 3);
+
+        let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(message.contains("ops@example.com"));
+        assert!(message.contains(r#"Counter "Signups" crossed value >= 2"#));
+
+        // Crossing again right away is rate limited.
+        change("decrement", 2);
+        change("increment", 1);
+
+        assert!(messages.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let invalid_response = client
+            .post(format!("/counter/{}/alerts", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "recipients": ["ops@example.com\r\nBcc: x@example.com"], "idle_hours": 1 }"#)
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+
+        let unconfigured_client = Client::new(rocket()).expect("Init failed");
+        let unconfigured_response = unconfigured_client
+            .post(format!("/counter/{}/alerts", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "recipients": ["ops@example.com"], "idle_hours": 24 }"#)
+            .dispatch();
+
+        assert_eq!(unconfigured_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,242 @@
+//! Other names a counter can be found by in paths, as in `/counter/signups/increment`, for
+//! counters that were renamed or that people would rather not address by id. Aliases are
+//! unique per tenant and resolved to the counter's id before routing, so every counter route
+//! takes them.
+//!
+//! Keys are names chosen by the caller for counters they have yet to create, such as an
+//! integration's `repo:owner/name`, given URL-encoded as in `/counter/key:repo%3Aowner%2Fname`.
+//! A key is not looked up: the counter's id is derived from it, so counters are created by key
+//! like they are by id, and a counter's key can never change.
+
+use rocket::data::Data;
+use rocket::http::uri::Origin;
+use rocket::http::{RawStr, Status};
+use rocket::request::Request;
+use rocket_contrib::json::Json;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::negotiation::Unused;
+use crate::store::AliasTaken;
+use crate::tenancy::TenantStore;
+use crate::{error, not_found_error, parse_id, ApiError, Counter, V1};
+
+const PREFIX: &str = "/counter/";
+const MAX_LENGTH: usize = 64;
+const MAX_ALIASES: usize = 16;
+pub const MAX_KEY_LENGTH: usize = 256;
+const KEY_PREFIX: &str = "key:";
+/// Paths under `/counter` that are routes rather than counters.
+const RESERVED: [&str; 7] = [
+    "compare",
+    "export.csv",
+    "lookup",
+    "search",
+    "snapshot",
+    "stats",
+    "top",
+];
+
+impl From<AliasTaken> for ApiError {
+    fn from(_: AliasTaken) -> ApiError {
+        error(Status::Conflict, "Another counter has that alias.")
+    }
+}
+
+fn valid(alias: &str) -> bool {
+    alias.len() <= MAX_LENGTH
+        && alias.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && alias
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c))
+        && !RESERVED.contains(&alias)
+        && Uuid::parse_str(alias).is_err()
+}
+
+/// Keys are any text up to 256 characters but control characters, not only whitespace.
+pub fn valid_key(key: &str) -> bool {
+    !key.trim().is_empty()
+        && key.chars().count() <= MAX_KEY_LENGTH
+        && !key.chars().any(char::is_control)
+}
+
+/// The id of the counter with `key`.
+pub fn key_id(key: &str) -> Uuid {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("urn:caas:key:{}", key).as_bytes(),
+    )
+}
+
+/// Replaces an alias or key in the path of a counter route with the id of the counter it names.
+pub fn resolve(request: &mut Request, _: &Data) {
+    let path = request.uri().path().to_string();
+    let (version, rest) = if path.starts_with(V1) {
+        (V1, &path[V1.len()..])
+    } else {
+        ("", &path[..])
+    };
+
+    if !rest.starts_with(PREFIX) {
+        return;
+    }
+
+    let rest = &rest[PREFIX.len()..];
+    let (segment, rest) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, ""),
+    };
+    let key = RawStr::from_str(segment)
+        .percent_decode()
+        .ok()
+        .filter(|decoded| decoded.starts_with(KEY_PREFIX))
+        .map(|decoded| decoded[KEY_PREFIX.len()..].to_string());
+
+    let id = match key {
+        Some(key) if valid_key(&key) => key_id(&key),
+        Some(_) => return,
+        None if valid(segment) => match request.guard::<TenantStore>().succeeded() {
+            Some(store) => match store.resolve(segment) {
+                Some(id) => id,
+                None => return,
+            },
+            None => return,
+        },
+        None => return,
+    };
+    let uri = match request.uri().query() {
+        Some(query) => format!("{}{}{}{}?{}", version, PREFIX, id, rest, query),
+        None => format!("{}{}{}{}", version, PREFIX, id, rest),
+    };
+
+    if let Ok(uri) = Origin::parse_owned(uri) {
+        request.set_uri(uri);
+    }
+}
+
+#[get("/<id>/aliases")]
+pub fn list_aliases(id: String, store: TenantStore) -> Result<Json<Vec<String>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    store
+        .get(parsed_uuid)
+        .map(|counter| Json(counter.aliases.into_iter().collect()))
+        .ok_or_else(not_found_error)
+}
+
+/// `PUT /counter/<id>/aliases/<alias>`. Aliases are 1 to 64 lowercase letters, digits, `-`,
+/// `_` and `.`, starting with a letter or digit.
+#[put("/<id>/aliases/<alias>", data = "<_body>")]
+pub fn add_alias(
+    id: String,
+    alias: String,
+    store: TenantStore,
+    writer: Writer,
+    _body: Unused,
+) -> Result<Json<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    if !valid(&alias) {
+        return Err(error(
+            Status::BadRequest,
+            "Aliases are up to 64 lowercase letters, digits, '-', '_' and '.', and not an id.",
+        ));
+    }
+
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    if counter.aliases.len() >= MAX_ALIASES && !counter.aliases.contains(&alias) {
+        return Err(error(
+            Status::BadRequest,
+            "A counter can have at most 16 aliases.",
+        ));
+    }
+
+    store
+        .add_alias(parsed_uuid, &alias)?
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[delete("/<id>/aliases/<alias>")]
+pub fn remove_alias(
+    id: String,
+    alias: String,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+    store.write(parsed_uuid, |hashmap| {
+        let counter = hashmap.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+        if counter.aliases.remove(&alias) {
+            Ok(Json(counter.clone()))
+        } else {
+            Err(not_found_error())
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn find_counter_by_alias() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let create = || {
+            let mut response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+            serde_json::from_str::<Counter>(&response.body_string().unwrap()).unwrap()
+        };
+        let renamed = create();
+        let other = create();
+
+        let alias_response = client
+            .put(format!("/counter/{}/aliases/old-name", renamed.id))
+            .dispatch();
+
+        assert_eq!(alias_response.status(), Status::Ok);
+
+        let taken_response = client
+            .put(format!("/counter/{}/aliases/old-name", other.id))
+            .dispatch();
+
+        assert_eq!(taken_response.status(), Status::Conflict);
+
+        let invalid_response = client
+            .put(format!("/counter/{}/aliases/search", other.id))
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+
+        client.put("/v1/counter/old-name/increment").dispatch();
+
+        let mut response = client.get("/counter/old-name").dispatch();
+        let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.id, renamed.id);
+        assert_eq!(counter.value, 1);
+
+        let mut aliases_response = client.get("/counter/old-name/aliases").dispatch();
+
+        assert_eq!(aliases_response.body_string().unwrap(), r#"["old-name"]"#);
+
+        let remove_response = client
+            .delete(format!("/counter/{}/aliases/old-name", renamed.id))
+            .dispatch();
+
+        assert_eq!(remove_response.status(), Status::Ok);
+        assert_eq!(
+            client.get("/counter/old-name").dispatch().status(),
+            Status::BadRequest
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Who changed what, for compliance. Every successful request to a route that changes counters
+//! is recorded with its actor, source address and, when it concerns a single counter, the
+//! change to that counter's value. The trail keeps the most recent `audit_limit` entries and is
+//! read from `GET /admin/audit`.
+
+use rocket::data::Data;
+use rocket::http::{ContentType, StatusClass};
+use rocket::request::{LenientForm, Request};
+use rocket::{Config, Response, State};
+use rocket_contrib::json::Json;
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::auth::Admin;
+use crate::history;
+use crate::lock;
+use crate::request_id;
+use crate::tenancy::TenantStore;
+use crate::{parse_id, ApiError};
+
+const DEFAULT_LIMIT: usize = 10_000;
+const MAX_PAGE: usize = 1000;
+
+#[derive(Serialize, Clone)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    /// Name of the route, such as `increment_counter`.
+    operation: String,
+    method: String,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counter: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<u32>,
+    /// `api_key:<name>` or `jwt:<subject>`, missing for anonymous requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    tenant: String,
+    request_id: String,
+}
+
+pub struct Audit {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    limit: usize,
+}
+
+impl Audit {
+    pub fn from_config(config: &Config) -> Audit {
+        Audit {
+            entries: Mutex::default(),
+            limit: config
+                .get_int("audit_limit")
+                .ok()
+                .filter(|limit| *limit > 0)
+                .map_or(DEFAULT_LIMIT, |limit| limit as usize),
+        }
+    }
+
+    fn push(&self, entry: AuditEntry) {
+        let mut entries = lock(&self.entries);
+
+        if entries.len() >= self.limit {
+            entries.pop_front();
+        }
+
+        entries.push_back(entry);
+    }
+}
+
+/// Set by the guards of the routes that change counters.
+pub struct Changes(pub bool);
+
+/// Who made the request, set once the caller has been identified.
+pub struct Actor(pub Option<String>);
+
+/// The counter the request's path names, with its value before the request.
+struct Before(Option<(Uuid, Option<u32>)>);
+
+/// Notes the value of the counter in the path before the route changes it.
+pub fn note_value(request: &mut Request, _: &Data) {
+    let id = request
+        .uri()
+        .segments()
+        .filter_map(|segment| Uuid::parse_str(segment).ok())
+        .next();
+    let before = id.map(|id| {
+        let value = request
+            .guard::<TenantStore>()
+            .succeeded()
+            .and_then(|store| store.get(id))
+            .map(|counter| counter.value);
+
+        (id, value)
+    });
+
+    request.local_cache(|| Before(before));
+}
+
+/// Records successful requests to routes that change counters.
+pub fn record(request: &Request, response: &mut Response) {
+    if !request.local_cache(|| Changes(false)).0
+        || response.status().class() != StatusClass::Success
+    {
+        return;
+    }
+
+    let audit = match request.guard::<State<Audit>>().succeeded() {
+        Some(audit) => audit,
+        None => return,
+    };
+    let store = request.guard::<TenantStore>().succeeded();
+    let (counter, before) = match request.local_cache(|| Before(None)).0 {
+        Some((id, before)) => (Some(id), before),
+        None => (created_id(response), None),
+    };
+    let value = counter
+        .and_then(|id| store.as_ref().and_then(|store| store.get(id)))
+        .map(|counter| counter.value);
+    let delta = value.map(|value| i64::from(value) - i64::from(before.unwrap_or(value)));
+
+    audit.push(AuditEntry {
+        timestamp: history::timestamp(),
+        operation: request
+            .route()
+            .and_then(|route| route.name)
+            .unwrap_or("unknown")
+            .to_string(),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        counter: value.and(counter),
+        delta,
+        value,
+        actor: request.local_cache(|| Actor(None)).0.clone(),
+        ip: request.client_ip().map(|ip| ip.to_string()),
+        tenant: store
+            .as_ref()
+            .map_or(String::new(), |store| store.tenant().to_string()),
+        request_id: request_id::of(request).to_string(),
+    });
+}
+
+/// The `id` of a counter the response created, read from its JSON body.
+fn created_id(response: &mut Response) -> Option<Uuid> {
+    if response.content_type() != Some(ContentType::JSON) {
+        return None;
+    }
+
+    let body = response.body_bytes()?;
+    let id = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json["id"].as_str().and_then(|id| Uuid::parse_str(id).ok()));
+
+    response.set_sized_body(Cursor::new(body));
+    id
+}
+
+#[derive(FromForm)]
+pub struct AuditQuery {
+    counter: Option<String>,
+    actor: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Audit entries, newest first.
+#[get("/audit?<query..>")]
+pub fn get_audit(
+    query: LenientForm<AuditQuery>,
+    audit: State<Audit>,
+    _admin: Admin,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    let counter = match &query.counter {
+        Some(id) => Some(parse_id(id)?),
+        None => None,
+    };
+    let entries = lock(&audit.entries);
+
+    Ok(Json(
+        entries
+            .iter()
+            .rev()
+            .filter(|entry| counter.map_or(true, |id| entry.counter == Some(id)))
+            .filter(|entry| {
+                query
+                    .actor
+                    .as_ref()
+                    .map_or(true, |actor| entry.actor.as_ref() == Some(actor))
+            })
+            .filter(|entry| query.from.map_or(true, |from| entry.timestamp >= from))
+            .filter(|entry| query.to.map_or(true, |to| entry.timestamp <= to))
+            .take(query.limit.unwrap_or(100).min(MAX_PAGE))
+            .cloned()
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn audit_changes() {
+        let mut key = BTreeMap::new();
+        let mut api_keys = BTreeMap::new();
+
+        key.insert("name".to_string(), Value::String("ops".to_string()));
+        key.insert("role".to_string(), Value::String("admin".to_string()));
+        api_keys.insert("s3cret".to_string(), Value::Table(key));
+
+        let config = Config::build(Environment::Development)
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(Header::new("X-Api-Key", "s3cret"))
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .header(Header::new("X-Api-Key", "s3cret"))
+            .dispatch();
+        client
+            .get(format!("/counter/{}/hit", counter.id))
+            .dispatch();
+
+        let mut audit_response = client
+            .get(format!("/admin/audit?counter={}", counter.id))
+            .header(Header::new("X-Api-Key", "s3cret"))
+            .dispatch();
+        let entries: Vec<serde_json::Value> =
+            serde_json::from_str(&audit_response.body_string().unwrap()).unwrap();
+        let operations: Vec<&str> = entries
+            .iter()
+            .map(|entry| entry["operation"].as_str().unwrap())
+            .collect();
+
+        assert_eq!(
+            operations,
+            vec!["hit_counter", "increment_counter", "create_counter"]
+        );
+        assert_eq!(entries[0]["actor"], serde_json::Value::Null);
+        assert_eq!(entries[1]["actor"], "api_key:ops");
+        assert_eq!(entries[1]["delta"], 1);
+        assert_eq!(entries[1]["value"], 1);
+    }
+}
@@ -0,0 +1,932 @@
+use jsonwebtoken::{Algorithm, Validation};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::audit::Actor;
+use crate::lock;
+use crate::ratelimit::Throttle;
+use crate::signing::Signing;
+use crate::store::Store;
+use crate::{error, ApiError, Counter};
+
+/// What a caller may do. Readers may only read, writers may also change counters and delete
+/// the ones they own, and admins may also delete any counter and export, import, purge or
+/// restore every counter at once.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    Reader,
+    Writer,
+    Admin,
+}
+
+impl Role {
+    fn parse(name: &str) -> Option<Role> {
+        match name {
+            "reader" => Some(Role::Reader),
+            "writer" => Some(Role::Writer),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ApiKey {
+    name: String,
+    role: Role,
+    prefix: Option<String>,
+    tenant: Option<String>,
+}
+
+/// Keys that requests changing counters must carry in `X-Api-Key`, configured as
+/// `api_keys = { "<key>" = "<name>" }` for writers or
+/// `api_keys = { "<key>" = { name = "<name>", role = "reader" } }`. The name, never the key, is
+/// logged with every write. With no keys configured, anyone may write.
+///
+/// A key with `prefix = "team-a/"` may only change counters whose key or name starts with it,
+/// and is at most a writer whatever its role. One with `tenant = "acme"` is bound to that tenant
+/// in path mode; see `tenant_access`.
+///
+/// Clones share the keys, and so see them reloaded.
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    keys: Arc<Mutex<HashMap<String, ApiKey>>>,
+}
+
+impl ApiKeys {
+    pub fn from_config(config: &Config) -> ApiKeys {
+        ApiKeys {
+            keys: Arc::new(Mutex::new(ApiKeys::parse(config))),
+        }
+    }
+
+    /// Replaces the keys with the ones in `config`.
+    pub fn reload(&self, config: &Config) {
+        *lock(&self.keys) = ApiKeys::parse(config);
+    }
+
+    fn parse(config: &Config) -> HashMap<String, ApiKey> {
+        config
+            .get_table("api_keys")
+            .map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        let api_key = match value.as_str() {
+                            Some(name) => ApiKey {
+                                name: name.to_string(),
+                                role: Role::Writer,
+                                prefix: None,
+                                tenant: None,
+                            },
+                            None => {
+                                let prefix = match value.get("prefix") {
+                                    Some(prefix) => Some(prefix.as_str()?.to_string()),
+                                    None => None,
+                                };
+                                let tenant = match value.get("tenant") {
+                                    Some(tenant) => Some(tenant.as_str()?.to_string()),
+                                    None => None,
+                                };
+                                let role = match value.get("role") {
+                                    Some(role) => Role::parse(role.as_str()?)?,
+                                    None => Role::Writer,
+                                };
+
+                                ApiKey {
+                                    name: value.get("name")?.as_str()?.to_string(),
+                                    role: scoped(role, &prefix),
+                                    prefix,
+                                    tenant,
+                                }
+                            }
+                        };
+
+                        Some((key.clone(), api_key))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn is_empty(&self) -> bool {
+        lock(&self.keys).is_empty()
+    }
+
+    /// The configured key, comparing every one in full so that response times do not give
+    /// away how much of a guess was right.
+    fn find(&self, given: &str) -> Option<ApiKey> {
+        lock(&self.keys).iter().fold(None, |found, (key, api_key)| {
+            if same(key.as_bytes(), given.as_bytes()) {
+                Some(api_key.clone())
+            } else {
+                found
+            }
+        })
+    }
+
+    /// Name of the key.
+    pub fn name(&self, given: &str) -> Option<String> {
+        self.find(given).map(|api_key| api_key.name)
+    }
+}
+
+/// `role`, capped at writer for callers limited to a prefix.
+fn scoped(role: Role, prefix: &Option<String>) -> Role {
+    match prefix {
+        Some(_) => role.min(Role::Writer),
+        None => role,
+    }
+}
+
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Why a request was turned away with 401, for the catcher.
+pub struct Unauthorized(pub &'static str);
+
+impl Default for Unauthorized {
+    fn default() -> Unauthorized {
+        Unauthorized("A valid X-Api-Key header or bearer token is required.")
+    }
+}
+
+/// Secret that bearer JWTs are signed with using HS256, configured as `jwt_secret`. Without
+/// it, `Authorization` headers are ignored and every caller is anonymous.
+#[derive(Clone, Default)]
+pub struct Jwt {
+    secret: Option<String>,
+}
+
+impl Jwt {
+    pub fn from_config(config: &Config) -> Jwt {
+        Jwt {
+            secret: config.get_str("jwt_secret").ok().map(String::from),
+        }
+    }
+
+    /// The claims of the bearer token in an `Authorization` header, if there is one and a
+    /// secret to check it with. Fails when the token is invalid or expired.
+    fn claims(&self, authorization: Option<&str>) -> Result<Option<Claims>, ()> {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+        let token = match authorization {
+            Some(value) if value.starts_with("Bearer ") => value["Bearer ".len()..].trim(),
+            _ => return Ok(None),
+        };
+
+        jsonwebtoken::decode::<Claims>(token, secret.as_bytes(), &Validation::new(Algorithm::HS256))
+            .map(|data| Some(data.claims))
+            .map_err(|_| ())
+    }
+}
+
+/// Whether API keys or a JWT secret are configured, so that anonymous callers may not write.
+pub fn secured(api_keys: &ApiKeys, jwt: &Jwt) -> bool {
+    !api_keys.is_empty() || jwt.secret.is_some()
+}
+
+/// For connections served outside of Rocket, such as WebSocket ones: whether the caller is
+/// identified by a configured API key or a valid bearer JWT, given its `X-Api-Key` and
+/// `Authorization` headers, as the `Reader` guard requires. Fails when the token is invalid.
+pub fn authenticate(
+    api_keys: &ApiKeys,
+    jwt: &Jwt,
+    api_key: Option<&str>,
+    authorization: Option<&str>,
+) -> Result<bool, ()> {
+    if api_key.and_then(|given| api_keys.find(given)).is_some() {
+        return Ok(true);
+    }
+
+    jwt.claims(authorization).map(|claims| claims.is_some())
+}
+
+/// Claims read from a JWT. The token must also carry `exp`, which is checked on decoding.
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// Space-separated, as in OAuth.
+    #[serde(default)]
+    scope: String,
+    /// Limits the token to counters whose key or name starts with it, as for API keys.
+    #[serde(default)]
+    prefix: Option<String>,
+    /// Binds the token to a tenant in path mode, as for API keys.
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+/// Who is calling, from a bearer JWT in `Authorization`. Fails with 401 when the token is
+/// invalid or expired rather than treating the caller as anonymous.
+pub struct Caller {
+    claims: Option<Claims>,
+}
+
+impl Caller {
+    pub fn subject(&self) -> Option<&str> {
+        self.claims.as_ref().map(|claims| claims.sub.as_str())
+    }
+
+    /// The highest role among the token's scopes. Tokens without any are writers, and so are
+    /// tokens limited to a prefix at most.
+    fn role(&self) -> Option<Role> {
+        let claims = self.claims.as_ref()?;
+        let role = claims
+            .scope
+            .split_whitespace()
+            .filter_map(Role::parse)
+            .max()
+            .unwrap_or(Role::Writer);
+
+        Some(scoped(role, &claims.prefix))
+    }
+
+    fn prefix(&self) -> Option<String> {
+        self.claims.as_ref()?.prefix.clone()
+    }
+
+    fn tenant(&self) -> Option<String> {
+        self.claims.as_ref()?.tenant.clone()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Caller {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Caller, ()> {
+        let jwt = match request.guard::<State<Jwt>>().succeeded() {
+            Some(jwt) => jwt,
+            None => return Outcome::Success(Caller { claims: None }),
+        };
+
+        match jwt.claims(request.headers().get_one("Authorization")) {
+            Ok(claims) => Outcome::Success(Caller { claims }),
+            Err(()) => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Whether new counters get a write token, `write_tokens = true`. Changing a counter with a
+/// token takes its `X-Write-Token` header, so that anyone can read it but only whoever created
+/// it can count on it, without any accounts.
+#[derive(Default)]
+pub struct WriteTokens {
+    enabled: bool,
+}
+
+impl WriteTokens {
+    pub fn from_config(config: &Config) -> WriteTokens {
+        WriteTokens {
+            enabled: config.get_bool("write_tokens").unwrap_or(false),
+        }
+    }
+
+    /// A token for a new counter, if tokens are enabled.
+    pub fn issue(&self) -> Option<String> {
+        if self.enabled {
+            Some(Uuid::new_v4().to_simple().to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// Who a request is from and what they may do. The role is `None` for anonymous requests
+/// when no API keys are configured, which may change counters while no JWTs are either. They only get admin privileges
+/// while neither API keys nor JWTs are configured, when there is no one else to give them to.
+struct Identity {
+    caller: Caller,
+    role: Option<Role>,
+    /// Whether API keys or JWTs are configured.
+    secured: bool,
+    /// Start of the keys or names of the only counters the caller may change.
+    prefix: Option<String>,
+    /// The only tenant besides the default one that the caller may use in path mode.
+    tenant: Option<String>,
+    /// For the log.
+    name: Option<String>,
+    /// For the audit trail.
+    actor: Option<String>,
+}
+
+/// Resolves the role of the request from its API key or else its bearer JWT. Fails with 401
+/// for anonymous requests once API keys are configured.
+fn identify(request: &Request) -> request::Outcome<Identity, ()> {
+    let caller = match request.guard::<Caller>() {
+        Outcome::Success(caller) => caller,
+        Outcome::Failure(failure) => return Outcome::Failure(failure),
+        Outcome::Forward(()) => return Outcome::Forward(()),
+    };
+    let api_keys = request.guard::<State<ApiKeys>>().succeeded();
+    let api_key = request.headers().get_one("X-Api-Key").and_then(|given| {
+        api_keys
+            .as_ref()
+            .and_then(|api_keys| api_keys.inner().find(given))
+    });
+    let keyed = api_keys.map_or(false, |api_keys| !api_keys.is_empty());
+    let secured = keyed
+        || request
+            .guard::<State<Jwt>>()
+            .succeeded()
+            .map_or(false, |jwt| jwt.secret.is_some());
+
+    if let Some(api_key) = api_key {
+        return Outcome::Success(Identity {
+            caller,
+            role: Some(api_key.role),
+            secured,
+            prefix: api_key.prefix,
+            tenant: api_key.tenant,
+            name: Some(format!("API key \"{}\"", api_key.name)),
+            actor: Some(format!("api_key:{}", api_key.name)),
+        });
+    }
+
+    let name = caller
+        .subject()
+        .map(|subject| format!("JWT subject \"{}\"", subject));
+
+    if name.is_none() && keyed {
+        return Outcome::Failure((Status::Unauthorized, ()));
+    }
+
+    Outcome::Success(Identity {
+        role: caller.role(),
+        secured,
+        prefix: caller.prefix(),
+        tenant: caller.tenant(),
+        actor: caller.subject().map(|subject| format!("jwt:{}", subject)),
+        caller,
+        name,
+    })
+}
+
+/// Guard of the routes that change counters. Counts against the client's rate limit, and fails
+/// with 401 unless the request carries a configured API key or a valid bearer JWT, once either
+/// is configured, or with 403 for readers. The beacon-style routes
+/// that browsers call directly, such as hits and reactions, cannot hold a secret and do not
+/// use it.
+pub struct Writer {
+    pub caller: Caller,
+    role: Option<Role>,
+    secured: bool,
+    prefix: Option<String>,
+    tenant: Option<String>,
+    write_token: Option<String>,
+}
+
+impl Writer {
+    fn is_admin(&self) -> bool {
+        self.role == Some(Role::Admin)
+    }
+
+    /// Counters without an owner can be changed by anyone allowed to write, owned ones only by
+    /// their owner or an admin.
+    fn may_change(&self, counter: &Counter) -> bool {
+        match &counter.owner {
+            Some(owner) => self.caller.subject() == Some(owner.as_str()) || self.is_admin(),
+            None => true,
+        }
+    }
+
+    /// Fails with 403 unless the caller may change counter `id`, or with 401 if the counter
+    /// has a write token that the request does not carry. Admins need neither. A counter that
+    /// does not exist is left for the route to deal with, unless the caller is limited to a
+    /// prefix, as it would be created without a key or name under it.
+    pub fn check(&self, id: Uuid, store: &Store) -> Result<(), ApiError> {
+        let counter = match store.get(id) {
+            Some(counter) => counter,
+            None => return self.check_scope(&Counter::new(id)),
+        };
+
+        self.check_scope(&counter)?;
+
+        if !self.may_change(&counter) {
+            return Err(error(
+                Status::Forbidden,
+                "Only the counter's owner can change it.",
+            ));
+        }
+
+        match (&counter.write_token, &self.write_token) {
+            (Some(expected), Some(given)) if same(expected.as_bytes(), given.as_bytes()) => Ok(()),
+            (Some(_), _) if !self.is_admin() => Err(error(
+                Status::Unauthorized,
+                "A valid X-Write-Token header is required.",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with 403 if the caller is limited to a prefix that neither the key nor the name of
+    /// `counter` starts with. Checked by `check`, and by routes creating or renaming counters
+    /// once they know the new name.
+    pub fn check_scope(&self, counter: &Counter) -> Result<(), ApiError> {
+        let prefix = match &self.prefix {
+            Some(prefix) => prefix,
+            None => return Ok(()),
+        };
+        let in_scope = [&counter.key, &counter.name].iter().any(|label| {
+            label
+                .as_ref()
+                .map_or(false, |label| label.starts_with(prefix))
+        });
+
+        if in_scope {
+            Ok(())
+        } else {
+            Err(error(
+                Status::Forbidden,
+                &format!(
+                    "Only counters whose key or name starts with \"{}\" can be changed.",
+                    prefix
+                ),
+            ))
+        }
+    }
+
+    /// Fails with 403 unless the caller owns `counter` or is an admin, for handing it to someone
+    /// else. Taking a counter without an owner is up to admins.
+    pub fn check_owner(&self, counter: &Counter) -> Result<(), ApiError> {
+        match &counter.owner {
+            Some(owner) if self.caller.subject() == Some(owner.as_str()) || self.is_admin() => {
+                Ok(())
+            }
+            Some(_) => Err(error(
+                Status::Forbidden,
+                "Only the counter's owner or an admin can transfer it.",
+            )),
+            None => self.check_admin(),
+        }
+    }
+
+    /// For routes that delete or change every counter at once: fails with 403 for identified
+    /// callers without the admin role, and with 401 for anonymous ones once API keys or JWTs
+    /// are configured.
+    pub fn check_admin(&self) -> Result<(), ApiError> {
+        match self.role {
+            Some(role) if role >= Role::Admin => Ok(()),
+            Some(_) => Err(error(Status::Forbidden, "The admin role is required.")),
+            None if self.secured => Err(error(Status::Unauthorized, Unauthorized::default().0)),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails with 403 unless the caller may use `tenant` in path mode, for moving counters to
+    /// it. Anyone may while neither API keys nor JWTs are configured.
+    pub fn check_tenant(&self, tenant: &str) -> Result<(), ApiError> {
+        if !self.secured || grants(&self.tenant, self.role, tenant) {
+            Ok(())
+        } else {
+            Err(error(
+                Status::Forbidden,
+                "Only keys and tokens bound to the tenant can use it.",
+            ))
+        }
+    }
+
+    /// Whether the caller's key or token is bound to `tenant` or is an admin's, so that its
+    /// writes may create the tenant.
+    pub fn is_bound_to(&self, tenant: &str) -> bool {
+        self.secured && grants(&self.tenant, self.role, tenant)
+    }
+}
+
+/// Whether a caller bound to `bound`, if any, may use `tenant`, empty for the default tenant,
+/// which anyone may use. Callers bound to no tenant may only use other ones as admins.
+fn grants(bound: &Option<String>, role: Option<Role>, tenant: &str) -> bool {
+    tenant.is_empty()
+        || match bound {
+            Some(bound) => bound == tenant,
+            None => role == Some(Role::Admin),
+        }
+}
+
+/// Who may use a tenant other than the default one in path mode.
+#[derive(PartialEq)]
+pub enum TenantAccess {
+    /// Neither API keys nor JWTs are configured, so there are no credentials to bind it to.
+    Open,
+    /// The request's API key or bearer JWT is bound to the tenant, with `tenant` in the key's
+    /// configuration or in the token's claims, or is an admin's bound to no tenant.
+    Bound,
+    Denied,
+}
+
+pub fn tenant_access(request: &Request, tenant: &str) -> TenantAccess {
+    match identify(request) {
+        Outcome::Success(Identity { secured: false, .. }) => TenantAccess::Open,
+        Outcome::Success(identity) if grants(&identity.tenant, identity.role, tenant) => {
+            TenantAccess::Bound
+        }
+        _ => TenantAccess::Denied,
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Writer {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Writer, ()> {
+        if let Outcome::Failure(failure) = request.guard::<Throttle>() {
+            return Outcome::Failure(failure);
+        }
+
+        let signed = request
+            .guard::<State<Signing>>()
+            .succeeded()
+            .map_or(true, |signing| signing.check_header(request));
+
+        if !signed {
+            request.local_cache(|| Unauthorized("Request signature is missing, stale or invalid."));
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
+
+        let identity = match identify(request) {
+            Outcome::Success(identity) => identity,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
+
+        match identity.role {
+            Some(Role::Reader) => return Outcome::Failure((Status::Forbidden, ())),
+            None if identity.secured => return Outcome::Failure((Status::Unauthorized, ())),
+            _ => {}
+        }
+
+        request.local_cache(|| Actor(identity.actor.clone()));
+
+        Outcome::Success(Writer {
+            caller: identity.caller,
+            role: identity.role,
+            secured: identity.secured,
+            prefix: identity.prefix,
+            tenant: identity.tenant,
+            write_token: request.headers().get_one("X-Write-Token").map(String::from),
+        })
+    }
+}
+
+/// Guard of the admin routes that read every counter, such as exports. Fails like `Writer`
+/// does, with 403 for callers without the admin role, and with 401 for anonymous callers once
+/// API keys or JWTs are configured.
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        match identify(request) {
+            Outcome::Success(identity) => match identity.role {
+                Some(role) if role >= Role::Admin => Outcome::Success(Admin),
+                Some(_) => Outcome::Failure((Status::Forbidden, ())),
+                None if identity.secured => Outcome::Failure((Status::Unauthorized, ())),
+                None => Outcome::Success(Admin),
+            },
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(()) => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Guard of reads once `public_reads` is off. Fails with 401 unless the request carries a
+/// configured API key or a valid bearer JWT, whatever its role.
+pub struct Reader;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Reader {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Reader, ()> {
+        match identify(request) {
+            Outcome::Success(Identity { name: Some(_), .. }) => Outcome::Success(Reader),
+            Outcome::Success(_) => Outcome::Failure((Status::Unauthorized, ())),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(()) => Outcome::Forward(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn require_api_key_for_writes() {
+        let mut api_keys = BTreeMap::new();
+
+        api_keys.insert(
+            "s3cret".to_string(),
+            Value::String("deploy bot".to_string()),
+        );
+
+        let config = Config::build(Environment::Development)
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut anonymous_response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+        assert_eq!(anonymous_response.status(), Status::Unauthorized);
+        assert!(anonymous_response
+            .body_string()
+            .unwrap()
+            .contains("X-Api-Key"));
+
+        let wrong_key_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(Header::new("X-Api-Key", "s3cre"))
+            .dispatch();
+
+        assert_eq!(wrong_key_response.status(), Status::Unauthorized);
+
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(Header::new("X-Api-Key", "s3cret"))
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let read_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+
+        assert_eq!(read_response.status(), Status::Ok);
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn enforce_roles() {
+        let role = |name: &str, role: &str| {
+            let mut key = BTreeMap::new();
+
+            key.insert("name".to_string(), Value::String(name.to_string()));
+            key.insert("role".to_string(), Value::String(role.to_string()));
+            Value::Table(key)
+        };
+        let mut api_keys = BTreeMap::new();
+
+        api_keys.insert("r".to_string(), role("dashboard", "reader"));
+        api_keys.insert("w".to_string(), Value::String("deploy bot".to_string()));
+        api_keys.insert("a".to_string(), role("ops", "admin"));
+
+        let config = Config::build(Environment::Development)
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let create = |key: &'static str| {
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .header(Header::new("X-Api-Key", key))
+                .dispatch()
+                .status()
+        };
+        let export = |key: &'static str| {
+            client
+                .get("/counter/export.csv")
+                .header(Header::new("X-Api-Key", key))
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(create("r"), Status::Forbidden);
+        assert_eq!(create("w"), Status::Ok);
+        assert_eq!(create("a"), Status::Ok);
+        assert_eq!(export("r"), Status::Forbidden);
+        assert_eq!(export("w"), Status::Forbidden);
+        assert_eq!(export("a"), Status::Ok);
+        assert_eq!(
+            client.get("/counter/export.csv").dispatch().status(),
+            Status::Unauthorized
+        );
+    }
+
+    #[test]
+    fn scope_keys_to_prefix() {
+        let mut key = BTreeMap::new();
+        let mut api_keys = BTreeMap::new();
+
+        key.insert("name".to_string(), Value::String("team a".to_string()));
+        key.insert("role".to_string(), Value::String("admin".to_string()));
+        key.insert("prefix".to_string(), Value::String("team-a/".to_string()));
+        api_keys.insert("a".to_string(), Value::Table(key));
+        api_keys.insert("w".to_string(), Value::String("deploy bot".to_string()));
+
+        let config = Config::build(Environment::Development)
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let create = |key: &'static str, body: &'static str| {
+            let mut response = client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .header(Header::new("X-Api-Key", key))
+                .body(body)
+                .dispatch();
+            let status = response.status();
+
+            (status, response.body_string().unwrap())
+        };
+        let increment = |key: &'static str, id: uuid::Uuid| {
+            client
+                .put(format!("/counter/{}/increment", id))
+                .header(Header::new("X-Api-Key", key))
+                .dispatch()
+                .status()
+        };
+
+        let (status, body) = create("a", r#"{ "name": "team-a/signups" }"#);
+        let ours: Counter = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, Status::Ok);
+        assert_eq!(
+            create("a", r#"{ "key": "team-b/signups" }"#).0,
+            Status::Forbidden
+        );
+        assert_eq!(create("a", "{}").0, Status::Forbidden);
+
+        let (_, body) = create("w", r#"{ "name": "team-b/signups" }"#);
+        let theirs: Counter = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(increment("a", ours.id), Status::Ok);
+        assert_eq!(increment("a", theirs.id), Status::Forbidden);
+        assert_eq!(increment("a", uuid::Uuid::new_v4()), Status::Forbidden);
+        assert_eq!(increment("w", ours.id), Status::Ok);
+
+        let rename = client
+            .patch(format!("/counter/{}", ours.id))
+            .header(ContentType::JSON)
+            .header(Header::new("X-Api-Key", "a"))
+            .body(r#"{ "name": "team-b/signups" }"#)
+            .dispatch();
+
+        assert_eq!(rename.status(), Status::Forbidden);
+
+        // The prefix caps the key at writer, whatever its role.
+        let export = client
+            .get("/counter/export.csv")
+            .header(Header::new("X-Api-Key", "a"))
+            .dispatch();
+
+        assert_eq!(export.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn refuse_anonymous_admins_with_jwt() {
+        let config = Config::build(Environment::Development)
+            .extra("jwt_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let deprecations = |scope: Option<&str>| {
+            let mut request = client.get("/admin/deprecations");
+
+            if let Some(scope) = scope {
+                let claims =
+                    serde_json::json!({ "sub": "alice", "scope": scope, "exp": 4_102_444_800u64 });
+                let token =
+                    jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, b"s3cret")
+                        .unwrap();
+
+                request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+            }
+
+            request.dispatch().status()
+        };
+
+        assert_eq!(deprecations(None), Status::Unauthorized);
+        assert_eq!(deprecations(Some("")), Status::Forbidden);
+        assert_eq!(deprecations(Some("admin")), Status::Ok);
+        assert_eq!(
+            client.get("/counter/export.csv").dispatch().status(),
+            Status::Unauthorized
+        );
+        // Nor may anonymous callers write.
+        assert_eq!(
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .dispatch()
+                .status(),
+            Status::Unauthorized
+        );
+    }
+
+    #[test]
+    fn restrict_counters_to_owner() {
+        let config = Config::build(Environment::Development)
+            .extra("jwt_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let token = |subject: &str, scope: &str| {
+            let claims =
+                serde_json::json!({ "sub": subject, "scope": scope, "exp": 4_102_444_800u64 });
+            let token =
+                jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, b"s3cret").unwrap();
+
+            Header::new("Authorization", format!("Bearer {}", token))
+        };
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(token("alice", ""))
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.owner, Some("alice".to_string()));
+
+        let increment = |authorization: Option<Header<'static>>| {
+            let mut request = client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON);
+
+            if let Some(authorization) = authorization {
+                request = request.header(authorization);
+            }
+
+            request.dispatch().status()
+        };
+
+        assert_eq!(increment(None), Status::Unauthorized);
+        assert_eq!(increment(Some(token("bob", ""))), Status::Forbidden);
+        assert_eq!(increment(Some(token("alice", ""))), Status::Ok);
+        assert_eq!(increment(Some(token("bob", "read admin"))), Status::Ok);
+        assert_eq!(
+            increment(Some(Header::new("Authorization", "Bearer not-a-token"))),
+            Status::Unauthorized
+        );
+
+        let mut mine_response = client
+            .get("/counter?mine=true")
+            .header(token("alice", ""))
+            .dispatch();
+        let mine: Vec<Counter> =
+            serde_json::from_str(&mine_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(mine.len(), 1);
+        assert_eq!(mine[0].value, 2);
+
+        let anonymous_response = client.get("/counter?mine=true").dispatch();
+
+        assert_eq!(anonymous_response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn require_write_token() {
+        let config = Config::build(Environment::Development)
+            .extra("write_tokens", true)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let created: serde_json::Value =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let id = created["id"].as_str().unwrap();
+        let write_token = created["write_token"].as_str().unwrap().to_string();
+
+        let anonymous_response = client
+            .put(format!("/counter/{}/increment", id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(anonymous_response.status(), Status::Unauthorized);
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", id))
+            .header(ContentType::JSON)
+            .header(Header::new("X-Write-Token", write_token))
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Ok);
+
+        let mut read_response = client.get(format!("/counter/{}", id)).dispatch();
+        let body_string = read_response.body_string().unwrap();
+
+        assert!(body_string.contains(r#""value":1"#));
+        assert!(!body_string.contains("write_token"));
+    }
+}
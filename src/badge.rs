@@ -0,0 +1,223 @@
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::Json;
+use std::io::Cursor;
+
+use crate::tenancy::TenantStore;
+use crate::{not_found_error, parse_id, ApiError};
+
+const NAMED_COLORS: &[(&str, &str)] = &[
+    ("brightgreen", "#4c1"),
+    ("green", "#97ca00"),
+    ("yellowgreen", "#a4a61d"),
+    ("yellow", "#dfb317"),
+    ("orange", "#fe7d37"),
+    ("red", "#e05d44"),
+    ("blue", "#007ec6"),
+    ("lightgrey", "#9f9f9f"),
+    ("grey", "#555"),
+];
+const LABEL_COLOR: &str = "#555";
+const MAX_LABEL_LENGTH: usize = 64;
+
+/// `color` if it is one of the shields.io names or a 3 or 6 digit hex code, with or without
+/// the leading `#`, which is dropped. Anything else is blue.
+fn color(color: Option<String>) -> String {
+    let color = color.unwrap_or_default();
+    let color = color.trim_start_matches('#');
+    let named = NAMED_COLORS.iter().any(|(name, _)| *name == color);
+    let hex =
+        (color.len() == 3 || color.len() == 6) && color.chars().all(|c| c.is_ascii_hexdigit());
+
+    if named || hex {
+        color.to_string()
+    } else {
+        "blue".to_string()
+    }
+}
+
+fn css_color(color: &str) -> String {
+    match NAMED_COLORS.iter().find(|(name, _)| *name == color) {
+        Some((_, css)) => css.to_string(),
+        None => format!("#{}", color),
+    }
+}
+
+fn label(label: Option<String>) -> String {
+    label
+        .unwrap_or_else(|| "count".to_string())
+        .chars()
+        .take(MAX_LABEL_LENGTH)
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Rough width of `text` in 11px Verdana, which is what the badge is set in.
+fn text_width(text: &str) -> usize {
+    text.chars().count() * 7 + 10
+}
+
+fn render(label: &str, message: &str, color: &str) -> String {
+    let label_width = text_width(label);
+    let message_width = text_width(message);
+    let width = label_width + message_width;
+    let label = escape(label);
+    let message = escape(message);
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}">
+<title>{label}: {message}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="{label_color}"/><rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+<text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{label_x}" y="14">{label}</text>
+<text x="{message_x}" y="15" fill="#010101" fill-opacity=".3">{message}</text><text x="{message_x}" y="14">{message}</text>
+</g>
+</svg>
+"##,
+        width = width,
+        label_width = label_width,
+        message_width = message_width,
+        label_color = LABEL_COLOR,
+        color = color,
+        label = label,
+        message = message,
+        label_x = label_width / 2,
+        message_x = label_width + message_width / 2,
+    )
+}
+
+/// An SVG image that caches may keep for a minute.
+pub struct Svg(String);
+
+impl<'r> Responder<'r> for Svg {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::SVG)
+            .raw_header("Cache-Control", "public, max-age=60")
+            .sized_body(Cursor::new(self.0))
+            .ok()
+    }
+}
+
+/// A shields-style badge showing the counter's value, for READMEs and web pages.
+#[get("/<id>/badge.svg?<label>&<color>")]
+pub fn badge(
+    id: String,
+    label: Option<String>,
+    color: Option<String>,
+    store: TenantStore,
+) -> Result<Svg, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    Ok(Svg(render(
+        &self::label(label),
+        &counter.value.to_string(),
+        &css_color(&self::color(color)),
+    )))
+}
+
+/// Endpoint badge for shields.io, see https://shields.io/badges/endpoint-badge
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Shield {
+    schema_version: u8,
+    label: String,
+    message: String,
+    color: String,
+}
+
+#[get("/<id>/shield?<label>&<color>")]
+pub fn shield(
+    id: String,
+    label: Option<String>,
+    color: Option<String>,
+    store: TenantStore,
+) -> Result<Json<Shield>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    Ok(Json(Shield {
+        schema_version: 1,
+        label: self::label(label),
+        message: counter.value.to_string(),
+        color: self::color(color),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn render_badge() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let mut response = client
+            .get(format!(
+                "/counter/{}/badge.svg?label=visitors%20%3C3&color=green",
+                counter.id
+            ))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("public, max-age=60")
+        );
+
+        let svg = response.body_string().unwrap();
+
+        assert!(svg.contains("<title>visitors &lt;3: 1</title>"));
+        assert!(svg.contains("fill=\"#97ca00\""));
+    }
+
+    #[test]
+    fn serve_shields_endpoint() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut response = client
+            .get(format!(
+                "/counter/{}/shield?label=stars&color=%23ff69b4",
+                counter.id
+            ))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.body_string(),
+            Some(
+                r#"{"schemaVersion":1,"label":"stars","message":"0","color":"ff69b4"}"#.to_string()
+            )
+        );
+
+        let unknown_response = client
+            .get(format!("/counter/{}/shield", uuid::Uuid::new_v4()))
+            .dispatch();
+
+        assert_eq!(unknown_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,388 @@
+//! `caas bench`, a load test of a running instance, as in
+//! `caas bench --concurrency 32 --duration 60 --mix inc=80,get=20`. It creates `--counters`
+//! counters named `bench-<n>`, which are left behind, and keeps that many workers sending the
+//! operations of the mix to them at random, each as soon as its previous one is answered. One
+//! counter puts every worker on the same lock; more spread them out.
+//!
+//! At the end it prints the throughput and the latency percentiles of every operation. For soak
+//! tests, `--report-every` also prints the throughput and errors of every interval while it
+//! runs, so that a slowdown or a leak shows up as it happens.
+
+use counter_client::{CounterClient, Error, NewCounter};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::client::Command;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Increment,
+    Decrement,
+    Get,
+    List,
+}
+
+impl Operation {
+    fn parse(name: &str) -> Option<Operation> {
+        match name {
+            "inc" => Some(Operation::Increment),
+            "dec" => Some(Operation::Decrement),
+            "get" => Some(Operation::Get),
+            "list" => Some(Operation::List),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Increment => "inc",
+            Operation::Decrement => "dec",
+            Operation::Get => "get",
+            Operation::List => "list",
+        }
+    }
+}
+
+pub struct Plan {
+    pub concurrency: usize,
+    pub duration: Duration,
+    pub counters: usize,
+    /// Operations with their relative weights.
+    pub mix: Vec<(Operation, u32)>,
+    /// How often to print progress, if at all.
+    pub report_every: Option<Duration>,
+}
+
+/// A mix such as `inc=80,get=20`, each operation with its weight.
+pub fn parse_mix(mix: &str) -> Result<Vec<(Operation, u32)>, String> {
+    let mix = mix
+        .split(',')
+        .map(|part| {
+            let mut halves = part.trim().splitn(2, '=');
+            let operation = halves.next().and_then(Operation::parse).ok_or_else(|| {
+                format!("unknown operation in {:?}, use inc, dec, get or list", part)
+            })?;
+            let weight = match halves.next() {
+                Some(weight) => weight
+                    .parse()
+                    .map_err(|_| format!("weight of {:?} must be a number", part))?,
+                None => 1,
+            };
+
+            Ok((operation, weight))
+        })
+        .collect::<Result<Vec<(Operation, u32)>, String>>()?;
+
+    if mix.iter().all(|(_, weight)| *weight == 0) {
+        return Err("at least one operation must have a weight".to_string());
+    }
+
+    Ok(mix)
+}
+
+/// xorshift64*, random enough to spread operations and counters, without a request's worth
+/// of work per pick.
+struct Random(u64);
+
+impl Random {
+    fn new() -> Random {
+        let mut seed = [0; 8];
+
+        seed.copy_from_slice(&Uuid::new_v4().as_bytes()[..8]);
+        Random(u64::from_le_bytes(seed) | 1)
+    }
+
+    /// A number below `bound`, which must be positive.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % bound
+    }
+
+    fn pick(&mut self, mix: &[(Operation, u32)]) -> Operation {
+        let total: u64 = mix.iter().map(|(_, weight)| u64::from(*weight)).sum();
+        let mut pick = self.below(total);
+
+        for (operation, weight) in mix {
+            if pick < u64::from(*weight) {
+                return *operation;
+            }
+
+            pick -= u64::from(*weight);
+        }
+
+        mix[0].0
+    }
+}
+
+/// Latencies in microseconds and errors of one operation.
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<u64>,
+    errors: u64,
+}
+
+impl Samples {
+    fn extend(&mut self, other: Samples) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+}
+
+#[derive(Serialize)]
+struct Latency {
+    requests: u64,
+    errors: u64,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+impl Latency {
+    fn of(samples: &mut Samples) -> Latency {
+        samples.latencies.sort_unstable();
+
+        let latencies = &samples.latencies;
+        let percentile = |percent: usize| {
+            if latencies.is_empty() {
+                return 0.0;
+            }
+
+            let index = (latencies.len() * percent / 100).min(latencies.len() - 1);
+
+            latencies[index] as f64 / 1000.0
+        };
+
+        Latency {
+            requests: latencies.len() as u64 + samples.errors,
+            errors: samples.errors,
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: latencies.last().map_or(0.0, |max| *max as f64 / 1000.0),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report {
+    seconds: f64,
+    concurrency: usize,
+    requests_per_second: f64,
+    overall: Latency,
+    operations: BTreeMap<&'static str, Latency>,
+}
+
+fn client(url: &str, api_key: &Option<String>) -> CounterClient {
+    let client = CounterClient::new(url);
+
+    match api_key {
+        Some(api_key) => client.with_api_key(api_key),
+        None => client,
+    }
+}
+
+/// Sends one operation, counting a change that is accepted but still running as done.
+fn send(client: &CounterClient, operation: Operation, id: Uuid) -> Result<(), Error> {
+    let result = match operation {
+        Operation::Increment => client.increment(id).map(|_| ()),
+        Operation::Decrement => client.decrement(id).map(|_| ()),
+        Operation::Get => client.get(id).map(|_| ()),
+        Operation::List => client.list().map(|_| ()),
+    };
+
+    match result {
+        Err(Error::Pending { .. }) => Ok(()),
+        result => result,
+    }
+}
+
+/// Counts of requests and errors so far, for progress reports.
+#[derive(Default)]
+struct Progress {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+fn work(
+    client: &CounterClient,
+    mix: &[(Operation, u32)],
+    ids: &[Uuid],
+    until: Instant,
+    progress: &Progress,
+) -> BTreeMap<Operation, Samples> {
+    let mut random = Random::new();
+    let mut samples: BTreeMap<Operation, Samples> = BTreeMap::new();
+
+    while Instant::now() < until {
+        let operation = random.pick(mix);
+        let id = ids[random.below(ids.len() as u64) as usize];
+        let started = Instant::now();
+        let result = send(client, operation, id);
+        let samples = samples.entry(operation).or_default();
+
+        progress.requests.fetch_add(1, Ordering::Relaxed);
+
+        match result {
+            Ok(()) => samples.latencies.push(started.elapsed().as_micros() as u64),
+            Err(_) => {
+                samples.errors += 1;
+                progress.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    samples
+}
+
+/// Prints the throughput and errors of every `interval` that ends by `until`.
+fn report_progress(progress: &Progress, interval: Duration, started: Instant, until: Instant) {
+    let (mut requests, mut errors) = (0, 0);
+    let mut next = started + interval;
+
+    while next <= until {
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+        next += interval;
+
+        let now_requests = progress.requests.load(Ordering::Relaxed);
+        let now_errors = progress.errors.load(Ordering::Relaxed);
+
+        eprintln!(
+            "{:>6.0}s  {:>10.1} req/s  {:>6} errors",
+            started.elapsed().as_secs_f64(),
+            (now_requests - requests) as f64 / interval.as_secs_f64(),
+            now_errors - errors
+        );
+        requests = now_requests;
+        errors = now_errors;
+    }
+}
+
+/// Runs the plan against the command's instance, returning the process exit code.
+pub fn run(command: &Command, plan: &Plan) -> i32 {
+    let setup = client(&command.url, &command.api_key);
+    let mut ids = Vec::with_capacity(plan.counters);
+
+    for number in 1..=plan.counters {
+        match setup.create(&NewCounter::named(&format!("bench-{}", number))) {
+            Ok(counter) => ids.push(counter.id),
+            Err(error) => {
+                eprintln!("Could not create the counters to run against: {}", error);
+                return 1;
+            }
+        }
+    }
+
+    let ids = Arc::new(ids);
+    let mix = Arc::new(plan.mix.clone());
+    let progress = Arc::new(Progress::default());
+    let started = Instant::now();
+    let until = started + plan.duration;
+    let workers: Vec<_> = (0..plan.concurrency)
+        .map(|_| {
+            let client = client(&command.url, &command.api_key);
+            let (ids, mix, progress) = (Arc::clone(&ids), Arc::clone(&mix), Arc::clone(&progress));
+
+            thread::spawn(move || work(&client, &mix, &ids, until, &progress))
+        })
+        .collect();
+    let reporter = plan.report_every.map(|interval| {
+        let progress = Arc::clone(&progress);
+
+        thread::spawn(move || report_progress(&progress, interval, started, until))
+    });
+    let mut samples: BTreeMap<Operation, Samples> = BTreeMap::new();
+
+    for worker in workers {
+        for (operation, worker_samples) in worker.join().unwrap_or_default() {
+            samples.entry(operation).or_default().extend(worker_samples);
+        }
+    }
+
+    if let Some(reporter) = reporter {
+        let _ = reporter.join();
+    }
+
+    let seconds = started.elapsed().as_secs_f64();
+    let mut overall = Samples::default();
+    let mut operations = BTreeMap::new();
+
+    for (operation, mut samples) in samples {
+        operations.insert(operation.name(), Latency::of(&mut samples));
+        overall.extend(samples);
+    }
+
+    let overall = Latency::of(&mut overall);
+    let report = Report {
+        seconds,
+        concurrency: plan.concurrency,
+        requests_per_second: overall.requests as f64 / seconds,
+        overall,
+        operations,
+    };
+
+    print(&report, command.json);
+    0
+}
+
+fn print(report: &Report, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(report).unwrap());
+        return;
+    }
+
+    println!(
+        "{} requests in {:.1}s with {} workers, {:.1} req/s, {} errors",
+        report.overall.requests,
+        report.seconds,
+        report.concurrency,
+        report.requests_per_second,
+        report.overall.errors
+    );
+    println!(
+        "{:<8}{:>10}{:>8}{:>10}{:>10}{:>10}{:>10}",
+        "", "requests", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+
+    let line = |name: &str, latency: &Latency| {
+        println!(
+            "{:<8}{:>10}{:>8}{:>10.2}{:>10.2}{:>10.2}{:>10.2}",
+            name,
+            latency.requests,
+            latency.errors,
+            latency.p50_ms,
+            latency.p90_ms,
+            latency.p99_ms,
+            latency.max_ms
+        )
+    };
+
+    for (name, latency) in &report.operations {
+        line(name, latency);
+    }
+
+    line("all", &report.overall);
+}
+
+#[cfg(test)]
+mod test {
+
+    #[test]
+    fn parse_bench_mix() {
+        let mix = super::parse_mix("inc=80, get=20,list").unwrap();
+
+        assert_eq!(
+            mix.iter().map(|(_, weight)| *weight).collect::<Vec<u32>>(),
+            vec![80, 20, 1]
+        );
+        assert!(super::parse_mix("inc=80,explode=1").is_err());
+        assert!(super::parse_mix("inc=lots").is_err());
+        assert!(super::parse_mix("inc=0,dec=0").is_err());
+    }
+}
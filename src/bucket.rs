@@ -0,0 +1,215 @@
+//! Token buckets that other services take from, so that a fleet can share one rate limit. A
+//! bucket holds up to `capacity` tokens and gains `refill_per_second` of them back over time.
+//! Taking is atomic: either every token asked for is taken, or none is.
+
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::lock;
+use crate::negotiation::{Body, Unused};
+use crate::validation::Validate;
+use crate::{error, not_found_error, parse_id, ApiError};
+
+/// Most buckets that can exist at once.
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    capacity: u64,
+    refill_per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = (now - self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity as f64);
+        self.updated = now;
+    }
+
+    fn view(&self, id: Uuid) -> View {
+        View {
+            id,
+            capacity: self.capacity,
+            refill_per_second: self.refill_per_second,
+            remaining: self.tokens.floor() as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Buckets(Mutex<HashMap<Uuid, Bucket>>);
+
+#[derive(Deserialize)]
+pub struct NewBucket {
+    capacity: u64,
+    refill_per_second: f64,
+}
+
+impl Validate for NewBucket {}
+
+#[derive(Serialize)]
+pub struct View {
+    id: Uuid,
+    capacity: u64,
+    refill_per_second: f64,
+    /// Whole tokens that can be taken right now.
+    remaining: u64,
+}
+
+#[derive(Serialize)]
+pub struct Taken {
+    allowed: bool,
+    remaining: u64,
+    /// Milliseconds until the tokens asked for will be there, when they were not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+}
+
+/// `POST /bucket` with `{ "capacity": 100, "refill_per_second": 10 }`. New buckets are full.
+#[post("/", data = "<new_bucket>")]
+pub fn create_bucket(
+    new_bucket: Body<NewBucket>,
+    buckets: State<Buckets>,
+    dry_run: DryRun,
+    _writer: Writer,
+) -> Result<Json<View>, ApiError> {
+    dry_run.refuse()?;
+
+    let NewBucket {
+        capacity,
+        refill_per_second,
+    } = new_bucket.into_inner();
+
+    if capacity == 0 || !refill_per_second.is_finite() || refill_per_second <= 0.0 {
+        return Err(error(
+            Status::BadRequest,
+            "Capacity and refill rate must be positive.",
+        ));
+    }
+
+    let mut buckets = lock(&buckets.0);
+
+    if buckets.len() >= MAX_BUCKETS {
+        return Err(error(Status::InsufficientStorage, "Bucket limit reached."));
+    }
+
+    let id = Uuid::new_v4();
+    let bucket = Bucket {
+        capacity,
+        refill_per_second,
+        tokens: capacity as f64,
+        updated: Instant::now(),
+    };
+    let view = bucket.view(id);
+
+    buckets.insert(id, bucket);
+    Ok(Json(view))
+}
+
+#[get("/<id>")]
+pub fn get_bucket(id: String, buckets: State<Buckets>) -> Result<Json<View>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let mut buckets = lock(&buckets.0);
+    let bucket = buckets.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+    bucket.refill(Instant::now());
+    Ok(Json(bucket.view(parsed_uuid)))
+}
+
+/// `POST /bucket/<id>/take?n=1` takes `n` tokens if there are that many, answering whether it
+/// did with 200 either way, so that a denial is not mistaken for this service's own rate limit.
+#[post("/<id>/take?<n>", data = "<_body>")]
+pub fn take(
+    id: String,
+    n: Option<u64>,
+    buckets: State<Buckets>,
+    dry_run: DryRun,
+    _writer: Writer,
+    _body: Unused,
+) -> Result<Json<Taken>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let n = n.unwrap_or(1);
+    let mut buckets = lock(&buckets.0);
+    let bucket = buckets.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+    if n == 0 || n > bucket.capacity {
+        return Err(error(
+            Status::BadRequest,
+            "Tokens taken must be between 1 and the bucket's capacity.",
+        ));
+    }
+
+    bucket.refill(Instant::now());
+
+    let wanted = n as f64;
+    let allowed = bucket.tokens >= wanted;
+
+    if allowed {
+        bucket.tokens -= wanted;
+    }
+
+    Ok(Json(Taken {
+        allowed,
+        remaining: bucket.tokens.floor() as u64,
+        retry_after_ms: if allowed {
+            None
+        } else {
+            Some(((wanted - bucket.tokens) / bucket.refill_per_second * 1000.0).ceil() as u64)
+        },
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::ContentType;
+    use rocket::local::Client;
+
+    use crate::rocket;
+
+    #[test]
+    fn take_from_token_bucket() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/v1/bucket")
+            .header(ContentType::JSON)
+            .body(r#"{ "capacity": 3, "refill_per_second": 0.01 }"#)
+            .dispatch();
+        let bucket: serde_json::Value =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let take = |n: u32| {
+            let mut response = client
+                .post(format!(
+                    "/v1/bucket/{}/take?n={}",
+                    bucket["id"].as_str().unwrap(),
+                    n
+                ))
+                .dispatch();
+
+            serde_json::from_str::<serde_json::Value>(&response.body_string().unwrap()).unwrap()
+        };
+
+        let taken = take(2);
+
+        assert_eq!(taken["allowed"], true);
+        assert_eq!(taken["remaining"], 1);
+
+        let denied = take(2);
+
+        assert_eq!(denied["allowed"], false);
+        assert_eq!(denied["remaining"], 1);
+        assert!(denied["retry_after_ms"].as_u64().unwrap() > 0);
+
+        assert_eq!(take(1)["allowed"], true);
+    }
+}
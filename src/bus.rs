@@ -0,0 +1,257 @@
+use kafka::producer::{Producer, Record, RequiredAcks};
+use rocket::Config;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::history;
+use crate::lock;
+use crate::store::Store;
+use crate::Counter;
+
+const DEFAULT_TOPIC: &str = "counters";
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'static str,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    counter: &'a Counter,
+}
+
+/// Publishing of a JSON event for every counter change to a message bus, for analytics
+/// downstream. With `kafka_brokers = "host:port,..."` events go to the `kafka_topic` topic keyed
+/// by counter id, so that each counter's events stay in order. With `nats_server = "host:port"`
+/// they go to subject `<nats_subject>.<id>`. Both topic and subject default to `counters`.
+pub enum Bus {
+    Kafka { hosts: Vec<String>, topic: String },
+    Nats { address: String, subject: String },
+}
+
+impl Bus {
+    pub fn from_config(config: &Config) -> Option<Bus> {
+        let name = |key| {
+            config
+                .get_str(key)
+                .unwrap_or(DEFAULT_TOPIC)
+                .trim()
+                .to_string()
+        };
+
+        if let Ok(brokers) = config.get_str("kafka_brokers") {
+            Some(Bus::Kafka {
+                hosts: brokers
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(String::from)
+                    .collect(),
+                topic: name("kafka_topic"),
+            })
+        } else if let Ok(server) = config.get_str("nats_server") {
+            Some(Bus::Nats {
+                address: server.trim().to_string(),
+                subject: name("nats_subject"),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Starts publishing changes to the counters in `store`. Events that cannot be delivered
+    /// are logged and dropped.
+    pub fn publish(self, store: &Store) -> Result<(), String> {
+        let changes = store.subscribe();
+
+        match self {
+            Bus::Kafka { hosts, topic } => {
+                let mut producer = Producer::from_hosts(hosts)
+                    .with_ack_timeout(ACK_TIMEOUT)
+                    .with_required_acks(RequiredAcks::One)
+                    .create()
+                    .map_err(|error| error.to_string())?;
+
+                thread::spawn(move || {
+                    for counter in changes {
+                        let key = counter.id.to_string();
+                        let record =
+                            Record::from_key_value(&topic, key.as_bytes(), event(&counter));
+
+                        if let Err(error) = producer.send(&record) {
+                            eprintln!("Kafka publish failed: {}", error);
+                        }
+                    }
+                });
+            }
+            Bus::Nats { address, subject } => {
+                let mut nats = Nats::connect(address).map_err(|error| error.to_string())?;
+
+                thread::spawn(move || {
+                    for counter in changes {
+                        let subject = format!("{}.{}", subject, counter.id);
+
+                        if let Err(error) = nats.publish(&subject, &event(&counter)) {
+                            eprintln!("NATS publish failed: {}", error);
+                        }
+                    }
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn event(counter: &Counter) -> Vec<u8> {
+    serde_json::to_vec(&Event {
+        event: "change",
+        timestamp: history::timestamp(),
+        counter,
+    })
+    .unwrap_or_default()
+}
+
+/// Just enough of the NATS client protocol to publish: a `CONNECT` after the server's `INFO`,
+/// `PUB` messages, and a `PONG` for every `PING` so the server keeps the connection open.
+struct Nats {
+    address: String,
+    connection: Option<Arc<Mutex<TcpStream>>>,
+}
+
+impl Nats {
+    fn connect(address: String) -> io::Result<Nats> {
+        let connection = Some(open(&address)?);
+
+        Ok(Nats {
+            address,
+            connection,
+        })
+    }
+
+    /// Publishes `payload`, reconnecting once if the connection was lost.
+    fn publish(&mut self, subject: &str, payload: &[u8]) -> io::Result<()> {
+        let mut message = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+
+        message.extend_from_slice(payload);
+        message.extend_from_slice(b"\r\n");
+
+        if let Some(connection) = &self.connection {
+            if lock(connection).write_all(&message).is_ok() {
+                return Ok(());
+            }
+        }
+
+        self.connection = None;
+
+        let connection = open(&self.address)?;
+        let written = lock(&connection).write_all(&message);
+
+        self.connection = Some(connection);
+        written
+    }
+}
+
+fn open(address: &str) -> io::Result<Arc<Mutex<TcpStream>>> {
+    let mut stream = TcpStream::connect(address)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut info = String::new();
+
+    reader.read_line(&mut info)?;
+
+    if !info.starts_with("INFO") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a NATS server",
+        ));
+    }
+
+    stream.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"caas\"}\r\n")?;
+
+    let writer = Arc::new(Mutex::new(stream));
+    let ponger = Arc::clone(&writer);
+
+    thread::spawn(move || {
+        for line in reader.lines() {
+            match line {
+                Ok(ref line) if line.starts_with("PING") => {
+                    if lock(&ponger).write_all(b"PONG\r\n").is_err() {
+                        break;
+                    }
+                }
+                Ok(ref line) if line.starts_with("-ERR") => eprintln!("NATS error: {}", line),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    });
+
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::ContentType;
+    use rocket::local::Client;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Bus;
+    use crate::store::Store;
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn publish_events_to_nats() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, messages) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+
+            stream.write_all(b"INFO {}\r\n").unwrap();
+
+            // The event's counter object closes right before the end of the message.
+            while !received.ends_with(b"}}\r\n") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => received.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            sender
+                .send(String::from_utf8_lossy(&received).into_owned())
+                .unwrap();
+        });
+
+        let bus = Bus::Nats {
+            address,
+            subject: "counters".to_string(),
+        };
+
+        bus.publish(client.rocket().state::<Store>().unwrap())
+            .unwrap();
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let received = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(received.starts_with("CONNECT "));
+        assert!(received.contains(&format!("PUB counters.{} ", counter.id)));
+        assert!(received.contains(r#""event":"change""#));
+        assert!(received.contains(r#""value":1"#));
+    }
+}
@@ -0,0 +1,207 @@
+//! `Cache-Control` for successful reads by class of response, so that a CDN in front can take
+//! read traffic off the service. Configured in seconds as
+//! `cache_control = { badges = 300, text = 30, json = 0 }`, where 0 means `no-store`. Classes
+//! left out keep what the route sets, and a route's `no-store` or `no-cache` always wins.
+
+use rocket::http::{ContentType, Method, Status};
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::{Config, State};
+
+#[derive(Clone, Copy)]
+enum Class {
+    /// Images: badges, shields and QR codes.
+    Badges,
+    Text,
+    Json,
+}
+
+#[derive(Default)]
+pub struct CachePolicy {
+    badges: Option<u64>,
+    text: Option<u64>,
+    json: Option<u64>,
+}
+
+impl CachePolicy {
+    pub fn from_config(config: &Config) -> CachePolicy {
+        let mut policy = CachePolicy::default();
+        let table = match config.get_table("cache_control") {
+            Ok(table) => table,
+            Err(_) => return policy,
+        };
+
+        for (name, value) in table {
+            let seconds = value.as_integer().filter(|seconds| *seconds >= 0);
+            let class = match name.as_str() {
+                "badges" => &mut policy.badges,
+                "text" => &mut policy.text,
+                "json" => &mut policy.json,
+                _ => {
+                    eprintln!("Ignoring unknown cache_control class {}", name);
+                    continue;
+                }
+            };
+
+            match seconds {
+                Some(seconds) => *class = Some(seconds as u64),
+                None => eprintln!("Ignoring cache_control.{}: not a number of seconds", name),
+            }
+        }
+
+        policy
+    }
+
+    fn seconds(&self, class: Class) -> Option<u64> {
+        match class {
+            Class::Badges => self.badges,
+            Class::Text => self.text,
+            Class::Json => self.json,
+        }
+    }
+}
+
+fn class(content_type: &ContentType) -> Option<Class> {
+    if content_type.top() == "image" {
+        Some(Class::Badges)
+    } else if *content_type == ContentType::Plain {
+        Some(Class::Text)
+    } else if *content_type == ContentType::JSON {
+        Some(Class::Json)
+    } else {
+        None
+    }
+}
+
+/// Sets the configured policy on `200` answers to `GET` and `HEAD`. Answers to authenticated
+/// requests may only be cached privately, as they can hold what anonymous callers do not see.
+pub fn annotate_response(request: &Request, response: &mut Response) {
+    let policy = match request.guard::<State<CachePolicy>>().succeeded() {
+        Some(policy) => policy,
+        None => return,
+    };
+    let seconds = match response
+        .content_type()
+        .and_then(|content_type| class(&content_type))
+        .and_then(|class| policy.seconds(class))
+    {
+        Some(seconds) => seconds,
+        None => return,
+    };
+    let read = request.method() == Method::Get || request.method() == Method::Head;
+    let uncacheable = response
+        .headers()
+        .get_one("Cache-Control")
+        .map_or(false, |value| {
+            value.contains("no-store") || value.contains("no-cache")
+        });
+
+    if !read || response.status() != Status::Ok || uncacheable {
+        return;
+    }
+
+    let headers = request.headers();
+    let authenticated = headers.contains("X-Api-Key") || headers.contains("Authorization");
+    let value = match (seconds, authenticated) {
+        (0, _) => "no-store".to_string(),
+        (seconds, true) => format!("private, max-age={}", seconds),
+        (seconds, false) => format!("public, max-age={}", seconds),
+    };
+
+    response.set_raw_header("Cache-Control", value);
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn cache_counter_listing() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let response = client.get("/counter").dispatch();
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+        assert_eq!(response.headers().get_one("X-Total-Count"), Some("1"));
+
+        let response = client
+            .get("/counter")
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::NotModified);
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let mut response = client
+            .get("/counter")
+            .header(Header::new("If-None-Match", etag))
+            .dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(counters[0].value, 1);
+
+        client
+            .put(format!("/counter/{}/archive", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let mut response = client.get("/counter").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert!(counters.is_empty());
+    }
+
+    #[test]
+    fn apply_cache_policy() {
+        let mut cache_control = BTreeMap::new();
+
+        cache_control.insert("badges".to_string(), Value::Integer(300));
+        cache_control.insert("json".to_string(), Value::Integer(0));
+
+        let config = Config::build(Environment::Development)
+            .extra("cache_control", Value::Table(cache_control))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let cache_control = |path: String| {
+            client
+                .get(path)
+                .dispatch()
+                .headers()
+                .get_one("Cache-Control")
+                .map(String::from)
+        };
+
+        assert_eq!(create_response.headers().get_one("Cache-Control"), None);
+        assert_eq!(
+            cache_control(format!("/counter/{}/badge.svg", counter.id)),
+            Some("public, max-age=300".to_string())
+        );
+        assert_eq!(
+            cache_control(format!("/counter/{}", counter.id)),
+            Some("no-store".to_string())
+        );
+        assert_eq!(
+            cache_control(format!("/counter/{}/hit", counter.id)),
+            Some("no-store, max-age=0".to_string())
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Proleptic Gregorian calendar arithmetic on days since the Unix epoch, see
+//! http://howardhinnant.github.io/date_algorithms.html
+
+pub const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// Year, month (1-12) and day (1-31) of the given day since the Unix epoch.
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Day since the Unix epoch of the given date, which must not be before 1970-01-01.
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// `timestamp` (in milliseconds) moved `months` calendar months ahead, keeping the time of
+/// day. Days past the end of the target month are clamped, so January 31st plus one month is
+/// the last day of February.
+pub fn add_months(timestamp: u64, months: u64) -> u64 {
+    let (year, month, day) = civil_from_days(timestamp / MILLIS_PER_DAY);
+    let index = year * 12 + (month - 1) + months;
+    let (year, month) = (index / 12, index % 12 + 1);
+    let day = day.min(days_in_month(year, month));
+
+    days_from_civil(year, month, day) * MILLIS_PER_DAY + timestamp % MILLIS_PER_DAY
+}
+
+/// Midnight UTC of a `YYYY-MM-DD` date, in milliseconds since the Unix epoch.
+pub fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date
+        .trim()
+        .splitn(3, '-')
+        .map(|part| part.parse::<u64>().ok());
+    let (year, month, day) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(Some(year)), Some(Some(month)), Some(Some(day))) => (year, month, day),
+        _ => return None,
+    };
+
+    if year < 1970 || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * MILLIS_PER_DAY)
+}
+
+/// A UTC `YYYY-MM-DDTHH:MM[:SS]Z` time, or midnight of a bare `YYYY-MM-DD` date, in
+/// milliseconds since the Unix epoch.
+pub fn parse_datetime(datetime: &str) -> Option<u64> {
+    let datetime = datetime.trim();
+    let (date, time) = match datetime.find('T') {
+        Some(index) => (&datetime[..index], &datetime[index + 1..]),
+        None => return parse_date(datetime),
+    };
+
+    if !time.ends_with('Z') {
+        return None;
+    }
+
+    let parts = time[..time.len() - 1]
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [hours, minutes] => (*hours, *minutes, 0),
+        [hours, minutes, seconds] => (*hours, *minutes, *seconds),
+        _ => return None,
+    };
+
+    if hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+
+    Some(parse_date(date)? + ((hours * 60 + minutes) * 60 + seconds) * 1000)
+}
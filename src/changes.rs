@@ -0,0 +1,238 @@
+//! `GET /changes?cursor=<n>`, every change to the tenant's counters in the order it was made,
+//! for downstream services to catch up after downtime without a full export. Each change carries
+//! a cursor; a client keeps the last one it has seen and asks for what came after it. Counters
+//! that are deleted or evicted appear as `delete` changes.
+//!
+//! The feed is kept alongside the history, in memory, and holds the most recent `changes_limit`
+//! changes (10000 by default). A cursor it no longer reaches back to, or one it never issued,
+//! such as one from before a restart, answers 410 Gone, and the client starts over from an
+//! export.
+
+use rocket::http::Status;
+use rocket::request::LenientForm;
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::auth::Admin;
+use crate::features::{Feature, Features};
+use crate::history::Entry;
+use crate::lock;
+use crate::tenancy::TenantStore;
+use crate::{error, ApiError};
+
+const DEFAULT_LIMIT: usize = 10_000;
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+#[derive(Serialize, Clone)]
+pub struct Change {
+    cursor: u64,
+    counter: Uuid,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+#[derive(Default, Clone)]
+struct Log {
+    /// Cursor of the newest change, 0 before the first.
+    last: u64,
+    changes: VecDeque<Change>,
+}
+
+/// The most recent changes to every counter of a store, oldest first.
+#[derive(Default)]
+pub struct Feed {
+    log: Mutex<Log>,
+    limit: usize,
+}
+
+/// Why a cursor cannot be read from.
+pub struct Gone;
+
+#[derive(Serialize)]
+pub struct Page {
+    changes: Vec<Change>,
+    /// To ask for the next page with; the given cursor again when nothing has changed since.
+    cursor: u64,
+    /// Whether there are changes after this page already.
+    more: bool,
+}
+
+impl Feed {
+    pub fn new(limit: usize) -> Feed {
+        Feed {
+            log: Mutex::default(),
+            limit,
+        }
+    }
+
+    /// A feed keeping `changes_limit` changes, or none when the history is off.
+    pub fn from_config(config: &Config) -> Feed {
+        if !Features::from_config(config).enabled(Feature::History) {
+            return Feed::new(0);
+        }
+
+        Feed::new(
+            config
+                .get_int("changes_limit")
+                .ok()
+                .filter(|limit| *limit >= 0)
+                .map_or(DEFAULT_LIMIT, |limit| limit as usize),
+        )
+    }
+
+    /// A separate feed with the same changes.
+    pub fn copy(&self) -> Feed {
+        Feed {
+            log: Mutex::new(lock(&self.log).clone()),
+            limit: self.limit,
+        }
+    }
+
+    pub fn record(&self, counter: Uuid, entry: Entry) {
+        if self.limit == 0 {
+            return;
+        }
+
+        let mut log = lock(&self.log);
+
+        log.last += 1;
+
+        let cursor = log.last;
+
+        log.changes.push_back(Change {
+            cursor,
+            counter,
+            entry,
+        });
+
+        while log.changes.len() > self.limit {
+            log.changes.pop_front();
+        }
+    }
+
+    /// Up to `limit` changes after `cursor`, or after the oldest one kept without it.
+    pub fn since(&self, cursor: Option<u64>, limit: usize) -> Result<Page, Gone> {
+        let log = lock(&self.log);
+        let oldest = log
+            .changes
+            .front()
+            .map_or(log.last + 1, |change| change.cursor);
+        let cursor = match cursor {
+            Some(cursor) if cursor > log.last || cursor + 1 < oldest => return Err(Gone),
+            Some(cursor) => cursor,
+            None => oldest - 1,
+        };
+        let changes: Vec<Change> = log
+            .changes
+            .iter()
+            .skip((cursor + 1 - oldest) as usize)
+            .take(limit)
+            .cloned()
+            .collect();
+        let next = changes.last().map_or(cursor, |change| change.cursor);
+
+        Ok(Page {
+            more: next < log.last,
+            cursor: next,
+            changes,
+        })
+    }
+}
+
+#[derive(FromForm)]
+pub struct FeedQuery {
+    cursor: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// `GET /changes?cursor=<n>&limit=<m>`, the changes after cursor `n`, oldest first, or from the
+/// oldest kept without one.
+#[get("/?<query..>")]
+pub fn get_changes(
+    query: LenientForm<FeedQuery>,
+    store: TenantStore,
+    features: State<Features>,
+    _admin: Admin,
+) -> Result<Json<Page>, ApiError> {
+    features.require(Feature::History)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE).min(MAX_PAGE).max(1);
+
+    store
+        .history()
+        .feed()
+        .since(query.cursor, limit)
+        .map(Json)
+        .map_err(|Gone| {
+            error(
+                Status::Gone,
+                "The feed no longer reaches back to this cursor, start over from an export.",
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn follow_change_feed() {
+        let config = Config::build(Environment::Development)
+            .extra("changes_limit", 3i64)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let changes = |cursor: u64| {
+            let mut response = client
+                .get(format!("/changes?cursor={}&limit=2", cursor))
+                .dispatch();
+            let status = response.status();
+
+            (
+                status,
+                serde_json::from_str::<serde_json::Value>(&response.body_string().unwrap())
+                    .unwrap(),
+            )
+        };
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .dispatch();
+
+        let (status, page) = changes(0);
+
+        assert_eq!(status, Status::Ok);
+        assert_eq!(page["changes"][0]["operation"], "create");
+        assert_eq!(page["changes"][1]["operation"], "increment");
+        assert_eq!(page["changes"][1]["counter"], counter.id.to_string());
+        assert_eq!(page["cursor"], 2);
+        assert_eq!(page["more"], false);
+        assert_eq!(changes(2).1["changes"].as_array().unwrap().len(), 0);
+
+        for _ in 0..2 {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .dispatch();
+        }
+
+        let (_, page) = changes(2);
+
+        assert_eq!(page["changes"][0]["cursor"], 3);
+        assert_eq!(page["changes"][1]["value"], 3);
+        assert_eq!(page["more"], false);
+        // Only the last three changes are kept, and cursor 9 was never issued.
+        assert_eq!(changes(0).0, Status::Gone);
+        assert_eq!(changes(9).0, Status::Gone);
+    }
+}
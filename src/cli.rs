@@ -0,0 +1,541 @@
+//! Command-line options, and subcommands that call a running instance instead of launching
+//! one. Rocket reads its configuration from `Rocket.toml` and from `ROCKET_*` environment
+//! variables, which take precedence, so the options are passed on as those variables. Settings
+//! from a `--config` file, or from `counter.toml` in the working directory if there is one, are
+//! passed on the same way, except where the environment or another option already sets them.
+//! Such a file may list settings at the top level as well as in the sections of `Rocket.toml`.
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rocket::config::{Environment, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::bench::{self, Plan};
+use crate::client::{Action, Command};
+use crate::storage::Storage;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:7000";
+/// Read unless `--config` names another file.
+const DEFAULT_CONFIG: &str = "counter.toml";
+/// The sections of a configuration file that are not settings of their own.
+const SECTIONS: [&str; 4] = ["global", "development", "staging", "production"];
+
+/// What to do with the storage file instead of launching.
+pub enum Migration {
+    /// Show its format version and the migrations it is missing.
+    Status,
+    /// Rewrite it in this format version.
+    To(u64),
+}
+
+pub struct Options {
+    /// Check the configuration and exit instead of launching.
+    pub validate_config: bool,
+    pub migration: Option<Migration>,
+    /// Call a running instance instead of launching.
+    pub command: Option<Command>,
+}
+
+fn id_argument() -> Arg<'static, 'static> {
+    Arg::with_name("id")
+        .help("Id of the counter")
+        .required(true)
+        .validator(|id| {
+            Uuid::parse_str(&id)
+                .map(|_| ())
+                .map_err(|_| "must be a counter id".to_string())
+        })
+}
+
+fn by_argument() -> Arg<'static, 'static> {
+    Arg::with_name("by")
+        .long("by")
+        .value_name("STEPS")
+        .help("Number of steps to change the counter by")
+        .takes_value(true)
+        .default_value("1")
+        .validator(|by| {
+            by.parse::<u32>()
+                .ok()
+                .filter(|by| *by > 0)
+                .map(|_| ())
+                .ok_or_else(|| "must be a positive number".to_string())
+        })
+}
+
+fn positive(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|value| *value > 0)
+        .map(|_| ())
+        .ok_or_else(|| "must be a positive number".to_string())
+}
+
+fn bench_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("bench")
+        .about("Load-tests an instance and reports throughput and latency")
+        .arg(
+            Arg::with_name("concurrency")
+                .long("concurrency")
+                .value_name("WORKERS")
+                .help("Requests in flight at once")
+                .takes_value(true)
+                .default_value("8")
+                .validator(positive),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .value_name("SECONDS")
+                .help("How long to run")
+                .takes_value(true)
+                .default_value("30")
+                .validator(positive),
+        )
+        .arg(
+            Arg::with_name("counters")
+                .long("counters")
+                .value_name("COUNT")
+                .help("Counters to spread the requests over")
+                .takes_value(true)
+                .default_value("1")
+                .validator(positive),
+        )
+        .arg(
+            Arg::with_name("mix")
+                .long("mix")
+                .value_name("MIX")
+                .help("Operations with their weights, of inc, dec, get and list")
+                .takes_value(true)
+                .default_value("inc=80,get=20")
+                .validator(|mix| bench::parse_mix(&mix).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("report-every")
+                .long("report-every")
+                .value_name("SECONDS")
+                .help("Prints progress this often, for soak tests")
+                .takes_value(true)
+                .validator(positive),
+        )
+}
+
+/// The subcommand given, if any.
+fn command(matches: &ArgMatches) -> Option<Command> {
+    let (name, arguments) = match matches.subcommand() {
+        (name, Some(arguments)) => (name, arguments),
+        _ => return None,
+    };
+    let id = || {
+        arguments
+            .value_of("id")
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .unwrap_or_default()
+    };
+    let by = || {
+        arguments
+            .value_of("by")
+            .and_then(|by| by.parse().ok())
+            .unwrap_or(1)
+    };
+    let action = match name {
+        "list" => Action::List,
+        "get" => Action::Get(id()),
+        "create" => Action::Create {
+            name: arguments.value_of("name").map(String::from),
+        },
+        "inc" => Action::Increment { id: id(), by: by() },
+        "dec" => Action::Decrement { id: id(), by: by() },
+        "bench" => {
+            let number = |name| {
+                arguments
+                    .value_of(name)
+                    .and_then(|value| value.parse::<u64>().ok())
+            };
+
+            Action::Bench(Plan {
+                concurrency: number("concurrency").unwrap_or(8) as usize,
+                duration: Duration::from_secs(number("duration").unwrap_or(30)),
+                counters: number("counters").unwrap_or(1) as usize,
+                mix: arguments
+                    .value_of("mix")
+                    .and_then(|mix| bench::parse_mix(mix).ok())
+                    .unwrap_or_default(),
+                report_every: number("report-every").map(Duration::from_secs),
+            })
+        }
+        _ => return None,
+    };
+
+    Some(Command {
+        url: arguments
+            .value_of("url")
+            .unwrap_or(DEFAULT_URL)
+            .trim_end_matches('/')
+            .to_string(),
+        api_key: arguments.value_of("api-key").map(String::from),
+        json: arguments.is_present("json"),
+        action,
+    })
+}
+
+pub fn parse() -> Result<Options, String> {
+    let matches = App::new("caas")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Counter as a Service")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .env("CAAS_CONFIG")
+                .help("TOML file with settings, counter.toml unless given")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("env")
+                .long("env")
+                .value_name("ENVIRONMENT")
+                .help("Section of the configuration to use")
+                .possible_values(&["development", "staging", "production"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("address")
+                .long("address")
+                .value_name("ADDRESS")
+                .help("Address to listen on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .value_name("PORT")
+                .help("Port to listen on")
+                .takes_value(true)
+                .validator(|port| {
+                    port.parse::<u16>()
+                        .map(|_| ())
+                        .map_err(|_| "must be a port number".to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .long("storage")
+                .value_name("STORAGE")
+                .help("Where to keep counters: memory, or file:<path>")
+                .takes_value(true)
+                .validator(|storage| Storage::parse(&storage).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("How much to log")
+                .possible_values(&["off", "critical", "normal", "debug"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("validate-config")
+                .long("validate-config")
+                .help("Checks the configuration and exits"),
+        )
+        .arg(
+            Arg::with_name("migrate-status")
+                .long("migrate-status")
+                .help("Shows the format version of the storage file and exits"),
+        )
+        .arg(
+            Arg::with_name("migrate-to")
+                .long("migrate-to")
+                .value_name("VERSION")
+                .help("Migrates the storage file up or down to a format version and exits")
+                .takes_value(true)
+                .conflicts_with("migrate-status")
+                .validator(positive),
+        )
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .subcommands(
+            vec![
+                SubCommand::with_name("list").about("Lists counters"),
+                SubCommand::with_name("get")
+                    .about("Shows a counter")
+                    .arg(id_argument()),
+                SubCommand::with_name("create")
+                    .about("Creates a counter")
+                    .arg(
+                        Arg::with_name("name")
+                            .long("name")
+                            .value_name("NAME")
+                            .takes_value(true),
+                    ),
+                SubCommand::with_name("inc")
+                    .about("Increments a counter")
+                    .arg(id_argument())
+                    .arg(by_argument()),
+                SubCommand::with_name("dec")
+                    .about("Decrements a counter")
+                    .arg(id_argument())
+                    .arg(by_argument()),
+                bench_subcommand(),
+            ]
+            .into_iter()
+            .map(|subcommand| {
+                subcommand
+                    .arg(
+                        Arg::with_name("url")
+                            .long("url")
+                            .value_name("URL")
+                            .env("CAAS_URL")
+                            .help("Instance to call")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("api-key")
+                            .long("api-key")
+                            .value_name("KEY")
+                            .env("CAAS_API_KEY")
+                            .help("Sent as X-Api-Key")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name("json")
+                            .long("json")
+                            .help("Prints the API's JSON"),
+                    )
+            }),
+        )
+        .get_matches();
+
+    if let Some(command) = command(&matches) {
+        return Ok(Options {
+            validate_config: false,
+            migration: None,
+            command: Some(command),
+        });
+    }
+
+    for (option, variable) in &[
+        ("env", "ROCKET_ENV"),
+        ("address", "ROCKET_ADDRESS"),
+        ("port", "ROCKET_PORT"),
+        ("storage", "ROCKET_STORAGE"),
+        ("log-level", "ROCKET_LOG"),
+    ] {
+        if let Some(value) = matches.value_of(option) {
+            env::set_var(variable, value);
+        }
+    }
+
+    let config = matches
+        .value_of("config")
+        .or_else(|| Some(DEFAULT_CONFIG).filter(|path| Path::new(path).exists()));
+
+    if let Some(path) = config {
+        let document = fs::read_to_string(path)
+            .map_err(|error| format!("Could not read {}: {}", path, error))?
+            .parse::<Value>()
+            .map_err(|error| format!("Could not parse {}: {}", path, error))?;
+        let environment = Environment::active().map_err(|_| {
+            "ROCKET_ENV must be one of development, staging or production".to_string()
+        })?;
+
+        for (variable, value) in file_settings(&document, environment) {
+            if env::var_os(&variable).is_none() {
+                env::set_var(variable, value);
+            }
+        }
+    }
+
+    let migration = if matches.is_present("migrate-status") {
+        Some(Migration::Status)
+    } else {
+        matches
+            .value_of("migrate-to")
+            .and_then(|version| version.parse().ok())
+            .map(Migration::To)
+    };
+
+    Ok(Options {
+        validate_config: matches.is_present("validate-config"),
+        migration,
+        command: None,
+    })
+}
+
+/// The settings `document`, laid out like Rocket.toml, makes for `environment`: those at its
+/// top level and in its `[global]` table, overridden by the environment's own.
+pub fn settings(document: &Value, environment: Environment) -> BTreeMap<String, Value> {
+    let environment = environment.to_string();
+    let mut settings: BTreeMap<String, Value> = document
+        .as_table()
+        .map(|table| {
+            table
+                .iter()
+                .filter(|(key, _)| !SECTIONS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for section in &["global", environment.as_str()] {
+        if let Some(table) = document.get(*section).and_then(Value::as_table) {
+            settings.extend(table.clone());
+        }
+    }
+
+    settings
+}
+
+/// The `ROCKET_*` variables that set what `document` does for `environment`.
+pub fn file_settings(document: &Value, environment: Environment) -> Vec<(String, String)> {
+    settings(document, environment)
+        .iter()
+        .map(|(key, value)| (format!("ROCKET_{}", key.to_uppercase()), inline(value)))
+        .collect()
+}
+
+/// `value` as an inline TOML value, the form Rocket parses environment variables in.
+fn inline(value: &Value) -> String {
+    match value {
+        Value::String(string) => basic_string(string),
+        Value::Integer(integer) => integer.to_string(),
+        Value::Float(float) => float.to_string(),
+        Value::Boolean(boolean) => boolean.to_string(),
+        Value::Datetime(datetime) => datetime.to_string(),
+        Value::Array(array) => format!(
+            "[{}]",
+            array.iter().map(inline).collect::<Vec<_>>().join(", ")
+        ),
+        Value::Table(table) => format!(
+            "{{ {} }}",
+            table
+                .iter()
+                .map(|(key, value)| format!("{} = {}", inline_key(key), inline(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn inline_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if bare {
+        key.to_string()
+    } else {
+        basic_string(key)
+    }
+}
+
+/// `string` as a TOML basic string, with quotes, backslashes and control characters escaped as
+/// the TOML specification has them.
+fn basic_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+
+    quoted.push('"');
+
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\u{8}' => quoted.push_str("\\b"),
+            '\t' => quoted.push_str("\\t"),
+            '\n' => quoted.push_str("\\n"),
+            '\u{c}' => quoted.push_str("\\f"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Environment, Value};
+
+    use crate::rocket;
+
+    #[test]
+    fn read_config_file() {
+        let document: Value = r#"
+            [global]
+            port = 8000
+            api_keys = { "secret" = "ci" }
+            public_url = "https://caf\u00e9.example/\"quoted\"\\\u0001"
+
+            [production]
+            port = 80
+            cors_origins = ["https://example.com"]
+        "#
+        .parse()
+        .unwrap();
+        let settings = super::file_settings(&document, Environment::Production);
+
+        assert!(settings.contains(&("ROCKET_PORT".to_string(), "80".to_string())));
+        assert!(settings.contains(&(
+            "ROCKET_API_KEYS".to_string(),
+            r#"{ secret = "ci" }"#.to_string()
+        )));
+        assert!(settings.contains(&(
+            "ROCKET_CORS_ORIGINS".to_string(),
+            r#"["https://example.com"]"#.to_string()
+        )));
+        assert_eq!(settings.len(), 4);
+
+        // Strings are escaped as TOML, so that Rocket reads back what the file said.
+        let (_, public_url) = settings
+            .iter()
+            .find(|(variable, _)| variable == "ROCKET_PUBLIC_URL")
+            .unwrap();
+        let parsed: Value = format!("public_url = {}", public_url).parse().unwrap();
+
+        assert_eq!(
+            parsed.get("public_url"),
+            document["global"].get("public_url")
+        );
+    }
+
+    #[test]
+    fn read_flat_config_file() {
+        let document: Value = r#"
+            address = "0.0.0.0"
+            port = 8000
+            storage = "file:/var/lib/caas/counters.json"
+            cors_origins = ["https://example.com"]
+            max_snapshots = 10
+
+            [api_keys]
+            secret = "ci"
+
+            [production]
+            port = 80
+        "#
+        .parse()
+        .unwrap();
+        let settings = super::file_settings(&document, Environment::Production);
+
+        assert!(settings.contains(&("ROCKET_ADDRESS".to_string(), r#""0.0.0.0""#.to_string())));
+        assert!(settings.contains(&("ROCKET_PORT".to_string(), "80".to_string())));
+        assert!(settings.contains(&(
+            "ROCKET_STORAGE".to_string(),
+            r#""file:/var/lib/caas/counters.json""#.to_string()
+        )));
+        assert!(settings.contains(&(
+            "ROCKET_API_KEYS".to_string(),
+            r#"{ secret = "ci" }"#.to_string()
+        )));
+        assert!(settings.contains(&("ROCKET_MAX_SNAPSHOTS".to_string(), "10".to_string())));
+        assert_eq!(settings.len(), 6);
+    }
+}
@@ -0,0 +1,159 @@
+//! Subcommands that call a running instance, as in `caas inc <id> --by 5`, so that scripts do
+//! not have to put together requests by hand. Requests go through the `counter-client` crate.
+//! Counters are printed one per line as id, value and name, or as JSON with `--json`.
+
+use counter_client::{Counter, CounterClient, NewCounter};
+use uuid::Uuid;
+
+use crate::bench::{self, Plan};
+
+pub enum Action {
+    List,
+    Get(Uuid),
+    Create { name: Option<String> },
+    Increment { id: Uuid, by: u32 },
+    Decrement { id: Uuid, by: u32 },
+    Bench(Plan),
+}
+
+pub struct Command {
+    /// Base URL of the instance, such as `http://localhost:8000`.
+    pub url: String,
+    pub api_key: Option<String>,
+    pub json: bool,
+    pub action: Action,
+}
+
+/// What a command prints: the counters listed, or the one it got or changed.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Output {
+    One(Counter),
+    Many(Vec<Counter>),
+}
+
+/// Runs the command, returning the process exit code.
+pub fn run(command: Command) -> i32 {
+    if let Action::Bench(plan) = &command.action {
+        return bench::run(&command, plan);
+    }
+
+    let mut client = CounterClient::new(&command.url);
+
+    if let Some(api_key) = &command.api_key {
+        client = client.with_api_key(api_key);
+    }
+
+    let result = match &command.action {
+        Action::List => client.list().map(Output::Many),
+        Action::Get(id) => client.get(*id).map(Output::One),
+        Action::Create { name } => {
+            let new_counter = NewCounter {
+                name: name.clone(),
+                ..NewCounter::default()
+            };
+
+            client.create(&new_counter).map(Output::One)
+        }
+        Action::Increment { id, by } => client.increment_by(*id, *by).map(Output::One),
+        Action::Decrement { id, by } => client.decrement_by(*id, *by).map(Output::One),
+        Action::Bench(_) => unreachable!("benchmarks are run above"),
+    };
+
+    match result {
+        Ok(output) => {
+            print(&output, command.json);
+            0
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            1
+        }
+    }
+}
+
+fn print(output: &Output, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(output).unwrap());
+        return;
+    }
+
+    let line = |counter: &Counter| {
+        println!(
+            "{}\t{}\t{}",
+            counter.id,
+            counter.value,
+            counter.name.as_deref().unwrap_or("")
+        )
+    };
+
+    match output {
+        Output::Many(counters) => counters.iter().for_each(line),
+        Output::One(counter) => line(counter),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn increment_remote_counter() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        let id = uuid::Uuid::new_v4();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            let body = format!(
+                r#"{{"id":"{}","value":2,"created_at":0,"updated_at":0,"archived":false,"frozen":false,"step":1}}"#,
+                id
+            );
+
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Connection: close\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            sender
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .unwrap();
+        });
+
+        let command = super::Command {
+            url,
+            api_key: Some("secret".to_string()),
+            json: true,
+            action: super::Action::Increment { id, by: 2 },
+        };
+
+        assert_eq!(super::run(command), 0);
+
+        // One request for all the steps.
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(request.starts_with(&format!("PUT /v1/counter/{}/increment?steps=2 ", id)));
+        assert!(request.to_lowercase().contains("x-api-key: secret"));
+    }
+}
@@ -0,0 +1,231 @@
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use std::cmp::Ordering;
+use uuid::Uuid;
+
+use crate::auth::Reader;
+use crate::negotiation::Body;
+use crate::routes::change_since;
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::validation::Validate;
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+const MAX_PAIRS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct Pair {
+    a: String,
+    b: String,
+}
+
+#[derive(Deserialize)]
+pub struct Pairs {
+    pairs: Vec<Pair>,
+    since: Option<u64>,
+}
+
+impl Validate for Pairs {}
+
+#[derive(Serialize)]
+pub struct Side {
+    id: Uuid,
+    value: u32,
+}
+
+/// How counter `a` relates to counter `b`. `ratio` is `a / b`, or null when `b` is zero.
+#[derive(Serialize)]
+pub struct Comparison {
+    a: Side,
+    b: Side,
+    difference: i64,
+    ratio: Option<f64>,
+    /// `a`, `b` or `equal`.
+    larger: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window: Option<Window>,
+}
+
+/// How much each counter changed from `since` until now, compared the same way.
+#[derive(Serialize)]
+pub struct Window {
+    since: u64,
+    a: i64,
+    b: i64,
+    difference: i64,
+    ratio: Option<f64>,
+}
+
+fn ratio(a: f64, b: f64) -> Option<f64> {
+    if b == 0.0 {
+        None
+    } else {
+        Some(a / b)
+    }
+}
+
+fn compare(
+    a: &str,
+    b: &str,
+    since: Option<u64>,
+    reader: &Option<Reader>,
+    store: &Store,
+) -> Result<Comparison, ApiError> {
+    let find = |id: &str| -> Result<Counter, ApiError> {
+        store
+            .get(parse_id(id)?)
+            .filter(|counter| counter.visibility.is_listed_for(reader))
+            .ok_or_else(not_found_error)
+    };
+    let a = find(a)?;
+    let b = find(b)?;
+    let window = match since {
+        Some(since) => {
+            let change = |counter: &Counter| {
+                change_since(counter, since, store).ok_or_else(|| {
+                    error(
+                        Status::NotFound,
+                        "Neither the history nor the time series reaches back that far.",
+                    )
+                })
+            };
+            let (a, b) = (change(&a)?, change(&b)?);
+
+            Some(Window {
+                since,
+                a,
+                b,
+                difference: a - b,
+                ratio: ratio(a as f64, b as f64),
+            })
+        }
+        None => None,
+    };
+
+    Ok(Comparison {
+        difference: i64::from(a.value) - i64::from(b.value),
+        ratio: ratio(f64::from(a.value), f64::from(b.value)),
+        larger: match a.value.cmp(&b.value) {
+            Ordering::Greater => "a",
+            Ordering::Less => "b",
+            Ordering::Equal => "equal",
+        },
+        a: Side {
+            id: a.id,
+            value: a.value,
+        },
+        b: Side {
+            id: b.id,
+            value: b.value,
+        },
+        window,
+    })
+}
+
+/// `GET /counter/compare?a=<id>&b=<id>&since=<ms>`. With `since`, also compares how much each
+/// counter changed since then, which is 404 when neither the history nor the time series
+/// reaches back that far.
+#[get("/compare?<a>&<b>&<since>")]
+pub fn compare_pair(
+    a: String,
+    b: String,
+    since: Option<u64>,
+    reader: Option<Reader>,
+    store: TenantStore,
+) -> Result<Json<Comparison>, ApiError> {
+    compare(&a, &b, since, &reader, &store).map(Json)
+}
+
+/// Comparisons in the order the pairs were given. Any unknown counter fails the whole batch,
+/// as a scoreboard with a missing entry is not worth showing.
+#[post("/compare", data = "<pairs>")]
+pub fn compare_pairs(
+    pairs: Body<Pairs>,
+    reader: Option<Reader>,
+    store: TenantStore,
+) -> Result<Json<Vec<Comparison>>, ApiError> {
+    if pairs.pairs.len() > MAX_PAIRS {
+        return Err(error(
+            Status::BadRequest,
+            "At most 100 pairs can be compared at once.",
+        ));
+    }
+
+    pairs
+        .pairs
+        .iter()
+        .map(|pair| compare(&pair.a, &pair.b, pairs.since, &reader, &store))
+        .collect::<Result<Vec<Comparison>, ApiError>>()
+        .map(Json)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn compare_counters() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut ids = Vec::new();
+
+        for increments in &[3, 2] {
+            let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+            let counter: Counter =
+                serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+            for _ in 0..*increments {
+                client
+                    .put(format!("/counter/{}/increment", counter.id))
+                    .header(ContentType::JSON)
+                    .dispatch();
+            }
+
+            ids.push(counter.id);
+        }
+
+        let mut response = client
+            .get(format!("/counter/compare?a={}&b={}", ids[0], ids[1]))
+            .dispatch();
+        let comparison: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(comparison["difference"], 1);
+        assert_eq!(comparison["ratio"], 1.5);
+        assert_eq!(comparison["larger"], "a");
+        assert!(comparison.get("window").is_none());
+
+        let mut window_response = client
+            .get(format!(
+                "/counter/compare?a={}&b={}&since=0",
+                ids[0], ids[1]
+            ))
+            .dispatch();
+        let window_comparison: serde_json::Value =
+            serde_json::from_str(&window_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(window_response.status(), Status::Ok);
+        assert_eq!(window_comparison["window"]["a"], 3);
+        assert_eq!(window_comparison["window"]["b"], 2);
+        assert_eq!(window_comparison["window"]["difference"], 1);
+        assert_eq!(window_comparison["window"]["ratio"], 1.5);
+
+        let mut batch_response = client
+            .post("/counter/compare")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "pairs": [{{ "a": "{0}", "b": "{1}" }}, {{ "a": "{1}", "b": "{1}" }}] }}"#,
+                ids[0], ids[1]
+            ))
+            .dispatch();
+        let comparisons: Vec<serde_json::Value> =
+            serde_json::from_str(&batch_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(comparisons.len(), 2);
+        assert_eq!(comparisons[1]["larger"], "equal");
+        assert_eq!(comparisons[1]["difference"], 0);
+    }
+}
@@ -0,0 +1,137 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Read, Seek};
+
+use crate::calendar::civil_from_days;
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A JSON response carrying an `ETag` (and optionally `Last-Modified`) that turns into an
+/// empty 304 Not Modified when the request's `If-None-Match` already names that tag.
+pub struct Conditional<T> {
+    body: T,
+    /// Milliseconds since the Unix epoch.
+    last_modified: Option<u64>,
+}
+
+impl<T: Serialize> Conditional<T> {
+    pub fn new(body: T, last_modified: Option<u64>) -> Conditional<T> {
+        Conditional {
+            body,
+            last_modified,
+        }
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r> for Conditional<T> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let json = serde_json::to_string(&self.body).map_err(|_| Status::InternalServerError)?;
+        let etag = etag(&json);
+
+        respond(request, Cursor::new(json), etag, self.last_modified)
+    }
+}
+
+/// Answers with `json`, a body already serialized and tagged with `etag`, or with 304 Not
+/// Modified when the request's `If-None-Match` names that tag.
+pub fn respond<'r, B: Read + Seek + 'r>(
+    request: &Request,
+    json: B,
+    etag: String,
+    last_modified: Option<u64>,
+) -> response::Result<'r> {
+    let not_modified = request.headers().get("If-None-Match").any(|header| {
+        header.split(',').any(|tag| {
+            let tag = tag.trim();
+
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        })
+    });
+    let mut response = Response::build();
+
+    if let Some(last_modified) = last_modified {
+        response.raw_header("Last-Modified", http_date(last_modified / 1000));
+    }
+
+    response.raw_header("ETag", etag);
+
+    if not_modified {
+        response.status(Status::NotModified);
+    } else {
+        response.header(ContentType::JSON).sized_body(json);
+    }
+
+    response.ok()
+}
+
+pub fn etag(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Formats seconds since the Unix epoch as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(seconds: u64) -> String {
+    let days = seconds / 86_400;
+    let time = seconds % 86_400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn conditional_get_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+
+        assert!(response.headers().get_one("Last-Modified").is_some());
+
+        let response = client
+            .get(format!("/counter/{}", counter.id))
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::NotModified);
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let response = client
+            .get(format!("/counter/{}", counter.id))
+            .header(Header::new("If-None-Match", etag))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
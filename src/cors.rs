@@ -0,0 +1,157 @@
+//! Cross-origin requests from browsers, answered by `rocket_cors` with the origins in
+//! `cors_origins`. The policy can be swapped while running, when the configuration is reloaded.
+
+use rocket::data::Data;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::{Config, Rocket};
+use rocket_cors::{AllowedHeaders, AllowedOrigins, CorsOptions};
+use std::sync::{Arc, Mutex};
+
+use crate::lock;
+
+/// The origins in `cors_origins`, or any origin when it is not set.
+fn allowed_origins(config: &Config) -> AllowedOrigins {
+    match config.get_slice("cors_origins") {
+        Ok(origins) => {
+            let origins: Vec<&str> = origins
+                .iter()
+                .filter_map(|origin| origin.as_str())
+                .collect();
+
+            AllowedOrigins::some_exact(&origins)
+        }
+        Err(_) => AllowedOrigins::All,
+    }
+}
+
+fn policy(config: &Config) -> Result<rocket_cors::Cors, String> {
+    CorsOptions {
+        allowed_origins: allowed_origins(config),
+        allowed_methods: vec![
+            Method::Options,
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Patch,
+            Method::Delete,
+        ]
+        .into_iter()
+        .map(From::from)
+        .collect(),
+        allowed_headers: AllowedHeaders::some(&[
+            "Accept",
+            "Content-Type",
+            "If-None-Match",
+            "X-Api-Key",
+            "Authorization",
+            "X-Write-Token",
+            "X-Signature",
+            "X-Request-Id",
+        ]),
+        expose_headers: [
+            "X-Total-Count",
+            "ETag",
+            "Last-Modified",
+            "Deprecation",
+            "Link",
+            "X-Request-Id",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect(),
+        allow_credentials: true,
+        ..Default::default()
+    }
+    .to_cors()
+    .map_err(|error| format!("Invalid CORS settings: {}", error))
+}
+
+/// Fairing applying the current policy. Clones share it.
+#[derive(Clone)]
+pub struct Cors(Arc<Mutex<Arc<rocket_cors::Cors>>>);
+
+impl Cors {
+    pub fn from_config(config: &Config) -> Cors {
+        let policy = policy(config).unwrap();
+
+        Cors(Arc::new(Mutex::new(Arc::new(policy))))
+    }
+
+    /// Replaces the policy with the one `config` describes, leaving it as it was if that is
+    /// invalid.
+    pub fn reload(&self, config: &Config) -> Result<(), String> {
+        let policy = policy(config)?;
+
+        *lock(&self.0) = Arc::new(policy);
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<rocket_cors::Cors> {
+        lock(&self.0).clone()
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        self.current().on_attach(rocket)
+    }
+
+    fn on_request(&self, request: &mut Request, data: &Data) {
+        self.current().on_request(request, data)
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        self.current().on_response(request, response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use crate::{build, rocket};
+
+    #[test]
+    fn restrict_cors_origins() {
+        let config = Config::build(Environment::Development)
+            .extra(
+                "cors_origins",
+                Value::Array(vec![Value::String("https://example.com".to_string())]),
+            )
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+
+        let response = client
+            .get("/counter")
+            .header(Header::new("Origin", "https://example.com"))
+            .dispatch();
+
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            Some("https://example.com")
+        );
+
+        let response = client
+            .get("/counter")
+            .header(Header::new("Origin", "https://elsewhere.example"))
+            .dispatch();
+
+        assert!(response
+            .headers()
+            .get_one("Access-Control-Allow-Origin")
+            .is_none());
+    }
+}
@@ -0,0 +1,343 @@
+//! A counter: its value, the history of values it has been at, and the settings that govern
+//! how it changes, such as its step, bounds and billing cycle.
+
+use rocket::http::Status;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+use crate::cycle::{Burst, Cycle};
+use crate::decay::Decay;
+use crate::distinct::Distinct;
+use crate::visibility::Visibility;
+use crate::{error, history, ApiError};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Counter {
+    pub(crate) id: Uuid,
+    pub(crate) value: u32,
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub(crate) created_at: u64,
+    /// Milliseconds since the Unix epoch.
+    pub(crate) updated_at: u64,
+    pub(crate) max_seen: u32,
+    pub(crate) min_seen: u32,
+    pub(crate) archived: bool,
+    pub(crate) frozen: bool,
+    #[serde(default)]
+    pub(crate) tags: BTreeSet<String>,
+    /// Other names the counter can be found by in paths, unique per tenant.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) aliases: BTreeSet<String>,
+    /// Name chosen by the caller that the counter's id was derived from, if it was created
+    /// with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<String>,
+    /// Amount added or removed by a single increment or decrement.
+    #[serde(default = "default_step")]
+    pub(crate) step: u32,
+    #[serde(default)]
+    pub(crate) bounds: Bounds,
+    /// Billing cycle the value is reset at the end of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cycle: Option<Cycle>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) burst: Option<Burst>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) decay: Option<Decay>,
+    /// Sketch of the items added, for counters whose value is the number of distinct items.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) distinct: Option<Distinct>,
+    /// Hits bucketed into labeled sub-counters such as `referrer:example.com`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) breakdown: BTreeMap<String, u32>,
+    #[serde(default, skip_serializing_if = "Visibility::is_public")]
+    pub(crate) visibility: Visibility,
+    /// Subject of the JWT the counter was created with. Only they can change it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) owner: Option<String>,
+    /// Secret that changing the counter takes, shown only once when it is created.
+    #[serde(default, skip_serializing)]
+    pub(crate) write_token: Option<String>,
+    /// Secret in the counter's `/hook/<token>` URLs, shown only when it is issued.
+    #[serde(default, skip_serializing)]
+    pub(crate) hook_token: Option<String>,
+}
+
+impl Counter {
+    pub(crate) fn new(id: Uuid) -> Counter {
+        let now = history::timestamp();
+
+        Counter {
+            id,
+            value: 0,
+            name: None,
+            description: None,
+            created_at: now,
+            updated_at: now,
+            max_seen: 0,
+            min_seen: 0,
+            archived: false,
+            frozen: false,
+            tags: BTreeSet::new(),
+            aliases: BTreeSet::new(),
+            key: None,
+            step: default_step(),
+            bounds: Bounds::default(),
+            cycle: None,
+            burst: None,
+            decay: None,
+            distinct: None,
+            breakdown: BTreeMap::new(),
+            visibility: Visibility::default(),
+            owner: None,
+            write_token: None,
+            hook_token: None,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub(crate) fn set_value(&mut self, value: u32) {
+        self.value = value;
+        self.updated_at = history::timestamp();
+        self.max_seen = self.max_seen.max(value);
+        self.min_seen = self.min_seen.min(value);
+
+        if let (Some(burst), Some(max)) = (self.burst.as_mut(), self.bounds.max) {
+            burst.track(value, max);
+        }
+    }
+
+    /// Resets the value to zero if its billing cycle has ended, returning the value the cycle
+    /// closed with.
+    pub(crate) fn roll_over(&mut self, now: u64) -> Option<u32> {
+        let value = self.value;
+        let closed = self
+            .cycle
+            .as_mut()
+            .map_or(0, |cycle| cycle.roll_over(value, now));
+
+        if closed > 0 {
+            if let Some(burst) = self.burst.as_mut() {
+                burst.close();
+
+                // A cycle that passed without usage repays everything.
+                if closed > 1 {
+                    burst.close();
+                }
+            }
+
+            self.set_value(0);
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Brings the decaying score, if any, up to `now`.
+    pub(crate) fn fade(&mut self, now: u64) {
+        if let Some(decay) = self.decay.as_mut() {
+            decay.advance(now);
+        }
+    }
+
+    /// Adds a change that happened at `occurred_at`, or now, to the decaying score, if any.
+    pub(crate) fn score(&mut self, delta: i64, occurred_at: Option<u64>) {
+        if let Some(decay) = self.decay.as_mut() {
+            let now = history::timestamp();
+
+            decay.add(delta as f64, occurred_at.unwrap_or(now), now);
+        }
+    }
+
+    /// Adds one step, stopping at the upper bound or, with a burst pool, at what can still be
+    /// borrowed above it. Returns the change actually applied.
+    pub(crate) fn step_up(&mut self) -> u32 {
+        self.step_up_by(1)
+    }
+
+    /// Adds `steps` steps at once, as if one at a time.
+    pub(crate) fn step_up_by(&mut self, steps: u32) -> u32 {
+        let max = self.ceiling();
+
+        if self.value >= max {
+            return 0;
+        }
+
+        let value = self
+            .value
+            .saturating_add(self.step.saturating_mul(steps))
+            .min(max);
+        let delta = value - self.value;
+
+        self.set_value(value);
+        delta
+    }
+
+    /// Highest value the counter can be taken to right now, its upper bound unless a burst
+    /// pool lets it go further.
+    pub(crate) fn ceiling(&self) -> u32 {
+        match (self.bounds.max, self.burst) {
+            (Some(max), Some(burst)) => burst.ceiling(max),
+            (Some(max), None) => max,
+            (None, _) => u32::max_value(),
+        }
+    }
+
+    /// Removes one step, stopping at the lower bound. Returns the change actually applied.
+    pub(crate) fn step_down(&mut self) -> u32 {
+        self.step_down_by(1)
+    }
+
+    /// Removes `steps` steps at once, as if one at a time.
+    pub(crate) fn step_down_by(&mut self, steps: u32) -> u32 {
+        let min = self.bounds.min.unwrap_or(0);
+
+        if self.value <= min {
+            return 0;
+        }
+
+        let value = self
+            .value
+            .saturating_sub(self.step.saturating_mul(steps))
+            .max(min);
+        let delta = self.value - value;
+
+        self.set_value(value);
+        delta
+    }
+
+    /// Distinct-count counters only change as items are added to them.
+    pub(crate) fn ensure_steppable(&self) -> Result<(), ApiError> {
+        if self.distinct.is_some() {
+            Err(error(
+                Status::Conflict,
+                "Counter counts distinct items; add them at /items instead.",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn ensure_mutable(&self) -> Result<(), ApiError> {
+        if self.archived {
+            Err(error(Status::Conflict, "Counter is archived."))
+        } else if self.frozen {
+            Err(error(Status::Locked, "Counter is frozen."))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn default_step() -> u32 {
+    1
+}
+
+/// Inclusive limits that increments and decrements stop at. The value itself is never
+/// clamped when bounds change.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct Bounds {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) min: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max: Option<u32>,
+}
+
+impl Bounds {
+    pub(crate) fn validate(self) -> Result<(), ApiError> {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min > max => Err(error(
+                Status::BadRequest,
+                "Lower bound must not exceed the upper bound.",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn track_watermarks() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for action in &["increment", "increment", "decrement"] {
+            client
+                .put(format!("/counter/{}/{}", counter.id, action))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let counter: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 1);
+        assert_eq!(counter.max_seen, 2);
+        assert_eq!(counter.min_seen, 0);
+    }
+
+    #[test]
+    fn step_within_bounds() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let invalid_response = client
+            .patch(format!("/counter/{}", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "bounds": { "min": 10, "max": 5 } }"#)
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+
+        let mut patch_response = client
+            .patch(format!("/counter/{}", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Seats", "step": 4, "bounds": { "max": 10 } }"#)
+            .dispatch();
+        let patched: Counter =
+            serde_json::from_str(&patch_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(patched.name, Some("Seats".to_string()));
+        assert_eq!(patched.value, 0);
+
+        let mut values = Vec::new();
+
+        for action in &["increment", "increment", "increment", "decrement"] {
+            let mut response = client
+                .put(format!("/counter/{}/{}", counter.id, action))
+                .header(ContentType::JSON)
+                .dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            values.push(counter.value);
+        }
+
+        assert_eq!(values, vec![4, 8, 10, 6]);
+    }
+}
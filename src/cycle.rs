@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+
+use crate::calendar::add_months;
+
+/// Totals of this many past cycles are kept, three years of monthly billing.
+const MAX_TOTALS: usize = 36;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Monthly,
+    Yearly,
+}
+
+impl Period {
+    fn months(self) -> u64 {
+        match self {
+            Period::Monthly => 1,
+            Period::Yearly => 12,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Total {
+    /// Milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// Milliseconds since the Unix epoch.
+    pub ended_at: u64,
+    pub total: u32,
+}
+
+/// Headroom above the counter's upper bound that can be borrowed within a billing cycle. What
+/// was borrowed is paid back by lowering the next cycle's limit by the same amount, and cannot
+/// be borrowed again until then.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Burst {
+    pub pool: u32,
+    /// Borrowed in the previous cycle and deducted from this cycle's limit.
+    pub repaying: u32,
+    /// Borrowed so far in this cycle.
+    pub borrowed: u32,
+}
+
+impl Burst {
+    pub fn new(pool: u32) -> Burst {
+        Burst {
+            pool,
+            repaying: 0,
+            borrowed: 0,
+        }
+    }
+
+    /// The highest value allowed this cycle for a counter whose upper bound is `limit`.
+    pub fn ceiling(&self, limit: u32) -> u32 {
+        self.base(limit)
+            .saturating_add(self.pool.saturating_sub(self.repaying))
+    }
+
+    pub fn track(&mut self, value: u32, limit: u32) {
+        self.borrowed = value.saturating_sub(self.base(limit));
+    }
+
+    /// Starts the next cycle, which repays what was borrowed in this one.
+    pub fn close(&mut self) {
+        self.repaying = self.borrowed;
+        self.borrowed = 0;
+    }
+
+    fn base(&self, limit: u32) -> u32 {
+        limit.saturating_sub(self.repaying)
+    }
+}
+
+/// A billing cycle the counter is bound to. Cycles start on the anchor date and repeat every
+/// period, keeping the anchor's day of month where the month is long enough.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Cycle {
+    pub period: Period,
+    /// Milliseconds since the Unix epoch.
+    pub anchor: u64,
+    /// Number of cycles completed since the anchor.
+    index: u64,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// Milliseconds since the Unix epoch.
+    pub resets_at: u64,
+    /// Most recent completed cycles first.
+    #[serde(default)]
+    pub totals: VecDeque<Total>,
+}
+
+impl Cycle {
+    /// The cycle `now` falls in, or `None` if the anchor is still in the future.
+    pub fn new(period: Period, anchor: u64, now: u64) -> Option<Cycle> {
+        if anchor > now {
+            return None;
+        }
+
+        let mut cycle = Cycle {
+            period,
+            anchor,
+            index: 0,
+            started_at: anchor,
+            resets_at: add_months(anchor, period.months()),
+            totals: VecDeque::new(),
+        };
+
+        while cycle.resets_at <= now {
+            cycle.advance();
+        }
+
+        Some(cycle)
+    }
+
+    /// Closes every cycle that has ended by `now`, the first with `value` as its total and any
+    /// further ones that passed without activity with zero. Returns how many were closed.
+    pub fn roll_over(&mut self, value: u32, now: u64) -> u32 {
+        let mut total = value;
+        let mut closed = 0;
+
+        while self.resets_at <= now {
+            self.totals.push_front(Total {
+                started_at: self.started_at,
+                ended_at: self.resets_at,
+                total,
+            });
+            self.totals.truncate(MAX_TOTALS);
+            self.advance();
+            total = 0;
+            closed += 1;
+        }
+
+        closed
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+        self.started_at = self.resets_at;
+        self.resets_at = add_months(self.anchor, (self.index + 1) * self.period.months());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn borrow_from_burst_pool() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let invalid_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "bounds": { "max": 2 }, "burst": 1 }"#)
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "bounds": { "max": 2 }, "cycle": { "period": "monthly" }, "burst": 1 }"#)
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut last = counter.clone();
+
+        for _ in 0..4 {
+            let mut response = client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+
+            last = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        }
+
+        let burst = last.burst.unwrap();
+
+        assert_eq!(last.value, 3);
+        assert_eq!(burst.pool, 1);
+        assert_eq!(burst.borrowed, 1);
+        assert_eq!(burst.repaying, 0);
+    }
+
+    #[test]
+    fn bind_counter_to_billing_cycle() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "cycle": { "period": "monthly", "anchor": 1580428800000 } }"#)
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let cycle = counter.cycle.unwrap();
+        let now = crate::history::timestamp();
+
+        assert!(cycle.started_at <= now && now < cycle.resets_at);
+        assert!(cycle.resets_at - cycle.started_at <= 31 * 86_400_000);
+        assert!(cycle.totals.is_empty());
+
+        let future_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "cycle": {{ "period": "yearly", "anchor": {} }} }}"#,
+                now + 86_400_000
+            ))
+            .dispatch();
+
+        assert_eq!(future_response.status(), Status::BadRequest);
+    }
+}
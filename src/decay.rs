@@ -0,0 +1,92 @@
+/// A score that fades over time, for "trending now" lists. Every increment adds its step to
+/// the score and every decrement takes it away, and the score halves every `half_life`
+/// seconds. It is brought up to date lazily, whenever the counter is read or changed, so
+/// nothing runs on a timer.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Decay {
+    /// Seconds.
+    pub half_life: u64,
+    pub score: f64,
+    /// When the score was last brought up to date, in milliseconds since the Unix epoch.
+    pub scored_at: u64,
+}
+
+impl Decay {
+    pub fn new(half_life: u64, now: u64) -> Decay {
+        Decay {
+            half_life,
+            score: 0.0,
+            scored_at: now,
+        }
+    }
+
+    /// `score` as it has decayed from `from` to `to`, both in milliseconds.
+    fn faded(&self, score: f64, from: u64, to: u64) -> f64 {
+        let elapsed = to.saturating_sub(from) as f64 / 1000.0;
+
+        score * 0.5_f64.powf(elapsed / self.half_life as f64)
+    }
+
+    pub fn advance(&mut self, now: u64) {
+        if now > self.scored_at {
+            self.score = self.faded(self.score, self.scored_at, now);
+            self.scored_at = now;
+        }
+    }
+
+    /// Adds `delta` as of `occurred_at`, which has decayed since if that was before `now`. The
+    /// score never goes below zero.
+    pub fn add(&mut self, delta: f64, occurred_at: u64, now: u64) {
+        self.advance(now);
+        self.score = (self.score + self.faded(delta, occurred_at, now)).max(0.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn decay_score() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "decay": { "half_life": 1 } }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for _ in 0..2 {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .dispatch();
+        }
+
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let score = counter.decay.unwrap().score;
+
+        assert_eq!(counter.value, 2);
+        assert!(score > 0.5 && score < 1.1, "score was {}", score);
+
+        let invalid_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "decay": { "half_life": 0 } }"#)
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::UnprocessableEntity);
+
+        let sorted_response = client.get("/counter?sort=score&order=desc").dispatch();
+
+        assert_eq!(sorted_response.status(), Status::Ok);
+    }
+}
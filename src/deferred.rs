@@ -0,0 +1,239 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::lock;
+use crate::pool::BlockingPool;
+use crate::telemetry;
+use crate::{ApiError, V1};
+
+/// Results that nobody picked up are dropped after this long.
+const RESULT_TTL: Duration = Duration::from_secs(600);
+
+/// Latency budgets per route, configured in milliseconds in the `latency_budgets` table, e.g.
+/// `latency_budgets = { increment = 200 }`. A request that has not completed within its
+/// route's budget, typically because it is waiting for a contended counter, is answered with
+/// 202 Accepted and a token; the result is then available at `/v1/operations/<token>`.
+/// Requests with a budget run on the blocking pool, so a contended counter holds up a pool
+/// thread rather than an HTTP worker.
+#[derive(Default)]
+pub struct Deferred {
+    budgets: HashMap<String, Duration>,
+    operations: Arc<Operations>,
+    pool: BlockingPool,
+}
+
+#[derive(Default)]
+struct Operations {
+    results: Mutex<HashMap<Uuid, Operation>>,
+    completed: Condvar,
+}
+
+enum Operation {
+    Pending,
+    Completed(Completed, Instant),
+}
+
+/// Status and JSON body of a finished request.
+pub struct Completed {
+    status: Status,
+    body: serde_json::Value,
+}
+
+impl Completed {
+    fn new<T: Serialize>(result: Result<T, ApiError>) -> Completed {
+        match result {
+            Ok(value) => Completed {
+                status: Status::Ok,
+                body: serde_json::to_value(value).unwrap_or(serde_json::Value::Null),
+            },
+            Err(error) => Completed {
+                status: error.0,
+                body: (error.1).0,
+            },
+        }
+    }
+}
+
+pub enum Reply {
+    Ready(Completed),
+    Accepted(Uuid),
+}
+
+impl<'r> Responder<'r> for Reply {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            Reply::Ready(completed) => {
+                Response::build_from(Json(completed.body).respond_to(request)?)
+                    .status(completed.status)
+                    .ok()
+            }
+            Reply::Accepted(token) => {
+                let location = format!("{}/operations/{}", V1, token);
+
+                Response::build_from(
+                    json!({
+                        "status": "accepted",
+                        "token": token,
+                        "location": location,
+                    })
+                    .respond_to(request)?,
+                )
+                .status(Status::Accepted)
+                .raw_header("Location", location)
+                .ok()
+            }
+        }
+    }
+}
+
+impl Deferred {
+    pub fn from_config(config: &Config) -> Deferred {
+        let budgets = config
+            .get_table("latency_budgets")
+            .map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(route, millis)| {
+                        millis
+                            .as_integer()
+                            .filter(|millis| *millis >= 0)
+                            .map(|millis| (route.clone(), Duration::from_millis(millis as u64)))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Deferred {
+            budgets,
+            operations: Arc::default(),
+            pool: BlockingPool::from_config(config),
+        }
+    }
+
+    /// Runs `job` within the latency budget of `route`, or directly if it has none.
+    pub fn run<T, F>(&self, route: &str, job: F) -> Reply
+    where
+        T: Serialize,
+        F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    {
+        let budget = match self.budgets.get(route) {
+            Some(budget) => *budget,
+            None => return Reply::Ready(Completed::new(job())),
+        };
+        let token = Uuid::new_v4();
+        let operations = Arc::clone(&self.operations);
+        let deadline = Instant::now() + budget;
+
+        {
+            let mut results = lock(&operations.results);
+
+            results.retain(|_, operation| match operation {
+                Operation::Completed(_, at) => at.elapsed() < RESULT_TTL,
+                Operation::Pending => true,
+            });
+            results.insert(token, Operation::Pending);
+        }
+
+        let worker = Arc::clone(&operations);
+        let trace = telemetry::context();
+
+        self.pool.spawn(move || {
+            let completed = Completed::new(trace.enter(job));
+
+            lock(&worker.results).insert(token, Operation::Completed(completed, Instant::now()));
+            worker.completed.notify_all();
+        });
+
+        let mut results = lock(&operations.results);
+
+        loop {
+            if let Some(Reply::Ready(completed)) = take(&mut results, token) {
+                return Reply::Ready(completed);
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Reply::Accepted(token);
+            }
+
+            results = operations
+                .completed
+                .wait_timeout(results, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+/// The result of a deferred request: ready once, pending until then, `None` if unknown.
+fn take(results: &mut HashMap<Uuid, Operation>, token: Uuid) -> Option<Reply> {
+    match results.remove(&token)? {
+        Operation::Completed(completed, _) => Some(Reply::Ready(completed)),
+        Operation::Pending => {
+            results.insert(token, Operation::Pending);
+            Some(Reply::Accepted(token))
+        }
+    }
+}
+
+#[get("/<token>")]
+pub fn get_operation(token: String, deferred: State<Deferred>) -> Option<Reply> {
+    let token = Uuid::parse_str(&token).ok()?;
+
+    take(&mut lock(&deferred.operations.results), token)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn defer_requests_over_latency_budget() {
+        let mut budgets = BTreeMap::new();
+
+        budgets.insert("increment".to_string(), Value::Integer(0));
+
+        let config = Config::build(Environment::Development)
+            .extra("latency_budgets", Value::Table(budgets))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        // With a zero budget the increment is answered right away unless it already finished.
+        while response.status() == Status::Accepted {
+            let location = response.headers().get_one("Location").unwrap().to_string();
+
+            thread::sleep(Duration::from_millis(10));
+            response = client.get(location).dispatch();
+        }
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let incremented: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(incremented.value, 1);
+    }
+}
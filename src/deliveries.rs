@@ -0,0 +1,620 @@
+//! Posts to webhooks and milestone hooks, queued until the endpoint accepts them. A post that
+//! fails is retried after `delay` seconds, twice that after the next failure and so on, up to
+//! `attempts` tries in all, as configured with `webhook_retries = { attempts = 5, delay = 1 }`.
+//! After the last try it stays on a dead-letter list until replayed with
+//! `POST /counter/<id>/webhooks/deliveries/<delivery_id>/replay`, or all of a counter's at once
+//! with `POST /counter/<id>/webhooks/deliveries/replay`.
+//!
+//! With `webhook_queue_file = "/var/lib/caas/webhooks.json"` the queue and the dead letters are
+//! kept in that file, so that posts still waiting when the instance stops are sent after it
+//! starts again. Retries run from a thread of their own rather than as a job, as they fall due
+//! at exact times rather than intervals.
+
+use rocket::http::Status;
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::history;
+use crate::lock;
+use crate::negotiation::Unused;
+use crate::pool::BlockingPool;
+use crate::tenancy::TenantStore;
+use crate::webhooks::Webhooks;
+use crate::{error, not_found_error, parse_id, ApiError};
+
+const DEFAULT_ATTEMPTS: u32 = 5;
+const DEFAULT_DELAY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Most dead letters kept, oldest dropped first.
+const MAX_DEAD_LETTERS: usize = 1000;
+/// Longest the runner sleeps before checking whether it is still needed.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Threshold,
+    Milestone,
+    /// Sent for a notification channel.
+    Channel,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting for its next attempt.
+    Pending,
+    Sending,
+    /// Out of attempts, kept until replayed.
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Delivery {
+    id: Uuid,
+    counter_id: Uuid,
+    /// The webhook, milestone hook or channel it was sent for.
+    hook_id: Uuid,
+    kind: Kind,
+    url: String,
+    payload: serde_json::Value,
+    status: DeliveryStatus,
+    /// Failed attempts so far.
+    attempts: u32,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    /// Milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_attempt_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+/// How posts to one URL have gone since the service started.
+#[derive(Serialize, Clone)]
+pub struct Endpoint {
+    url: String,
+    delivered: u64,
+    failures: u64,
+    /// Failures since the last successful post.
+    consecutive_failures: u64,
+    /// Milliseconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_failure_at: Option<u64>,
+}
+
+impl Endpoint {
+    fn new(url: &str) -> Endpoint {
+        Endpoint {
+            url: url.to_string(),
+            delivered: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_failure_at: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Oldest first.
+    deliveries: Vec<Delivery>,
+    endpoints: HashMap<String, Endpoint>,
+}
+
+/// Cheaply cloneable handle to the delivery queue.
+#[derive(Clone)]
+pub struct Deliveries {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    client: reqwest::Client,
+    pool: BlockingPool,
+    attempts: u32,
+    delay: Duration,
+    file: Option<PathBuf>,
+}
+
+impl Shared {
+    /// Writes the queue to the file, if there is one.
+    fn save(&self, queue: &Queue) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let saved = serde_json::to_vec(&queue.deliveries)
+            .map_err(|reason| reason.to_string())
+            .and_then(|saved| fs::write(file, saved).map_err(|reason| reason.to_string()));
+
+        if let Err(reason) = saved {
+            eprintln!("Could not save the webhook queue: {}", reason);
+        }
+    }
+
+    /// Milliseconds to wait after the `attempts`th failure.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let doublings = attempts.saturating_sub(1).min(20);
+
+        (self.delay.as_millis() as u64).saturating_mul(1 << doublings)
+    }
+}
+
+impl Deliveries {
+    pub fn from_config(config: &Config) -> Deliveries {
+        let retries = |key: &str| {
+            config
+                .get_table("webhook_retries")
+                .ok()
+                .and_then(|table| table.get(key).and_then(|value| value.as_integer()))
+                .filter(|value| *value > 0)
+        };
+        let file = config.get_str("webhook_queue_file").ok().map(PathBuf::from);
+        let deliveries: Vec<Delivery> = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|saved| match serde_json::from_str(&saved) {
+                Ok(deliveries) => Some(deliveries),
+                Err(reason) => {
+                    eprintln!("Ignoring the saved webhook queue: {}", reason);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        let deliveries = Deliveries {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    deliveries: deliveries
+                        .into_iter()
+                        .map(|mut delivery| {
+                            // Posts cut off by the restart are tried again.
+                            if delivery.status == DeliveryStatus::Sending {
+                                delivery.status = DeliveryStatus::Pending;
+                            }
+
+                            delivery
+                        })
+                        .collect(),
+                    endpoints: HashMap::new(),
+                }),
+                changed: Condvar::new(),
+                client: reqwest::Client::builder()
+                    .timeout(TIMEOUT)
+                    .build()
+                    .expect("Failed to build the webhook client"),
+                pool: BlockingPool::from_config(config),
+                attempts: retries("attempts").map_or(DEFAULT_ATTEMPTS, |attempts| attempts as u32),
+                delay: retries("delay")
+                    .map_or(DEFAULT_DELAY, |delay| Duration::from_secs(delay as u64)),
+                file,
+            }),
+        };
+        let shared = Arc::downgrade(&deliveries.shared);
+
+        thread::spawn(move || run(&shared));
+        deliveries
+    }
+
+    fn queue(&self) -> MutexGuard<Queue> {
+        lock(&self.shared.queue)
+    }
+
+    /// Queues `payload` to be posted to `url` as JSON, and posts it right away.
+    pub fn enqueue(
+        &self,
+        counter_id: Uuid,
+        hook_id: Uuid,
+        kind: Kind,
+        url: String,
+        payload: serde_json::Value,
+    ) {
+        let delivery = Delivery {
+            id: Uuid::new_v4(),
+            counter_id,
+            hook_id,
+            kind,
+            url,
+            payload,
+            status: DeliveryStatus::Sending,
+            attempts: 0,
+            created_at: history::timestamp(),
+            next_attempt_at: None,
+            last_error: None,
+        };
+        let mut queue = self.queue();
+
+        send(&self.shared, &delivery);
+        queue.deliveries.push(delivery);
+        self.shared.save(&queue);
+    }
+
+    /// Deliveries to `counter_id` that are waiting or have failed, oldest first.
+    pub fn list(&self, counter_id: Uuid) -> Vec<Delivery> {
+        self.queue()
+            .deliveries
+            .iter()
+            .filter(|delivery| delivery.counter_id == counter_id)
+            .cloned()
+            .collect()
+    }
+
+    /// How posts to each of `urls` have gone.
+    pub fn endpoints(&self, urls: &[String]) -> Vec<Endpoint> {
+        let queue = self.queue();
+
+        urls.iter()
+            .map(|url| {
+                queue
+                    .endpoints
+                    .get(url)
+                    .cloned()
+                    .unwrap_or_else(|| Endpoint::new(url))
+            })
+            .collect()
+    }
+
+    /// Queues the failed deliveries to `counter_id` that `matches` again, starting over with
+    /// their attempts, and returns them.
+    pub fn replay<F>(&self, counter_id: Uuid, matches: F) -> Vec<Delivery>
+    where
+        F: Fn(&Delivery) -> bool,
+    {
+        let mut queue = self.queue();
+        let mut replayed = Vec::new();
+
+        for delivery in queue.deliveries.iter_mut().filter(|delivery| {
+            delivery.counter_id == counter_id
+                && delivery.status == DeliveryStatus::Failed
+                && matches(delivery)
+        }) {
+            delivery.status = DeliveryStatus::Pending;
+            delivery.attempts = 0;
+            delivery.next_attempt_at = None;
+            replayed.push(delivery.clone());
+        }
+
+        if !replayed.is_empty() {
+            self.shared.save(&queue);
+            self.shared.changed.notify_one();
+        }
+
+        replayed
+    }
+}
+
+/// Posts `delivery` on the pool and notes how it went.
+fn send(shared: &Arc<Shared>, delivery: &Delivery) {
+    let id = delivery.id;
+    let url = delivery.url.clone();
+    let body = serde_json::to_vec(&delivery.payload).unwrap_or_default();
+    let client = shared.client.clone();
+    let finished = Arc::clone(shared);
+
+    shared.pool.spawn(move || {
+        let outcome = client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .map_err(|reason| reason.to_string())
+            .and_then(|response| match response.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(format!("Answered {}", status)),
+            });
+
+        finish(&finished, id, outcome);
+    });
+}
+
+/// Drops a delivered post from the queue, or schedules its next attempt, or gives up on it.
+fn finish(shared: &Shared, id: Uuid, outcome: Result<(), String>) {
+    let mut guard = lock(&shared.queue);
+    let queue = &mut *guard;
+    let index = match queue
+        .deliveries
+        .iter()
+        .position(|delivery| delivery.id == id)
+    {
+        Some(index) => index,
+        None => return,
+    };
+    let now = history::timestamp();
+    let delivery = &mut queue.deliveries[index];
+    let endpoint = queue
+        .endpoints
+        .entry(delivery.url.clone())
+        .or_insert_with(|| Endpoint::new(&delivery.url));
+
+    match outcome {
+        Ok(()) => {
+            endpoint.delivered += 1;
+            endpoint.consecutive_failures = 0;
+            queue.deliveries.remove(index);
+        }
+        Err(reason) => {
+            endpoint.failures += 1;
+            endpoint.consecutive_failures += 1;
+            endpoint.last_failure_at = Some(now);
+            delivery.attempts += 1;
+            delivery.last_error = Some(reason);
+
+            if delivery.attempts < shared.attempts {
+                delivery.status = DeliveryStatus::Pending;
+                delivery.next_attempt_at = Some(now + shared.backoff(delivery.attempts));
+            } else {
+                eprintln!(
+                    "Webhook to {} failed {} times, giving up",
+                    delivery.url, delivery.attempts
+                );
+                delivery.status = DeliveryStatus::Failed;
+                delivery.next_attempt_at = None;
+            }
+
+            let failed = queue
+                .deliveries
+                .iter()
+                .filter(|delivery| delivery.status == DeliveryStatus::Failed)
+                .count();
+
+            if failed > MAX_DEAD_LETTERS {
+                if let Some(oldest) = queue
+                    .deliveries
+                    .iter()
+                    .position(|delivery| delivery.status == DeliveryStatus::Failed)
+                {
+                    queue.deliveries.remove(oldest);
+                }
+            }
+        }
+    }
+
+    shared.save(queue);
+    shared.changed.notify_one();
+}
+
+/// Sends the pending deliveries as they fall due, until the queue is dropped.
+fn run(shared: &Weak<Shared>) {
+    while let Some(shared) = shared.upgrade() {
+        let now = history::timestamp();
+        let mut queue = lock(&shared.queue);
+
+        for delivery in queue.deliveries.iter_mut().filter(|delivery| {
+            delivery.status == DeliveryStatus::Pending
+                && delivery.next_attempt_at.map_or(true, |at| at <= now)
+        }) {
+            delivery.status = DeliveryStatus::Sending;
+            delivery.next_attempt_at = None;
+            send(&shared, delivery);
+        }
+
+        let sleep = queue
+            .deliveries
+            .iter()
+            .filter_map(|delivery| delivery.next_attempt_at)
+            .map(|at| Duration::from_millis(at.saturating_sub(now)))
+            .min()
+            .unwrap_or(MAX_SLEEP)
+            .min(MAX_SLEEP);
+        let _ = shared.changed.wait_timeout(queue, sleep);
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    /// Every URL the counter's hooks post to.
+    endpoints: Vec<Endpoint>,
+    deliveries: Vec<Delivery>,
+}
+
+/// `GET /counter/<id>/webhooks/deliveries`, the counter's posts that are waiting to be sent
+/// again or have failed for good, and how its endpoints have been answering. Hook URLs can be
+/// secret, so only those who may change the counter see them.
+#[get("/<id>/webhooks/deliveries")]
+pub fn list_deliveries(
+    id: String,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Report>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let deliveries = webhooks.deliveries().list(parsed_uuid);
+    let mut urls = webhooks.urls_of(parsed_uuid);
+
+    urls.extend(deliveries.iter().map(|delivery| delivery.url.clone()));
+    urls.sort();
+    urls.dedup();
+
+    Ok(Json(Report {
+        endpoints: webhooks.deliveries().endpoints(&urls),
+        deliveries,
+    }))
+}
+
+#[post("/<id>/webhooks/deliveries/<delivery_id>/replay", data = "<_body>")]
+pub fn replay_delivery(
+    id: String,
+    delivery_id: String,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+    _body: Unused,
+) -> Result<Json<Delivery>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let delivery_id = parse_id(&delivery_id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let delivery = webhooks
+        .deliveries()
+        .list(parsed_uuid)
+        .into_iter()
+        .find(|delivery| delivery.id == delivery_id)
+        .ok_or_else(not_found_error)?;
+
+    if delivery.status != DeliveryStatus::Failed {
+        return Err(error(
+            Status::Conflict,
+            "Only deliveries that have failed for good can be replayed.",
+        ));
+    }
+
+    webhooks
+        .deliveries()
+        .replay(parsed_uuid, |delivery| delivery.id == delivery_id)
+        .pop()
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+/// Replays every failed delivery of the counter.
+#[post("/<id>/webhooks/deliveries/replay", data = "<_body>")]
+pub fn replay_deliveries(
+    id: String,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+    _body: Unused,
+) -> Result<Json<Vec<Delivery>>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    Ok(Json(webhooks.deliveries().replay(parsed_uuid, |_| true)))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn replay_failed_webhook_deliveries() {
+        let mut retries = BTreeMap::new();
+
+        retries.insert("attempts".to_string(), Value::Integer(1));
+
+        let config = Config::build(Environment::Development)
+            .extra("webhook_retries", Value::Table(retries))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        // Nothing listens on the port until the delivery is replayed.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = format!("http://{}/hook", address);
+
+        client
+            .post(format!("/counter/{}/webhooks", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "url": "{}", "condition": "value >= 1" }}"#,
+                url
+            ))
+            .dispatch();
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let report = |client: &Client| -> serde_json::Value {
+            let mut response = client
+                .get(format!("/counter/{}/webhooks/deliveries", counter.id))
+                .dispatch();
+
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+        let wait_for = |client: &Client, done: &dyn Fn(&serde_json::Value) -> bool| {
+            for _ in 0..50 {
+                let report = report(client);
+
+                if done(&report) {
+                    return report;
+                }
+
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            panic!("Deliveries did not settle: {}", report(client));
+        };
+        let failed = wait_for(&client, &|report| {
+            report["deliveries"][0]["status"] == "failed"
+        });
+        let delivery = &failed["deliveries"][0];
+
+        assert_eq!(delivery["kind"], "threshold");
+        assert_eq!(delivery["attempts"], 1);
+        assert_eq!(delivery["payload"]["counter"]["value"], 1);
+        assert!(delivery["last_error"].is_string());
+        assert_eq!(failed["endpoints"][0]["url"], url.as_str());
+        assert_eq!(failed["endpoints"][0]["failures"], 1);
+
+        let listener = TcpListener::bind(address).unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+        });
+
+        let replay_response = client
+            .post(format!(
+                "/counter/{}/webhooks/deliveries/{}/replay",
+                counter.id,
+                delivery["id"].as_str().unwrap()
+            ))
+            .dispatch();
+
+        assert_eq!(replay_response.status(), Status::Ok);
+
+        let delivered = wait_for(&client, &|report| {
+            report["deliveries"].as_array().unwrap().is_empty()
+        });
+
+        assert_eq!(delivered["endpoints"][0]["delivered"], 1);
+        assert_eq!(delivered["endpoints"][0]["consecutive_failures"], 0);
+    }
+}
@@ -0,0 +1,200 @@
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::{Config, State};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::calendar::parse_date;
+use crate::conditional::http_date;
+use crate::history;
+use crate::lock;
+
+/// Clients tracked per deprecated route; calls from others are still counted in the total.
+const MAX_CLIENTS: usize = 100;
+
+/// Registry of deprecated mount points and who still calls them. Every route mounted at a
+/// registered base answers with `Deprecation`, `Sunset` (if `sunset_date` is configured) and
+/// a `Link` to its successor. `deprecation_date` and `sunset_date` are `YYYY-MM-DD` dates.
+#[derive(Default)]
+pub struct Deprecations {
+    /// Milliseconds since the Unix epoch.
+    deprecated_at: Option<u64>,
+    /// Milliseconds since the Unix epoch.
+    sunset_at: Option<u64>,
+    /// Deprecated mount points and the mount points that replace them.
+    successors: BTreeMap<String, String>,
+    usage: Mutex<BTreeMap<String, Usage>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    successor: Option<String>,
+    calls: u64,
+    clients: BTreeMap<String, ClientUsage>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct ClientUsage {
+    calls: u64,
+    /// Milliseconds since the Unix epoch.
+    last_seen: u64,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    route: String,
+    successor: Option<String>,
+    deprecated_at: Option<u64>,
+    sunset_at: Option<u64>,
+    calls: u64,
+    /// Keyed by `User-Agent`, as clients are not otherwise identified.
+    clients: BTreeMap<String, ClientUsage>,
+}
+
+impl Deprecations {
+    pub fn from_config(config: &Config) -> Deprecations {
+        let date = |key: &str| config.get_str(key).ok().and_then(parse_date);
+
+        Deprecations {
+            deprecated_at: date("deprecation_date"),
+            sunset_at: date("sunset_date"),
+            ..Default::default()
+        }
+    }
+
+    pub fn deprecate(&mut self, base: &str, successor: &str) {
+        self.successors
+            .insert(base.to_string(), successor.to_string());
+    }
+
+    /// `path` of a request to a route mounted at `base`, under the successor mount point.
+    fn successor(&self, base: &str, path: &str) -> Option<String> {
+        self.successors
+            .get(base)
+            .map(|successor| format!("{}{}", successor, &path[base.len()..]))
+    }
+
+    fn record(&self, route: String, successor: Option<String>, client: &str) {
+        let mut usage = lock(&self.usage);
+        let usage = usage.entry(route).or_default();
+        let now = history::timestamp();
+
+        usage.successor = successor;
+        usage.calls += 1;
+
+        if usage.clients.len() < MAX_CLIENTS || usage.clients.contains_key(client) {
+            let client = usage
+                .clients
+                .entry(client.to_string())
+                .or_insert(ClientUsage {
+                    calls: 0,
+                    last_seen: now,
+                });
+
+            client.calls += 1;
+            client.last_seen = now;
+        }
+    }
+
+    /// Deprecated routes that have been called, most called first.
+    pub fn report(&self) -> Vec<Report> {
+        let usage = lock(&self.usage);
+        let mut reports: Vec<Report> = usage
+            .iter()
+            .map(|(route, usage)| Report {
+                route: route.clone(),
+                successor: usage.successor.clone(),
+                deprecated_at: self.deprecated_at,
+                sunset_at: self.sunset_at,
+                calls: usage.calls,
+                clients: usage.clients.clone(),
+            })
+            .collect();
+
+        reports.sort_by(|a, b| b.calls.cmp(&a.calls));
+        reports
+    }
+}
+
+/// Adds the deprecation headers to responses of deprecated routes and records the call.
+pub fn annotate_response(request: &Request, response: &mut Response) {
+    let deprecations = match request.guard::<State<Deprecations>>().succeeded() {
+        Some(deprecations) => deprecations,
+        None => return,
+    };
+    let route = match request.route() {
+        Some(route) => route,
+        None => return,
+    };
+    let successor = match deprecations.successor(route.base(), request.uri().path()) {
+        Some(successor) => successor,
+        None => return,
+    };
+
+    response.set_raw_header(
+        "Deprecation",
+        deprecations
+            .deprecated_at
+            .map_or("true".to_string(), |at| format!("@{}", at / 1000)),
+    );
+
+    if let Some(sunset_at) = deprecations.sunset_at {
+        response.set_raw_header("Sunset", http_date(sunset_at / 1000));
+    }
+
+    response.set_raw_header(
+        "Link",
+        format!("<{}>; rel=\"successor-version\"", successor),
+    );
+    deprecations.record(
+        format!("{} {}", route.method, route.uri.path()),
+        deprecations.successor(route.base(), route.uri.path()),
+        request.headers().get_one("User-Agent").unwrap_or("unknown"),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use crate::{build, rocket};
+
+    #[test]
+    fn report_deprecated_route_usage() {
+        let config = Config::build(Environment::Development)
+            .extra("deprecation_date", "2026-01-01")
+            .extra("sunset_date", "2027-01-01")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let response = client
+            .get("/counter")
+            .header(Header::new("User-Agent", "legacy-sdk/1.0"))
+            .dispatch();
+
+        assert_eq!(
+            response.headers().get_one("Deprecation"),
+            Some("@1767225600")
+        );
+        assert_eq!(
+            response.headers().get_one("Sunset"),
+            Some("Fri, 01 Jan 2027 00:00:00 GMT")
+        );
+
+        client.get("/v1/counter").dispatch();
+
+        let mut report_response = client.get("/v1/admin/deprecations").dispatch();
+        let report: serde_json::Value =
+            serde_json::from_str(&report_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(report.as_array().unwrap().len(), 1);
+        assert!(report[0]["route"]
+            .as_str()
+            .unwrap()
+            .starts_with("GET /counter"));
+        assert_eq!(report[0]["calls"], 1);
+        assert_eq!(report[0]["clients"]["legacy-sdk/1.0"]["calls"], 1);
+    }
+}
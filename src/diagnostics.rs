@@ -0,0 +1,435 @@
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::State;
+use rocket_contrib::json::{Json, JsonValue};
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::auth::Admin;
+use crate::history;
+use crate::schedule::Schedules;
+use crate::snapshots::Snapshots;
+use crate::store::Store;
+use crate::webhooks::Webhooks;
+
+/// Store lock waits above these are worth a look, and above the second ones requests suffer.
+const SLOW_STORAGE: Duration = Duration::from_millis(10);
+const FAILING_STORAGE: Duration = Duration::from_millis(100);
+/// Writers queued on a single counter right now.
+const BUSY_WRITERS: usize = 8;
+const STUCK_WRITERS: usize = 64;
+/// Milliseconds a schedule may be overdue before the runner counts as lagging.
+const SCHEDULE_LAG: u64 = 5_000;
+const STALLED_SCHEDULES: u64 = 60_000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    name: &'static str,
+    status: Verdict,
+    detail: String,
+    /// What to do about a warning or failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remediation: Option<&'static str>,
+}
+
+impl Check {
+    fn new(
+        name: &'static str,
+        status: Verdict,
+        detail: String,
+        remediation: &'static str,
+    ) -> Check {
+        Check {
+            name,
+            status,
+            detail,
+            remediation: if status == Verdict::Pass {
+                None
+            } else {
+                Some(remediation)
+            },
+        }
+    }
+}
+
+/// The outcome of every check, and the worst of them as `status`.
+#[derive(Serialize)]
+pub struct Diagnostics {
+    status: Verdict,
+    checks: Vec<Check>,
+}
+
+fn grade<T: PartialOrd>(measured: T, warn: T, fail: T) -> Verdict {
+    if measured >= fail {
+        Verdict::Fail
+    } else if measured >= warn {
+        Verdict::Warn
+    } else {
+        Verdict::Pass
+    }
+}
+
+fn storage(store: &Store) -> Check {
+    let started = Instant::now();
+    let count = store.count();
+    let elapsed = started.elapsed();
+
+    Check::new(
+        "storage",
+        grade(elapsed, SLOW_STORAGE, FAILING_STORAGE),
+        format!("Read {} counters in {} µs.", count, elapsed.as_micros()),
+        "The store lock is busy. See /v1/admin/contention for the counters holding it.",
+    )
+}
+
+fn locks(store: &Store) -> Check {
+    let busiest = store
+        .contention(usize::max_value())
+        .into_iter()
+        .max_by_key(|report| report.current_writers);
+    let (detail, writers) = match busiest {
+        Some(ref report) if report.current_writers > 0 => (
+            format!(
+                "{} writers are queued on counter {}.",
+                report.current_writers, report.id
+            ),
+            report.current_writers,
+        ),
+        _ => ("No writers are queued.".to_string(), 0),
+    };
+
+    Check::new(
+        "locks",
+        grade(writers, BUSY_WRITERS, STUCK_WRITERS),
+        detail,
+        "A hot counter is serializing writes. Raise its latency budget or spread the load \
+         over several counters.",
+    )
+}
+
+fn sampler(store: &Store, now: u64) -> Check {
+    let interval = match store.timeseries().interval() {
+        Some(interval) => interval.as_millis() as u64,
+        None => {
+            return Check::new(
+                "sampler",
+                Verdict::Pass,
+                "Time-series sampling is disabled.".to_string(),
+                "",
+            )
+        }
+    };
+    // Right after launch nothing has been sampled yet, which is not lag.
+    let lag = store
+        .timeseries()
+        .last_sampled()
+        .map_or(0, |last| now.saturating_sub(last));
+
+    Check::new(
+        "sampler",
+        grade(lag, interval * 2, interval * 5),
+        format!("Last sample was taken {} ms ago.", lag),
+        "The sampler thread is falling behind. Check the host's CPU load or raise \
+         `timeseries_interval`.",
+    )
+}
+
+fn scheduler(schedules: &Schedules, now: u64) -> Check {
+    let lag = schedules.lag(now);
+
+    Check::new(
+        "scheduler",
+        grade(lag, SCHEDULE_LAG, STALLED_SCHEDULES),
+        format!("The most overdue schedule is {} ms late.", lag),
+        "Scheduled operations are not running on time. Check the host's CPU load and restart \
+         if the lag keeps growing.",
+    )
+}
+
+fn reachable(url: &str) -> bool {
+    let address = reqwest::Url::parse(url).ok().and_then(|url| {
+        let host = url.host_str()?.to_string();
+        let port = url.port_or_known_default()?;
+
+        (host.as_str(), port).to_socket_addrs().ok()?.next()
+    });
+
+    address.map_or(false, |address| {
+        TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).is_ok()
+    })
+}
+
+fn webhook_endpoints(webhooks: &Webhooks) -> Check {
+    let urls = webhooks.urls();
+    let unreachable: Vec<String> = urls.iter().filter(|url| !reachable(url)).cloned().collect();
+
+    Check::new(
+        "webhooks",
+        if unreachable.is_empty() {
+            Verdict::Pass
+        } else {
+            Verdict::Warn
+        },
+        if unreachable.is_empty() {
+            format!("All {} webhook endpoints accept connections.", urls.len())
+        } else {
+            format!("Unreachable: {}.", unreachable.join(", "))
+        },
+        "Deliveries to these endpoints are retried and then dropped. Ask their owners to fix \
+         them, or delete the webhooks.",
+    )
+}
+
+/// Self-checks for whoever is on call, each with a hint on what to do if it does not pass.
+/// Responds with 503 when any check fails, so that it can double as a health check.
+#[get("/diagnostics")]
+pub fn diagnostics(
+    store: State<Store>,
+    schedules: State<Schedules>,
+    webhooks: State<Webhooks>,
+    _admin: Admin,
+) -> status::Custom<Json<Diagnostics>> {
+    let now = history::timestamp();
+    let checks = vec![
+        storage(&store),
+        locks(&store),
+        sampler(&store, now),
+        scheduler(&schedules, now),
+        webhook_endpoints(&webhooks),
+    ];
+    let status = checks
+        .iter()
+        .map(|check| check.status)
+        .fold(
+            Verdict::Pass,
+            |worst, status| {
+                if status > worst {
+                    status
+                } else {
+                    worst
+                }
+            },
+        );
+    let code = if status == Verdict::Fail {
+        Status::ServiceUnavailable
+    } else {
+        Status::Ok
+    };
+
+    status::Custom(code, Json(Diagnostics { status, checks }))
+}
+
+/// Liveness probe, answering as long as the process can serve requests at all.
+#[get("/healthz")]
+pub fn healthz() -> JsonValue {
+    json!({ "status": "ok" })
+}
+
+/// Readiness probe, failing with 503 while the store does not respond in time. Counters live
+/// in memory with nothing to load at startup, so the store being reachable is all it takes.
+#[get("/readyz")]
+pub fn readyz(store: State<Store>) -> status::Custom<Json<Check>> {
+    let check = storage(&store);
+    let code = if check.status == Verdict::Fail {
+        Status::ServiceUnavailable
+    } else {
+        Status::Ok
+    };
+
+    status::Custom(code, Json(check))
+}
+
+/// When the process started serving.
+pub struct Launched(Instant);
+
+impl Default for Launched {
+    fn default() -> Launched {
+        Launched(Instant::now())
+    }
+}
+
+/// Memory use of the process, as the kernel reports it. Only known on Linux.
+#[derive(Serialize)]
+pub struct Memory {
+    resident_bytes: u64,
+    virtual_bytes: u64,
+}
+
+impl Memory {
+    fn current() -> Option<Memory> {
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        let kilobytes = |field: &str| {
+            status
+                .lines()
+                .find(|line| line.starts_with(field))?
+                .split_whitespace()
+                .nth(1)?
+                .parse::<u64>()
+                .ok()
+        };
+
+        Some(Memory {
+            resident_bytes: kilobytes("VmRSS:")? * 1024,
+            virtual_bytes: kilobytes("VmSize:")? * 1024,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct Task {
+    name: &'static str,
+    detail: String,
+}
+
+/// A look inside the running process, for diagnosing a sick instance.
+#[derive(Serialize)]
+pub struct Introspection {
+    uptime_seconds: u64,
+    memory: Option<Memory>,
+    counters: usize,
+    archived_counters: usize,
+    /// Milliseconds since the most recent snapshot was taken.
+    snapshot_age_ms: Option<u64>,
+    tasks: Vec<Task>,
+}
+
+#[get("/debug")]
+pub fn debug(
+    launched: State<Launched>,
+    store: State<Store>,
+    schedules: State<Schedules>,
+    snapshots: State<Snapshots>,
+    webhooks: State<Webhooks>,
+    _admin: Admin,
+) -> Json<Introspection> {
+    let now = history::timestamp();
+    let (counters, archived_counters) = {
+        let counters = store.read();
+
+        (
+            counters.len(),
+            counters.values().filter(|counter| counter.archived).count(),
+        )
+    };
+    let sampler = match store.timeseries().last_sampled() {
+        Some(last) => format!("Last sample was taken {} ms ago.", now.saturating_sub(last)),
+        None => "Nothing has been sampled yet.".to_string(),
+    };
+
+    Json(Introspection {
+        uptime_seconds: launched.0.elapsed().as_secs(),
+        memory: Memory::current(),
+        counters,
+        archived_counters,
+        snapshot_age_ms: snapshots.newest().map(|newest| now.saturating_sub(newest)),
+        tasks: vec![
+            Task {
+                name: "sampler",
+                detail: sampler,
+            },
+            Task {
+                name: "scheduler",
+                detail: format!(
+                    "{} schedules pending, the most overdue {} ms late.",
+                    schedules.pending(),
+                    schedules.lag(now)
+                ),
+            },
+            Task {
+                name: "webhooks",
+                detail: format!("{} webhooks registered.", webhooks.urls().len()),
+            },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::rocket;
+
+    #[test]
+    fn introspect_process() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        client.post("/counter").header(ContentType::JSON).dispatch();
+        client
+            .post("/admin/snapshots")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "before" }"#)
+            .dispatch();
+
+        let mut response = client.get("/admin/debug").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let debug: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let tasks: Vec<&str> = debug["tasks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|task| task["name"].as_str().unwrap())
+            .collect();
+
+        assert_eq!(debug["counters"], 1);
+        assert!(debug["uptime_seconds"].is_u64());
+        assert!(debug["snapshot_age_ms"].is_u64());
+        assert_eq!(tasks, vec!["sampler", "scheduler", "webhooks"]);
+    }
+
+    #[test]
+    fn probe_health_and_readiness() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut health_response = client.get("/healthz").dispatch();
+
+        assert_eq!(health_response.status(), Status::Ok);
+        assert_eq!(
+            health_response.body_string(),
+            Some(r#"{"status":"ok"}"#.into())
+        );
+
+        let mut ready_response = client.get("/readyz").dispatch();
+
+        assert_eq!(ready_response.status(), Status::Ok);
+        assert!(ready_response
+            .body_string()
+            .unwrap()
+            .contains(r#""status":"pass""#));
+    }
+
+    #[test]
+    fn run_diagnostics() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut response = client.get("/admin/diagnostics").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let body_string = response.body_string().unwrap();
+        let diagnostics: serde_json::Value = serde_json::from_str(&body_string).unwrap();
+        let names: Vec<&str> = diagnostics["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|check| check["name"].as_str().unwrap())
+            .collect();
+
+        assert_eq!(diagnostics["status"], "pass");
+        assert_eq!(
+            names,
+            vec!["storage", "locks", "sampler", "scheduler", "webhooks"]
+        );
+    }
+}
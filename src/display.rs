@@ -0,0 +1,139 @@
+use rocket::response::content::Html;
+
+use crate::tenancy::TenantStore;
+use crate::{not_found_error, parse_id, ApiError};
+
+/// Background and text colors of each theme.
+const THEMES: &[(&str, &str, &str)] = &[
+    ("dark", "#111", "#fafafa"),
+    ("light", "#fafafa", "#111"),
+    ("contrast", "#000", "#ff0"),
+];
+const DEFAULT_REFRESH: u32 = 5;
+const MAX_REFRESH: u32 = 3600;
+
+/// Fits the value to the screen and keeps it up to date by polling the counter. A failed poll
+/// leaves the last value on screen so a flaky venue network does not blank the display.
+const PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+  html, body { height: 100%; margin: 0; }
+  body {
+    display: flex;
+    flex-direction: column;
+    align-items: center;
+    justify-content: center;
+    background: {background};
+    color: {foreground};
+    font-family: system-ui, -apple-system, "Segoe UI", Roboto, sans-serif;
+    overflow: hidden;
+    cursor: none;
+  }
+  #name { font-size: 5vmin; opacity: 0.7; }
+  #value { font-size: 40vmin; font-weight: 700; font-variant-numeric: tabular-nums; line-height: 1; }
+</style>
+</head>
+<body>
+<div id="name">{name}</div>
+<div id="value">{value}</div>
+<script>
+  (function () {
+    var value = document.getElementById("value");
+
+    setInterval(function () {
+      fetch("/v1/counter/{id}", { headers: { Accept: "application/json" }, cache: "no-store" })
+        .then(function (response) {
+          return response.ok ? response.json() : null;
+        })
+        .then(function (counter) {
+          if (counter) {
+            value.textContent = counter.value;
+          }
+        })
+        .catch(function () {});
+    }, {refresh} * 1000);
+  })();
+</script>
+</body>
+</html>
+"#;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A full-screen page showing the counter's value in large type, for wall-mounted screens.
+/// `theme` is `dark` (the default), `light` or `contrast`, and the value is refreshed every
+/// `refresh` seconds.
+#[get("/<id>?<theme>&<refresh>")]
+pub fn display(
+    id: String,
+    theme: Option<String>,
+    refresh: Option<u32>,
+    store: TenantStore,
+) -> Result<Html<String>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+    let (_, background, foreground) = THEMES
+        .iter()
+        .find(|(name, _, _)| Some(*name) == theme.as_deref())
+        .unwrap_or(&THEMES[0]);
+    let refresh = refresh.unwrap_or(DEFAULT_REFRESH).max(1).min(MAX_REFRESH);
+    let name = escape(counter.name.as_ref().map_or("", String::as_str));
+
+    Ok(Html(
+        // The name goes in last so that braces in it are not taken for placeholders.
+        PAGE.replace("{background}", background)
+            .replace("{foreground}", foreground)
+            .replace("{value}", &counter.value.to_string())
+            .replace("{id}", &parsed_uuid.to_string())
+            .replace("{refresh}", &refresh.to_string())
+            .replace("{title}", if name.is_empty() { "Counter" } else { &name })
+            .replace("{name}", &name),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn show_kiosk_display() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "<Visitors>" }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut response = client
+            .get(format!("/v1/display/{}?theme=light&refresh=0", counter.id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+
+        let page = response.body_string().unwrap();
+
+        assert!(page.contains("<div id=\"name\">&lt;Visitors&gt;</div>"));
+        assert!(page.contains("background: #fafafa;"));
+        assert!(page.contains("}, 1 * 1000);"));
+
+        let unknown_response = client
+            .get(format!("/v1/display/{}", uuid::Uuid::new_v4()))
+            .dispatch();
+
+        assert_eq!(unknown_response.status(), Status::NotFound);
+    }
+}
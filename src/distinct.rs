@@ -0,0 +1,195 @@
+//! Approximate counts of distinct items, such as unique visitors, without keeping the items.
+//! Each counter holds a HyperLogLog sketch of 2^10 one-byte registers, so it takes 1 KiB
+//! however many items it has seen, for a standard error of about 3%.
+
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::auth::Writer;
+use crate::history::{Entry, Operation};
+use crate::negotiation::Body;
+use crate::signing::{decode_hex, encode_hex};
+use crate::tenancy::TenantStore;
+use crate::validation::Validate;
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+/// Most items that one request can add.
+const MAX_ITEMS: usize = 1000;
+
+/// The sketch. Serialized as the registers in hex, so that it survives exports and replicas.
+#[derive(Clone, PartialEq)]
+pub struct Distinct {
+    registers: Vec<u8>,
+}
+
+impl Default for Distinct {
+    fn default() -> Distinct {
+        Distinct {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl Distinct {
+    pub fn add(&mut self, item: &str) {
+        let digest = Sha256::digest(item.as_bytes());
+        let mut bytes = [0; 8];
+
+        bytes.copy_from_slice(&digest[..8]);
+
+        let hash = u64::from_be_bytes(bytes);
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // Position of the first set bit among the rest, counting from one.
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    /// The number of distinct items added so far, approximately.
+    pub fn estimate(&self) -> u32 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|rank| 2_f64.powi(-i32::from(*rank)))
+            .sum();
+        let raw = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+
+        // Small counts are estimated better from the share of registers still empty.
+        let estimate = if raw <= 2.5 * m && empty > 0 {
+            m * (m / empty as f64).ln()
+        } else {
+            raw
+        };
+
+        estimate.round().min(f64::from(u32::max_value())) as u32
+    }
+}
+
+impl Serialize for Distinct {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_hex(&self.registers))
+    }
+}
+
+impl<'de> Deserialize<'de> for Distinct {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Distinct, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+
+        match decode_hex(&hex) {
+            Some(registers) if registers.len() == REGISTERS => Ok(Distinct { registers }),
+            _ => Err(de::Error::custom("invalid distinct-count sketch")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewItems {
+    items: Vec<String>,
+}
+
+impl Validate for NewItems {}
+
+/// `POST /counter/<id>/items` with `{ "items": ["visitor-1", "visitor-2"] }` adds the items to
+/// a distinct-count counter, whose value becomes the estimated number of distinct items.
+#[post("/<id>/items", data = "<new_items>")]
+pub fn add_items(
+    id: String,
+    new_items: Body<NewItems>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    if new_items.items.len() > MAX_ITEMS {
+        return Err(error(
+            Status::BadRequest,
+            &format!("At most {} items can be added at once.", MAX_ITEMS),
+        ));
+    }
+
+    store.write(parsed_uuid, |hashmap| {
+        let counter = hashmap.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+        counter.ensure_mutable()?;
+
+        let distinct = counter
+            .distinct
+            .as_mut()
+            .ok_or_else(|| error(Status::Conflict, "Counter does not count distinct items."))?;
+
+        for item in &new_items.items {
+            distinct.add(item);
+        }
+
+        let estimate = distinct.estimate();
+
+        if estimate != counter.value {
+            let delta = i64::from(estimate) - i64::from(counter.value);
+
+            counter.set_value(estimate);
+            store.history().record(
+                parsed_uuid,
+                Entry::new(Operation::Increment, delta, estimate, None),
+            );
+        }
+
+        Ok(Json(counter.clone()))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn count_distinct_items() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "distinct": true }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let items: Vec<String> = (0..500).map(|n| format!("visitor-{}", n)).collect();
+        let body = serde_json::to_string(&serde_json::json!({ "items": items })).unwrap();
+        let mut counter_value = 0;
+
+        // The same visitors twice.
+        for _ in 0..2 {
+            let mut response = client
+                .post(format!("/counter/{}/items", counter.id))
+                .header(ContentType::JSON)
+                .body(&body)
+                .dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            counter_value = counter.value;
+        }
+
+        assert!(
+            counter_value > 450 && counter_value < 550,
+            "estimated {}",
+            counter_value
+        );
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Conflict);
+    }
+}
@@ -0,0 +1,163 @@
+//! `GET /counter/stats/distribution`, how counter values are spread: a histogram of equally
+//! wide bins between the lowest and highest value, and percentiles by the nearest-rank method.
+
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+
+use crate::auth::Reader;
+use crate::routes::TagFilter;
+use crate::tenancy::TenantStore;
+use crate::{error, ApiError};
+
+const DEFAULT_BINS: u32 = 10;
+const MAX_BINS: u32 = 100;
+
+/// Values from `from` to `to`, both included.
+#[derive(Serialize)]
+pub struct Bin {
+    from: u32,
+    to: u32,
+    count: usize,
+}
+
+#[derive(Serialize)]
+pub struct Percentiles {
+    p50: u32,
+    p90: u32,
+    p99: u32,
+}
+
+#[derive(Serialize)]
+pub struct Distribution {
+    count: usize,
+    /// Missing when no counter matches.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percentiles: Option<Percentiles>,
+    histogram: Vec<Bin>,
+}
+
+/// The smallest value that at least `percent` of `sorted` is no greater than.
+fn percentile(sorted: &[u32], percent: u64) -> u32 {
+    let rank = (sorted.len() as u64 * percent + 99) / 100;
+
+    sorted[(rank.max(1) - 1) as usize]
+}
+
+/// At most `bins` bins covering `sorted`, fewer when the values span less than that.
+fn histogram(sorted: &[u32], bins: u32) -> Vec<Bin> {
+    let (min, max) = match (sorted.first(), sorted.last()) {
+        (Some(min), Some(max)) => (u64::from(*min), u64::from(*max)),
+        _ => return Vec::new(),
+    };
+    let span = max - min + 1;
+    let width = (span + u64::from(bins) - 1) / u64::from(bins);
+    let mut histogram: Vec<Bin> = (0..(span + width - 1) / width)
+        .map(|bin| Bin {
+            from: (min + bin * width) as u32,
+            to: (min + (bin + 1) * width - 1).min(max) as u32,
+            count: 0,
+        })
+        .collect();
+
+    for value in sorted {
+        histogram[((u64::from(*value) - min) / width) as usize].count += 1;
+    }
+
+    histogram
+}
+
+/// Counters that carry every `tag`, leaving out archived ones unless `include_archived` is set
+/// and private ones for anonymous callers. Each tenant's counters are described separately.
+#[get("/stats/distribution?<include_archived>&<bins>")]
+pub(crate) fn distribution(
+    include_archived: Option<bool>,
+    bins: Option<u32>,
+    tags: TagFilter,
+    reader: Option<Reader>,
+    store: TenantStore,
+) -> Result<Json<Distribution>, ApiError> {
+    let bins = bins.unwrap_or(DEFAULT_BINS);
+
+    if bins == 0 || bins > MAX_BINS {
+        return Err(error(
+            Status::BadRequest,
+            &format!("Bins must be between 1 and {}.", MAX_BINS),
+        ));
+    }
+
+    let include_archived = include_archived.unwrap_or(false);
+    let mut values: Vec<u32> = store
+        .read()
+        .values()
+        .filter(|counter| include_archived || !counter.archived)
+        .filter(|counter| tags.matches(counter))
+        .filter(|counter| counter.visibility.is_listed_for(&reader))
+        .map(|counter| counter.value)
+        .collect();
+
+    values.sort_unstable();
+
+    Ok(Json(Distribution {
+        count: values.len(),
+        percentiles: if values.is_empty() {
+            None
+        } else {
+            Some(Percentiles {
+                p50: percentile(&values, 50),
+                p90: percentile(&values, 90),
+                p99: percentile(&values, 99),
+            })
+        },
+        histogram: histogram(&values, bins),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn describe_distribution() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        for hits in 1..=10 {
+            let mut response = client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .body(r#"{ "tags": ["game"] }"#)
+                .dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            for _ in 0..hits {
+                client
+                    .put(format!("/counter/{}/increment", counter.id))
+                    .header(ContentType::JSON)
+                    .dispatch();
+            }
+        }
+
+        client.post("/counter").header(ContentType::JSON).dispatch();
+
+        let mut response = client
+            .get("/counter/stats/distribution?tag=game&bins=5")
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let distribution: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(distribution["count"], 10);
+        assert_eq!(distribution["percentiles"]["p50"], 5);
+        assert_eq!(distribution["percentiles"]["p90"], 9);
+        assert_eq!(distribution["percentiles"]["p99"], 10);
+        assert_eq!(
+            distribution["histogram"][0],
+            serde_json::json!({ "from": 1, "to": 2, "count": 2 })
+        );
+        assert_eq!(distribution["histogram"].as_array().unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,118 @@
+//! Dry runs, asked for with `?dry_run=true` or an `X-Dry-Run: true` header, which show what a
+//! request that changes counters would do without doing it. The route runs as usual against a
+//! copy of the tenant's counters and answers as it would have, with the counter as changed, or
+//! with the error it would have got, such as a bound being hit. The copy is dropped with the
+//! request, so nothing reaches the audit trail, webhooks or other integrations. Routes whose
+//! changes are kept apart from the counters, such as registering a webhook, refuse dry runs.
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+use crate::store::Store;
+use crate::{error, ApiError};
+
+/// Whether the request asks for a dry run.
+pub fn requested(request: &Request) -> bool {
+    let header = request
+        .headers()
+        .get_one("X-Dry-Run")
+        .map(|value| value.eq_ignore_ascii_case("true") || value == "1");
+
+    request
+        .get_query_value::<bool>("dry_run")
+        .and_then(Result::ok)
+        .or(header)
+        .unwrap_or(false)
+}
+
+/// The copy of the counters that a dry run works on, made once per request so that every guard
+/// sees the same one.
+struct Scratch(Store);
+
+/// `store`, or a copy of it to try the request's changes on if it is a dry run.
+pub fn store(request: &Request, store: Store) -> Store {
+    if requested(request) {
+        request.local_cache(|| Scratch(store.scratch())).0.clone()
+    } else {
+        store
+    }
+}
+
+/// Guard of the routes that change more than the counters, which cannot be tried out on a
+/// copy of them.
+pub struct DryRun(bool);
+
+impl DryRun {
+    /// Fails with 400 if this is a dry run.
+    pub fn refuse(&self) -> Result<(), ApiError> {
+        if self.0 {
+            Err(error(
+                Status::BadRequest,
+                "This request cannot be made as a dry run.",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for DryRun {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<DryRun, ()> {
+        Outcome::Success(DryRun(requested(request)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn dry_run_changes() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let mut dry_response = client
+            .put(format!("/counter/{}/increment?dry_run=true", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        let would_be: Counter = serde_json::from_str(&dry_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(dry_response.status(), Status::Ok);
+        assert_eq!(would_be.value, 1);
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let unchanged: Counter =
+            serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(unchanged.value, 0);
+
+        client
+            .put(format!("/counter/{}/freeze", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let frozen_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .header(Header::new("X-Dry-Run", "true"))
+            .dispatch();
+
+        assert_eq!(frozen_response.status(), Status::Locked);
+
+        let webhook_response = client
+            .post(format!("/counter/{}/webhooks?dry_run=true", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "url": "http://localhost:9/hook", "condition": "value >= 1" }"#)
+            .dispatch();
+
+        assert_eq!(webhook_response.status(), Status::BadRequest);
+    }
+}
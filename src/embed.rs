@@ -0,0 +1,181 @@
+use rocket::response::content::JavaScript;
+
+use crate::{not_found_error, parse_id, ApiError};
+
+/// Renders the counter value in place of the `<script>` tag that loaded it, optionally
+/// followed by a button that registers a hit. Requests go back to the origin serving the
+/// script.
+const SNIPPET: &str = r#"(function () {
+  var script = document.currentScript;
+  var origin = new URL(script.src).origin;
+  var url = origin + "/v1/counter/{id}";
+  var container = document.createElement("span");
+  var value = document.createElement("span");
+
+  container.className = "counter-widget";
+  value.className = "counter-widget-value";
+  value.textContent = "…";
+  container.appendChild(value);
+
+  function refresh() {
+    fetch(url, { headers: { Accept: "application/json" } })
+      .then(function (response) {
+        return response.ok ? response.json() : { value: 0 };
+      })
+      .then(function (counter) {
+        value.textContent = counter.value;
+      });
+  }
+
+  if ({button}) {
+    var button = document.createElement("button");
+
+    button.className = "counter-widget-button";
+    button.type = "button";
+    button.textContent = "+1";
+    button.addEventListener("click", function () {
+      button.disabled = true;
+      fetch(url + "/hit?pixel=false", { credentials: "include" }).then(refresh);
+    });
+    container.appendChild(document.createTextNode(" "));
+    container.appendChild(button);
+  }
+
+  script.parentNode.insertBefore(container, script);
+  refresh();
+})();
+"#;
+
+/// Renders the counter value into every element marked `data-counter="<id>"`, or those matching
+/// the selector in the script tag's `data-target`, or else in place of the script tag. With
+/// live updates the value follows the counter's event stream, which `EventSource` reconnects
+/// by itself after network hiccups.
+const WIDGET: &str = r#"(function () {
+  var script = document.currentScript;
+  var origin = new URL(script.src).origin;
+  var url = origin + "/v1/counter/{id}";
+  var selector = script.getAttribute("data-target") || '[data-counter="{id}"]';
+  var targets = Array.prototype.slice.call(document.querySelectorAll(selector));
+
+  if (targets.length === 0) {
+    var placeholder = document.createElement("span");
+
+    script.parentNode.insertBefore(placeholder, script);
+    targets.push(placeholder);
+  }
+
+  targets.forEach(function (target) {
+    target.classList.add("counter-widget");
+  });
+
+  function render(value) {
+    targets.forEach(function (target) {
+      target.textContent = value;
+    });
+  }
+
+  fetch(url, { headers: { Accept: "application/json" } })
+    .then(function (response) {
+      return response.ok ? response.json() : null;
+    })
+    .then(function (counter) {
+      if (counter) {
+        render(counter.value);
+      }
+    })
+    .catch(function () {});
+
+  if ({live} && window.EventSource) {
+    new EventSource(url + "/events").addEventListener("change", function (event) {
+      render(JSON.parse(event.data).value);
+    });
+  }
+})();
+"#;
+
+/// `GET /embed/<id>.js`, with `?button=true` to render an increment button.
+#[get("/<file>?<button>")]
+pub fn snippet(file: String, button: Option<bool>) -> Result<JavaScript<String>, ApiError> {
+    if !file.ends_with(".js") {
+        return Err(not_found_error());
+    }
+
+    let id = parse_id(&file[..file.len() - 3])?;
+
+    Ok(JavaScript(
+        SNIPPET.replace("{id}", &id.to_string()).replace(
+            "{button}",
+            if button.unwrap_or(false) {
+                "true"
+            } else {
+                "false"
+            },
+        ),
+    ))
+}
+
+/// `GET /counter/<id>/widget.js`, with `?live=true` to keep the value up to date. Meant to be
+/// embedded as `<span data-counter="<id>"></span><script src=".../widget.js" async></script>`.
+#[get("/<id>/widget.js?<live>")]
+pub fn widget(id: String, live: Option<bool>) -> Result<JavaScript<String>, ApiError> {
+    let id = parse_id(&id)?;
+
+    Ok(JavaScript(WIDGET.replace("{id}", &id.to_string()).replace(
+        "{live}",
+        if live.unwrap_or(false) {
+            "true"
+        } else {
+            "false"
+        },
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::rocket;
+
+    #[test]
+    fn serve_embed_snippet() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let id = uuid::Uuid::new_v4();
+        let mut response = client
+            .get(format!("/embed/{}.js?button=true", id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JavaScript));
+
+        let body = response.body_string().unwrap();
+
+        assert!(body.contains(&format!("/v1/counter/{}", id)));
+        assert!(body.contains("if (true)"));
+
+        let invalid_response = client.get("/embed/not-a-counter.js").dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn serve_widget_script() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let id = uuid::Uuid::new_v4();
+        let mut response = client
+            .get(format!("/counter/{}/widget.js?live=true", id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JavaScript));
+
+        let body = response.body_string().unwrap();
+
+        assert!(body.contains(&format!(r#"[data-counter="{}"]"#, id)));
+        assert!(body.contains("if (true && window.EventSource)"));
+
+        let invalid_response = client.get("/counter/not-a-counter/widget.js").dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,319 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::Json;
+use std::io::{self, Read};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+/// Proxies tend to close connections that stay quiet for a minute, so an idle stream sends
+/// a comment this often.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// Seconds a long poll waits by default and at most.
+const DEFAULT_WAIT: u64 = 30;
+const MAX_WAIT: u64 = 60;
+
+/// Server-Sent Events for a single counter: a `change` event with the counter's state on
+/// connecting and after every change, and heartbeat comments while nothing happens.
+pub struct Events {
+    id: Uuid,
+    changes: Receiver<Counter>,
+    pending: Vec<u8>,
+    position: usize,
+    flushed: bool,
+    next_heartbeat: Instant,
+}
+
+impl Events {
+    fn new(counter: &Counter, changes: Receiver<Counter>) -> Events {
+        Events {
+            id: counter.id,
+            changes,
+            pending: event(counter),
+            position: 0,
+            flushed: false,
+            next_heartbeat: Instant::now() + HEARTBEAT,
+        }
+    }
+}
+
+fn event(counter: &Counter) -> Vec<u8> {
+    let data = serde_json::to_string(counter).unwrap_or_default();
+
+    format!("event: change\ndata: {}\n\n", data).into_bytes()
+}
+
+impl Read for Events {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.pending.len() {
+                let read = (&self.pending[self.position..]).read(buffer)?;
+
+                self.position += read;
+                return Ok(read);
+            }
+
+            // With Rocket's `sse` feature, `WouldBlock` makes it flush what has been read so
+            // far instead of waiting for a full chunk.
+            if !self.flushed {
+                self.flushed = true;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            let now = Instant::now();
+            let next = if now >= self.next_heartbeat {
+                Err(RecvTimeoutError::Timeout)
+            } else {
+                self.changes.recv_timeout(self.next_heartbeat - now)
+            };
+
+            self.pending = match next {
+                Ok(counter) if counter.id == self.id => event(&counter),
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => b": heartbeat\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.position = 0;
+            self.flushed = false;
+            self.next_heartbeat = Instant::now() + HEARTBEAT;
+        }
+    }
+}
+
+impl<'r> Responder<'r> for Events {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            .raw_header("X-Accel-Buffering", "no")
+            .streamed_body(self)
+            .ok()
+    }
+}
+
+/// The counter's value as it changes, for `EventSource` clients.
+#[get("/<id>/events")]
+pub fn events(id: String, store: TenantStore) -> Result<Events, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    // Subscribing first means no change slips in between reading the counter and following it.
+    let changes = store.subscribe();
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    Ok(Events::new(&counter, changes))
+}
+
+/// Waits until `done` holds for counter `id`, checking it as it is first and then after every
+/// change, or until `timeout` seconds have passed. `done` is told whether the counter has
+/// changed yet. Returns the counter as last seen and whether `done` held.
+fn wait_until<F>(
+    id: &str,
+    timeout: Option<u64>,
+    store: &Store,
+    done: F,
+) -> Result<(Counter, bool), ApiError>
+where
+    F: Fn(&Counter, bool) -> bool,
+{
+    let parsed_uuid = parse_id(id)?;
+    let changes = store.subscribe();
+    let mut counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+    let deadline =
+        Instant::now() + Duration::from_secs(timeout.unwrap_or(DEFAULT_WAIT).min(MAX_WAIT));
+
+    if done(&counter, false) {
+        return Ok((counter, true));
+    }
+
+    loop {
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Ok((counter, false));
+        }
+
+        match changes.recv_timeout(deadline - now) {
+            Ok(changed) if changed.id == parsed_uuid => {
+                counter = changed;
+
+                if done(&counter, true) {
+                    return Ok((counter, true));
+                }
+            }
+            Ok(_) => continue,
+            Err(_) => return Ok((counter, false)),
+        }
+    }
+}
+
+/// Long polling: the counter once it changes, or once its value reaches `min_value` if given,
+/// or as it is when `timeout` seconds have passed. Each waiting client holds an HTTP worker,
+/// so the wait is capped at a minute.
+#[get("/<id>/wait?<timeout>&<min_value>")]
+pub fn wait(
+    id: String,
+    timeout: Option<u64>,
+    min_value: Option<u32>,
+    store: TenantStore,
+) -> Result<Json<Counter>, ApiError> {
+    wait_until(&id, timeout, &store, |counter, changed| match min_value {
+        Some(min) => counter.value >= min,
+        None => changed,
+    })
+    .map(|(counter, _)| Json(counter))
+}
+
+#[derive(Serialize)]
+pub struct Watched {
+    /// Whether the threshold was reached before the timeout.
+    reached: bool,
+    counter: Counter,
+}
+
+/// `GET /counter/<id>/watch?gte=100&timeout=60` holds the request until the counter's value is
+/// at least `gte` (or at most `lte`), answering right away if it already is. Unlike `wait`, it
+/// does not return on other changes, and tells whether the threshold was reached; the wait is
+/// capped at a minute all the same, so callers waiting longer ask again.
+#[get("/<id>/watch?<gte>&<lte>&<timeout>")]
+pub fn watch(
+    id: String,
+    gte: Option<u32>,
+    lte: Option<u32>,
+    timeout: Option<u64>,
+    store: TenantStore,
+) -> Result<Json<Watched>, ApiError> {
+    if gte.is_none() && lte.is_none() {
+        return Err(error(
+            Status::BadRequest,
+            "A threshold is required, as gte or lte.",
+        ));
+    }
+
+    let (counter, reached) = wait_until(&id, timeout, &store, |counter, _| {
+        gte.map_or(true, |gte| counter.value >= gte) && lte.map_or(true, |lte| counter.value <= lte)
+    })?;
+
+    Ok(Json(Watched { reached, counter }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::store::Store;
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn stream_counter_events() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut response = client
+            .get(format!("/v1/counter/{}/events", counter.id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Content-Type"),
+            Some("text/event-stream")
+        );
+
+        let mut buffer = [0; 4096];
+        let read = response
+            .body()
+            .unwrap()
+            .into_inner()
+            .read(&mut buffer)
+            .unwrap();
+        let event = String::from_utf8_lossy(&buffer[..read]);
+
+        assert!(event.starts_with("event: change\ndata: {"));
+        assert!(event.contains(&format!(r#""id":"{}""#, counter.id)));
+        assert!(event.ends_with("}\n\n"));
+    }
+
+    #[test]
+    fn wait_for_counter_change() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let store = client.rocket().state::<Store>().unwrap().clone();
+        let id = counter.id;
+
+        thread::spawn(move || {
+            for _ in 0..2 {
+                thread::sleep(Duration::from_millis(100));
+                crate::routes::increment(id, &[], Default::default(), &store).unwrap();
+            }
+        });
+
+        let mut response = client
+            .get(format!(
+                "/counter/{}/wait?timeout=5&min_value=2",
+                counter.id
+            ))
+            .dispatch();
+        let waited: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(waited.value, 2);
+
+        let mut timeout_response = client
+            .get(format!("/counter/{}/wait?timeout=0", counter.id))
+            .dispatch();
+        let unchanged: Counter =
+            serde_json::from_str(&timeout_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(unchanged.value, 2);
+    }
+
+    #[test]
+    fn watch_until_threshold() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let store = client.rocket().state::<Store>().unwrap().clone();
+        let id = counter.id;
+
+        thread::spawn(move || {
+            for _ in 0..3 {
+                thread::sleep(Duration::from_millis(100));
+                crate::routes::increment(id, &[], Default::default(), &store).unwrap();
+            }
+        });
+
+        let mut response = client
+            .get(format!("/counter/{}/watch?gte=3&timeout=5", counter.id))
+            .dispatch();
+        let watched: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(watched["reached"], true);
+        assert_eq!(watched["counter"]["value"], 3);
+
+        let mut timeout_response = client
+            .get(format!("/counter/{}/watch?gte=4&timeout=0", counter.id))
+            .dispatch();
+        let watched: serde_json::Value =
+            serde_json::from_str(&timeout_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(watched["reached"], false);
+
+        let missing_threshold_response = client
+            .get(format!("/counter/{}/watch", counter.id))
+            .dispatch();
+
+        assert_eq!(missing_threshold_response.status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,433 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde::Serialize;
+use std::io::{self, Cursor, Read};
+use uuid::Uuid;
+
+use crate::auth::Admin;
+use crate::history::Entry;
+use crate::parquet::{self, Values};
+use crate::tenancy::TenantStore;
+use crate::{error, ApiError, Counter};
+
+/// A CSV download named `counters.csv`.
+pub struct Csv(String);
+
+impl<'r> Responder<'r> for Csv {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::CSV)
+            .raw_header(
+                "Content-Disposition",
+                "attachment; filename=\"counters.csv\"",
+            )
+            .sized_body(Cursor::new(self.0))
+            .ok()
+    }
+}
+
+/// Quotes a field when needed. Text that a spreadsheet would evaluate as a formula is prefixed
+/// with an apostrophe.
+fn field(text: &str) -> String {
+    let text = if text.starts_with(|c: char| c == '=' || c == '+' || c == '-' || c == '@') {
+        format!("'{}", text)
+    } else {
+        text.to_string()
+    };
+
+    if text.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn row(counter: &Counter) -> String {
+    format!(
+        "{},{},{},{},{},{}\r\n",
+        counter.id,
+        field(counter.name.as_ref().map_or("", String::as_str)),
+        counter.value,
+        counter.archived,
+        counter.created_at,
+        counter.updated_at
+    )
+}
+
+/// Every counter, archived ones included, oldest first. Timestamps are milliseconds since the
+/// Unix epoch, as elsewhere in the API.
+#[get("/export.csv")]
+pub fn export_csv(store: TenantStore, _admin: Admin) -> Csv {
+    let mut counters: Vec<Counter> = store.read().values().cloned().collect();
+    let mut csv = String::from("id,name,value,archived,created_at,updated_at\r\n");
+
+    counters.sort_by_key(|counter| (counter.created_at, counter.id));
+
+    for counter in &counters {
+        csv.push_str(&row(counter));
+    }
+
+    Csv(csv)
+}
+
+/// A Parquet download named after the table it holds.
+pub struct Parquet {
+    table: &'static str,
+    file: Vec<u8>,
+}
+
+impl<'r> Responder<'r> for Parquet {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("application", "vnd.apache.parquet"))
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.parquet\"", self.table),
+            )
+            .sized_body(Cursor::new(self.file))
+            .ok()
+    }
+}
+
+/// The same columns as `export.csv`, with timestamps typed as such.
+fn counters_table(counters: &[Counter]) -> Vec<u8> {
+    parquet::write(&[
+        (
+            "id",
+            Values::Text(
+                counters
+                    .iter()
+                    .map(|counter| counter.id.to_string())
+                    .collect(),
+            ),
+        ),
+        (
+            "name",
+            Values::OptionalText(
+                counters
+                    .iter()
+                    .map(|counter| counter.name.clone())
+                    .collect(),
+            ),
+        ),
+        (
+            "value",
+            Values::Int64(
+                counters
+                    .iter()
+                    .map(|counter| i64::from(counter.value))
+                    .collect(),
+            ),
+        ),
+        (
+            "archived",
+            Values::Boolean(counters.iter().map(|counter| counter.archived).collect()),
+        ),
+        (
+            "created_at",
+            Values::Timestamp(counters.iter().map(|counter| counter.created_at).collect()),
+        ),
+        (
+            "updated_at",
+            Values::Timestamp(counters.iter().map(|counter| counter.updated_at).collect()),
+        ),
+    ])
+}
+
+/// Every history entry that is still kept, by counter and then oldest first.
+fn history_table(counters: &[Counter], store: &TenantStore) -> Vec<u8> {
+    let mut ids = Vec::new();
+    let mut operations = Vec::new();
+    let mut deltas = Vec::new();
+    let mut values = Vec::new();
+    let mut timestamps = Vec::new();
+    let mut reasons = Vec::new();
+
+    for counter in counters {
+        for entry in store.history().entries(&counter.id) {
+            ids.push(counter.id.to_string());
+            operations.push(entry.operation.name().to_string());
+            deltas.push(entry.delta);
+            values.push(i64::from(entry.value));
+            timestamps.push(entry.timestamp);
+            reasons.push(entry.reason);
+        }
+    }
+
+    parquet::write(&[
+        ("counter_id", Values::Text(ids)),
+        ("operation", Values::Text(operations)),
+        ("delta", Values::Int64(deltas)),
+        ("value", Values::Int64(values)),
+        ("timestamp", Values::Timestamp(timestamps)),
+        ("reason", Values::OptionalText(reasons)),
+    ])
+}
+
+/// Every counter as a Parquet file, archived ones included, oldest first, or with
+/// `table=history` their history as kept.
+#[get("/export.parquet?<table>")]
+pub fn export_parquet(
+    table: Option<String>,
+    store: TenantStore,
+    _admin: Admin,
+) -> Result<Parquet, ApiError> {
+    let mut counters: Vec<Counter> = store.read().values().cloned().collect();
+
+    counters.sort_by_key(|counter| (counter.created_at, counter.id));
+
+    match table.as_ref().map_or("counters", String::as_str) {
+        "counters" => Ok(Parquet {
+            table: "counters",
+            file: counters_table(&counters),
+        }),
+        "history" => Ok(Parquet {
+            table: "history",
+            file: history_table(&counters, &store),
+        }),
+        _ => Err(error(
+            Status::BadRequest,
+            "Table must be counters or history.",
+        )),
+    }
+}
+
+/// A history entry as a line of `export.ndjson?table=history`, which `import.ndjson` takes back.
+#[derive(Serialize, Deserialize)]
+pub struct Event {
+    pub counter_id: Uuid,
+    #[serde(flatten)]
+    pub entry: Entry,
+}
+
+/// Lines serialized one at a time as the response is read, so that the export is never held
+/// in memory whole.
+struct Lines<I> {
+    lines: I,
+    line: Cursor<Vec<u8>>,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Read for Lines<I> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.line.read(buffer)?;
+
+            if read > 0 || buffer.is_empty() {
+                return Ok(read);
+            }
+
+            match self.lines.next() {
+                Some(line) => self.line = Cursor::new(line),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+fn line<T: Serialize>(item: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(item).unwrap_or_default();
+
+    line.push(b'\n');
+    line
+}
+
+/// A streamed NDJSON download named after the table it holds.
+pub struct Ndjson {
+    table: &'static str,
+    lines: Box<dyn Read + Send>,
+}
+
+impl<'r> Responder<'r> for Ndjson {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("application", "x-ndjson"))
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.ndjson\"", self.table),
+            )
+            .streamed_body(self.lines)
+            .ok()
+    }
+}
+
+/// Every counter as a line of JSON, as the API returns it, archived ones included and by id.
+/// With `table=history`, every history entry that is still kept instead, by counter and then
+/// oldest first. Counters are read one at a time as the download goes on, so a counter
+/// changed during it may be exported as before or after the change.
+#[get("/export.ndjson?<table>")]
+pub fn export_ndjson(
+    table: Option<String>,
+    store: TenantStore,
+    _admin: Admin,
+) -> Result<Ndjson, ApiError> {
+    let store = store.inner().clone();
+    let mut ids = store.ids();
+
+    ids.sort();
+
+    match table.as_ref().map_or("counters", String::as_str) {
+        "counters" => Ok(Ndjson {
+            table: "counters",
+            lines: Box::new(Lines {
+                lines: ids
+                    .into_iter()
+                    .filter_map(move |id| store.get(id))
+                    .map(|counter| line(&counter)),
+                line: Cursor::default(),
+            }),
+        }),
+        "history" => Ok(Ndjson {
+            table: "history",
+            lines: Box::new(Lines {
+                lines: ids.into_iter().flat_map(move |id| {
+                    store.history().entries(&id).into_iter().map(move |entry| {
+                        line(&Event {
+                            counter_id: id,
+                            entry,
+                        })
+                    })
+                }),
+                line: Cursor::default(),
+            }),
+        }),
+        _ => Err(error(
+            Status::BadRequest,
+            "Table must be counters or history.",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn export_counters_as_csv() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Signups, \"beta\"" }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut response = client.get("/counter/export.csv").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::CSV));
+
+        let csv = response.body_string().unwrap();
+        let mut lines = csv.lines();
+        let expected = format!(
+            r#"{},"Signups, ""beta""",0,false,{},{}"#,
+            counter.id, counter.created_at, counter.updated_at
+        );
+
+        assert_eq!(
+            lines.next(),
+            Some("id,name,value,archived,created_at,updated_at")
+        );
+        assert_eq!(lines.next(), Some(expected.as_str()));
+    }
+
+    #[test]
+    fn move_counters_as_ndjson() {
+        let source = Client::new(rocket()).expect("Init failed");
+        let mut create_response = source
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Signups" }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for _ in 0..2 {
+            source
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let mut counters_response = source.get("/admin/export.ndjson").dispatch();
+
+        assert_eq!(counters_response.status(), Status::Ok);
+        assert_eq!(
+            counters_response.content_type(),
+            Some(ContentType::new("application", "x-ndjson"))
+        );
+
+        let counters = counters_response.body_string().unwrap();
+        let history = source
+            .get("/admin/export.ndjson?table=history")
+            .dispatch()
+            .body_string()
+            .unwrap();
+
+        assert_eq!(counters.lines().count(), 1);
+        assert_eq!(history.lines().count(), 3);
+
+        let target = Client::new(rocket()).expect("Init failed");
+        let mut import_response = target
+            .post("/admin/import.ndjson")
+            .body(format!("{}{}not json\n", counters, history))
+            .dispatch();
+        let report: serde_json::Value =
+            serde_json::from_str(&import_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(report["created"], 1);
+        assert_eq!(report["events"], 3);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["errors"][0]["row"], 5);
+
+        let mut imported_response = target.get(format!("/counter/{}", counter.id)).dispatch();
+        let imported: Counter =
+            serde_json::from_str(&imported_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(imported.value, 2);
+        assert_eq!(imported.name, Some("Signups".to_string()));
+
+        let mut again_response = target
+            .post("/admin/import.ndjson")
+            .body(counters)
+            .dispatch();
+        let again: serde_json::Value =
+            serde_json::from_str(&again_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(again["unchanged"], 1);
+    }
+
+    #[test]
+    fn export_counters_as_parquet() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Signups" }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for table in &["counters", "history"] {
+            let mut response = client
+                .get(format!("/admin/export.parquet?table={}", table))
+                .dispatch();
+
+            assert_eq!(response.status(), Status::Ok);
+
+            let file = response.body_bytes().unwrap();
+            let id = counter.id.to_string();
+
+            assert_eq!(&file[..4], b"PAR1");
+            assert_eq!(&file[file.len() - 4..], b"PAR1");
+            assert!(file.windows(id.len()).any(|window| window == id.as_bytes()));
+        }
+
+        let invalid_response = client.get("/admin/export.parquet?table=tags").dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,284 @@
+//! Switches for optional behaviour, so that a deployment can turn it off without a rebuild.
+//! Configured as `features = { auto_create = false, public_reads = false }`, or as
+//! `ROCKET_FEATURES='{ history = false }'`, and read at launch. Everything not switched off is on,
+//! except `hooks`, which has to be switched on.
+
+use rocket::data::Data;
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+use uuid::Uuid;
+
+use crate::auth::Reader;
+use crate::store::Store;
+use crate::visibility;
+use crate::{error, not_found_error, ApiError, V1};
+
+/// Where anonymous reads are sent instead, when reads are not public.
+pub const PRIVATE_PATH: &str = "/private";
+
+/// Paths whose reads `public_reads` covers, with or without the `/v1` prefix.
+const READ_PATHS: [&str; 4] = ["/counter", "/reactions", "/display", "/bucket"];
+
+#[derive(Clone, Copy)]
+pub enum Feature {
+    /// Changing a counter that does not exist creates it.
+    AutoCreate,
+    /// Anyone may read counters. When off, reads take an API key or a bearer JWT like writes.
+    PublicReads,
+    /// Changes are kept for `/counter/<id>/history` and `/counter/<id>/rate`.
+    History,
+    /// Webhooks can be registered and are called.
+    Webhooks,
+    /// Counters can be given `/hook/<token>` URLs that change them with a plain GET.
+    Hooks,
+}
+
+impl Feature {
+    const ALL: [Feature; 5] = [
+        Feature::AutoCreate,
+        Feature::PublicReads,
+        Feature::History,
+        Feature::Webhooks,
+        Feature::Hooks,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Feature::AutoCreate => "auto_create",
+            Feature::PublicReads => "public_reads",
+            Feature::History => "history",
+            Feature::Webhooks => "webhooks",
+            Feature::Hooks => "hooks",
+        }
+    }
+}
+
+pub struct Features {
+    auto_create: bool,
+    public_reads: bool,
+    history: bool,
+    webhooks: bool,
+    hooks: bool,
+}
+
+impl Default for Features {
+    fn default() -> Features {
+        Features {
+            auto_create: true,
+            public_reads: true,
+            history: true,
+            webhooks: true,
+            hooks: false,
+        }
+    }
+}
+
+impl Features {
+    pub fn from_config(config: &Config) -> Features {
+        let mut features = Features::default();
+        let table = match config.get_table("features") {
+            Ok(table) => table,
+            Err(_) => return features,
+        };
+
+        for (name, value) in table {
+            let feature = Feature::ALL.iter().find(|feature| feature.name() == name);
+
+            match (feature, value.as_bool()) {
+                (Some(feature), Some(on)) => *features.flag(*feature) = on,
+                (Some(_), None) => eprintln!("Ignoring feature {}: not true or false", name),
+                (None, _) => eprintln!("Ignoring unknown feature {}", name),
+            }
+        }
+
+        features
+    }
+
+    fn flag(&mut self, feature: Feature) -> &mut bool {
+        match feature {
+            Feature::AutoCreate => &mut self.auto_create,
+            Feature::PublicReads => &mut self.public_reads,
+            Feature::History => &mut self.history,
+            Feature::Webhooks => &mut self.webhooks,
+            Feature::Hooks => &mut self.hooks,
+        }
+    }
+
+    pub fn enabled(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::AutoCreate => self.auto_create,
+            Feature::PublicReads => self.public_reads,
+            Feature::History => self.history,
+            Feature::Webhooks => self.webhooks,
+            Feature::Hooks => self.hooks,
+        }
+    }
+
+    /// For routes that only make sense with `feature`: fails with 404 when it is off.
+    pub fn require(&self, feature: Feature) -> Result<(), ApiError> {
+        if self.enabled(feature) {
+            Ok(())
+        } else {
+            Err(error(
+                Status::NotFound,
+                &format!("The {} feature is disabled.", feature.name()),
+            ))
+        }
+    }
+
+    /// For routes that change counter `id`: fails with 404 if it does not exist and would be
+    /// created, with `auto_create` off.
+    pub fn check_auto_create(&self, id: Uuid, store: &Store) -> Result<(), ApiError> {
+        if self.auto_create || store.get(id).is_some() {
+            Ok(())
+        } else {
+            Err(not_found_error())
+        }
+    }
+}
+
+fn is_read(request: &Request) -> bool {
+    let path = request.uri().path();
+    let path = if path.starts_with(V1) {
+        &path[V1.len()..]
+    } else {
+        path
+    };
+    let covered = READ_PATHS.iter().any(|prefix| {
+        path.starts_with(prefix)
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    });
+
+    // Hits and one-time links are how browsers change counters, not reads.
+    let beacon = path.ends_with("/hit") || path.contains("/redeem/");
+
+    match request.method() {
+        Method::Get | Method::Head => covered && !beacon,
+        _ => false,
+    }
+}
+
+/// Whether the request was an anonymous read.
+struct Flag(bool);
+
+/// Sends anonymous reads to `PRIVATE_PATH` when `public_reads` is off, or when they are of a
+/// private counter.
+pub fn guard_reads(request: &mut Request, _: &Data) {
+    let public = request
+        .guard::<State<Features>>()
+        .succeeded()
+        .map_or(true, |features| features.public_reads);
+
+    if !is_read(request)
+        || (public && !visibility::is_private(request))
+        || request.guard::<Reader>().is_success()
+    {
+        return;
+    }
+
+    request.local_cache(|| Flag(true));
+    request.set_method(Method::Get);
+    request.set_uri(Origin::parse(PRIVATE_PATH).unwrap());
+}
+
+/// Guard that only lets anonymous reads through, so that asking for `PRIVATE_PATH` directly
+/// finds nothing.
+pub struct Private;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Private {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Private, ()> {
+        if request.local_cache(|| Flag(false)).0 {
+            Outcome::Success(Private)
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
+#[get("/")]
+pub fn private(_private: Private) -> ApiError {
+    error(
+        Status::Unauthorized,
+        "Reading counters takes a valid X-Api-Key header or bearer token.",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn switch_off_features() {
+        let mut api_keys = BTreeMap::new();
+        let mut features = BTreeMap::new();
+
+        api_keys.insert("s3cret".to_string(), Value::String("dashboard".to_string()));
+
+        for feature in &["auto_create", "public_reads", "history", "webhooks"] {
+            features.insert(feature.to_string(), Value::Boolean(false));
+        }
+
+        let config = Config::build(Environment::Development)
+            .extra("api_keys", Value::Table(api_keys))
+            .extra("features", Value::Table(features))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let key = || Header::new("X-Api-Key", "s3cret");
+
+        let unknown_response = client
+            .put(format!("/counter/{}/increment", uuid::Uuid::new_v4()))
+            .header(key())
+            .dispatch();
+
+        assert_eq!(unknown_response.status(), Status::NotFound);
+
+        let anonymous_response = client.get("/v1/counter").dispatch();
+
+        assert_eq!(anonymous_response.status(), Status::Unauthorized);
+
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(key())
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(key())
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Ok);
+
+        let read_response = client.get("/v1/counter").header(key()).dispatch();
+
+        assert_eq!(read_response.status(), Status::Ok);
+
+        let history_response = client
+            .get(format!("/counter/{}/history", counter.id))
+            .header(key())
+            .dispatch();
+
+        assert_eq!(history_response.status(), Status::NotFound);
+
+        let webhook_response = client
+            .post(format!("/counter/{}/webhooks", counter.id))
+            .header(ContentType::JSON)
+            .header(key())
+            .body(r#"{ "url": "http://localhost/hook", "condition": "value >= 2" }"#)
+            .dispatch();
+
+        assert_eq!(webhook_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,241 @@
+//! Allow and deny lists of client networks, applied before routing so that a blocked request
+//! never reaches a route. Configured separately for reads and writes, as in
+//! `ip_rules = { write = { allow = ["10.8.0.0/16"] } }`. A request is blocked if its address is
+//! denied, or if there is an allow list that it is not on. Writes are requests with any method
+//! but `GET`, `HEAD` and `OPTIONS`.
+
+use rocket::data::Data;
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+use std::net::IpAddr;
+
+use crate::{error, ApiError};
+
+/// Where blocked requests are sent instead.
+pub const BLOCKED_PATH: &str = "/blocked";
+
+/// A network such as `10.8.0.0/16`, or a single address.
+#[derive(Clone)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(text: &str) -> Option<Cidr> {
+        let mut parts = text.trim().splitn(2, '/');
+        let network: IpAddr = parts.next()?.parse().ok()?;
+        let bits = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+
+        Some(Cidr { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => same_prefix(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(network), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(a: u128, b: u128, bits: u32, prefix: u32) -> bool {
+    prefix == 0 || (a ^ b) >> (bits - prefix) == 0
+}
+
+#[derive(Clone, Default)]
+struct Policy {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Policy {
+    fn from_table(rules: &rocket::config::Table, name: &str) -> Policy {
+        let list = |key: &str| {
+            rules
+                .get(name)
+                .and_then(|policy| policy.get(key))
+                .and_then(|list| list.as_array())
+                .map(|list| {
+                    list.iter()
+                        .filter_map(|entry| {
+                            let cidr = entry.as_str().and_then(Cidr::parse);
+
+                            if cidr.is_none() {
+                                eprintln!("Ignoring invalid network in ip_rules: {}", entry);
+                            }
+
+                            cidr
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        Policy {
+            allow: list("allow"),
+            deny: list("deny"),
+        }
+    }
+
+    fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let listed = |networks: &[Cidr]| {
+            ip.map_or(false, |ip| networks.iter().any(|cidr| cidr.contains(ip)))
+        };
+
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// The read and write policies.
+#[derive(Clone, Default)]
+pub struct IpRules {
+    read: Policy,
+    write: Policy,
+}
+
+impl IpRules {
+    pub fn from_config(config: &Config) -> IpRules {
+        match config.get_table("ip_rules") {
+            Ok(rules) => IpRules {
+                read: Policy::from_table(rules, "read"),
+                write: Policy::from_table(rules, "write"),
+            },
+            Err(_) => IpRules::default(),
+        }
+    }
+
+    /// Whether the read policy lets `ip` in, for connections served outside of Rocket.
+    pub fn allows_read(&self, ip: Option<IpAddr>) -> bool {
+        self.read.allows(ip)
+    }
+
+    /// Whether the write policy lets `ip` in, for writes served outside of Rocket.
+    pub fn allows_write(&self, ip: Option<IpAddr>) -> bool {
+        self.write.allows(ip)
+    }
+
+    fn allows(&self, request: &Request) -> bool {
+        let policy = match request.method() {
+            Method::Get | Method::Head | Method::Options => &self.read,
+            _ => &self.write,
+        };
+
+        policy.allows(request.client_ip())
+    }
+}
+
+/// Whether the request was blocked.
+struct Flag(bool);
+
+/// Sends requests from blocked addresses to `BLOCKED_PATH`.
+pub fn filter(request: &mut Request, _: &Data) {
+    let allowed = request
+        .guard::<State<IpRules>>()
+        .succeeded()
+        .map_or(true, |rules| rules.allows(request));
+
+    if !allowed {
+        request.local_cache(|| Flag(true));
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(BLOCKED_PATH).unwrap());
+    }
+}
+
+/// Guard that only lets blocked requests through, so that asking for `BLOCKED_PATH` directly
+/// finds nothing.
+pub struct Blocked;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Blocked {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Blocked, ()> {
+        if request.local_cache(|| Flag(false)).0 {
+            Outcome::Success(Blocked)
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
+#[get("/")]
+pub fn blocked(_blocked: Blocked) -> ApiError {
+    error(
+        Status::Forbidden,
+        "Requests from your network are not allowed here.",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+
+    use crate::{build, rocket};
+
+    #[test]
+    fn restrict_writes_by_network() {
+        let mut write = BTreeMap::new();
+        let mut ip_rules = BTreeMap::new();
+
+        write.insert(
+            "allow".to_string(),
+            Value::Array(vec![Value::String("10.8.0.0/16".to_string())]),
+        );
+        ip_rules.insert("write".to_string(), Value::Table(write));
+
+        let config = Config::build(Environment::Development)
+            .extra("ip_rules", Value::Table(ip_rules))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let office: SocketAddr = "10.8.1.2:4000".parse().unwrap();
+        let outside: SocketAddr = "203.0.113.9:4000".parse().unwrap();
+        let mut blocked_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .remote(outside)
+            .dispatch();
+
+        assert_eq!(blocked_response.status(), Status::Forbidden);
+        assert!(blocked_response
+            .body_string()
+            .unwrap()
+            .contains("not allowed"));
+
+        let read_response = client.get("/counter").remote(outside).dispatch();
+
+        assert_eq!(read_response.status(), Status::Ok);
+
+        let create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .remote(office)
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+        assert_eq!(
+            client.get("/blocked").remote(office).dispatch().status(),
+            Status::NotFound
+        );
+    }
+}
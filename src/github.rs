@@ -0,0 +1,284 @@
+//! `POST /integrations/github/<id>`, a GitHub webhook that counts repository events, such as
+//! commits pushed. Deliveries are signed with the webhook secret, configured as
+//! `github_webhook_secret`, which stands in for an API key.
+
+use hmac::{Hmac, Mac};
+use rocket::data::Data;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+use rocket_contrib::json::Json;
+use sha2::Sha256;
+use std::io::Read;
+
+use crate::history::{Entry, Operation};
+use crate::ratelimit::Throttle;
+use crate::signing::decode_hex;
+use crate::tenancy::TenantStore;
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+/// Push payloads list up to a couple of thousand commits.
+const MAX_BYTES: u64 = 8 * 1024 * 1024;
+const DEFAULT_EVENTS: &str = "push";
+
+#[derive(Default)]
+pub struct GitHubSecret(Option<Vec<u8>>);
+
+impl GitHubSecret {
+    pub fn from_config(config: &Config) -> GitHubSecret {
+        GitHubSecret(
+            config
+                .get_str("github_webhook_secret")
+                .ok()
+                .map(|secret| secret.as_bytes().to_vec()),
+        )
+    }
+}
+
+/// The headers GitHub sends with every delivery.
+pub struct Delivery {
+    event: Option<String>,
+    mac: Option<Vec<u8>>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Delivery {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Delivery, ()> {
+        let headers = request.headers();
+
+        Outcome::Success(Delivery {
+            event: headers.get_one("X-GitHub-Event").map(String::from),
+            mac: headers
+                .get_one("X-Hub-Signature-256")
+                .filter(|signature| signature.starts_with("sha256="))
+                .and_then(|signature| decode_hex(&signature["sha256=".len()..])),
+        })
+    }
+}
+
+impl Delivery {
+    fn verify(&self, secret: &[u8], body: &[u8]) -> bool {
+        let expected = match &self.mac {
+            Some(expected) => expected,
+            None => return false,
+        };
+        let mut mac = match Hmac::<Sha256>::new_varkey(secret) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+
+        mac.input(body);
+        mac.verify(expected).is_ok()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Counted {
+    /// How much the delivery added, 0 for events that are not counted.
+    counted: u32,
+    counter: Counter,
+}
+
+/// Whether `event` with `action` is one of `events`, where `issues` stands for issue events
+/// of any kind and `issues.opened` only for opened issues.
+fn selected(events: &str, event: &str, action: Option<&str>) -> bool {
+    events.split(',').map(str::trim).any(|selected| {
+        let mut parts = selected.splitn(2, '.');
+
+        parts.next() == Some(event) && parts.next().map_or(true, |wanted| action == Some(wanted))
+    })
+}
+
+/// How much a delivery counts for: the distinct commits for a push, which leaves out commits
+/// already pushed to another branch, and one for anything else.
+fn amount(event: &str, payload: &serde_json::Value) -> u32 {
+    match (event, payload["commits"].as_array()) {
+        ("push", Some(commits)) => commits
+            .iter()
+            .filter(|commit| commit["distinct"].as_bool().unwrap_or(true))
+            .count() as u32,
+        ("push", None) => 0,
+        _ => 1,
+    }
+}
+
+/// Meant as the payload URL of a webhook with content type `application/json`, such as
+/// `/integrations/github/<id>?events=push,issues.opened,release.published`. `events` defaults
+/// to `push`. Deliveries of other events, including GitHub's initial `ping`, are answered
+/// with 200 and counted as 0. Counters with an owner or a write token only change through
+/// the API.
+#[post("/github/<id>?<events>", data = "<payload>")]
+pub fn webhook(
+    id: String,
+    events: Option<String>,
+    payload: Data,
+    delivery: Delivery,
+    secret: State<GitHubSecret>,
+    store: TenantStore,
+    _throttle: Throttle,
+) -> Result<Json<Counted>, ApiError> {
+    let secret = secret.0.as_ref().ok_or_else(|| {
+        error(
+            Status::NotFound,
+            "The GitHub integration is not configured.",
+        )
+    })?;
+    let parsed_uuid = parse_id(&id)?;
+    let mut body = Vec::new();
+
+    payload
+        .open()
+        .take(MAX_BYTES)
+        .read_to_end(&mut body)
+        .map_err(|_| error(Status::BadRequest, "Could not read the request."))?;
+
+    if !delivery.verify(secret, &body) {
+        return Err(error(
+            Status::Unauthorized,
+            "GitHub signature is missing or invalid.",
+        ));
+    }
+
+    let event = delivery
+        .event
+        .ok_or_else(|| error(Status::BadRequest, "X-GitHub-Event is missing."))?;
+    let payload = serde_json::from_slice::<serde_json::Value>(&body)
+        .map_err(|_| error(Status::BadRequest, "Payload must be JSON."))?;
+    let events = events.unwrap_or_else(|| DEFAULT_EVENTS.to_string());
+    let amount = if selected(&events, &event, payload["action"].as_str()) {
+        amount(&event, &payload)
+    } else {
+        0
+    };
+
+    store.write(parsed_uuid, |hashmap| {
+        let counter = hashmap.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+        if counter.owner.is_some() || counter.write_token.is_some() {
+            return Err(error(
+                Status::Forbidden,
+                "Counter can only be changed through the API.",
+            ));
+        }
+
+        if amount == 0 {
+            return Ok(Json(Counted {
+                counted: 0,
+                counter: counter.clone(),
+            }));
+        }
+
+        counter.ensure_mutable()?;
+        counter.ensure_steppable()?;
+
+        let value = counter
+            .value
+            .saturating_add(amount)
+            .min(counter.ceiling())
+            .max(counter.value);
+        let delta = value - counter.value;
+
+        counter.set_value(value);
+        counter.score(i64::from(delta), None);
+        store.history().record(
+            parsed_uuid,
+            Entry::new(
+                Operation::Increment,
+                i64::from(delta),
+                value,
+                Some(format!("GitHub {}", event)),
+            ),
+        );
+        Ok(Json(Counted {
+            counted: delta,
+            counter: counter.clone(),
+        }))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::signing::encode_hex;
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn count_github_events() {
+        use hmac::{Hmac, Mac};
+
+        let config = Config::build(Environment::Development)
+            .extra("github_webhook_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let deliver = |query: &str, event: &str, body: serde_json::Value| {
+            let body = body.to_string();
+            let mut mac = Hmac::<sha2::Sha256>::new_varkey(b"s3cret").unwrap();
+
+            mac.input(body.as_bytes());
+
+            let signature = encode_hex(&mac.result().code());
+
+            client
+                .post(format!("/integrations/github/{}{}", counter.id, query))
+                .header(ContentType::JSON)
+                .header(Header::new("X-GitHub-Event", event.to_string()))
+                .header(Header::new(
+                    "X-Hub-Signature-256",
+                    format!("sha256={}", signature),
+                ))
+                .body(body)
+                .dispatch()
+        };
+        let counted = |mut response: rocket::local::LocalResponse| -> serde_json::Value {
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+        let push = serde_json::json!({
+            "ref": "refs/heads/master",
+            "commits": [
+                { "id": "a1", "distinct": true },
+                { "id": "b2", "distinct": true },
+                { "id": "c3", "distinct": false }
+            ]
+        });
+
+        assert_eq!(
+            counted(deliver("", "ping", serde_json::json!({})))["counted"],
+            0
+        );
+
+        let pushed = counted(deliver("", "push", push.clone()));
+
+        assert_eq!(pushed["counted"], 2);
+        assert_eq!(pushed["counter"]["value"], 2);
+
+        let events = "?events=push,issues.opened";
+        let opened = serde_json::json!({ "action": "opened" });
+        let closed = serde_json::json!({ "action": "closed" });
+
+        assert_eq!(counted(deliver(events, "issues", opened))["counted"], 1);
+        assert_eq!(counted(deliver(events, "issues", closed))["counted"], 0);
+        assert_eq!(
+            counted(deliver("?events=release", "push", push))["counter"]["value"],
+            3
+        );
+
+        let forged_response = client
+            .post(format!("/integrations/github/{}", counter.id))
+            .header(ContentType::JSON)
+            .header(Header::new("X-GitHub-Event", "push"))
+            .header(Header::new("X-Hub-Signature-256", "sha256=00"))
+            .body("{}")
+            .dispatch();
+
+        assert_eq!(forged_response.status(), Status::Unauthorized);
+    }
+}
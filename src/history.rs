@@ -0,0 +1,410 @@
+use rocket::Config;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::changes::Feed;
+use crate::features::{Feature, Features};
+use crate::lock;
+
+const DEFAULT_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Create,
+    Increment,
+    Decrement,
+    Archive,
+    Unarchive,
+    Freeze,
+    Unfreeze,
+    Update,
+    Rollback,
+    Reset,
+    Undo,
+    Transfer,
+    /// A change made in another region.
+    Merge,
+    /// Only in the change feed, as a deleted counter's history goes with it.
+    Delete,
+}
+
+impl Operation {
+    pub fn parse(name: &str) -> Option<Operation> {
+        match name {
+            "create" => Some(Operation::Create),
+            "increment" => Some(Operation::Increment),
+            "decrement" => Some(Operation::Decrement),
+            "archive" => Some(Operation::Archive),
+            "unarchive" => Some(Operation::Unarchive),
+            "freeze" => Some(Operation::Freeze),
+            "unfreeze" => Some(Operation::Unfreeze),
+            "update" => Some(Operation::Update),
+            "rollback" => Some(Operation::Rollback),
+            "reset" => Some(Operation::Reset),
+            "undo" => Some(Operation::Undo),
+            "transfer" => Some(Operation::Transfer),
+            "merge" => Some(Operation::Merge),
+            "delete" => Some(Operation::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Increment => "increment",
+            Operation::Decrement => "decrement",
+            Operation::Archive => "archive",
+            Operation::Unarchive => "unarchive",
+            Operation::Freeze => "freeze",
+            Operation::Unfreeze => "unfreeze",
+            Operation::Update => "update",
+            Operation::Rollback => "rollback",
+            Operation::Reset => "reset",
+            Operation::Undo => "undo",
+            Operation::Transfer => "transfer",
+            Operation::Merge => "merge",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub operation: Operation,
+    pub delta: i64,
+    pub value: u32,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Free-form explanation supplied with the mutation, e.g. "manual correction after outage".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Entry {
+    pub fn new(operation: Operation, delta: i64, value: u32, reason: Option<String>) -> Entry {
+        Entry {
+            operation,
+            delta,
+            value,
+            timestamp: timestamp(),
+            reason,
+        }
+    }
+
+    /// The entry as of `timestamp` rather than now, if given.
+    pub fn occurred_at(self, timestamp: Option<u64>) -> Entry {
+        Entry {
+            timestamp: timestamp.unwrap_or(self.timestamp),
+            ..self
+        }
+    }
+}
+
+/// Narrows down which history entries are returned, and in which order.
+#[derive(Default)]
+pub struct Filter {
+    pub operations: Option<Vec<Operation>>,
+    /// Inclusive lower bound, in milliseconds since the Unix epoch.
+    pub from: Option<u64>,
+    /// Inclusive upper bound, in milliseconds since the Unix epoch.
+    pub to: Option<u64>,
+    pub newest_first: bool,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.operations
+            .as_ref()
+            .map_or(true, |operations| operations.contains(&entry.operation))
+            && self.from.map_or(true, |from| entry.timestamp >= from)
+            && self.to.map_or(true, |to| entry.timestamp <= to)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Page {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+pub struct Rate {
+    window_seconds: u64,
+    increments: u64,
+    per_second: f64,
+    per_minute: f64,
+    per_hour: f64,
+}
+
+/// Per-counter log of mutations, keeping at most `limit` of the most recent entries, and the
+/// feed of every counter's changes in the order they were recorded.
+pub struct History {
+    entries: Mutex<HashMap<Uuid, VecDeque<Entry>>>,
+    limit: usize,
+    feed: Feed,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(DEFAULT_LIMIT)
+    }
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            entries: Mutex::new(HashMap::new()),
+            limit,
+            feed: Feed::default(),
+        }
+    }
+
+    /// A separate history with the same entries.
+    pub fn copy(&self) -> History {
+        History {
+            entries: Mutex::new(lock(&self.entries).clone()),
+            limit: self.limit,
+            feed: self.feed.copy(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> History {
+        let limit = if Features::from_config(config).enabled(Feature::History) {
+            config
+                .get_int("history_limit")
+                .map(|limit| limit as usize)
+                .unwrap_or(DEFAULT_LIMIT)
+        } else {
+            0
+        };
+
+        History {
+            feed: Feed::from_config(config),
+            ..History::new(limit)
+        }
+    }
+
+    pub fn feed(&self) -> &Feed {
+        &self.feed
+    }
+
+    pub fn record(&self, id: Uuid, entry: Entry) {
+        if self.limit == 0 {
+            return;
+        }
+
+        self.feed.record(id, entry.clone());
+
+        let mut entries = lock(&self.entries);
+        let log = entries.entry(id).or_default();
+        // Backdated entries go where they belong, keeping the log in time order.
+        let position = log
+            .iter()
+            .rposition(|recorded| recorded.timestamp <= entry.timestamp)
+            .map_or(0, |position| position + 1);
+
+        log.insert(position, entry);
+
+        while log.len() > self.limit {
+            log.pop_front();
+        }
+    }
+
+    /// The newest change of value that has not been undone, passing over one change for every
+    /// undo recorded after it.
+    pub fn last_change(&self, id: &Uuid) -> Option<Entry> {
+        let entries = lock(&self.entries);
+        let mut undone = 0;
+
+        for entry in entries.get(id)?.iter().rev() {
+            if entry.operation == Operation::Undo {
+                undone += 1;
+            } else if entry.delta != 0 {
+                if undone == 0 {
+                    return Some(entry.clone());
+                }
+
+                undone -= 1;
+            }
+        }
+
+        None
+    }
+
+    pub fn forget(&self, id: &Uuid) {
+        lock(&self.entries).remove(id);
+    }
+
+    /// Every entry kept for `id`, oldest first.
+    pub fn entries(&self, id: &Uuid) -> Vec<Entry> {
+        lock(&self.entries)
+            .get(id)
+            .map_or_else(Vec::new, |log| log.iter().cloned().collect())
+    }
+
+    /// Entries matching `filter`, or `None` if nothing was ever recorded for `id`.
+    pub fn page(&self, id: &Uuid, filter: &Filter, offset: usize, limit: usize) -> Option<Page> {
+        let entries = lock(&self.entries);
+        let log = entries.get(id)?;
+        let mut matching: Vec<&Entry> = log.iter().filter(|entry| filter.matches(entry)).collect();
+
+        if filter.newest_first {
+            matching.reverse();
+        }
+
+        Some(Page {
+            total: matching.len(),
+            offset,
+            limit,
+            entries: matching
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// The sum of every change from `since` on, in milliseconds, if the log still reaches back
+    /// that far or to the counter's creation.
+    pub fn delta_since(&self, id: &Uuid, since: u64) -> Option<i64> {
+        let entries = lock(&self.entries);
+        let log = entries.get(id)?;
+        let complete = log.front().map_or(false, |oldest| {
+            oldest.timestamp < since || oldest.operation == Operation::Create
+        });
+
+        if !complete {
+            return None;
+        }
+
+        Some(
+            log.iter()
+                .rev()
+                .take_while(|entry| entry.timestamp >= since)
+                .map(|entry| entry.delta)
+                .sum(),
+        )
+    }
+
+    /// Increments per second, minute and hour over the trailing `window_seconds`.
+    pub fn rate(&self, id: &Uuid, window_seconds: u64) -> Option<Rate> {
+        let entries = lock(&self.entries);
+        let log = entries.get(id)?;
+        let since = timestamp().saturating_sub(window_seconds * 1000);
+        let increments: u64 = log
+            .iter()
+            .rev()
+            .take_while(|entry| entry.timestamp >= since)
+            .filter(|entry| entry.operation == Operation::Increment)
+            .map(|entry| entry.delta.max(0) as u64)
+            .sum();
+        let per_second = increments as f64 / window_seconds.max(1) as f64;
+
+        Some(Rate {
+            window_seconds,
+            increments,
+            per_second,
+            per_minute: per_second * 60.0,
+            per_hour: per_second * 3600.0,
+        })
+    }
+}
+
+pub fn timestamp() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before Unix epoch");
+
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn counter_history() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for action in &["increment", "increment", "decrement"] {
+            client
+                .put(format!("/counter/{}/{}", counter.id, action))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let mut response = client
+            .get(format!("/counter/{}/history?offset=1&limit=2", counter.id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let page: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(page["total"], 4);
+        assert_eq!(page["entries"][0]["operation"], "increment");
+        assert_eq!(page["entries"][1]["value"], 2);
+        assert_eq!(page["entries"].as_array().unwrap().len(), 2);
+
+        let mut response = client
+            .get(format!(
+                "/counter/{}/history?operation=increment&order=desc",
+                counter.id
+            ))
+            .dispatch();
+        let page: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["entries"][0]["value"], 2);
+        assert_eq!(page["entries"][1]["value"], 1);
+
+        let response = client
+            .get(format!("/counter/{}/history?operation=explode", counter.id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn increment_rate() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for _ in 0..6 {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let mut response = client
+            .get(format!("/counter/{}/rate?window=60", counter.id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let rate: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(rate["increments"], 6);
+        assert_eq!(rate["per_second"], 0.1);
+        assert_eq!(rate["per_minute"], 6.0);
+    }
+}
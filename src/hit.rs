@@ -0,0 +1,346 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::{Config, Outcome, State};
+use std::io::Cursor;
+
+/// A transparent 1x1 GIF.
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+const BOT_MARKERS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "preview",
+    "headless",
+    "lighthouse",
+    "monitor",
+    "curl",
+    "wget",
+    "python-requests",
+    "go-http-client",
+    "java/",
+];
+
+/// Consent-aware counting: with `privacy_mode` enabled, hits carrying `DNT: 1` or
+/// `Sec-GPC: 1` are not counted, and neither are hits without the `consent_cookie` if one is
+/// configured.
+#[derive(Default)]
+pub struct Privacy {
+    enabled: bool,
+    consent_cookie: Option<String>,
+}
+
+impl Privacy {
+    pub fn from_config(config: &Config) -> Privacy {
+        Privacy {
+            enabled: config.get_bool("privacy_mode").unwrap_or(false),
+            consent_cookie: config.get_str("consent_cookie").ok().map(String::from),
+        }
+    }
+
+    fn allows_tracking(&self, request: &Request) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let headers = request.headers();
+        let opted_out = ["DNT", "Sec-GPC"]
+            .iter()
+            .any(|name| headers.get_one(name) == Some("1"));
+
+        if opted_out {
+            return false;
+        }
+
+        match &self.consent_cookie {
+            Some(name) => match request.cookies().get(name) {
+                Some(cookie) => match cookie.value() {
+                    "" | "0" | "false" | "no" | "denied" => false,
+                    _ => true,
+                },
+                None => false,
+            },
+            None => true,
+        }
+    }
+}
+
+/// The parts of a hit request used to decide whether it came from a human visitor who
+/// agreed to be counted.
+pub struct Visitor {
+    user_agent: Option<String>,
+    referrer: Option<String>,
+    prefetch: bool,
+    tracking_allowed: bool,
+}
+
+impl Visitor {
+    pub fn may_be_counted(&self) -> bool {
+        self.tracking_allowed && !self.is_bot()
+    }
+
+    pub fn is_bot(&self) -> bool {
+        if self.prefetch {
+            return true;
+        }
+
+        match &self.user_agent {
+            Some(user_agent) if !user_agent.trim().is_empty() => {
+                let user_agent = user_agent.to_lowercase();
+
+                BOT_MARKERS.iter().any(|marker| user_agent.contains(marker))
+            }
+            _ => true,
+        }
+    }
+
+    /// Coarse sub-counter labels for the requested comma separated dimensions
+    /// (`referrer`, `agent`). Only the referrer's domain and a device class are kept.
+    pub fn labels(&self, dimensions: &str) -> Vec<String> {
+        dimensions
+            .split(',')
+            .filter_map(|dimension| match dimension.trim() {
+                "referrer" => Some(format!("referrer:{}", self.referrer_domain())),
+                "agent" => Some(format!("agent:{}", self.agent_class())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn referrer_domain(&self) -> String {
+        let referrer = match &self.referrer {
+            Some(referrer) => referrer.to_lowercase(),
+            None => return "direct".to_string(),
+        };
+        let without_scheme = referrer.splitn(2, "://").last().unwrap_or("");
+        let host = without_scheme
+            .split(|c: char| c == '/' || c == '?' || c == '#')
+            .next()
+            .unwrap_or("");
+        let host = host.rsplit('@').next().unwrap_or("");
+        let host = host.split(':').next().unwrap_or("");
+        let host = host.trim_start_matches("www.");
+
+        if host.is_empty() {
+            "direct".to_string()
+        } else {
+            host.to_string()
+        }
+    }
+
+    fn agent_class(&self) -> &'static str {
+        let user_agent = match &self.user_agent {
+            Some(user_agent) => user_agent.to_lowercase(),
+            None => return "other",
+        };
+
+        if user_agent.contains("ipad") || user_agent.contains("tablet") {
+            "tablet"
+        } else if user_agent.contains("mobi")
+            || user_agent.contains("iphone")
+            || user_agent.contains("android")
+        {
+            "mobile"
+        } else if user_agent.contains("windows")
+            || user_agent.contains("macintosh")
+            || user_agent.contains("x11")
+            || user_agent.contains("linux")
+        {
+            "desktop"
+        } else {
+            "other"
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Visitor {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Visitor, ()> {
+        let headers = request.headers();
+        let prefetch = ["Purpose", "X-Purpose", "X-Moz"]
+            .iter()
+            .filter_map(|name| headers.get_one(name))
+            .any(|value| value.eq_ignore_ascii_case("prefetch") || value == "preview");
+        let tracking_allowed = request
+            .guard::<State<Privacy>>()
+            .succeeded()
+            .map_or(true, |privacy| privacy.allows_tracking(request));
+
+        Outcome::Success(Visitor {
+            user_agent: headers.get_one("User-Agent").map(String::from),
+            referrer: headers.get_one("Referer").map(String::from),
+            prefetch,
+            tracking_allowed,
+        })
+    }
+}
+
+/// Response of the hit endpoint: either a tracking pixel or an empty 204.
+pub enum Hit {
+    Pixel,
+    Empty,
+}
+
+impl<'r> Responder<'r> for Hit {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+
+        response.raw_header("Cache-Control", "no-store, max-age=0");
+
+        match self {
+            Hit::Pixel => response
+                .header(ContentType::GIF)
+                .sized_body(Cursor::new(PIXEL)),
+            Hit::Empty => response.status(Status::NoContent),
+        };
+
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Cookie, Header, Status};
+    use rocket::local::Client;
+
+    use crate::maintenance::Maintenance;
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn hit_counter_ignores_bots() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let hit_response = client
+            .get(format!("/counter/{}/hit", counter.id))
+            .header(Header::new(
+                "User-Agent",
+                "Mozilla/5.0 (X11; Linux x86_64; rv:68.0) Gecko/20100101 Firefox/68.0",
+            ))
+            .dispatch();
+
+        assert_eq!(hit_response.status(), Status::Ok);
+        assert_eq!(hit_response.content_type(), Some(ContentType::GIF));
+
+        let bot_response = client
+            .get(format!("/counter/{}/hit?pixel=false", counter.id))
+            .header(Header::new("User-Agent", "Googlebot/2.1"))
+            .dispatch();
+
+        assert_eq!(bot_response.status(), Status::NoContent);
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let counter: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 1);
+    }
+
+    #[test]
+    fn hit_counter_spares_guarded_counters() {
+        let config = Config::build(Environment::Development)
+            .extra("write_tokens", true)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let browser = "Mozilla/5.0 (X11; Linux x86_64; rv:68.0) Gecko/20100101 Firefox/68.0";
+
+        let hit_response = client
+            .get(format!("/counter/{}/hit", counter.id))
+            .header(Header::new("User-Agent", browser))
+            .dispatch();
+
+        assert_eq!(hit_response.status(), Status::Ok);
+
+        let maintenance = client.rocket().state::<Maintenance>().unwrap();
+
+        maintenance.drain();
+
+        let maintenance_response = client
+            .get(format!("/counter/{}/hit", counter.id))
+            .header(Header::new("User-Agent", browser))
+            .dispatch();
+
+        assert_eq!(maintenance_response.status(), Status::ServiceUnavailable);
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let counter: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 0);
+    }
+
+    #[test]
+    fn hit_counter_with_dimensions() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .get(format!("/counter/{}/hit?by=referrer,agent", counter.id))
+            .header(Header::new(
+                "User-Agent",
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 12_4 like Mac OS X) Mobile/15E148",
+            ))
+            .header(Header::new(
+                "Referer",
+                "https://www.example.com/blog/post?utm_source=feed",
+            ))
+            .dispatch();
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let counter: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 1);
+        assert_eq!(counter.breakdown.get("referrer:example.com"), Some(&1));
+        assert_eq!(counter.breakdown.get("agent:mobile"), Some(&1));
+    }
+
+    #[test]
+    fn hit_counter_respects_privacy_signals() {
+        let config = Config::build(Environment::Development)
+            .extra("privacy_mode", true)
+            .extra("consent_cookie", "analytics_consent")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let browser = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:68.0) Firefox/68.0";
+
+        client
+            .get(format!("/counter/{}/hit", counter.id))
+            .header(Header::new("User-Agent", browser))
+            .dispatch();
+        client
+            .get(format!("/counter/{}/hit", counter.id))
+            .header(Header::new("User-Agent", browser))
+            .header(Header::new("DNT", "1"))
+            .cookie(Cookie::new("analytics_consent", "yes"))
+            .dispatch();
+        client
+            .get(format!("/counter/{}/hit", counter.id))
+            .header(Header::new("User-Agent", browser))
+            .cookie(Cookie::new("analytics_consent", "yes"))
+            .dispatch();
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let counter: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 1);
+    }
+}
@@ -0,0 +1,197 @@
+//! `/hook/<token>/increment` and `/hook/<token>/decrement`, URLs that change a counter with a
+//! plain GET, for low-code tools and webhook buttons that cannot send headers or a body. The
+//! token is both the credential and the address, so it is issued per counter, only with the
+//! `hooks` feature on, and can be rotated or revoked.
+
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::features::{Feature, Features};
+use crate::negotiation::Unused;
+use crate::ratelimit::Throttle;
+use crate::routes::{decrement, increment, Annotation};
+use crate::tenancy::TenantStore;
+use crate::{error, not_found_error, parse_id, ApiError, Counter, V1};
+
+#[derive(Serialize)]
+pub struct Hook {
+    token: String,
+    increment: String,
+    decrement: String,
+}
+
+/// The counter a hook token belongs to. Tokens start with the counter's id, so that it can be
+/// looked up directly, followed by a random secret.
+fn find(token: &str, store: &TenantStore) -> Result<Uuid, ApiError> {
+    let invalid = || error(Status::NotFound, "Hook was not found.");
+    let id = token
+        .get(..32)
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .ok_or_else(invalid)?;
+    let counter = store.get(id).ok_or_else(invalid)?;
+
+    if counter.hook_token.as_deref() == Some(token) {
+        Ok(id)
+    } else {
+        Err(invalid())
+    }
+}
+
+fn annotation() -> Annotation {
+    Annotation {
+        reason: Some("Hook".to_string()),
+        occurred_at: None,
+    }
+}
+
+/// Issues the counter's hook token, replacing the one it had, if any.
+#[post("/<id>/hook", data = "<_body>")]
+pub fn create_hook(
+    id: String,
+    store: TenantStore,
+    features: State<Features>,
+    writer: Writer,
+    _body: Unused,
+) -> Result<Json<Hook>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    features.require(Feature::Hooks)?;
+    writer.check(parsed_uuid, &store)?;
+
+    let token = format!("{}{}", parsed_uuid.to_simple(), Uuid::new_v4().to_simple());
+
+    store.write(parsed_uuid, |hashmap| -> Result<(), ApiError> {
+        let counter = hashmap.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+        counter.hook_token = Some(token.clone());
+        Ok(())
+    })?;
+
+    Ok(Json(Hook {
+        increment: format!("{}/hook/{}/increment", V1, token),
+        decrement: format!("{}/hook/{}/decrement", V1, token),
+        token,
+    }))
+}
+
+/// Revokes the counter's hook token, so that its URLs stop working.
+#[delete("/<id>/hook")]
+pub fn revoke_hook(
+    id: String,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+    store.write(parsed_uuid, |hashmap| {
+        let counter = hashmap.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+        counter.hook_token.take().ok_or_else(not_found_error)?;
+        Ok(Json(counter.clone()))
+    })
+}
+
+#[get("/<token>/increment")]
+pub fn increment_hook(
+    token: String,
+    store: TenantStore,
+    features: State<Features>,
+    _throttle: Throttle,
+) -> Result<Json<Counter>, ApiError> {
+    features.require(Feature::Hooks)?;
+
+    let id = find(&token, &store)?;
+
+    increment(id, &[], annotation(), &store).map(Json)
+}
+
+#[get("/<token>/decrement")]
+pub fn decrement_hook(
+    token: String,
+    store: TenantStore,
+    features: State<Features>,
+    _throttle: Throttle,
+) -> Result<Json<Counter>, ApiError> {
+    features.require(Feature::Hooks)?;
+
+    let id = find(&token, &store)?;
+
+    decrement(id, annotation(), &store).map(Json)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn change_counter_through_hook() {
+        let mut features = BTreeMap::new();
+
+        features.insert("hooks".to_string(), Value::Boolean(true));
+
+        let config = Config::build(Environment::Development)
+            .extra("features", Value::Table(features))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut hook_response = client
+            .post(format!("/counter/{}/hook", counter.id))
+            .dispatch();
+        let hook: serde_json::Value =
+            serde_json::from_str(&hook_response.body_string().unwrap()).unwrap();
+        let increment_url = hook["increment"].as_str().unwrap().to_string();
+
+        assert_eq!(hook_response.status(), Status::Ok);
+        assert!(increment_url.starts_with("/v1/hook/"));
+
+        client.get(increment_url.clone()).dispatch();
+
+        let mut increment_response = client.get(increment_url.clone()).dispatch();
+        let incremented: Counter =
+            serde_json::from_str(&increment_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(incremented.value, 2);
+
+        let decrement_response = client.get(hook["decrement"].as_str().unwrap()).dispatch();
+
+        assert_eq!(decrement_response.status(), Status::Ok);
+
+        let guessed_response = client
+            .get(format!(
+                "/hook/{}{}/increment",
+                counter.id.to_simple(),
+                counter.id.to_simple()
+            ))
+            .dispatch();
+
+        assert_eq!(guessed_response.status(), Status::NotFound);
+
+        client
+            .delete(format!("/counter/{}/hook", counter.id))
+            .dispatch();
+
+        assert_eq!(
+            client.get(increment_url).dispatch().status(),
+            Status::NotFound
+        );
+
+        let disabled_client = Client::new(rocket()).expect("Init failed");
+        let disabled_response = disabled_client
+            .post(format!("/counter/{}/hook", counter.id))
+            .dispatch();
+
+        assert_eq!(disabled_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,513 @@
+//! `POST /admin/import.csv`, for bringing in counters kept elsewhere, such as in a
+//! spreadsheet. Each row is a name and a value: the counter with that name is set to the value,
+//! or created with it if there is none. The upload is read a row at a time, so its size does
+//! not matter up to the limit, which is Rocket's `csv` limit. Rows that cannot be applied are
+//! reported and skipped, and the rest are applied anyway.
+//!
+//! `POST /admin/import.ndjson` takes back what `export.ndjson` gives, for moving whole
+//! instances: counters as they are, and history entries. It is read a line at a time in the
+//! same way, up to Rocket's `ndjson` limit.
+
+use rocket::data::Data;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome as RequestOutcome;
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::mem;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::export::Event;
+use crate::history::{Entry, Operation};
+use crate::routes::create;
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::{error, ApiError, Counter};
+
+/// Largest CSV upload read, in bytes, unless configured as `limits.csv`.
+const DEFAULT_LIMIT: u64 = 16 * 1024 * 1024;
+/// Largest NDJSON upload read, in bytes, unless configured as `limits.ndjson`.
+const DEFAULT_NDJSON_LIMIT: u64 = 1024 * 1024 * 1024;
+/// Errors reported beyond this many are only counted.
+const MAX_ERRORS: usize = 1000;
+const REASON: &str = "CSV import";
+const NDJSON_REASON: &str = "NDJSON import";
+
+#[derive(Serialize, Default)]
+pub struct Report {
+    created: usize,
+    updated: usize,
+    unchanged: usize,
+    failed: usize,
+    /// History entries added, by NDJSON imports.
+    #[serde(skip_serializing_if = "is_zero")]
+    events: usize,
+    /// The first rows that failed. Rows are numbered from 1, the header included.
+    errors: Vec<RowError>,
+}
+
+fn is_zero(count: &usize) -> bool {
+    *count == 0
+}
+
+#[derive(Serialize)]
+struct RowError {
+    row: usize,
+    reason: String,
+}
+
+/// The fields of one record, or `None` while a quoted field is still open.
+fn parse_record(record: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(mem::take(&mut field)),
+            (_, c) => field.push(c),
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+
+    fields.push(field);
+    Some(fields)
+}
+
+/// The name as it was before the export escaped it for spreadsheets.
+fn unescape(name: &str) -> &str {
+    let escaped = name.starts_with('\'')
+        && name[1..].starts_with(|c: char| c == '=' || c == '+' || c == '-' || c == '@');
+
+    if escaped {
+        &name[1..]
+    } else {
+        name
+    }
+}
+
+fn parse_row(fields: &[String]) -> Result<(String, u32), String> {
+    match fields {
+        [name, value] | [name, value, ..] => {
+            let name = unescape(name.trim());
+
+            if name.is_empty() {
+                return Err("Name is empty.".to_string());
+            }
+
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("Value {:?} is not a whole number.", value.trim()))?;
+
+            Ok((name.to_string(), value))
+        }
+        _ => Err("Rows must have a name and a value.".to_string()),
+    }
+}
+
+/// The size of an upload and how large it may be, by the limit named after its extension.
+pub struct Upload {
+    limit: u64,
+    length: Option<u64>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Upload {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Upload, ()> {
+        let limit = if request.uri().path().ends_with(".ndjson") {
+            request
+                .limits()
+                .get("ndjson")
+                .unwrap_or(DEFAULT_NDJSON_LIMIT)
+        } else {
+            request.limits().get("csv").unwrap_or(DEFAULT_LIMIT)
+        };
+
+        RequestOutcome::Success(Upload {
+            limit,
+            length: request
+                .headers()
+                .get_one("Content-Length")
+                .and_then(|length| length.parse().ok()),
+        })
+    }
+}
+
+enum Outcome {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+fn set_value(id: Uuid, value: u32, store: &Store) -> Result<bool, ApiError> {
+    store.write(id, |hashmap| {
+        let counter = match hashmap.get_mut(&id) {
+            Some(counter) => counter,
+            None => return Err(error(Status::NotFound, "Counter is gone.")),
+        };
+
+        counter.ensure_mutable()?;
+        counter.ensure_steppable()?;
+
+        if counter.value == value {
+            return Ok(false);
+        }
+
+        let delta = i64::from(value) - i64::from(counter.value);
+
+        counter.set_value(value);
+        store.history().record(
+            id,
+            Entry::new(Operation::Update, delta, value, Some(REASON.to_string())),
+        );
+        Ok(true)
+    })
+}
+
+/// Applies one row, keeping `names` up to date with any counter it creates.
+fn apply(
+    name: String,
+    value: u32,
+    names: &mut HashMap<String, Vec<Uuid>>,
+    store: &Store,
+) -> Result<Outcome, String> {
+    let reason = |error: ApiError| error.1["reason"].as_str().unwrap_or("").to_string();
+
+    match names.get(&name).map(Vec::as_slice) {
+        Some([id]) => {
+            if set_value(*id, value, store).map_err(reason)? {
+                Ok(Outcome::Updated)
+            } else {
+                Ok(Outcome::Unchanged)
+            }
+        }
+        Some(_) => Err(format!("Several counters are named {:?}.", name)),
+        None => {
+            let mut counter = Counter::new(Uuid::new_v4());
+
+            counter.name = Some(name.clone());
+
+            let id = create(counter, Some(REASON.to_string()), store)
+                .map_err(reason)?
+                .id;
+
+            names.insert(name, vec![id]);
+            set_value(id, value, store).map_err(reason)?;
+            Ok(Outcome::Created)
+        }
+    }
+}
+
+/// Rows of `name,value`, with an optional header row of exactly that. Names are matched
+/// exactly, against archived counters too. Uploads declared larger than the limit are refused
+/// with 413; of those sent without a length, only rows up to the limit are read.
+#[post("/import.csv", data = "<csv>")]
+pub fn import_csv(
+    csv: Data,
+    upload: Upload,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Report>, ApiError> {
+    writer.check_admin()?;
+
+    if upload.length.map_or(false, |length| length > upload.limit) {
+        return Err(error(
+            Status::PayloadTooLarge,
+            &format!("Uploads can be at most {} bytes.", upload.limit),
+        ));
+    }
+
+    let mut names: HashMap<String, Vec<Uuid>> = HashMap::new();
+
+    for counter in store.read().values() {
+        if let Some(name) = &counter.name {
+            names.entry(name.clone()).or_default().push(counter.id);
+        }
+    }
+
+    let mut report = Report::default();
+    let mut record = String::new();
+    let mut row = 0;
+    let mut stream = csv.open();
+
+    for line in BufReader::new((&mut stream).take(upload.limit)).lines() {
+        let line = line.map_err(|_| error(Status::BadRequest, "The upload is not UTF-8 text."))?;
+
+        if record.is_empty() {
+            row += 1;
+        }
+
+        record.push_str(line.trim_end_matches('\r'));
+
+        let fields = match parse_record(&record) {
+            Some(fields) => fields,
+            None => {
+                record.push('\n');
+                continue;
+            }
+        };
+
+        record.clear();
+
+        let header = row == 1
+            && fields.len() == 2
+            && fields[0].trim().eq_ignore_ascii_case("name")
+            && fields[1].trim().eq_ignore_ascii_case("value");
+
+        if header || fields.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+
+        let outcome =
+            parse_row(&fields).and_then(|(name, value)| apply(name, value, &mut names, &store));
+
+        match outcome {
+            Ok(Outcome::Created) => report.created += 1,
+            Ok(Outcome::Updated) => report.updated += 1,
+            Ok(Outcome::Unchanged) => report.unchanged += 1,
+            Err(reason) => {
+                report.failed += 1;
+
+                if report.errors.len() < MAX_ERRORS {
+                    report.errors.push(RowError { row, reason });
+                }
+            }
+        }
+    }
+
+    if !record.is_empty() {
+        report.failed += 1;
+        report.errors.push(RowError {
+            row,
+            reason: "A quoted field is not closed.".to_string(),
+        });
+    }
+
+    if stream.read(&mut [0]).map_or(false, |read| read > 0) {
+        report.failed += 1;
+        report.errors.push(RowError {
+            row: row + 1,
+            reason: format!(
+                "The upload is larger than {} bytes; the rest was not read.",
+                upload.limit
+            ),
+        });
+    }
+
+    Ok(Json(report))
+}
+
+/// Puts `counter` in place of the one with its id, or adds it. Write and hook tokens are never
+/// exported, so those of a counter being replaced are kept.
+fn restore(mut counter: Counter, store: &Store) -> Result<Outcome, String> {
+    let id = counter.id;
+
+    for alias in &counter.aliases {
+        if store.resolve(alias).map_or(false, |owner| owner != id) {
+            return Err(format!("Alias {:?} belongs to another counter.", alias));
+        }
+    }
+
+    let outcome = store.write_or_create(id, |hashmap| {
+        let previous = match hashmap.get(&id) {
+            Some(previous) => previous.clone(),
+            None => {
+                store.history().record(
+                    id,
+                    Entry::new(
+                        Operation::Create,
+                        0,
+                        counter.value,
+                        Some(NDJSON_REASON.to_string()),
+                    ),
+                );
+                hashmap.insert(id, counter);
+                return Outcome::Created;
+            }
+        };
+
+        counter.write_token = previous.write_token.clone();
+        counter.hook_token = previous.hook_token.clone();
+
+        if counter == previous {
+            return Outcome::Unchanged;
+        }
+
+        if counter.value != previous.value {
+            store.history().record(
+                id,
+                Entry::new(
+                    Operation::Update,
+                    i64::from(counter.value) - i64::from(previous.value),
+                    counter.value,
+                    Some(NDJSON_REASON.to_string()),
+                ),
+            );
+        }
+
+        hashmap.insert(id, counter);
+        Outcome::Updated
+    });
+
+    outcome.map_err(|quota| {
+        ApiError::from(quota).1["reason"]
+            .as_str()
+            .unwrap_or("")
+            .to_string()
+    })
+}
+
+/// One line of `export.ndjson`: a history entry if it names a `counter_id`, else a counter.
+fn apply_line(line: &str, store: &Store) -> Result<Option<Outcome>, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(line).map_err(|reason| format!("Not JSON: {}", reason))?;
+
+    if value.get("counter_id").is_none() {
+        let counter: Counter =
+            serde_json::from_value(value).map_err(|reason| format!("Not a counter: {}", reason))?;
+
+        return restore(counter, store).map(Some);
+    }
+
+    let event: Event = serde_json::from_value(value)
+        .map_err(|reason| format!("Not a history entry: {}", reason))?;
+
+    if store.get(event.counter_id).is_none() {
+        return Err(format!("Counter {} does not exist.", event.counter_id));
+    }
+
+    store.history().record(event.counter_id, event.entry);
+    Ok(None)
+}
+
+/// Lines of `export.ndjson`, counters and history entries alike, such as both of its tables one
+/// after the other. Counters replace those with the same id, and are created if there is none,
+/// even if archived or frozen. History entries are added to the end of their counter's history,
+/// so counters should come first. Blank lines are skipped; rows in the report are lines.
+#[post("/import.ndjson", data = "<ndjson>")]
+pub fn import_ndjson(
+    ndjson: Data,
+    upload: Upload,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Report>, ApiError> {
+    writer.check_admin()?;
+
+    if upload.length.map_or(false, |length| length > upload.limit) {
+        return Err(error(
+            Status::PayloadTooLarge,
+            &format!("Uploads can be at most {} bytes.", upload.limit),
+        ));
+    }
+
+    let mut report = Report::default();
+    let mut stream = ndjson.open();
+    let mut row = 0;
+
+    for line in BufReader::new((&mut stream).take(upload.limit)).lines() {
+        let line = line.map_err(|_| error(Status::BadRequest, "The upload is not UTF-8 text."))?;
+
+        row += 1;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match apply_line(&line, &store) {
+            Ok(Some(Outcome::Created)) => report.created += 1,
+            Ok(Some(Outcome::Updated)) => report.updated += 1,
+            Ok(Some(Outcome::Unchanged)) => report.unchanged += 1,
+            Ok(None) => report.events += 1,
+            Err(reason) => {
+                report.failed += 1;
+
+                if report.errors.len() < MAX_ERRORS {
+                    report.errors.push(RowError { row, reason });
+                }
+            }
+        }
+    }
+
+    if stream.read(&mut [0]).map_or(false, |read| read > 0) {
+        report.failed += 1;
+        report.errors.push(RowError {
+            row: row + 1,
+            reason: format!(
+                "The upload is larger than {} bytes; the rest was not read.",
+                upload.limit
+            ),
+        });
+    }
+
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn import_counters_from_csv() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "imported-existing" }"#)
+            .dispatch();
+        let existing: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let mut response = client
+            .post("/admin/import.csv")
+            .header(ContentType::CSV)
+            .body(
+                "name,value\r\n\
+                 imported-existing,7\r\n\
+                 \"imported, \"\"quoted\"\"\",3\r\n\
+                 imported-bad,-1\r\n\
+                 ,4\r\n",
+            )
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let report: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(report["created"], 1);
+        assert_eq!(report["updated"], 1);
+        assert_eq!(report["failed"], 2);
+        assert_eq!(report["errors"][0]["row"], 4);
+        assert_eq!(report["errors"][1]["row"], 5);
+
+        let mut get_response = client.get(format!("/counter/{}", existing.id)).dispatch();
+        let counter: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 7);
+
+        let mut search_response = client.get("/counter/search?q=quoted").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&search_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].name.as_ref().unwrap(), "imported, \"quoted\"");
+        assert_eq!(counters[0].value, 3);
+    }
+}
@@ -0,0 +1,347 @@
+//! Periodic background work, such as sampling time series, checking for idle alerts and
+//! polling a leader, registered as named jobs and run from one scheduler thread rather than each
+//! sleeping in a thread of its own. Due jobs run on a blocking pool, so a slow one does not hold
+//! up the rest; a job still running when it falls due again is skipped until it finishes.
+//!
+//! Every run is followed by the next one an interval later, plus a random delay of up to
+//! `job_jitter` of the interval (0.1 by default), so that jobs with the same interval drift
+//! apart instead of all waking at once. `GET /admin/jobs` lists the jobs with their next runs,
+//! and `/metrics` counts their runs, failures and time taken.
+//!
+//! Schedules and webhook retries keep runners of their own, as they run at exact times rather
+//! than intervals.
+
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::auth::Admin;
+use crate::history;
+use crate::lock;
+use crate::pool::BlockingPool;
+
+const DEFAULT_JITTER: f64 = 0.1;
+/// Longest the scheduler sleeps before checking whether it is still needed.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+const THREADS: usize = 2;
+
+/// How a run of a job went.
+pub enum Run {
+    Done,
+    Failed(String),
+    /// The job is no longer needed, such as when the store it worked on is gone, and is
+    /// removed.
+    Finished,
+}
+
+type Task = Box<dyn FnMut() -> Run + Send>;
+
+#[derive(Default, Clone)]
+struct Stats {
+    runs: u64,
+    failures: u64,
+    total: Duration,
+    last_duration: Duration,
+    /// Milliseconds since the Unix epoch.
+    last_run_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+struct Job {
+    id: u64,
+    name: String,
+    interval: Duration,
+    /// Milliseconds since the Unix epoch.
+    next_run: u64,
+    /// Taken while the job runs.
+    task: Option<Task>,
+    stats: Stats,
+}
+
+/// Cheaply cloneable handle to the registered jobs.
+#[derive(Clone)]
+pub struct Jobs {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    jobs: Mutex<Vec<Job>>,
+    changed: Condvar,
+    jitter: f64,
+    pool: BlockingPool,
+}
+
+impl Shared {
+    /// When a job that runs every `interval` runs next, counting from `now`.
+    fn next_run(&self, interval: Duration, now: u64) -> u64 {
+        let interval = interval.as_millis() as u64;
+        let jitter = (interval as f64 * self.jitter * random_fraction()) as u64;
+
+        now + interval + jitter
+    }
+}
+
+/// A number from 0 to 1, random enough to spread jobs out.
+fn random_fraction() -> f64 {
+    let bytes = Uuid::new_v4();
+    let bytes = bytes.as_bytes();
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    f64::from(value) / f64::from(u32::max_value())
+}
+
+impl Jobs {
+    pub fn from_config(config: &Config) -> Jobs {
+        let jitter = config
+            .get_float("job_jitter")
+            .ok()
+            .filter(|jitter| *jitter >= 0.0 && *jitter <= 1.0)
+            .unwrap_or(DEFAULT_JITTER);
+        let jobs = Jobs {
+            shared: Arc::new(Shared {
+                jobs: Mutex::default(),
+                changed: Condvar::new(),
+                jitter,
+                pool: BlockingPool::new(THREADS),
+            }),
+        };
+        let shared = Arc::downgrade(&jobs.shared);
+
+        thread::spawn(move || run(&shared));
+        jobs
+    }
+
+    /// Runs `task` every `interval`, first an interval from now, until it returns
+    /// `Run::Finished` or the jobs are dropped.
+    pub fn every<F>(&self, name: &str, interval: Duration, task: F)
+    where
+        F: FnMut() -> Run + Send + 'static,
+    {
+        let mut jobs = lock(&self.shared.jobs);
+        let id = jobs.iter().map(|job| job.id + 1).max().unwrap_or(0);
+
+        jobs.push(Job {
+            id,
+            name: name.to_string(),
+            interval,
+            next_run: self.shared.next_run(interval, history::timestamp()),
+            task: Some(Box::new(task)),
+            stats: Stats::default(),
+        });
+        self.shared.changed.notify_one();
+    }
+}
+
+impl Jobs {
+    /// A `Waker` for the jobs named `name`.
+    pub fn waker(&self, name: &str) -> Waker {
+        Waker {
+            shared: Arc::downgrade(&self.shared),
+            name: name.to_string(),
+        }
+    }
+}
+
+/// Brings the next run of a job forward to now, such as when enough work has piled up for it.
+/// It does not keep the jobs from being dropped, so the job's own task may hold one.
+pub struct Waker {
+    shared: Weak<Shared>,
+    name: String,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let now = history::timestamp();
+
+        for job in lock(&shared.jobs)
+            .iter_mut()
+            .filter(|job| job.name == self.name)
+        {
+            job.next_run = job.next_run.min(now);
+        }
+        shared.changed.notify_one();
+    }
+}
+
+fn run(shared: &Weak<Shared>) {
+    while let Some(shared) = shared.upgrade() {
+        let now = history::timestamp();
+        let mut jobs = lock(&shared.jobs);
+
+        for job in jobs.iter_mut().filter(|job| job.next_run <= now) {
+            let mut task = match job.task.take() {
+                Some(task) => task,
+                None => continue,
+            };
+            let id = job.id;
+            let job_shared = Arc::clone(&shared);
+
+            job.next_run = shared.next_run(job.interval, now);
+            shared.pool.spawn(move || {
+                let started = Instant::now();
+                let outcome = task();
+
+                finish(&job_shared, id, task, outcome, started.elapsed(), now);
+            });
+        }
+
+        let sleep = jobs
+            .iter()
+            .filter(|job| job.task.is_some())
+            .map(|job| Duration::from_millis(job.next_run.saturating_sub(now)))
+            .min()
+            .unwrap_or(MAX_SLEEP)
+            .min(MAX_SLEEP);
+        let _ = shared.changed.wait_timeout(jobs, sleep);
+    }
+}
+
+/// Notes how a run went and hands the job its task back, or removes it once finished.
+fn finish(shared: &Shared, id: u64, task: Task, outcome: Run, took: Duration, started_at: u64) {
+    let mut jobs = lock(&shared.jobs);
+    let index = match jobs.iter().position(|job| job.id == id) {
+        Some(index) => index,
+        None => return,
+    };
+    let stats = &mut jobs[index].stats;
+
+    stats.runs += 1;
+    stats.total += took;
+    stats.last_duration = took;
+    stats.last_run_at = Some(started_at);
+
+    match outcome {
+        Run::Done => stats.last_error = None,
+        Run::Failed(error) => {
+            stats.failures += 1;
+            stats.last_error = Some(error);
+        }
+        Run::Finished => {
+            jobs.remove(index);
+            return;
+        }
+    }
+
+    jobs[index].task = Some(task);
+    shared.changed.notify_one();
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    name: String,
+    interval_seconds: f64,
+    /// Milliseconds since the Unix epoch.
+    next_run_at: u64,
+    running: bool,
+    runs: u64,
+    failures: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_run_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_duration_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mean_duration_ms: Option<f64>,
+    /// Why the last run failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+impl Jobs {
+    /// Every job, the one to run soonest first.
+    pub fn reports(&self) -> Vec<Report> {
+        let milliseconds = |duration: Duration| duration.as_micros() as f64 / 1000.0;
+        let mut reports: Vec<Report> = lock(&self.shared.jobs)
+            .iter()
+            .map(|job| {
+                let ran = job.stats.runs > 0;
+
+                Report {
+                    name: job.name.clone(),
+                    interval_seconds: job.interval.as_secs_f64(),
+                    next_run_at: job.next_run,
+                    running: job.task.is_none(),
+                    runs: job.stats.runs,
+                    failures: job.stats.failures,
+                    last_run_at: job.stats.last_run_at,
+                    last_duration_ms: Some(milliseconds(job.stats.last_duration)).filter(|_| ran),
+                    mean_duration_ms: Some(milliseconds(job.stats.total) / job.stats.runs as f64)
+                        .filter(|_| ran),
+                    last_error: job.stats.last_error.clone(),
+                }
+            })
+            .collect();
+
+        reports.sort_by_key(|report| report.next_run_at);
+        reports
+    }
+
+    /// Runs, failures and seconds spent running, by job.
+    pub fn totals(&self) -> Vec<(String, u64, u64, f64)> {
+        let mut totals: Vec<(String, u64, u64, f64)> = lock(&self.shared.jobs)
+            .iter()
+            .map(|job| {
+                (
+                    job.name.clone(),
+                    job.stats.runs,
+                    job.stats.failures,
+                    job.stats.total.as_secs_f64(),
+                )
+            })
+            .collect();
+
+        totals.sort_by(|a, b| a.0.cmp(&b.0));
+        totals
+    }
+}
+
+#[get("/jobs")]
+pub fn list_jobs(jobs: State<Jobs>, _admin: Admin) -> Json<Vec<Report>> {
+    Json(jobs.reports())
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::Status;
+    use rocket::local::Client;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{build, rocket};
+
+    #[test]
+    fn list_background_jobs() {
+        let config = Config::build(Environment::Development)
+            .extra("timeseries_interval", 1i64)
+            .extra("job_jitter", 0.0)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+
+        thread::sleep(Duration::from_millis(1500));
+
+        let mut response = client.get("/admin/jobs").dispatch();
+        let jobs: Vec<serde_json::Value> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0]["name"], "timeseries");
+        assert_eq!(jobs[0]["interval_seconds"], 1.0);
+        assert_eq!(jobs[0]["runs"], 1);
+        assert!(
+            jobs[0]["next_run_at"].as_u64().unwrap() > jobs[0]["last_run_at"].as_u64().unwrap()
+        );
+
+        let exposition = client.get("/metrics").dispatch().body_string().unwrap();
+
+        assert!(exposition.contains("job_runs_total{job=\"timeseries\"} 1"));
+    }
+}
@@ -0,0 +1,896 @@
+//! Counters as a service. The `caas` binary runs it on its own, configured from `Rocket.toml`;
+//! other Rust applications can embed the counters as a `CounterService`, with or without the
+//! HTTP API mounted onto a Rocket instance of their own.
+
+#![feature(proc_macro_hygiene, decl_macro)]
+
+#[macro_use]
+extern crate rocket;
+#[macro_use]
+extern crate rocket_contrib;
+#[macro_use]
+extern crate serde_derive;
+
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::status;
+use rocket::Route;
+use rocket_contrib::json::JsonValue;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use uuid::Uuid;
+
+mod activity;
+mod alerts;
+mod aliases;
+mod audit;
+mod auth;
+mod badge;
+mod bench;
+mod bucket;
+mod bus;
+mod caching;
+mod calendar;
+mod changes;
+pub mod cli;
+pub mod client;
+mod compare;
+mod conditional;
+mod cors;
+mod counter;
+mod cycle;
+mod decay;
+mod deferred;
+mod deliveries;
+mod deprecation;
+mod diagnostics;
+mod display;
+mod distinct;
+mod distribution;
+mod dry_run;
+mod embed;
+mod events;
+mod export;
+mod features;
+mod firewall;
+mod github;
+mod history;
+mod hit;
+mod hooks;
+mod import;
+mod jobs;
+mod links;
+mod listing;
+mod maintenance;
+mod metrics;
+mod milestones;
+mod mqtt;
+mod negotiation;
+mod notify;
+mod parquet;
+mod pool;
+mod protobuf;
+mod purge;
+mod qr;
+mod ranking;
+mod ratelimit;
+mod reactions;
+mod regions;
+mod reload;
+mod replica;
+mod request_id;
+mod routes;
+mod schedule;
+mod service;
+mod shutdown;
+mod signing;
+mod simulate;
+mod slack;
+mod snapshots;
+mod statsd;
+mod storage;
+mod store;
+mod telemetry;
+mod templates;
+mod tenancy;
+mod timeseries;
+mod tls;
+mod transaction;
+mod transfer;
+mod ui;
+mod usage;
+mod validation;
+mod visibility;
+mod watch;
+mod webhooks;
+mod websocket;
+
+use alerts::Alerts;
+use audit::Audit;
+use auth::{ApiKeys, Jwt, Unauthorized, WriteTokens};
+use bucket::Buckets;
+use bus::Bus;
+use caching::CachePolicy;
+use cors::Cors;
+pub use counter::Counter;
+use deferred::Deferred;
+use deprecation::Deprecations;
+use diagnostics::Launched;
+use features::{Feature, Features};
+use firewall::IpRules;
+use github::GitHubSecret;
+use hit::Privacy;
+use jobs::Jobs;
+use links::OneTimeLinks;
+use listing::Listings;
+use maintenance::{Maintenance, Unavailable};
+use metrics::RequestMetrics;
+use mqtt::Mqtt;
+use notify::Notifiers;
+use qr::PublicUrl;
+use ratelimit::{RateLimits, TooManyRequests};
+use reactions::Bundles;
+use regions::Regions;
+use replica::Leader;
+use schedule::Schedules;
+pub use service::CounterService;
+use signing::Signing;
+use slack::SlackSecret;
+use snapshots::Snapshots;
+use storage::Storage;
+use store::{QuotaExceeded, Store};
+use telemetry::Tracer;
+use templates::Templates;
+use tenancy::Tenants;
+use tls::Tls;
+use usage::Usage;
+use webhooks::Webhooks;
+
+pub type ApiError = status::Custom<JsonValue>;
+
+fn error(status: Status, reason: &str) -> ApiError {
+    status::Custom(
+        status,
+        json!({
+            "status": "error",
+            "reason": reason
+        }),
+    )
+}
+
+fn not_found_error() -> ApiError {
+    error(Status::NotFound, "Resource was not found.")
+}
+
+/// The counter (or other resource) id in a path. Malformed ids are the client's mistake, so
+/// they are answered with 400 rather than looked up.
+fn parse_id(id: &str) -> Result<Uuid, ApiError> {
+    Uuid::parse_str(id).map_err(|_| error(Status::BadRequest, "Invalid id."))
+}
+
+/// Locks `mutex` even if a thread panicked while holding it. Every mutation leaves the state
+/// consistent between statements, so a panic must not take the whole service down with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl From<QuotaExceeded> for ApiError {
+    fn from(exceeded: QuotaExceeded) -> ApiError {
+        match exceeded {
+            QuotaExceeded::Capacity => error(Status::InsufficientStorage, "Counter limit reached."),
+            QuotaExceeded::Plan => {
+                error(Status::Forbidden, "The tenant's counter quota is used up.")
+            }
+        }
+    }
+}
+
+// General routes
+
+#[get("/")]
+fn index() -> JsonValue {
+    json!({
+        "status": "ok",
+        "message": "Welcome to Counter a Service"
+    })
+}
+
+// Catchers, for requests that fail before a route can answer with an error of its own
+
+#[catch(400)]
+fn bad_request() -> JsonValue {
+    json!({
+        "status": "error",
+        "reason": "Request could not be understood."
+    })
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> JsonValue {
+    let Unauthorized(reason) = request.local_cache(Unauthorized::default);
+
+    json!({
+        "status": "error",
+        "reason": reason
+    })
+}
+
+#[catch(403)]
+fn forbidden() -> JsonValue {
+    json!({
+        "status": "error",
+        "reason": "Your role does not allow this."
+    })
+}
+
+#[catch(404)]
+fn not_found() -> JsonValue {
+    json!({
+        "status": "error",
+        "reason": "Resource was not found."
+    })
+}
+
+#[catch(415)]
+fn unsupported_media_type() -> JsonValue {
+    json!({
+        "status": "error",
+        "reason": "Expected JSON or MessagePack."
+    })
+}
+
+#[catch(422)]
+fn unprocessable_entity(request: &Request) -> JsonValue {
+    let errors = validation::errors(request);
+
+    if errors.is_empty() {
+        json!({
+            "status": "error",
+            "reason": "Request body is invalid."
+        })
+    } else {
+        json!({
+            "status": "error",
+            "reason": "Request body is invalid.",
+            "errors": errors
+        })
+    }
+}
+
+#[catch(429)]
+fn too_many_requests() -> TooManyRequests {
+    TooManyRequests
+}
+
+#[catch(503)]
+fn service_unavailable() -> Unavailable {
+    Unavailable
+}
+
+#[catch(500)]
+fn internal_error() -> JsonValue {
+    json!({
+        "status": "error",
+        "reason": "Internal server error."
+    })
+}
+
+// Setup
+
+const V1: &str = "/v1";
+
+/// Every mount point of the stable API. Each is served under `/v1` and, for clients that
+/// predate versioning, at its original unversioned path with deprecation headers. A `/v2`
+/// with breaking changes gets its own list and is mounted next to this one.
+fn v1_routes() -> Vec<(&'static str, Vec<Route>)> {
+    vec![
+        (
+            "/counter",
+            routes![
+                routes::get_all_counters,
+                purge::purge,
+                routes::search_counters,
+                routes::get_statistics,
+                distribution::distribution,
+                routes::get_top,
+                routes::get_snapshot,
+                routes::lookup_counters,
+                compare::compare_pair,
+                compare::compare_pairs,
+                export::export_csv,
+                routes::create_counter,
+                routes::get_counter,
+                routes::delete_counter,
+                badge::badge,
+                badge::shield,
+                qr::qr,
+                links::create_links,
+                links::redeem,
+                hooks::create_hook,
+                hooks::revoke_hook,
+                routes::patch_counter,
+                routes::upsert_counter,
+                routes::increment_counter,
+                routes::hit_counter,
+                routes::decrement_counter,
+                routes::next_range,
+                routes::undo_counter,
+                distinct::add_items,
+                routes::archive_counter,
+                routes::unarchive_counter,
+                routes::freeze_counter,
+                routes::unfreeze_counter,
+                routes::get_history,
+                routes::get_rate,
+                routes::get_delta,
+                routes::get_timeseries,
+                routes::get_rollup,
+                events::events,
+                events::wait,
+                events::watch,
+                embed::widget,
+                webhooks::create_webhook,
+                webhooks::list_webhooks,
+                webhooks::delete_webhook,
+                deliveries::list_deliveries,
+                deliveries::replay_delivery,
+                deliveries::replay_deliveries,
+                webhooks::create_milestone_hook,
+                webhooks::list_milestone_hooks,
+                webhooks::delete_milestone_hook,
+                milestones::get_milestones,
+                activity::get_activity,
+                alerts::create_alert,
+                alerts::list_alerts,
+                alerts::delete_alert,
+                notify::create_channel,
+                notify::list_channels,
+                notify::delete_channel,
+                simulate::simulate,
+                schedule::create_schedule,
+                schedule::list_schedules,
+                schedule::cancel_schedule,
+                aliases::list_aliases,
+                aliases::add_alias,
+                aliases::remove_alias,
+                transfer::transfer,
+                transfer::transfer_all,
+                regions::get_regions
+            ],
+        ),
+        (
+            "/reactions",
+            routes![
+                reactions::create_bundle,
+                reactions::get_bundle,
+                reactions::react
+            ],
+        ),
+        (
+            "/bucket",
+            routes![bucket::create_bucket, bucket::get_bucket, bucket::take],
+        ),
+        ("/transaction", routes![transaction::transaction]),
+        ("/changes", routes![changes::get_changes]),
+        ("/replication", routes![regions::receive]),
+        (
+            "/hook",
+            routes![hooks::increment_hook, hooks::decrement_hook],
+        ),
+        ("/integrations", routes![github::webhook, slack::command]),
+        ("/embed", routes![embed::snippet]),
+        ("/display", routes![display::display]),
+        ("/metrics", routes![metrics::service, metrics::counters]),
+        (
+            "/admin",
+            routes![
+                routes::get_contention,
+                routes::get_deprecations,
+                metrics::http_stats,
+                jobs::list_jobs,
+                usage::get_usage,
+                maintenance::get_maintenance,
+                maintenance::set_maintenance,
+                diagnostics::diagnostics,
+                diagnostics::debug,
+                audit::get_audit,
+                snapshots::list_snapshots,
+                snapshots::create_snapshot,
+                snapshots::rollback,
+                snapshots::diff,
+                templates::list_templates,
+                templates::put_template,
+                templates::delete_template,
+                import::import_csv,
+                import::import_ndjson,
+                export::export_parquet,
+                export::export_ndjson,
+                reload::reload
+            ],
+        ),
+    ]
+}
+
+/// The whole service as `caas` runs it, configured from `Rocket.toml` and the environment, and
+/// shutting down gracefully on SIGTERM and SIGINT once launched.
+pub fn rocket() -> rocket::Rocket {
+    build(rocket::ignite()).attach(AdHoc::on_launch("Graceful shutdown", shutdown::listen))
+}
+
+/// Shows or changes the format version of the storage file that the configuration names, for
+/// `caas --migrate-status` and `caas --migrate-to`, without loading the counters.
+pub fn migrate(migration: cli::Migration) -> Result<String, String> {
+    let storage = Storage::from_config(rocket::ignite().config())?;
+
+    match migration {
+        cli::Migration::Status => storage.migration_status(),
+        cli::Migration::To(version) => storage.migrate_to(version),
+    }
+}
+
+fn build(rocket: rocket::Rocket) -> rocket::Rocket {
+    match CounterService::from_config(rocket.config()) {
+        Ok(service) => service.mount(rocket),
+        Err(reason) => rocket.attach(AdHoc::on_attach("Storage", move |rocket| {
+            eprintln!("{}", reason);
+            Err(rocket)
+        })),
+    }
+}
+
+/// The HTTP API on top of the jobs and the store that `rocket` manages.
+fn api(rocket: rocket::Rocket) -> rocket::Rocket {
+    let cors = Cors::from_config(rocket.config());
+
+    let rocket = v1_routes()
+        .into_iter()
+        .fold(rocket, |rocket, (base, routes)| {
+            rocket
+                .mount(&format!("{}{}", V1, base), routes.clone())
+                .mount(base, routes)
+        });
+
+    rocket
+        .mount(
+            "/",
+            routes![index, diagnostics::healthz, diagnostics::readyz],
+        )
+        .mount("/ui", routes![ui::page])
+        .mount(firewall::BLOCKED_PATH, routes![firewall::blocked])
+        .mount(features::PRIVATE_PATH, routes![features::private])
+        .mount(replica::READ_ONLY_PATH, routes![replica::read_only])
+        .mount(usage::EXCEEDED_PATH, routes![usage::exceeded])
+        .mount(
+            &format!("{}/operations", V1),
+            routes![deferred::get_operation],
+        )
+        .attach(AdHoc::on_attach("Deprecations", |rocket| {
+            let mut deprecations = Deprecations::from_config(rocket.config());
+
+            for (base, _) in v1_routes() {
+                deprecations.deprecate(base, &format!("{}{}", V1, base));
+            }
+
+            Ok(rocket.manage(deprecations))
+        }))
+        .attach(AdHoc::on_attach("Tracing", |rocket| {
+            let tracer = Tracer::from_config(rocket.config());
+
+            Ok(rocket.manage(tracer))
+        }))
+        .attach(AdHoc::on_request("Tracing", telemetry::start))
+        .attach(AdHoc::on_response("Tracing", telemetry::finish))
+        .attach(AdHoc::on_attach("Request metrics", |rocket| {
+            Ok(rocket.manage(RequestMetrics::default()))
+        }))
+        .attach(AdHoc::on_request("Request metrics", metrics::start))
+        .attach(AdHoc::on_response("Request metrics", metrics::observe))
+        .attach(AdHoc::on_attach("statsd sink", |rocket| {
+            Ok(match statsd::Sink::from_config(rocket.config()) {
+                Some(sink) => rocket.manage(sink),
+                None => rocket,
+            })
+        }))
+        .attach(AdHoc::on_response("statsd sink", statsd::emit))
+        .attach(AdHoc::on_attach("Audit", |rocket| {
+            let audit = Audit::from_config(rocket.config());
+
+            Ok(rocket.manage(audit))
+        }))
+        // Before content negotiation, which may encode the JSON that these read.
+        .attach(AdHoc::on_response("Audit", audit::record))
+        .attach(AdHoc::on_response(
+            "Request ids",
+            request_id::annotate_response,
+        ))
+        .attach(AdHoc::on_response(
+            "Deprecations",
+            deprecation::annotate_response,
+        ))
+        .attach(AdHoc::on_attach("Cache policy", |rocket| {
+            let policy = CachePolicy::from_config(rocket.config());
+
+            Ok(rocket.manage(policy))
+        }))
+        .attach(AdHoc::on_response(
+            "Cache policy",
+            caching::annotate_response,
+        ))
+        .attach(AdHoc::on_response(
+            "Content negotiation",
+            negotiation::encode_response,
+        ))
+        .manage(cors.clone())
+        .attach(cors)
+        .register(catchers![
+            bad_request,
+            unauthorized,
+            forbidden,
+            not_found,
+            unsupported_media_type,
+            unprocessable_entity,
+            too_many_requests,
+            internal_error,
+            service_unavailable
+        ])
+        .attach(AdHoc::on_attach("Features", |rocket| {
+            let features = Features::from_config(rocket.config());
+
+            Ok(rocket.manage(features))
+        }))
+        .attach(AdHoc::on_attach("Maintenance", |rocket| {
+            let maintenance = Maintenance::from_config(rocket.config());
+
+            Ok(rocket.manage(maintenance))
+        }))
+        .attach(AdHoc::on_attach("IP rules", |rocket| {
+            let ip_rules = IpRules::from_config(rocket.config());
+
+            Ok(rocket.manage(ip_rules))
+        }))
+        .attach(AdHoc::on_request("IP filter", firewall::filter))
+        .attach(AdHoc::on_attach("Read replica", |rocket| {
+            let leader = match (
+                Leader::from_config(rocket.config()),
+                rocket.state::<Store>(),
+                rocket.state::<Jobs>(),
+            ) {
+                (Some(leader), Some(store), Some(jobs)) => {
+                    leader.follow(store, jobs);
+                    leader
+                }
+                _ => return Ok(rocket),
+            };
+
+            Ok(rocket.manage(leader))
+        }))
+        .attach(AdHoc::on_request("Read replica", replica::redirect_writes))
+        .attach(AdHoc::on_attach("Replication", |rocket| {
+            let regions = match (
+                Regions::from_config(rocket.config()),
+                rocket.state::<Store>(),
+                rocket.state::<Jobs>(),
+            ) {
+                (Some(regions), Some(store), Some(jobs)) => {
+                    regions.track(store);
+                    regions.replicate(jobs, store);
+                    regions
+                }
+                _ => return Ok(rocket),
+            };
+
+            Ok(rocket.manage(regions))
+        }))
+        .attach(AdHoc::on_attach("Tenants", |rocket| {
+            let tenants = match rocket.state::<Store>() {
+                Some(store) => Tenants::from_config(rocket.config(), store.clone()),
+                None => return Ok(rocket),
+            };
+
+            if let Err(reason) = tenants.check_integrations() {
+                eprintln!("{}", reason);
+                return Err(rocket);
+            }
+
+            if let Some(jobs) = rocket.state::<Jobs>() {
+                let jobs = jobs.clone();
+
+                tenants.on_create(move |tenant, store| {
+                    store.schedule_sampler(&jobs, &format!("timeseries:{}", tenant))
+                });
+            }
+
+            Ok(rocket.manage(tenants))
+        }))
+        .attach(AdHoc::on_request("Tenancy", tenancy::route))
+        .attach(AdHoc::on_attach("Usage", |rocket| {
+            let usage = Usage::from_config(rocket.config());
+
+            if let Some(store) = rocket.state::<Store>() {
+                usage.apply("", store);
+            }
+
+            if let Some(tenants) = rocket.state::<Tenants>() {
+                let usage = usage.clone();
+
+                tenants.on_create(move |tenant, store| usage.apply(tenant, store));
+            }
+
+            Ok(rocket.manage(usage))
+        }))
+        .attach(AdHoc::on_request("Usage", usage::meter))
+        .attach(AdHoc::on_request("Aliases", aliases::resolve))
+        .attach(AdHoc::on_request("Public reads", features::guard_reads))
+        .attach(AdHoc::on_request("Audit", audit::note_value))
+        .attach(AdHoc::on_attach("Notifications", |rocket| {
+            let notifiers = Notifiers::default();
+
+            if let Some(store) = rocket.state::<Store>() {
+                notifiers.watch(store);
+            }
+
+            if let Some(tenants) = rocket.state::<Tenants>() {
+                let notifiers = notifiers.clone();
+
+                tenants.on_create(move |_, store| notifiers.watch(store));
+            }
+
+            Ok(rocket.manage(notifiers))
+        }))
+        .attach(AdHoc::on_attach("Webhooks", |rocket| {
+            let webhooks = Webhooks::from_config(rocket.config());
+            let watching = rocket
+                .state::<Features>()
+                .map_or(true, |features| features.enabled(Feature::Webhooks));
+
+            if let (true, Some(store)) = (watching, rocket.state::<Store>()) {
+                webhooks.watch(store);
+            }
+
+            if let (true, Some(tenants)) = (watching, rocket.state::<Tenants>()) {
+                let webhooks = webhooks.clone();
+
+                tenants.on_create(move |_, store| webhooks.watch(store));
+            }
+
+            if let (true, Some(notifiers)) = (watching, rocket.state::<Notifiers>()) {
+                webhooks.register_notifiers(notifiers);
+            }
+
+            Ok(rocket.manage(webhooks))
+        }))
+        .attach(AdHoc::on_attach("Alerts", |rocket| {
+            let alerts = Alerts::from_config(rocket.config());
+
+            if alerts.enabled() {
+                if let Some(store) = rocket.state::<Store>() {
+                    alerts.watch(store);
+                }
+
+                if let Some(tenants) = rocket.state::<Tenants>() {
+                    let alerts = alerts.clone();
+
+                    tenants.on_create(move |_, store| alerts.watch(store));
+                }
+
+                if let Some(jobs) = rocket.state::<Jobs>() {
+                    alerts.schedule_idle_checks(jobs);
+                }
+
+                if let Some(notifiers) = rocket.state::<Notifiers>() {
+                    alerts.register_notifier(notifiers);
+                }
+            }
+
+            Ok(rocket.manage(alerts))
+        }))
+        .attach(AdHoc::on_attach("Schedules", |rocket| {
+            let schedules = Schedules::default();
+
+            schedules.spawn_runner();
+            Ok(rocket.manage(schedules))
+        }))
+        .attach(AdHoc::on_attach("MQTT", |rocket| {
+            if let (Some(mqtt), Some(store)) =
+                (Mqtt::from_config(rocket.config()), rocket.state::<Store>())
+            {
+                match (mqtt.publish(store), rocket.state::<Notifiers>()) {
+                    (Ok(notifier), Some(notifiers)) => notifiers.register(notifier),
+                    (Ok(_), None) => (),
+                    (Err(error), _) => eprintln!("Not publishing to MQTT: {}", error),
+                }
+            }
+
+            Ok(rocket)
+        }))
+        .attach(AdHoc::on_attach("Event bus", |rocket| {
+            if let (Some(bus), Some(store)) =
+                (Bus::from_config(rocket.config()), rocket.state::<Store>())
+            {
+                if let Err(error) = bus.publish(store) {
+                    eprintln!("Not publishing events: {}", error);
+                }
+            }
+
+            Ok(rocket)
+        }))
+        .attach(AdHoc::on_attach("Latency budgets", |rocket| {
+            let deferred = Deferred::from_config(rocket.config());
+
+            Ok(rocket.manage(deferred))
+        }))
+        .manage(Bundles::default())
+        .manage(Buckets::default())
+        .manage(OneTimeLinks::default())
+        .manage(Templates::default())
+        .manage(Listings::default())
+        .manage(Launched::default())
+        .attach(AdHoc::on_attach("Snapshots", |rocket| {
+            let snapshots = Snapshots::from_config(rocket.config());
+
+            Ok(rocket.manage(snapshots))
+        }))
+        .attach(AdHoc::on_attach("Public URL", |rocket| {
+            let public_url = PublicUrl::from_config(rocket.config());
+
+            Ok(rocket.manage(public_url))
+        }))
+        .attach(AdHoc::on_launch("WebSocket", |rocket| {
+            let config = rocket.config();
+
+            if let (Ok(port), Some(store)) =
+                (config.get_int("websocket_port"), rocket.state::<Store>())
+            {
+                websocket::serve(rocket, port as u16, store.clone());
+            }
+        }))
+        .attach(AdHoc::on_attach("HTTPS", |rocket| {
+            match Tls::from_config(rocket.config()) {
+                Ok(Some(tls)) => Ok(rocket.manage(tls)),
+                Ok(None) => Ok(rocket),
+                Err(reason) => {
+                    eprintln!("{}", reason);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_launch("HTTPS", |rocket| {
+            let config = rocket.config();
+
+            if let Some(tls) = rocket.state::<Tls>() {
+                if let Err(error) = tls.serve(&config.address, config.port) {
+                    eprintln!("Not serving HTTPS: {}", error);
+                }
+            }
+        }))
+        .attach(AdHoc::on_launch("HTTPS redirect", |rocket| {
+            let config = rocket.config();
+
+            if let Ok(port) = config.get_int("http_redirect_port") {
+                let https_port = config.get_int("https_port").unwrap_or(443);
+
+                if let Err(error) = tls::redirect(&config.address, port as u16, https_port as u16) {
+                    eprintln!("Not redirecting HTTP to HTTPS: {}", error);
+                }
+            }
+        }))
+        .attach(AdHoc::on_launch("statsd", |rocket| {
+            let config = rocket.config();
+
+            if let (Ok(port), Some(ingest)) = (
+                config.get_int("statsd_port"),
+                statsd::Ingest::from_rocket(rocket),
+            ) {
+                statsd::serve(config.address.clone(), port as u16, ingest);
+            }
+        }))
+        .attach(AdHoc::on_attach("Privacy", |rocket| {
+            let privacy = Privacy::from_config(rocket.config());
+
+            Ok(rocket.manage(privacy))
+        }))
+        .attach(AdHoc::on_attach("Authentication", |rocket| {
+            let api_keys = ApiKeys::from_config(rocket.config());
+            let jwt = Jwt::from_config(rocket.config());
+            let write_tokens = WriteTokens::from_config(rocket.config());
+            let signing = Signing::from_config(rocket.config());
+            let slack = SlackSecret::from_config(rocket.config());
+            let github = GitHubSecret::from_config(rocket.config());
+
+            Ok(rocket
+                .manage(api_keys)
+                .manage(jwt)
+                .manage(write_tokens)
+                .manage(signing)
+                .manage(slack)
+                .manage(github))
+        }))
+        .attach(AdHoc::on_attach("Rate limits", |rocket| {
+            let rate_limits = RateLimits::from_config(rocket.config());
+
+            Ok(rocket.manage(rate_limits))
+        }))
+}
+
+// Tests
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use super::{rocket, Counter};
+
+    #[test]
+    fn versioned_routes() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/v1/counter")
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+        assert!(create_response.headers().get_one("Deprecation").is_none());
+
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let response = client
+            .get(format!("/counter/{}", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Deprecation"), Some("true"));
+        assert_eq!(
+            response.headers().get_one("Link"),
+            Some(format!("</v1/counter/{}>; rel=\"successor-version\"", counter.id).as_str())
+        );
+    }
+
+    #[test]
+    fn reject_malformed_requests() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut get_response = client.get("/counter/xyz123").dispatch();
+
+        assert_eq!(get_response.status(), Status::BadRequest);
+        assert!(get_response
+            .body_string()
+            .unwrap()
+            .contains(r#""reason":"Invalid id.""#));
+
+        let increment_response = client
+            .put("/counter/xyz123/increment")
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::BadRequest);
+
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": 1 }"#)
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::UnprocessableEntity);
+        assert!(create_response
+            .body_string()
+            .unwrap()
+            .contains(r#""reason":"Request body is invalid.""#));
+
+        let unsupported_response = client
+            .post("/counter")
+            .header(ContentType::Plain)
+            .body("name=test")
+            .dispatch();
+
+        assert_eq!(unsupported_response.status(), Status::UnsupportedMediaType);
+    }
+
+    #[test]
+    fn get_nonexistign_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let response = client.get("/counters/xyz123").dispatch();
+
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,196 @@
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::history;
+use crate::lock;
+use crate::negotiation::OptionalBody;
+use crate::ratelimit::Throttle;
+use crate::routes::{increment, Annotation};
+use crate::tenancy::TenantStore;
+use crate::validation::{Validate, Violations};
+use crate::{error, not_found_error, parse_id, ApiError, Counter, V1};
+
+const MAX_LINKS: u32 = 1000;
+/// A week, in seconds.
+const DEFAULT_EXPIRES_IN: u64 = 604_800;
+/// A year, in seconds.
+const MAX_EXPIRES_IN: u64 = 31_536_000;
+
+/// Unguessable single-use increment links, e.g. for email confirmations or QR codes at events.
+/// A token is forgotten as soon as it is redeemed or found expired.
+#[derive(Default)]
+pub struct OneTimeLinks(Mutex<HashMap<Uuid, Link>>);
+
+struct Link {
+    counter: Uuid,
+    /// Milliseconds since the Unix epoch.
+    expires_at: u64,
+}
+
+#[derive(Deserialize, Default)]
+pub struct NewLinks {
+    count: Option<u32>,
+    /// Seconds until the links expire.
+    expires_in: Option<u64>,
+}
+
+impl Validate for NewLinks {
+    fn validate(&self, violations: &mut Violations) {
+        violations.positive("count", self.count);
+    }
+}
+
+#[derive(Serialize)]
+pub struct Links {
+    /// Milliseconds since the Unix epoch.
+    expires_at: u64,
+    urls: Vec<String>,
+}
+
+/// Path of the link that redeems `token` for `counter`.
+pub fn redeem_path(counter: Uuid, token: Uuid) -> String {
+    format!("{}/counter/{}/redeem/{}", V1, counter, token)
+}
+
+impl OneTimeLinks {
+    pub fn mint(&self, counter: Uuid, count: u32, expires_at: u64) -> Vec<Uuid> {
+        let mut links = lock(&self.0);
+        let now = history::timestamp();
+
+        links.retain(|_, link| link.expires_at > now);
+
+        (0..count)
+            .map(|_| {
+                let token = Uuid::new_v4();
+
+                links.insert(
+                    token,
+                    Link {
+                        counter,
+                        expires_at,
+                    },
+                );
+                token
+            })
+            .collect()
+    }
+}
+
+#[post("/<id>/links", data = "<new_links>")]
+pub fn create_links(
+    id: String,
+    new_links: OptionalBody<NewLinks>,
+    links: State<OneTimeLinks>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Links>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let new_links = new_links.into_inner().unwrap_or_default();
+    let count = new_links.count.unwrap_or(1);
+    let expires_in = new_links.expires_in.unwrap_or(DEFAULT_EXPIRES_IN);
+
+    if count == 0 || count > MAX_LINKS {
+        return Err(error(
+            Status::BadRequest,
+            "Between 1 and 1000 links can be created at once.",
+        ));
+    }
+
+    if expires_in == 0 || expires_in > MAX_EXPIRES_IN {
+        return Err(error(
+            Status::BadRequest,
+            "Links must expire within a year.",
+        ));
+    }
+
+    store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    let expires_at = history::timestamp() + expires_in * 1000;
+    let urls = links
+        .mint(parsed_uuid, count, expires_at)
+        .into_iter()
+        .map(|token| redeem_path(parsed_uuid, token))
+        .collect();
+
+    Ok(Json(Links { expires_at, urls }))
+}
+
+/// Increments the counter exactly once per token.
+#[get("/<id>/redeem/<token>")]
+pub fn redeem(
+    id: String,
+    token: String,
+    links: State<OneTimeLinks>,
+    store: TenantStore,
+    dry_run: DryRun,
+    _throttle: Throttle,
+) -> Result<Json<Counter>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let gone = || error(Status::Gone, "Link has expired or was already used.");
+    let token = Uuid::parse_str(&token).map_err(|_| gone())?;
+    let expires_at = {
+        let mut links = lock(&links.0);
+        let valid = links
+            .get(&token)
+            .map_or(false, |link| link.counter == parsed_uuid);
+
+        if !valid {
+            return Err(gone());
+        }
+
+        links.remove(&token).map(|link| link.expires_at)
+    };
+
+    if expires_at.map_or(true, |expires_at| expires_at <= history::timestamp()) {
+        return Err(gone());
+    }
+
+    increment(parsed_uuid, &[], Annotation::default(), &store).map(Json)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn redeem_one_time_link() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut links_response = client
+            .post(format!("/counter/{}/links", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "count": 2 }"#)
+            .dispatch();
+
+        assert_eq!(links_response.status(), Status::Ok);
+
+        let links: serde_json::Value =
+            serde_json::from_str(&links_response.body_string().unwrap()).unwrap();
+        let url = links["urls"][0].as_str().unwrap();
+        let mut response = client.get(url).dispatch();
+        let redeemed: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(redeemed.value, 1);
+        assert_eq!(client.get(url).dispatch().status(), Status::Gone);
+        assert_eq!(links["urls"].as_array().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,105 @@
+//! `GET /counter` without parameters, the listing most clients poll, serialized once per change
+//! to the counters instead of on every request. A cached listing is served as long as the
+//! store's version is the one it was built from and no billing cycle has ended since, which
+//! would roll a value over. Listings with decaying scores are never reused, as the scores
+//! change by the millisecond.
+
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use crate::conditional;
+use crate::history;
+use crate::lock;
+use crate::tenancy::TenantStore;
+use crate::visibility::Visibility;
+use crate::Counter;
+
+#[derive(Clone)]
+pub struct Listing {
+    json: Arc<String>,
+    etag: String,
+    total: usize,
+    last_modified: Option<u64>,
+    version: u64,
+    /// When the earliest billing cycle among the counters ends, or when the listing was built
+    /// if a score decays, in milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+}
+
+impl Listing {
+    fn build(store: &TenantStore) -> Listing {
+        let (counters, version) = store.snapshot();
+        let mut listed: Vec<&Counter> = counters
+            .values()
+            .filter(|counter| !counter.archived && counter.visibility != Visibility::Private)
+            .collect();
+
+        listed.sort_by_key(|counter| counter.id);
+
+        let json = serde_json::to_string(&listed).expect("counters serialize to JSON");
+
+        Listing {
+            etag: conditional::etag(&json),
+            json: Arc::new(json),
+            total: listed.len(),
+            last_modified: listed.iter().map(|counter| counter.updated_at).max(),
+            version,
+            expires_at: counters
+                .values()
+                .filter_map(|counter| match (&counter.cycle, &counter.decay) {
+                    (_, Some(decay)) => Some(decay.scored_at),
+                    (Some(cycle), None) => Some(cycle.resets_at),
+                    (None, None) => None,
+                })
+                .min(),
+        }
+    }
+
+    fn is_current(&self, store: &TenantStore, now: u64) -> bool {
+        self.version == store.version() && self.expires_at.map_or(true, |expires| now < expires)
+    }
+}
+
+/// The most recent listing of every tenant.
+#[derive(Default)]
+pub struct Listings(Mutex<HashMap<String, Listing>>);
+
+impl Listings {
+    pub fn get(&self, store: &TenantStore) -> Listing {
+        let now = history::timestamp();
+
+        if let Some(listing) = lock(&self.0).get(store.tenant()) {
+            if listing.is_current(store, now) {
+                return listing.clone();
+            }
+        }
+
+        let listing = Listing::build(store);
+
+        lock(&self.0).insert(store.tenant().to_string(), listing.clone());
+        listing
+    }
+}
+
+/// The listing's body, shared with the cache rather than copied.
+struct Shared(Arc<String>);
+
+impl AsRef<[u8]> for Shared {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl<'r> Responder<'r> for Listing {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = Cursor::new(Shared(self.json));
+        let response = conditional::respond(request, body, self.etag, self.last_modified)?;
+
+        Response::build_from(response)
+            .raw_header("X-Total-Count", self.total.to_string())
+            .ok()
+    }
+}
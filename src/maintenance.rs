@@ -0,0 +1,276 @@
+//! Read-only maintenance mode, for storage migrations and the like. While it is on, every route
+//! that changes counters answers 503 with `Retry-After`, and reads go on as usual. Switched with
+//! `POST /admin/maintenance`, which stays reachable throughout. With
+//! `maintenance_file = "/var/lib/caas/maintenance.json"` the mode is kept in that file while on,
+//! so that an instance restarted during the window comes back read-only.
+
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::Admin;
+use crate::dry_run::DryRun;
+use crate::history;
+use crate::lock;
+use crate::negotiation::Body;
+use crate::validation::{Validate, Violations};
+use crate::{error, ApiError};
+
+/// Seconds clients are told to wait unless the window says otherwise.
+const DEFAULT_RETRY_AFTER: u32 = 300;
+/// Seconds clients are told to wait while the instance shuts down, for its replacement.
+const DRAIN_RETRY_AFTER: u32 = 5;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Window {
+    /// Milliseconds since the Unix epoch.
+    since: u64,
+    retry_after: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Mode {
+    enabled: bool,
+    #[serde(flatten)]
+    window: Option<Window>,
+}
+
+#[derive(Deserialize)]
+pub struct Toggle {
+    enabled: bool,
+    /// Seconds to send in `Retry-After`.
+    retry_after: Option<u32>,
+    reason: Option<String>,
+}
+
+impl Validate for Toggle {
+    fn validate(&self, violations: &mut Violations) {
+        violations.positive("retry_after", self.retry_after);
+        violations.reason(&self.reason);
+    }
+}
+
+/// Clones share the mode, for writes that do not go through Rocket, such as statsd packets.
+#[derive(Clone, Default)]
+pub struct Maintenance {
+    window: Arc<Mutex<Option<Window>>>,
+    file: Option<PathBuf>,
+}
+
+impl Maintenance {
+    pub fn from_config(config: &Config) -> Maintenance {
+        let file = config.get_str("maintenance_file").ok().map(PathBuf::from);
+        let window = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|saved| match serde_json::from_str(&saved) {
+                Ok(window) => Some(window),
+                Err(reason) => {
+                    eprintln!("Ignoring the saved maintenance window: {}", reason);
+                    None
+                }
+            });
+
+        if window.is_some() {
+            println!("Starting in maintenance mode");
+        }
+
+        Maintenance {
+            window: Arc::new(Mutex::new(window)),
+            file,
+        }
+    }
+
+    /// The current window, if the mode is on.
+    pub fn window(&self) -> Option<Window> {
+        lock(&self.window).clone()
+    }
+
+    /// Refuses writes from now on, without saving the mode, for shutting down.
+    pub fn drain(&self) {
+        *lock(&self.window) = Some(Window {
+            since: history::timestamp(),
+            retry_after: DRAIN_RETRY_AFTER,
+            reason: Some("Shutting down".to_string()),
+        });
+    }
+
+    fn set(&self, window: Option<Window>) -> Result<(), String> {
+        let mut current = lock(&self.window);
+
+        if let Some(file) = &self.file {
+            match &window {
+                Some(window) => fs::write(file, serde_json::to_vec(window).unwrap_or_default()),
+                None if file.exists() => fs::remove_file(file),
+                None => Ok(()),
+            }
+            .map_err(|reason| format!("Could not save the maintenance mode: {}", reason))?;
+        }
+
+        *current = window;
+        Ok(())
+    }
+}
+
+fn mode(window: Option<Window>) -> Mode {
+    Mode {
+        enabled: window.is_some(),
+        window,
+    }
+}
+
+/// The window a write was refused for, for the 503 catcher.
+#[derive(Default)]
+struct Refused(Option<Window>);
+
+/// Whether writes are refused for maintenance, noting the window for the 503 catcher if so.
+/// Checked by the guard every route that changes counters takes.
+pub fn refuses_writes(request: &Request) -> bool {
+    let window = request
+        .guard::<State<Maintenance>>()
+        .succeeded()
+        .and_then(|maintenance| maintenance.window());
+
+    match window {
+        Some(window) => {
+            request.local_cache(|| Refused(Some(window)));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Body of the 503 catcher. Writes refused for maintenance get `Retry-After`; anything else
+/// unavailable is a write to a read replica.
+pub struct Unavailable;
+
+impl<'r> Responder<'r> for Unavailable {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let window = match &request.local_cache(Refused::default).0 {
+            Some(window) => window.clone(),
+            None => {
+                return json!({
+                    "status": "error",
+                    "reason": "This instance is a read-only replica."
+                })
+                .respond_to(request)
+            }
+        };
+        let reason = match &window.reason {
+            Some(reason) => format!("Down for maintenance: {}", reason),
+            None => "Down for maintenance, only reads are served.".to_string(),
+        };
+
+        Response::build_from(json!({ "status": "error", "reason": reason }).respond_to(request)?)
+            .raw_header("Retry-After", window.retry_after.to_string())
+            .ok()
+    }
+}
+
+#[get("/maintenance")]
+pub fn get_maintenance(maintenance: State<Maintenance>, _admin: Admin) -> Json<Mode> {
+    Json(mode(maintenance.window()))
+}
+
+/// `{ "enabled": true, "retry_after": 600, "reason": "Moving to new disks" }` to stop writes,
+/// and `{ "enabled": false }` to let them through again. Switching it on again keeps the
+/// original start time.
+#[post("/maintenance", data = "<toggle>")]
+pub fn set_maintenance(
+    toggle: Body<Toggle>,
+    maintenance: State<Maintenance>,
+    dry_run: DryRun,
+    _admin: Admin,
+) -> Result<Json<Mode>, ApiError> {
+    dry_run.refuse()?;
+
+    let Toggle {
+        enabled,
+        retry_after,
+        reason,
+    } = toggle.into_inner();
+    let window = if enabled {
+        Some(Window {
+            since: maintenance
+                .window()
+                .map_or_else(history::timestamp, |window| window.since),
+            retry_after: retry_after.unwrap_or(DEFAULT_RETRY_AFTER),
+            reason,
+        })
+    } else {
+        None
+    };
+
+    maintenance
+        .set(window.clone())
+        .map_err(|reason| error(Status::InternalServerError, &reason))?;
+    println!("Maintenance mode is {}", if enabled { "on" } else { "off" });
+    Ok(Json(mode(window)))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn refuse_writes_during_maintenance() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let toggle_response = client
+            .post("/admin/maintenance")
+            .header(ContentType::JSON)
+            .body(r#"{ "enabled": true, "retry_after": 60, "reason": "Moving disks" }"#)
+            .dispatch();
+
+        assert_eq!(toggle_response.status(), Status::Ok);
+
+        let mut write_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(write_response.status(), Status::ServiceUnavailable);
+        assert_eq!(write_response.headers().get_one("Retry-After"), Some("60"));
+        assert!(write_response
+            .body_string()
+            .unwrap()
+            .contains("Down for maintenance: Moving disks"));
+
+        let read_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+
+        assert_eq!(read_response.status(), Status::Ok);
+
+        let mut mode_response = client.get("/admin/maintenance").dispatch();
+        let mode: serde_json::Value =
+            serde_json::from_str(&mode_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(mode["enabled"], true);
+        assert_eq!(mode["retry_after"], 60);
+
+        client
+            .post("/admin/maintenance")
+            .header(ContentType::JSON)
+            .body(r#"{ "enabled": false }"#)
+            .dispatch();
+
+        let write_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(write_response.status(), Status::Ok);
+    }
+}
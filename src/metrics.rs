@@ -0,0 +1,518 @@
+use rocket::data::Data;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::auth::{Admin, Reader};
+use crate::jobs::Jobs;
+use crate::lock;
+use crate::signing::{Signing, REJECTIONS, SKEW_BUCKETS};
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::Counter;
+
+/// Upper bounds of the request duration histogram buckets, in seconds.
+const BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+const QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+/// A page in the Prometheus text exposition format.
+pub struct Exposition(String);
+
+impl<'r> Responder<'r> for Exposition {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::with_params(
+                "text",
+                "plain",
+                ("version", "0.0.4"),
+            ))
+            .sized_body(Cursor::new(self.0))
+            .ok()
+    }
+}
+
+/// Escapes a label value as the exposition format requires.
+fn label_value(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn sample(counter: &Counter) -> String {
+    format!(
+        "counter_value{{id=\"{}\",name=\"{}\"}} {}\n",
+        counter.id,
+        label_value(counter.name.as_ref().map_or("", String::as_str)),
+        counter.value
+    )
+}
+
+#[derive(Default)]
+struct Handler {
+    /// Responses by method and status.
+    responses: BTreeMap<(String, u16), u64>,
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Handler {
+    /// Estimate of the `q` quantile of request durations, in seconds, interpolated within
+    /// the bucket it falls in as Prometheus' `histogram_quantile` does. Durations beyond the
+    /// last bucket are reported as its bound.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = q * self.count as f64;
+        let mut lower = (0.0, 0);
+
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS.iter()) {
+            if *bucket as f64 >= rank {
+                let (lower_bound, lower_count) = lower;
+                let in_bucket = (bucket - lower_count) as f64;
+
+                return Some(
+                    lower_bound + (bound - lower_bound) * (rank - lower_count as f64) / in_bucket,
+                );
+            }
+
+            lower = (*bound, *bucket);
+        }
+
+        Some(BUCKETS[BUCKETS.len() - 1])
+    }
+
+    /// Responses by status class, such as `5xx`.
+    fn classes(&self) -> BTreeMap<String, u64> {
+        let mut classes = BTreeMap::new();
+
+        for ((_, status), count) in &self.responses {
+            *classes.entry(format!("{}xx", status / 100)).or_insert(0) += count;
+        }
+
+        classes
+    }
+}
+
+/// Requests seen by every route, recorded by the request fairings below. Routes are told
+/// apart by handler name, so that counter ids do not end up in labels.
+#[derive(Default)]
+pub struct RequestMetrics {
+    handlers: Mutex<BTreeMap<String, Handler>>,
+}
+
+/// When the request came in.
+struct Started(Option<Instant>);
+
+pub fn start(request: &mut Request, _: &Data) {
+    request.local_cache(|| Started(Some(Instant::now())));
+}
+
+/// How long the request has taken so far.
+pub fn elapsed(request: &Request) -> Option<Duration> {
+    request
+        .local_cache(|| Started(None))
+        .0
+        .map(|started| started.elapsed())
+}
+
+pub fn observe(request: &Request, response: &mut Response) {
+    let metrics = match request.guard::<State<RequestMetrics>>().succeeded() {
+        Some(metrics) => metrics,
+        None => return,
+    };
+    let seconds = match elapsed(request) {
+        Some(elapsed) => elapsed.as_secs_f64(),
+        None => return,
+    };
+    let name = request
+        .route()
+        .and_then(|route| route.name)
+        .unwrap_or("unmatched");
+    let mut handlers = lock(&metrics.handlers);
+    let handler = handlers.entry(name.to_string()).or_default();
+
+    *handler
+        .responses
+        .entry((request.method().to_string(), response.status().code))
+        .or_insert(0) += 1;
+
+    for (bucket, bound) in handler.buckets.iter_mut().zip(BUCKETS.iter()) {
+        if seconds <= *bound {
+            *bucket += 1;
+        }
+    }
+
+    handler.count += 1;
+    handler.sum += seconds;
+}
+
+/// Request counts by handler, method and status, from which error rates follow, request
+/// durations and their estimated quantiles, the number of counters, how long writers have
+/// waited for the store lock, how background jobs have run and how far off the clocks of
+/// clients signing requests are.
+#[get("/")]
+pub fn service(
+    metrics: State<RequestMetrics>,
+    store: State<Store>,
+    jobs: State<Jobs>,
+    signing: State<Signing>,
+) -> Exposition {
+    let mut exposition = String::new();
+    let handlers = lock(&metrics.handlers);
+
+    exposition.push_str(
+        "# HELP http_requests_total Requests handled, by handler, method and status.\n\
+         # TYPE http_requests_total counter\n",
+    );
+
+    for (name, handler) in handlers.iter() {
+        for ((method, status), count) in &handler.responses {
+            let _ = writeln!(
+                exposition,
+                "http_requests_total{{handler=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                name, method, status, count
+            );
+        }
+    }
+
+    exposition.push_str(
+        "# HELP http_request_duration_seconds Time taken to handle requests.\n\
+         # TYPE http_request_duration_seconds histogram\n",
+    );
+
+    for (name, handler) in handlers.iter() {
+        for (bucket, bound) in handler.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(
+                exposition,
+                "http_request_duration_seconds_bucket{{handler=\"{}\",le=\"{}\"}} {}",
+                name, bound, bucket
+            );
+        }
+
+        let _ = writeln!(
+            exposition,
+            "http_request_duration_seconds_bucket{{handler=\"{0}\",le=\"+Inf\"}} {1}\n\
+             http_request_duration_seconds_sum{{handler=\"{0}\"}} {2}\n\
+             http_request_duration_seconds_count{{handler=\"{0}\"}} {1}",
+            name, handler.count, handler.sum
+        );
+    }
+
+    exposition.push_str(
+        "# HELP http_request_duration_quantile_seconds Estimated quantiles of the time taken \
+         to handle requests, from the histogram.\n\
+         # TYPE http_request_duration_quantile_seconds gauge\n",
+    );
+
+    for (name, handler) in handlers.iter() {
+        for q in &QUANTILES {
+            if let Some(seconds) = handler.quantile(*q) {
+                let _ = writeln!(
+                    exposition,
+                    "http_request_duration_quantile_seconds{{\
+                     handler=\"{}\",quantile=\"{}\"}} {}",
+                    name, q, seconds
+                );
+            }
+        }
+    }
+
+    exposition.push_str(
+        "# HELP http_responses_total Responses sent, by handler and status class.\n\
+         # TYPE http_responses_total counter\n",
+    );
+
+    for (name, handler) in handlers.iter() {
+        for (class, count) in handler.classes() {
+            let _ = writeln!(
+                exposition,
+                "http_responses_total{{handler=\"{}\",class=\"{}\"}} {}",
+                name, class, count
+            );
+        }
+    }
+
+    let (archived, active) = store
+        .read()
+        .values()
+        .fold((0, 0), |(archived, active), counter| {
+            if counter.archived {
+                (archived + 1, active)
+            } else {
+                (archived, active + 1)
+            }
+        });
+    let contention = store.contention(usize::max_value());
+    let acquisitions: u64 = contention.iter().map(|report| report.acquisitions).sum();
+    let wait_us: u64 = contention.iter().map(|report| report.total_wait_us).sum();
+    let writers: usize = contention.iter().map(|report| report.current_writers).sum();
+
+    let _ = write!(
+        exposition,
+        "# HELP counters Counters in the store.\n\
+         # TYPE counters gauge\n\
+         counters{{archived=\"false\"}} {}\n\
+         counters{{archived=\"true\"}} {}\n\
+         # HELP counter_lock_acquisitions_total Times a writer took the store lock.\n\
+         # TYPE counter_lock_acquisitions_total counter\n\
+         counter_lock_acquisitions_total {}\n\
+         # HELP counter_lock_wait_seconds_total Time writers spent waiting for the store lock.\n\
+         # TYPE counter_lock_wait_seconds_total counter\n\
+         counter_lock_wait_seconds_total {}\n\
+         # HELP counter_lock_writers Writers holding or waiting for the store lock.\n\
+         # TYPE counter_lock_writers gauge\n\
+         counter_lock_writers {}\n",
+        active,
+        archived,
+        acquisitions,
+        wait_us as f64 / 1_000_000.0,
+        writers
+    );
+
+    let totals = jobs.totals();
+
+    exposition.push_str(
+        "# HELP job_runs_total Runs of background jobs, by job.\n\
+         # TYPE job_runs_total counter\n",
+    );
+
+    for (name, runs, _, _) in &totals {
+        let _ = writeln!(exposition, "job_runs_total{{job=\"{}\"}} {}", name, runs);
+    }
+
+    exposition.push_str(
+        "# HELP job_failures_total Runs of background jobs that failed, by job.\n\
+         # TYPE job_failures_total counter\n",
+    );
+
+    for (name, _, failures, _) in &totals {
+        let _ = writeln!(
+            exposition,
+            "job_failures_total{{job=\"{}\"}} {}",
+            name, failures
+        );
+    }
+
+    exposition.push_str(
+        "# HELP job_duration_seconds_total Time background jobs spent running, by job.\n\
+         # TYPE job_duration_seconds_total counter\n",
+    );
+
+    for (name, _, _, seconds) in &totals {
+        let _ = writeln!(
+            exposition,
+            "job_duration_seconds_total{{job=\"{}\"}} {}",
+            name, seconds
+        );
+    }
+
+    let observed = signing.observed();
+
+    exposition.push_str(
+        "# HELP request_signature_rejections_total Signed requests rejected, by reason: \
+         missing, stale or invalid.\n\
+         # TYPE request_signature_rejections_total counter\n",
+    );
+
+    for reason in &REJECTIONS {
+        let _ = writeln!(
+            exposition,
+            "request_signature_rejections_total{{reason=\"{}\"}} {}",
+            reason,
+            observed.rejections.get(reason).unwrap_or(&0)
+        );
+    }
+
+    exposition.push_str(
+        "# HELP request_signature_skew_seconds How far signature timestamps were from the \
+         server's clock, either way.\n\
+         # TYPE request_signature_skew_seconds histogram\n",
+    );
+
+    for (bucket, bound) in observed.skew_buckets.iter().zip(SKEW_BUCKETS.iter()) {
+        let _ = writeln!(
+            exposition,
+            "request_signature_skew_seconds_bucket{{le=\"{}\"}} {}",
+            bound, bucket
+        );
+    }
+
+    let _ = writeln!(
+        exposition,
+        "request_signature_skew_seconds_bucket{{le=\"+Inf\"}} {0}\n\
+         request_signature_skew_seconds_sum {1}\n\
+         request_signature_skew_seconds_count {0}",
+        observed.skew_count, observed.skew_sum
+    );
+
+    Exposition(exposition)
+}
+
+/// Every counter's current value, for Prometheus to scrape. Values can go down, so they are
+/// exposed as a gauge. Private counters are left out unless the scraper identifies itself.
+#[get("/counters")]
+pub fn counters(reader: Option<Reader>, store: TenantStore) -> Exposition {
+    let mut counters: Vec<Counter> = store
+        .read()
+        .values()
+        .filter(|counter| counter.visibility.is_listed_for(&reader))
+        .cloned()
+        .collect();
+    let mut exposition = String::from(
+        "# HELP counter_value Current value of the counter.\n# TYPE counter_value gauge\n",
+    );
+
+    counters.sort_by_key(|counter| (counter.created_at, counter.id));
+
+    for counter in &counters {
+        exposition.push_str(&sample(counter));
+    }
+
+    Exposition(exposition)
+}
+
+#[derive(Serialize)]
+pub struct HandlerStats {
+    handler: String,
+    requests: u64,
+    mean_ms: f64,
+    p50_ms: Option<f64>,
+    p95_ms: Option<f64>,
+    p99_ms: Option<f64>,
+    /// Responses by status class, such as `5xx`.
+    statuses: BTreeMap<String, u64>,
+}
+
+/// Latency and responses by handler, slowest first by p95. Quantiles are estimated from the
+/// same histogram as `/metrics`, so they are only as precise as its buckets.
+#[get("/stats/http")]
+pub fn http_stats(metrics: State<RequestMetrics>, _admin: Admin) -> Json<Vec<HandlerStats>> {
+    let handlers = lock(&metrics.handlers);
+    let milliseconds = |seconds: f64| seconds * 1000.0;
+    let mut stats: Vec<HandlerStats> = handlers
+        .iter()
+        .map(|(name, handler)| HandlerStats {
+            handler: name.clone(),
+            requests: handler.count,
+            mean_ms: milliseconds(handler.sum / handler.count.max(1) as f64),
+            p50_ms: handler.quantile(0.5).map(milliseconds),
+            p95_ms: handler.quantile(0.95).map(milliseconds),
+            p99_ms: handler.quantile(0.99).map(milliseconds),
+            statuses: handler.classes(),
+        })
+        .collect();
+
+    stats.sort_by(|a, b| b.p95_ms.partial_cmp(&a.p95_ms).unwrap_or(Ordering::Equal));
+    Json(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn expose_service_metrics() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        client.post("/counter").header(ContentType::JSON).dispatch();
+        client.get("/counter/not-an-id").dispatch();
+
+        let mut response = client.get("/metrics").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let exposition = response.body_string().unwrap();
+
+        assert!(exposition.contains(
+            "http_requests_total{handler=\"create_counter\",method=\"POST\",status=\"200\"} 1\n"
+        ));
+        assert!(exposition.contains(
+            "http_requests_total{handler=\"get_counter\",method=\"GET\",status=\"400\"} 1\n"
+        ));
+        assert!(exposition
+            .contains("http_request_duration_seconds_count{handler=\"create_counter\"} 1\n"));
+        assert!(exposition.contains("counters{archived=\"false\"} 1\n"));
+        assert!(exposition.contains("counter_lock_acquisitions_total "));
+    }
+
+    #[test]
+    fn break_down_request_metrics() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        client.post("/counter").header(ContentType::JSON).dispatch();
+        client.get("/counter/not-an-id").dispatch();
+
+        let exposition = client.get("/metrics").dispatch().body_string().unwrap();
+
+        assert!(
+            exposition.contains("http_responses_total{handler=\"get_counter\",class=\"4xx\"} 1\n")
+        );
+        assert!(exposition.contains(
+            "http_request_duration_quantile_seconds{handler=\"create_counter\",quantile=\"0.95\"}"
+        ));
+
+        let mut response = client.get("/admin/stats/http").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let stats: Vec<serde_json::Value> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let create = stats
+            .iter()
+            .find(|stats| stats["handler"] == "create_counter")
+            .unwrap();
+
+        assert_eq!(create["requests"], 1);
+        assert_eq!(create["statuses"]["2xx"], 1);
+        assert!(create["p99_ms"].as_f64().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn expose_prometheus_metrics() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Say \"hi\"" }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let mut response = client.get("/v1/metrics/counters").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Content-Type"),
+            Some("text/plain; version=0.0.4")
+        );
+
+        let exposition = response.body_string().unwrap();
+
+        assert!(exposition.contains("# TYPE counter_value gauge\n"));
+        assert!(exposition.contains(&format!(
+            "counter_value{{id=\"{}\",name=\"Say \\\"hi\\\"\"}} 1\n",
+            counter.id
+        )));
+    }
+}
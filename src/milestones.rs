@@ -0,0 +1,208 @@
+//! When each counter first reached its milestones, for `/counter/<id>/milestones`. Milestones
+//! are every power of ten from 10 on, or the values configured as
+//! `milestones = [1000, 10000, 100000]`. A counter reaches one when a change takes it from below
+//! the milestone to at or above it; dropping below and passing it again keeps the first time.
+
+use rocket::Config;
+use rocket_contrib::json::Json;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::history;
+use crate::lock;
+use crate::tenancy::TenantStore;
+use crate::{not_found_error, parse_id, ApiError, Counter};
+
+#[derive(Serialize, Clone)]
+pub struct Milestone {
+    value: u32,
+    /// Milliseconds since the Unix epoch.
+    reached_at: u64,
+}
+
+#[derive(Clone)]
+struct Tracked {
+    /// Value the counter was last seen at.
+    value: u32,
+    /// Lowest milestone first.
+    reached: Vec<Milestone>,
+}
+
+pub struct Milestones {
+    /// Ascending, without repeats.
+    values: Vec<u32>,
+    tracked: Mutex<HashMap<Uuid, Tracked>>,
+}
+
+impl Default for Milestones {
+    fn default() -> Milestones {
+        Milestones::new(powers_of_ten())
+    }
+}
+
+fn powers_of_ten() -> Vec<u32> {
+    let mut values = Vec::new();
+    let mut value: u32 = 10;
+
+    loop {
+        values.push(value);
+        value = match value.checked_mul(10) {
+            Some(value) => value,
+            None => return values,
+        };
+    }
+}
+
+impl Milestones {
+    fn new(mut values: Vec<u32>) -> Milestones {
+        values.sort();
+        values.dedup();
+
+        Milestones {
+            values,
+            tracked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Milestones {
+        let array = match config.get_slice("milestones") {
+            Ok(array) => array,
+            Err(_) => return Milestones::default(),
+        };
+        let values = array
+            .iter()
+            .filter_map(|value| match value.as_integer() {
+                Some(value) if value > 0 && value <= i64::from(u32::max_value()) => {
+                    Some(value as u32)
+                }
+                _ => {
+                    eprintln!("Ignoring milestone {}: not a positive number", value);
+                    None
+                }
+            })
+            .collect();
+
+        Milestones::new(values)
+    }
+
+    /// Separate milestones with the same ones reached so far.
+    pub fn copy(&self) -> Milestones {
+        Milestones {
+            values: self.values.clone(),
+            tracked: Mutex::new(lock(&self.tracked).clone()),
+        }
+    }
+
+    /// Records the milestones `counter` has just reached. Counters seen for the first time
+    /// only have their value noted, as it is not known when they got there.
+    pub fn update(&self, counter: &Counter) {
+        let mut all = lock(&self.tracked);
+        let tracked = match all.entry(counter.id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(Tracked {
+                    value: counter.value,
+                    reached: Vec::new(),
+                });
+                return;
+            }
+        };
+        let now = history::timestamp();
+
+        for value in &self.values {
+            let crossed = tracked.value < *value && *value <= counter.value;
+
+            if crossed
+                && tracked
+                    .reached
+                    .iter()
+                    .all(|reached| reached.value != *value)
+            {
+                tracked.reached.push(Milestone {
+                    value: *value,
+                    reached_at: now,
+                });
+            }
+        }
+
+        tracked.reached.sort_by_key(|reached| reached.value);
+        tracked.value = counter.value;
+    }
+
+    pub fn forget(&self, id: &Uuid) {
+        lock(&self.tracked).remove(id);
+    }
+
+    /// Milestones `id` has reached, lowest first.
+    pub fn reached(&self, id: &Uuid) -> Vec<Milestone> {
+        lock(&self.tracked)
+            .get(id)
+            .map_or_else(Vec::new, |tracked| tracked.reached.clone())
+    }
+}
+
+/// `GET /counter/<id>/milestones`, when the counter reached each of its milestones so far.
+#[get("/<id>/milestones")]
+pub fn get_milestones(id: String, store: TenantStore) -> Result<Json<Vec<Milestone>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    Ok(Json(store.milestones().reached(&parsed_uuid)))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn record_milestones() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for _ in 0..12 {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        // Going back under 10 and past it again keeps the first time.
+        for path in &["decrement", "decrement", "decrement", "increment"] {
+            client
+                .put(format!("/counter/{}/{}", counter.id, path))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let mut response = client
+            .get(format!("/counter/{}/milestones", counter.id))
+            .dispatch();
+        let milestones: Vec<serde_json::Value> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(milestones.len(), 1);
+        assert_eq!(milestones[0]["value"], 10);
+        assert!(milestones[0]["reached_at"].as_u64().unwrap() >= counter.created_at);
+
+        let hooks_response = client
+            .get(format!("/counter/{}/milestones/hooks", counter.id))
+            .dispatch();
+
+        assert_eq!(hooks_response.status(), Status::Ok);
+
+        let unknown_response = client
+            .get(format!("/counter/{}/milestones", uuid::Uuid::new_v4()))
+            .dispatch();
+
+        assert_eq!(unknown_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,94 @@
+use rocket::Config;
+use rumqtt::{MqttClient, MqttOptions, QoS};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use uuid::Uuid;
+
+use crate::lock;
+use crate::notify::{Event, Notifier};
+use crate::store::Store;
+
+const DEFAULT_PORT: u16 = 1883;
+
+/// Publishing of every counter change to an MQTT broker, as the counter's JSON on topic
+/// `counters/<id>`. Enabled by `mqtt_broker = "host:port"`, which like any setting can come
+/// from the environment as `ROCKET_MQTT_BROKER`.
+pub struct Mqtt {
+    host: String,
+    port: u16,
+}
+
+impl Mqtt {
+    pub fn from_config(config: &Config) -> Option<Mqtt> {
+        let broker = config.get_str("mqtt_broker").ok()?;
+        let mut parts = broker.rsplitn(2, ':');
+        let last = parts.next()?;
+
+        Some(match (parts.next(), last.parse()) {
+            (Some(host), Ok(port)) => Mqtt {
+                host: host.to_string(),
+                port,
+            },
+            _ => Mqtt {
+                host: broker.to_string(),
+                port: DEFAULT_PORT,
+            },
+        })
+    }
+
+    /// Connects to the broker and starts publishing changes to the counters in `store`.
+    /// Messages are queued while the connection is down and sent once it is back. Returns a
+    /// notifier for channels that publish to topics of their own over the same connection.
+    pub fn publish(self, store: &Store) -> Result<MqttNotifier, String> {
+        let options = MqttOptions::new(format!("caas-{}", Uuid::new_v4()), self.host, self.port);
+        let (client, notifications) =
+            MqttClient::start(options).map_err(|error| error.to_string())?;
+        let client = Arc::new(Mutex::new(client));
+        let publisher = Arc::clone(&client);
+        let changes = store.subscribe();
+
+        // Nothing is subscribed to, but the event loop stalls if its notifications pile up.
+        thread::spawn(move || for _ in notifications {});
+        thread::spawn(move || {
+            for counter in changes {
+                let topic = format!("counters/{}", counter.id);
+
+                if let Ok(payload) = serde_json::to_vec(&counter) {
+                    let published =
+                        lock(&publisher).publish(topic, QoS::AtLeastOnce, false, payload);
+
+                    if let Err(error) = published {
+                        eprintln!("MQTT publish failed: {}", error);
+                    }
+                }
+            }
+        });
+
+        Ok(MqttNotifier(client))
+    }
+}
+
+/// Channels that publish the event as JSON to a topic.
+pub struct MqttNotifier(Arc<Mutex<MqttClient>>);
+
+impl Notifier for MqttNotifier {
+    fn kind(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn check_target(&self, target: &str) -> Result<(), String> {
+        if target.is_empty() || target.contains(|c| c == '+' || c == '#' || c == '\0') {
+            Err("Topic must not be empty or contain wildcards.".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn send(&self, target: &str, event: &Event) -> Result<(), String> {
+        let payload = serde_json::to_vec(event).map_err(|reason| reason.to_string())?;
+
+        lock(&self.0)
+            .publish(target, QoS::AtLeastOnce, false, payload)
+            .map_err(|error| error.to_string())
+    }
+}
@@ -0,0 +1,234 @@
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::{ContentType, MediaType, Status};
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::Outcome;
+use serde::de::DeserializeOwned;
+use std::io::{Cursor, Read};
+use std::ops::Deref;
+
+use crate::auth::Unauthorized;
+use crate::protobuf;
+use crate::signing;
+use crate::validation::{self, Validate};
+
+const DEFAULT_LIMIT: u64 = 1 << 20;
+
+/// A request body in either JSON or MessagePack, chosen by the request's `Content-Type`.
+/// Bodies without a `Content-Type` are read as JSON. Bodies that fail validation get 422.
+pub struct Body<T>(pub T);
+
+impl<T> Body<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Body<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromDataSimple for Body<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Body<T>, String> {
+        match read(request, data) {
+            Outcome::Success(Some(value)) => Outcome::Success(Body(value)),
+            Outcome::Success(None) => Outcome::Failure((
+                Status::UnprocessableEntity,
+                "Request body is missing.".to_string(),
+            )),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(data) => Outcome::Forward(data),
+        }
+    }
+}
+
+/// A request body that may be left out. Rocket resolves `Option<Body<T>>` to `None` whenever
+/// the body fails to parse; this rejects a body that is present but malformed instead.
+pub struct OptionalBody<T>(pub Option<T>);
+
+impl<T> OptionalBody<T> {
+    pub fn into_inner(self) -> Option<T> {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromDataSimple for OptionalBody<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<OptionalBody<T>, String> {
+        read(request, data).map(OptionalBody)
+    }
+}
+
+/// The body of a route that takes none. It is read all the same, so that a signed request's
+/// signature is checked against what was sent, as for any other route that changes counters.
+pub struct Unused;
+
+impl FromDataSimple for Unused {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Unused, String> {
+        match read_signed(request, data) {
+            Ok(_) => Outcome::Success(Unused),
+            Err(failure) => Outcome::Failure(failure),
+        }
+    }
+}
+
+/// The raw body, once it matches the request's signature if there is one.
+fn read_signed(request: &Request, data: Data) -> Result<Vec<u8>, (Status, String)> {
+    let limit = request.limits().get("json").unwrap_or(DEFAULT_LIMIT);
+    let mut bytes = Vec::new();
+
+    if let Err(error) = data.open().take(limit).read_to_end(&mut bytes) {
+        return Err((Status::BadRequest, error.to_string()));
+    }
+
+    if !signing::verify_body(request, &bytes) {
+        let Unauthorized(reason) =
+            request.local_cache(|| Unauthorized("Request signature does not match the body."));
+
+        return Err((Status::Unauthorized, reason.to_string()));
+    }
+
+    Ok(bytes)
+}
+
+/// The body as a `T`, or `None` if it is empty.
+fn read<T: DeserializeOwned + Validate>(
+    request: &Request,
+    data: Data,
+) -> data::Outcome<Option<T>, String> {
+    let bytes = match read_signed(request, data) {
+        Ok(bytes) => bytes,
+        Err(failure) => return Outcome::Failure(failure),
+    };
+
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return Outcome::Success(None);
+    }
+
+    let msgpack = match request.content_type() {
+        None => false,
+        Some(content_type) if content_type.is_json() => false,
+        Some(content_type) if is_msgpack(content_type.media_type()) => true,
+        Some(_) => {
+            return Outcome::Failure((
+                Status::UnsupportedMediaType,
+                "Expected JSON or MessagePack.".to_string(),
+            ))
+        }
+    };
+    let parsed = if msgpack {
+        rmp_serde::from_read(bytes.as_slice()).map_err(|error| error.to_string())
+    } else {
+        serde_json::from_slice(&bytes).map_err(|error| error.to_string())
+    };
+
+    match parsed {
+        Ok(value) if validation::check(request, &value) => Outcome::Success(Some(value)),
+        Ok(_) => Outcome::Failure((
+            Status::UnprocessableEntity,
+            "Request body is invalid.".to_string(),
+        )),
+        Err(error) => Outcome::Failure((Status::UnprocessableEntity, error)),
+    }
+}
+
+fn is_msgpack(media_type: &MediaType) -> bool {
+    media_type.is_msgpack()
+        || (media_type.top() == "application" && media_type.sub() == "x-msgpack")
+}
+
+fn is_protobuf(media_type: &MediaType) -> bool {
+    media_type.top() == "application"
+        && (media_type.sub() == "x-protobuf" || media_type.sub() == "protobuf")
+}
+
+/// Re-encodes JSON responses in the format the client prefers. Routes keep producing JSON, so
+/// every endpoint, including errors, speaks MessagePack. Protobuf covers the bodies that have
+/// a message in `proto/counter.proto`; anything else is still sent as JSON.
+pub fn encode_response(request: &Request, response: &mut Response) {
+    if response.content_type() != Some(ContentType::JSON) {
+        return;
+    }
+
+    response.set_raw_header("Vary", "Accept");
+
+    let preferred = match request.accept() {
+        Some(accept) => accept.preferred().media_type().clone(),
+        None => return,
+    };
+
+    if !is_msgpack(&preferred) && !is_protobuf(&preferred) {
+        return;
+    }
+
+    let json = match response.body_bytes() {
+        Some(json) => json,
+        None => return,
+    };
+    let encoded = if is_msgpack(&preferred) {
+        serde_json::from_slice::<serde_json::Value>(&json)
+            .ok()
+            .and_then(|value| rmp_serde::to_vec_named(&value).ok())
+            .map(|encoded| (ContentType::MsgPack, encoded))
+    } else {
+        protobuf::encode(&json)
+            .map(|encoded| (ContentType::new("application", "x-protobuf"), encoded))
+    };
+
+    match encoded {
+        Some((content_type, encoded)) => {
+            response.set_header(content_type);
+            response.set_sized_body(Cursor::new(encoded));
+        }
+        None => response.set_sized_body(Cursor::new(json)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn negotiate_messagepack() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let body = rmp_serde::to_vec_named(&serde_json::json!({ "name": "Sensor" })).unwrap();
+        let mut response = client
+            .post("/counter")
+            .header(ContentType::MsgPack)
+            .header(Header::new("Accept", "application/msgpack"))
+            .body(body)
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::MsgPack));
+
+        let counter: Counter =
+            rmp_serde::from_read(response.body_bytes().unwrap().as_slice()).unwrap();
+
+        assert_eq!(counter.name, Some("Sensor".to_string()));
+
+        let json_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+
+        assert_eq!(json_response.content_type(), Some(ContentType::JSON));
+
+        let unsupported_response = client
+            .post("/counter/lookup")
+            .header(ContentType::Plain)
+            .body("ids")
+            .dispatch();
+
+        assert_eq!(unsupported_response.status(), Status::UnsupportedMediaType);
+    }
+}
@@ -0,0 +1,497 @@
+//! Notification channels: ways of telling someone that a counter's value has come to satisfy a
+//! condition, such as `value >= 1000`. Every kind of channel, be it a webhook, email, a chat
+//! message or an MQTT message, is a `Notifier` registered under its name at launch, and every
+//! counter can have channels of any registered kind at `/counter/<id>/channels`. Conditions
+//! are evaluated here for all of them, so a new kind of channel only has to implement sending.
+//!
+//! Kinds are registered by the integrations that provide them: `webhook`, `slack` and `discord`
+//! unless webhooks are switched off, `email` with `smtp` configured, and `mqtt` with
+//! `mqtt_broker` configured.
+
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::history;
+use crate::lock;
+use crate::negotiation::Body;
+use crate::pool::BlockingPool;
+use crate::simulate::{self, Fired};
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::validation::Validate;
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+const MAX_CHANNELS: usize = 20;
+const THREADS: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operator {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+const OPERATORS: &[(&str, Operator)] = &[
+    (">=", Operator::GreaterOrEqual),
+    ("<=", Operator::LessOrEqual),
+    ("==", Operator::Equal),
+    ("!=", Operator::NotEqual),
+    (">", Operator::Greater),
+    ("<", Operator::Less),
+];
+
+/// A comparison of the counter's value with a constant, written like `value >= 1000`.
+#[derive(Clone, Copy)]
+pub struct Condition {
+    operator: Operator,
+    threshold: u32,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Option<Condition> {
+        let text = text.trim();
+
+        if !text.starts_with("value") {
+            return None;
+        }
+
+        let rest = text["value".len()..].trim_start();
+        let (symbol, operator) = OPERATORS
+            .iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))?;
+        let threshold = rest[symbol.len()..].trim().parse().ok()?;
+
+        Some(Condition {
+            operator: *operator,
+            threshold,
+        })
+    }
+
+    pub fn matches(self, value: u32) -> bool {
+        match self.operator {
+            Operator::Greater => value > self.threshold,
+            Operator::GreaterOrEqual => value >= self.threshold,
+            Operator::Less => value < self.threshold,
+            Operator::LessOrEqual => value <= self.threshold,
+            Operator::Equal => value == self.threshold,
+            Operator::NotEqual => value != self.threshold,
+        }
+    }
+
+    /// Whether the condition has just come to hold for `value`, given whether it held at the
+    /// last change, which is updated. Notifications go out on this edge only, so that a value
+    /// that stays past a threshold is not announced again with every change.
+    pub fn became_true(self, value: u32, held: &mut bool) -> bool {
+        let holds = self.matches(value);
+        let became = holds && !*held;
+
+        *held = holds;
+        became
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let (symbol, _) = OPERATORS
+            .iter()
+            .find(|(_, operator)| *operator == self.operator)
+            .expect("every operator has a symbol");
+
+        write!(formatter, "value {} {}", symbol, self.threshold)
+    }
+}
+
+impl Serialize for Condition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A kind of notification channel.
+pub trait Notifier: Send + Sync {
+    /// Name that channels of this kind are created with, such as `email`.
+    fn kind(&self) -> &'static str;
+
+    /// Checks the target of a new channel, such as an address or URL, explaining what is wrong
+    /// with it if anything.
+    fn check_target(&self, target: &str) -> Result<(), String>;
+
+    /// Sends `event` to `target`. Runs on a pool of its own, so it may block.
+    fn send(&self, target: &str, event: &Event) -> Result<(), String>;
+}
+
+/// What a channel is told about: the counter, just after its value came to satisfy the
+/// channel's condition.
+#[derive(Serialize)]
+pub struct Event {
+    event: &'static str,
+    pub channel: Uuid,
+    pub condition: Condition,
+    pub counter: Counter,
+}
+
+impl Event {
+    /// The event in a sentence, for channels that carry text.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} crossed {} and is now at {}.",
+            label(&self.counter),
+            self.condition,
+            self.counter.value
+        )
+    }
+}
+
+/// `Counter "Signups"`, or the counter's id if it has no name.
+pub fn label(counter: &Counter) -> String {
+    match &counter.name {
+        Some(name) => format!("Counter \"{}\"", name),
+        None => format!("Counter {}", counter.id),
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Channel {
+    id: Uuid,
+    kind: &'static str,
+    target: String,
+    condition: Condition,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    /// Whether the condition held at the last change.
+    #[serde(skip)]
+    holds: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewChannel {
+    kind: String,
+    target: String,
+    condition: String,
+}
+
+impl Validate for NewChannel {}
+
+/// The registered kinds of channel, and every counter's channels.
+#[derive(Clone)]
+pub struct Notifiers {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    notifiers: Mutex<HashMap<&'static str, Arc<dyn Notifier>>>,
+    channels: Mutex<HashMap<Uuid, Vec<Channel>>>,
+    pool: BlockingPool,
+}
+
+impl Default for Notifiers {
+    fn default() -> Notifiers {
+        Notifiers {
+            shared: Arc::new(Shared {
+                notifiers: Mutex::default(),
+                channels: Mutex::default(),
+                pool: BlockingPool::new(THREADS),
+            }),
+        }
+    }
+}
+
+impl Notifiers {
+    /// Makes channels of the notifier's kind available, in place of any registered before.
+    pub fn register<N: Notifier + 'static>(&self, notifier: N) {
+        lock(&self.shared.notifiers).insert(notifier.kind(), Arc::new(notifier));
+    }
+
+    /// Names of the registered kinds, in alphabetical order.
+    pub fn kinds(&self) -> Vec<&'static str> {
+        let mut kinds: Vec<&'static str> = lock(&self.shared.notifiers).keys().cloned().collect();
+
+        kinds.sort();
+        kinds
+    }
+
+    fn notifier(&self, kind: &str) -> Option<Arc<dyn Notifier>> {
+        lock(&self.shared.notifiers).get(kind).cloned()
+    }
+
+    /// Starts checking changes to the counters in `store`. Checking stops with the store.
+    pub fn watch(&self, store: &Store) {
+        let changes = store.subscribe();
+        let notifiers = self.clone();
+
+        thread::spawn(move || {
+            for counter in changes {
+                notifiers.check(&counter);
+            }
+        });
+    }
+
+    /// Which of the channels of `id` would be notified if the counter took `values` in turn,
+    /// without notifying them.
+    pub fn simulate(&self, id: Uuid, values: &[u32]) -> Vec<Fired> {
+        let mut fired = Vec::new();
+
+        for channel in lock(&self.shared.channels).get(&id).into_iter().flatten() {
+            if self.notifier(channel.kind).is_none() {
+                continue;
+            }
+
+            let mut holds = channel.holds;
+
+            fired.extend(simulate::walk("channel", channel.id, values, |value| {
+                channel.condition.became_true(value, &mut holds)
+            }));
+        }
+        fired
+    }
+
+    fn check(&self, counter: &Counter) {
+        let mut channels = lock(&self.shared.channels);
+        let channels = match channels.get_mut(&counter.id) {
+            Some(channels) => channels,
+            None => return,
+        };
+
+        for channel in channels.iter_mut() {
+            if !channel
+                .condition
+                .became_true(counter.value, &mut channel.holds)
+            {
+                continue;
+            }
+
+            let notifier = match self.notifier(channel.kind) {
+                Some(notifier) => notifier,
+                None => continue,
+            };
+            let target = channel.target.clone();
+            let event = Event {
+                event: "threshold",
+                channel: channel.id,
+                condition: channel.condition,
+                counter: counter.clone(),
+            };
+
+            self.shared.pool.spawn(move || {
+                if let Err(reason) = notifier.send(&target, &event) {
+                    eprintln!(
+                        "Sending to a {} channel failed: {}",
+                        notifier.kind(),
+                        reason
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// `{ "kind": "email", "target": "ops@example.com", "condition": "value >= 1000" }`. The
+/// target is whatever the kind sends to: a URL for webhooks and chat, an address for email, a
+/// topic for MQTT.
+#[post("/<id>/channels", data = "<new_channel>")]
+pub fn create_channel(
+    id: String,
+    new_channel: Body<NewChannel>,
+    notifiers: State<Notifiers>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Channel>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let NewChannel {
+        kind,
+        target,
+        condition,
+    } = new_channel.into_inner();
+    let notifier = notifiers.notifier(&kind).ok_or_else(|| {
+        error(
+            Status::BadRequest,
+            &format!("Kind must be one of: {}.", notifiers.kinds().join(", ")),
+        )
+    })?;
+    let condition = Condition::parse(&condition).ok_or_else(|| {
+        error(
+            Status::BadRequest,
+            "Condition must look like `value >= 1000`.",
+        )
+    })?;
+
+    notifier
+        .check_target(&target)
+        .map_err(|reason| error(Status::BadRequest, &reason))?;
+
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+    let mut channels = lock(&notifiers.shared.channels);
+    let registered = channels.entry(parsed_uuid).or_default();
+
+    if registered.len() >= MAX_CHANNELS {
+        return Err(error(
+            Status::Conflict,
+            &format!("A counter can have at most {} channels.", MAX_CHANNELS),
+        ));
+    }
+
+    let channel = Channel {
+        id: Uuid::new_v4(),
+        kind: notifier.kind(),
+        target,
+        condition,
+        created_at: history::timestamp(),
+        holds: condition.matches(counter.value),
+    };
+
+    registered.push(channel.clone());
+    Ok(Json(channel))
+}
+
+/// Targets can be addresses or secret URLs, so only those who may change the counter see them.
+#[get("/<id>/channels")]
+pub fn list_channels(
+    id: String,
+    notifiers: State<Notifiers>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Vec<Channel>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let channels = lock(&notifiers.shared.channels);
+
+    Ok(Json(
+        channels.get(&parsed_uuid).cloned().unwrap_or_default(),
+    ))
+}
+
+#[delete("/<id>/channels/<channel_id>")]
+pub fn delete_channel(
+    id: String,
+    channel_id: String,
+    notifiers: State<Notifiers>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Channel>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let channel_id = parse_id(&channel_id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let mut channels = lock(&notifiers.shared.channels);
+    let registered = channels.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+    let index = registered
+        .iter()
+        .position(|channel| channel.id == channel_id)
+        .ok_or_else(not_found_error)?;
+
+    Ok(Json(registered.remove(index)))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn notify_through_channels() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/notify", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            sender
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .unwrap();
+        });
+
+        let create_channel = |body: String| {
+            client
+                .post(format!("/counter/{}/channels", counter.id))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+        let mut pagerduty_response = create_channel(
+            r#"{ "kind": "pagerduty", "target": "x", "condition": "value >= 1" }"#.to_string(),
+        );
+
+        assert_eq!(pagerduty_response.status(), Status::BadRequest);
+        assert!(pagerduty_response
+            .body_string()
+            .unwrap()
+            .contains("discord, slack, webhook"));
+
+        let channel_response = create_channel(format!(
+            r#"{{ "kind": "webhook", "target": "{}", "condition": "value >= 2" }}"#,
+            url
+        ));
+
+        assert_eq!(channel_response.status(), Status::Ok);
+
+        for _ in 0..3 {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let notification = received.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(notification.starts_with("POST /notify "));
+        assert!(notification.contains(r#""condition":"value >= 2""#));
+        assert!(notification.contains(r#""value":2"#));
+
+        let mut list_response = client
+            .get(format!("/counter/{}/channels", counter.id))
+            .dispatch();
+        let channels: Vec<serde_json::Value> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0]["kind"], "webhook");
+    }
+}
@@ -0,0 +1,350 @@
+//! Writing tables as Parquet files. A file holds one row group with a single data page per
+//! column, plain-encoded and uncompressed, which every Parquet reader takes. The metadata is
+//! written in Thrift's compact protocol, as the format requires.
+
+const MAGIC: &[u8] = b"PAR1";
+
+// Thrift compact protocol types.
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+// Parquet enums.
+const BOOLEAN_TYPE: i32 = 0;
+const INT64_TYPE: i32 = 2;
+const BYTE_ARRAY_TYPE: i32 = 6;
+const REQUIRED: i32 = 0;
+const OPTIONAL: i32 = 1;
+const UTF8: i32 = 0;
+const TIMESTAMP_MILLIS: i32 = 9;
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+const UNCOMPRESSED: i32 = 0;
+const DATA_PAGE: i32 = 0;
+
+/// The values of a column, one per row.
+pub enum Values {
+    Boolean(Vec<bool>),
+    Int64(Vec<i64>),
+    /// Milliseconds since the Unix epoch.
+    Timestamp(Vec<u64>),
+    Text(Vec<String>),
+    OptionalText(Vec<Option<String>>),
+}
+
+impl Values {
+    fn len(&self) -> usize {
+        match self {
+            Values::Boolean(values) => values.len(),
+            Values::Int64(values) => values.len(),
+            Values::Timestamp(values) => values.len(),
+            Values::Text(values) => values.len(),
+            Values::OptionalText(values) => values.len(),
+        }
+    }
+
+    fn physical_type(&self) -> i32 {
+        match self {
+            Values::Boolean(_) => BOOLEAN_TYPE,
+            Values::Int64(_) | Values::Timestamp(_) => INT64_TYPE,
+            Values::Text(_) | Values::OptionalText(_) => BYTE_ARRAY_TYPE,
+        }
+    }
+
+    fn converted_type(&self) -> Option<i32> {
+        match self {
+            Values::Boolean(_) | Values::Int64(_) => None,
+            Values::Timestamp(_) => Some(TIMESTAMP_MILLIS),
+            Values::Text(_) | Values::OptionalText(_) => Some(UTF8),
+        }
+    }
+
+    fn is_optional(&self) -> bool {
+        match self {
+            Values::OptionalText(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The data of a page: for optional columns, their length-prefixed definition levels, then
+    /// the values that are not null.
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        match self {
+            Values::Boolean(values) => {
+                let mut bytes = vec![0; (values.len() + 7) / 8];
+
+                for (index, value) in values.iter().enumerate() {
+                    bytes[index / 8] |= u8::from(*value) << (index % 8);
+                }
+
+                data.extend(bytes);
+            }
+            Values::Int64(values) => {
+                for value in values {
+                    data.extend(&value.to_le_bytes());
+                }
+            }
+            Values::Timestamp(values) => {
+                for value in values {
+                    data.extend(&(*value as i64).to_le_bytes());
+                }
+            }
+            Values::Text(values) => {
+                for value in values {
+                    byte_array(&mut data, value);
+                }
+            }
+            Values::OptionalText(values) => {
+                let levels = definition_levels(values.iter().map(Option::is_some));
+
+                data.extend(&(levels.len() as u32).to_le_bytes());
+                data.extend(levels);
+
+                for value in values.iter().flatten() {
+                    byte_array(&mut data, value);
+                }
+            }
+        }
+
+        data
+    }
+}
+
+fn byte_array(data: &mut Vec<u8>, value: &str) {
+    data.extend(&(value.len() as u32).to_le_bytes());
+    data.extend(value.as_bytes());
+}
+
+fn varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    bytes.push(value as u8);
+}
+
+/// Levels of a flat optional column, 1 for present and 0 for null, as runs of the RLE hybrid
+/// encoding with a bit width of 1.
+fn definition_levels<I: Iterator<Item = bool>>(present: I) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut run: Option<(bool, u64)> = None;
+
+    for present in present {
+        run = match run {
+            Some((level, length)) if level == present => Some((level, length + 1)),
+            Some((level, length)) => {
+                varint(&mut bytes, length << 1);
+                bytes.push(u8::from(level));
+                Some((present, 1))
+            }
+            None => Some((present, 1)),
+        };
+    }
+
+    if let Some((level, length)) = run {
+        varint(&mut bytes, length << 1);
+        bytes.push(u8::from(level));
+    }
+
+    bytes
+}
+
+/// A struct being written in Thrift's compact protocol, which encodes field ids as the
+/// difference from the previous field's.
+#[derive(Default)]
+struct Thrift {
+    bytes: Vec<u8>,
+    /// Id of the last field written in each struct that is open.
+    last_fields: Vec<i16>,
+}
+
+impl Thrift {
+    fn begin(&mut self) {
+        self.last_fields.push(0);
+    }
+
+    fn end(&mut self) {
+        self.bytes.push(0);
+        self.last_fields.pop();
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last_fields.last_mut().expect("a struct is open");
+        let delta = id - *last;
+
+        *last = id;
+
+        if delta > 0 && delta <= 15 {
+            self.bytes.push((delta as u8) << 4 | kind);
+        } else {
+            self.bytes.push(kind);
+            self.integer(i64::from(id));
+        }
+    }
+
+    /// A zigzag-encoded integer without a field header, as in lists.
+    fn integer(&mut self, value: i64) {
+        varint(&mut self.bytes, ((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, I32);
+        self.integer(i64::from(value));
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, I64);
+        self.integer(value);
+    }
+
+    fn string(&mut self, id: i16, value: &str) {
+        self.field(id, BINARY);
+        self.raw_string(value);
+    }
+
+    fn raw_string(&mut self, value: &str) {
+        varint(&mut self.bytes, value.len() as u64);
+        self.bytes.extend(value.as_bytes());
+    }
+
+    fn list(&mut self, id: i16, kind: u8, length: usize) {
+        self.field(id, LIST);
+
+        if length < 15 {
+            self.bytes.push((length as u8) << 4 | kind);
+        } else {
+            self.bytes.push(0xf0 | kind);
+            varint(&mut self.bytes, length as u64);
+        }
+    }
+
+    fn nested(&mut self, id: i16) {
+        self.field(id, STRUCT);
+        self.begin();
+    }
+}
+
+struct Chunk {
+    offset: usize,
+    size: usize,
+}
+
+fn page_header(values: &Values, size: usize) -> Vec<u8> {
+    let mut header = Thrift::default();
+
+    header.begin();
+    header.i32(1, DATA_PAGE);
+    header.i32(2, size as i32);
+    header.i32(3, size as i32);
+    header.nested(5);
+    header.i32(1, values.len() as i32);
+    header.i32(2, PLAIN);
+    header.i32(3, RLE);
+    header.i32(4, RLE);
+    header.end();
+    header.end();
+    header.bytes
+}
+
+fn file_metadata(columns: &[(&str, Values)], chunks: &[Chunk], rows: usize) -> Vec<u8> {
+    let mut metadata = Thrift::default();
+
+    metadata.begin();
+    metadata.i32(1, 1);
+    metadata.list(2, STRUCT, columns.len() + 1);
+    metadata.begin();
+    metadata.string(4, "schema");
+    metadata.i32(5, columns.len() as i32);
+    metadata.end();
+
+    for (name, values) in columns {
+        metadata.begin();
+        metadata.i32(1, values.physical_type());
+        metadata.i32(
+            3,
+            if values.is_optional() {
+                OPTIONAL
+            } else {
+                REQUIRED
+            },
+        );
+        metadata.string(4, name);
+
+        if let Some(converted_type) = values.converted_type() {
+            metadata.i32(6, converted_type);
+        }
+
+        metadata.end();
+    }
+
+    metadata.i64(3, rows as i64);
+
+    if rows == 0 {
+        metadata.list(4, STRUCT, 0);
+    } else {
+        metadata.list(4, STRUCT, 1);
+        metadata.begin();
+        metadata.list(1, STRUCT, columns.len());
+
+        for ((name, values), chunk) in columns.iter().zip(chunks) {
+            metadata.begin();
+            metadata.i64(2, chunk.offset as i64);
+            metadata.nested(3);
+            metadata.i32(1, values.physical_type());
+            metadata.list(2, I32, 2);
+            metadata.integer(i64::from(PLAIN));
+            metadata.integer(i64::from(RLE));
+            metadata.list(3, BINARY, 1);
+            metadata.raw_string(name);
+            metadata.i32(4, UNCOMPRESSED);
+            metadata.i64(5, rows as i64);
+            metadata.i64(6, chunk.size as i64);
+            metadata.i64(7, chunk.size as i64);
+            metadata.i64(9, chunk.offset as i64);
+            metadata.end();
+            metadata.end();
+        }
+
+        metadata.i64(2, chunks.iter().map(|chunk| chunk.size as i64).sum());
+        metadata.i64(3, rows as i64);
+        metadata.end();
+    }
+
+    metadata.string(6, "counter-as-a-service");
+    metadata.end();
+    metadata.bytes
+}
+
+/// A Parquet file of `columns`, which must all have the same number of values.
+pub fn write(columns: &[(&str, Values)]) -> Vec<u8> {
+    let rows = columns.first().map_or(0, |(_, values)| values.len());
+    let mut file = MAGIC.to_vec();
+    let mut chunks = Vec::with_capacity(columns.len());
+
+    if rows > 0 {
+        for (_, values) in columns {
+            let data = values.encode();
+            let header = page_header(values, data.len());
+            let offset = file.len();
+
+            file.extend(&header);
+            file.extend(&data);
+            chunks.push(Chunk {
+                offset,
+                size: header.len() + data.len(),
+            });
+        }
+    }
+
+    let metadata = file_metadata(columns, &chunks, rows);
+
+    file.extend(&metadata);
+    file.extend(&(metadata.len() as u32).to_le_bytes());
+    file.extend(MAGIC);
+    file
+}
@@ -0,0 +1,69 @@
+use rocket::Config;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::lock;
+
+const DEFAULT_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads for work that may block, such as waiting on a contended counter,
+/// so that it never ties up Rocket's HTTP workers. Sized by `blocking_threads`; jobs queue up
+/// when every thread is busy.
+pub struct BlockingPool {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl BlockingPool {
+    pub fn new(threads: usize) -> BlockingPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+
+            thread::Builder::new()
+                .name(format!("blocking-{}", index))
+                .spawn(move || loop {
+                    let job = match lock(&receiver).recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+
+                    job();
+                })
+                .expect("Failed to spawn blocking thread");
+        }
+
+        BlockingPool {
+            jobs: Mutex::new(sender),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> BlockingPool {
+        let threads = config
+            .get_int("blocking_threads")
+            .ok()
+            .filter(|threads| *threads > 0)
+            .map_or(DEFAULT_THREADS, |threads| threads as usize);
+
+        BlockingPool::new(threads)
+    }
+
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        lock(&self.jobs)
+            .send(Box::new(job))
+            .expect("Blocking pool has shut down");
+    }
+}
+
+impl Default for BlockingPool {
+    fn default() -> BlockingPool {
+        BlockingPool::new(DEFAULT_THREADS)
+    }
+}
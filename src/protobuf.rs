@@ -0,0 +1,234 @@
+//! Encoding of response bodies as the messages in `proto/counter.proto`.
+
+use crate::counter::Bounds;
+use crate::cycle::{Burst, Cycle, Period, Total};
+use crate::Counter;
+
+/// A message being written in the protobuf wire format. Fields at their default value are
+/// omitted, as proto3 does, except for those with explicit presence.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.raw_varint((u64::from(field) << 3) | u64::from(wire_type));
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+
+        self.0.push(value as u8);
+    }
+
+    fn varint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.optional_varint(field, Some(value));
+        }
+    }
+
+    fn optional_varint(&mut self, field: u32, value: Option<u64>) {
+        if let Some(value) = value {
+            self.key(field, 0);
+            self.raw_varint(value);
+        }
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        self.varint(field, u64::from(value));
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, 2);
+        self.raw_varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        if !value.is_empty() {
+            self.bytes(field, value.as_bytes());
+        }
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.0);
+    }
+}
+
+trait Encode {
+    fn encode(&self) -> Message;
+}
+
+impl Encode for Counter {
+    fn encode(&self) -> Message {
+        let mut message = Message::default();
+
+        message.string(1, &self.id.to_string());
+        message.varint(2, u64::from(self.value));
+        message.string(3, self.name.as_ref().map_or("", String::as_str));
+        message.string(4, self.description.as_ref().map_or("", String::as_str));
+        message.varint(5, self.created_at);
+        message.varint(6, self.updated_at);
+        message.varint(7, u64::from(self.max_seen));
+        message.varint(8, u64::from(self.min_seen));
+        message.bool(9, self.archived);
+        message.bool(10, self.frozen);
+
+        for tag in &self.tags {
+            message.bytes(11, tag.as_bytes());
+        }
+
+        for (label, count) in &self.breakdown {
+            let mut entry = Message::default();
+
+            entry.string(1, label);
+            entry.varint(2, u64::from(*count));
+            message.message(12, entry);
+        }
+
+        message.varint(13, u64::from(self.step));
+        message.message(14, self.bounds.encode());
+
+        if let Some(cycle) = &self.cycle {
+            message.message(15, cycle.encode());
+        }
+
+        if let Some(burst) = &self.burst {
+            message.message(16, burst.encode());
+        }
+
+        if let Some(owner) = &self.owner {
+            message.string(17, owner);
+        }
+
+        message
+    }
+}
+
+impl Encode for Bounds {
+    fn encode(&self) -> Message {
+        let mut message = Message::default();
+
+        message.optional_varint(1, self.min.map(u64::from));
+        message.optional_varint(2, self.max.map(u64::from));
+        message
+    }
+}
+
+impl Encode for Cycle {
+    fn encode(&self) -> Message {
+        let mut message = Message::default();
+
+        message.varint(
+            1,
+            match self.period {
+                Period::Monthly => 0,
+                Period::Yearly => 1,
+            },
+        );
+        message.varint(2, self.anchor);
+        message.varint(3, self.started_at);
+        message.varint(4, self.resets_at);
+
+        for total in &self.totals {
+            message.message(5, total.encode());
+        }
+
+        message
+    }
+}
+
+impl Encode for Total {
+    fn encode(&self) -> Message {
+        let mut message = Message::default();
+
+        message.varint(1, self.started_at);
+        message.varint(2, self.ended_at);
+        message.varint(3, u64::from(self.total));
+        message
+    }
+}
+
+impl Encode for Burst {
+    fn encode(&self) -> Message {
+        let mut message = Message::default();
+
+        message.varint(1, u64::from(self.pool));
+        message.varint(2, u64::from(self.repaying));
+        message.varint(3, u64::from(self.borrowed));
+        message
+    }
+}
+
+#[derive(Deserialize)]
+struct Error {
+    status: String,
+    reason: String,
+}
+
+/// The JSON response body as a `Counter`, `CounterList` or `Error` message, or `None` if it is
+/// none of those.
+pub fn encode(json: &[u8]) -> Option<Vec<u8>> {
+    if let Ok(counter) = serde_json::from_slice::<Counter>(json) {
+        return Some(counter.encode().0);
+    }
+
+    if let Ok(counters) = serde_json::from_slice::<Vec<Counter>>(json) {
+        let mut list = Message::default();
+
+        for counter in &counters {
+            list.message(1, counter.encode());
+        }
+
+        return Some(list.0);
+    }
+
+    serde_json::from_slice::<Error>(json).ok().map(|error| {
+        let mut message = Message::default();
+
+        message.string(1, &error.status);
+        message.string(2, &error.reason);
+        message.0
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn negotiate_protobuf() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut response = client
+            .get(format!("/counter/{}", counter.id))
+            .header(Header::new("Accept", "application/x-protobuf"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "x-protobuf"))
+        );
+
+        let body = response.body_bytes().unwrap();
+        let id = counter.id.to_string();
+
+        assert_eq!(body[..2], [0x0a, 36]);
+        assert_eq!(&body[2..38], id.as_bytes());
+
+        let stats_response = client
+            .get("/counter/stats")
+            .header(Header::new("Accept", "application/x-protobuf"))
+            .dispatch();
+
+        assert_eq!(stats_response.content_type(), Some(ContentType::JSON));
+    }
+}
@@ -0,0 +1,160 @@
+//! `DELETE /counter?tag=temp&older_than=30d&confirm=true`, removing every counter that matches
+//! at once, for cleaning up after tests and experiments. Only admins can, and only with
+//! `confirm=true`; without it nothing is deleted and the answer says how many counters would be.
+
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::history;
+use crate::routes::TagFilter;
+use crate::tenancy::TenantStore;
+use crate::{error, ApiError, Counter};
+
+#[derive(Serialize)]
+pub struct Purged {
+    deleted: usize,
+    ids: Vec<Uuid>,
+}
+
+/// `30d` as milliseconds. Takes whole numbers of seconds (`s`), minutes (`m`), hours (`h`),
+/// days (`d`) or weeks (`w`).
+fn age(text: &str) -> Option<u64> {
+    let unit = text.chars().last()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    text[..text.len() - unit.len_utf8()]
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(seconds * 1000))
+}
+
+/// Deletes the counters that carry every `tag` and, with `older_than`, were created at least
+/// that long ago. At least one of the two is required, so that a stray request cannot empty
+/// the store.
+#[delete("/?<older_than>&<confirm>")]
+pub(crate) fn purge(
+    older_than: Option<String>,
+    confirm: Option<bool>,
+    tags: TagFilter,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Purged>, ApiError> {
+    writer.check_admin()?;
+
+    if tags.0.is_empty() && older_than.is_none() {
+        return Err(error(
+            Status::BadRequest,
+            "Give a tag, older_than or both to choose the counters to delete.",
+        ));
+    }
+
+    let created_before = match older_than {
+        Some(older_than) => {
+            let age = age(&older_than).ok_or_else(|| {
+                error(
+                    Status::BadRequest,
+                    "older_than must be a number of s, m, h, d or w, such as 30d.",
+                )
+            })?;
+
+            Some(history::timestamp().saturating_sub(age))
+        }
+        None => None,
+    };
+    let matches = |counter: &Counter| {
+        tags.matches(counter)
+            && created_before.map_or(true, |created_before| counter.created_at <= created_before)
+    };
+
+    if confirm != Some(true) {
+        let count = store
+            .read()
+            .values()
+            .filter(|counter| matches(counter))
+            .count();
+
+        return Err(error(
+            Status::BadRequest,
+            &format!(
+                "This would delete {} counters. Add confirm=true to delete them.",
+                count
+            ),
+        ));
+    }
+
+    let mut ids: Vec<Uuid> = store
+        .remove_where(matches)
+        .iter()
+        .map(|counter| counter.id)
+        .collect();
+
+    ids.sort();
+    Ok(Json(Purged {
+        deleted: ids.len(),
+        ids,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn purge_counters() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        for tags in &[r#"["temp"]"#, r#"["temp", "load"]"#, r#"["prod"]"#] {
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "tags": {} }}"#, tags))
+                .dispatch();
+        }
+
+        let unfiltered_response = client.delete("/counter?confirm=true").dispatch();
+
+        assert_eq!(unfiltered_response.status(), Status::BadRequest);
+
+        let mut unconfirmed_response = client.delete("/counter?tag=temp").dispatch();
+
+        assert_eq!(unconfirmed_response.status(), Status::BadRequest);
+        assert!(unconfirmed_response
+            .body_string()
+            .unwrap()
+            .contains("This would delete 2 counters."));
+
+        let mut too_new_response = client
+            .delete("/counter?tag=temp&older_than=30d&confirm=true")
+            .dispatch();
+        let too_new: serde_json::Value =
+            serde_json::from_str(&too_new_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(too_new["deleted"], 0);
+
+        let mut response = client.delete("/counter?tag=temp&confirm=true").dispatch();
+        let purged: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(purged["deleted"], 2);
+
+        let mut list_response = client.get("/counter").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 1);
+        assert!(counters[0].tags.contains("prod"));
+    }
+}
@@ -0,0 +1,216 @@
+use qrcode::{Color, QrCode};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::{Config, Outcome, State};
+use std::io::Cursor;
+
+use crate::links::{redeem_path, OneTimeLinks};
+use crate::tenancy::TenantStore;
+use crate::{error, history, not_found_error, parse_id, ApiError, V1};
+
+/// Pixels per module of the code.
+const SCALE: usize = 8;
+/// Width of the blank border around the code, in modules, as the QR spec requires.
+const QUIET_ZONE: usize = 4;
+/// A day, in milliseconds.
+const SIGNED_LINK_TTL: u64 = 86_400_000;
+
+/// Scheme and host that URLs in QR codes point at, from the `public_url` setting, e.g.
+/// `public_url = "https://caas.example.com"`. Without it the request's `Host` is used over
+/// plain HTTP, which is only right when nothing terminates TLS in front of the service.
+#[derive(Default)]
+pub struct PublicUrl(Option<String>);
+
+impl PublicUrl {
+    pub fn from_config(config: &Config) -> PublicUrl {
+        PublicUrl(
+            config
+                .get_str("public_url")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string()),
+        )
+    }
+}
+
+/// The base URL of this service as seen by whoever scans the code.
+pub struct BaseUrl(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for BaseUrl {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<BaseUrl, ()> {
+        let configured = request
+            .guard::<State<PublicUrl>>()
+            .succeeded()
+            .and_then(|public_url| public_url.0.clone());
+
+        match configured {
+            Some(url) => Outcome::Success(BaseUrl(url)),
+            None => match request.headers().get_one("Host") {
+                Some(host) => Outcome::Success(BaseUrl(format!("http://{}", host))),
+                None => Outcome::Failure((Status::BadRequest, ())),
+            },
+        }
+    }
+}
+
+/// A PNG image. Signed codes are not cached, since every request mints a new link.
+pub struct Png {
+    image: Vec<u8>,
+    cacheable: bool,
+}
+
+impl<'r> Responder<'r> for Png {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::PNG)
+            .raw_header(
+                "Cache-Control",
+                if self.cacheable {
+                    "public, max-age=3600"
+                } else {
+                    "no-store"
+                },
+            )
+            .sized_body(Cursor::new(self.image))
+            .ok()
+    }
+}
+
+/// `text` as a black on white grayscale PNG.
+fn render(text: &str) -> Option<Vec<u8>> {
+    let code = QrCode::new(text.as_bytes()).ok()?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let side = (width + 2 * QUIET_ZONE) * SCALE;
+    let mut pixels = vec![0xff; side * side];
+
+    for (index, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+
+        let x = (index % width + QUIET_ZONE) * SCALE;
+        let y = (index / width + QUIET_ZONE) * SCALE;
+
+        for row in y..y + SCALE {
+            for pixel in &mut pixels[row * side + x..row * side + x + SCALE] {
+                *pixel = 0;
+            }
+        }
+    }
+
+    let mut image = Vec::new();
+
+    {
+        let mut encoder = png::Encoder::new(&mut image, side as u32, side as u32);
+
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .ok()?;
+    }
+
+    Some(image)
+}
+
+/// A QR code for a physical tally button. `increment` (the default) points at the hit
+/// endpoint, `view` at the counter itself. With `signed=true` the code carries a one-time
+/// link that is valid for a day instead, so each printed code counts once.
+#[get("/<id>/qr.png?<action>&<signed>")]
+pub fn qr(
+    id: String,
+    action: Option<String>,
+    signed: Option<bool>,
+    base_url: BaseUrl,
+    links: State<OneTimeLinks>,
+    store: TenantStore,
+) -> Result<Png, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let signed = signed.unwrap_or(false);
+
+    store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    let path = match (action.as_ref().map_or("increment", String::as_str), signed) {
+        ("increment", false) => format!("{}/counter/{}/hit?pixel=false", V1, parsed_uuid),
+        ("increment", true) => {
+            let expires_at = history::timestamp() + SIGNED_LINK_TTL;
+            let token = links.mint(parsed_uuid, 1, expires_at)[0];
+
+            redeem_path(parsed_uuid, token)
+        }
+        ("view", false) => format!("{}/counter/{}", V1, parsed_uuid),
+        ("view", true) => {
+            return Err(error(
+                Status::BadRequest,
+                "Only increment codes can be signed.",
+            ))
+        }
+        _ => {
+            return Err(error(
+                Status::BadRequest,
+                "Action must be increment or view.",
+            ))
+        }
+    };
+
+    render(&format!("{}{}", base_url.0, path))
+        .map(|image| Png {
+            image,
+            cacheable: !signed,
+        })
+        .ok_or_else(|| {
+            error(
+                Status::InternalServerError,
+                "QR code could not be rendered.",
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn render_qr_code() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut response = client
+            .get(format!("/counter/{}/qr.png?action=increment", counter.id))
+            .header(Header::new("Host", "caas.example.com"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::PNG));
+        assert!(response
+            .body_bytes()
+            .unwrap()
+            .starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        let signed_response = client
+            .get(format!("/counter/{}/qr.png?signed=true", counter.id))
+            .header(Header::new("Host", "caas.example.com"))
+            .dispatch();
+
+        assert_eq!(signed_response.status(), Status::Ok);
+        assert_eq!(
+            signed_response.headers().get_one("Cache-Control"),
+            Some("no-store")
+        );
+
+        let unknown_action_response = client
+            .get(format!("/counter/{}/qr.png?action=reset", counter.id))
+            .header(Header::new("Host", "caas.example.com"))
+            .dispatch();
+
+        assert_eq!(unknown_action_response.status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,127 @@
+//! Counters ordered by value, kept up to date as they change, so that the highest ones can be
+//! found without sorting every counter on each request.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::lock;
+use crate::visibility::Visibility;
+use crate::Counter;
+
+/// What filtering a leaderboard takes, so that it does not need the counters themselves.
+struct Ranked {
+    value: u32,
+    tags: BTreeSet<String>,
+    archived: bool,
+    private: bool,
+}
+
+#[derive(Default)]
+struct Index {
+    /// Highest value first, then by id.
+    order: BTreeSet<(Reverse<u32>, Uuid)>,
+    ranked: HashMap<Uuid, Ranked>,
+}
+
+#[derive(Default)]
+pub struct Ranking(Mutex<Index>);
+
+impl Ranking {
+    pub fn update(&self, counter: &Counter) {
+        let mut index = lock(&self.0);
+        let ranked = Ranked {
+            value: counter.value,
+            tags: counter.tags.clone(),
+            archived: counter.archived,
+            private: counter.visibility == Visibility::Private,
+        };
+
+        if let Some(previous) = index.ranked.insert(counter.id, ranked) {
+            index.order.remove(&(Reverse(previous.value), counter.id));
+        }
+
+        index.order.insert((Reverse(counter.value), counter.id));
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        let mut index = lock(&self.0);
+
+        if let Some(previous) = index.ranked.remove(&id) {
+            index.order.remove(&(Reverse(previous.value), id));
+        }
+    }
+
+    /// Ids of the `n` highest counters that are not archived and carry every one of `tags`,
+    /// leaving out private ones unless `private` is set.
+    pub fn top(&self, n: usize, tags: &[String], private: bool) -> Vec<Uuid> {
+        let index = lock(&self.0);
+
+        index
+            .order
+            .iter()
+            .filter(|(_, id)| {
+                let ranked = &index.ranked[id];
+
+                !ranked.archived
+                    && (private || !ranked.private)
+                    && tags.iter().all(|tag| ranked.tags.contains(tag))
+            })
+            .map(|(_, id)| *id)
+            .take(n)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn rank_top_counters() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let create = |tags: &str, hits: usize| -> Counter {
+            let mut response = client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "tags": {} }}"#, tags))
+                .dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            for _ in 0..hits {
+                client
+                    .put(format!("/counter/{}/increment", counter.id))
+                    .header(ContentType::JSON)
+                    .dispatch();
+            }
+
+            counter
+        };
+        let second = create(r#"["game"]"#, 2);
+        let first = create(r#"["game"]"#, 5);
+
+        create(r#"["game"]"#, 1);
+        create("[]", 9);
+
+        let mut response = client.get("/counter/top?n=2&tag=game").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let ids: Vec<uuid::Uuid> = counters.iter().map(|counter| counter.id).collect();
+
+        assert_eq!(ids, vec![first.id, second.id]);
+
+        let mut response = client.get("/counter/top").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 4);
+        assert_eq!(counters[0].value, 9);
+    }
+}
@@ -0,0 +1,222 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::{Config, Outcome, State};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::audit::Changes;
+use crate::auth::ApiKeys;
+use crate::dry_run;
+use crate::lock;
+use crate::maintenance;
+use crate::replica;
+
+/// Buckets are dropped once there are this many and they have filled back up.
+const PRUNE_AT: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per client on the routes that change counters, configured as
+/// `rate_limits = { ip = 120, api_key = 1200 }` requests per minute, each allowing bursts of
+/// up to a minute's worth. Requests with a configured API key are limited per key, others per
+/// IP address. Unlimited unless set.
+#[derive(Default)]
+pub struct RateLimits {
+    limits: Mutex<Limits>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+/// Requests per minute.
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    per_ip: Option<f64>,
+    per_key: Option<f64>,
+}
+
+impl Limits {
+    fn from_config(config: &Config) -> Limits {
+        let limit = |name| {
+            config
+                .get_table("rate_limits")
+                .ok()
+                .and_then(|table| table.get(name).and_then(|limit| limit.as_integer()))
+                .filter(|limit| *limit > 0)
+                .map(|limit| limit as f64)
+        };
+
+        Limits {
+            per_ip: limit("ip"),
+            per_key: limit("api_key"),
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn from_config(config: &Config) -> RateLimits {
+        RateLimits {
+            limits: Mutex::new(Limits::from_config(config)),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Replaces the limits with the ones in `config`. Clients keep the tokens they have left.
+    pub fn reload(&self, config: &Config) {
+        *lock(&self.limits) = Limits::from_config(config);
+    }
+
+    /// Takes a token from the client's bucket, or returns how many seconds until one is free.
+    fn take(&self, client: String, per_minute: f64, now: Instant) -> Result<(), u64> {
+        let rate = per_minute / 60.0;
+        let mut buckets = lock(&self.buckets);
+
+        if buckets.len() >= PRUNE_AT {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + rate * (now - bucket.updated).as_secs_f64() < per_minute
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: per_minute,
+            updated: now,
+        });
+
+        bucket.tokens =
+            (bucket.tokens + rate * (now - bucket.updated).as_secs_f64()).min(per_minute);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+}
+
+/// Seconds until a rate-limited client may try again, for the 429 catcher.
+#[derive(Default)]
+struct RetryAfter(u64);
+
+/// Guard that counts the request against the client's rate limit, failing with 429 once it
+/// is used up. Every route that changes counters takes it, so it also marks the request for
+/// the audit trail unless it is a dry run, and fails with 503 on read replicas and in
+/// maintenance mode.
+pub struct Throttle;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Throttle {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Throttle, ()> {
+        request.local_cache(|| Changes(!dry_run::requested(request)));
+
+        if replica::is_replica(request) || maintenance::refuses_writes(request) {
+            return Outcome::Failure((Status::ServiceUnavailable, ()));
+        }
+
+        let limits = match request.guard::<State<RateLimits>>().succeeded() {
+            Some(limits) => limits.inner(),
+            None => return Outcome::Success(Throttle),
+        };
+        let key = request.headers().get_one("X-Api-Key").filter(|given| {
+            request
+                .guard::<State<ApiKeys>>()
+                .succeeded()
+                .map_or(false, |api_keys| api_keys.name(given).is_some())
+        });
+        let Limits { per_ip, per_key } = *lock(&limits.limits);
+        let (client, per_minute) = match (key, per_key, request.client_ip()) {
+            (Some(key), Some(per_key), _) => (format!("key:{}", key), per_key),
+            (Some(_), None, _) => return Outcome::Success(Throttle),
+            (None, _, Some(ip)) => match per_ip {
+                Some(per_ip) => (format!("ip:{}", ip), per_ip),
+                None => return Outcome::Success(Throttle),
+            },
+            (None, _, None) => return Outcome::Success(Throttle),
+        };
+
+        match limits.take(client, per_minute, Instant::now()) {
+            Ok(()) => Outcome::Success(Throttle),
+            Err(seconds) => {
+                request.local_cache(|| RetryAfter(seconds));
+                Outcome::Failure((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+/// Body of the 429 catcher, with `Retry-After`.
+pub struct TooManyRequests;
+
+impl<'r> Responder<'r> for TooManyRequests {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let seconds = request.local_cache(RetryAfter::default).0;
+        let body = json!({
+            "status": "error",
+            "reason": "Too many requests, slow down."
+        });
+
+        Response::build_from(body.respond_to(request)?)
+            .raw_header("Retry-After", seconds.max(1).to_string())
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn rate_limit_writes_per_client() {
+        let mut rate_limits = BTreeMap::new();
+
+        rate_limits.insert("ip".to_string(), Value::Integer(2));
+
+        let config = Config::build(Environment::Development)
+            .extra("rate_limits", Value::Table(rate_limits))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let script: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let neighbor: SocketAddr = "203.0.113.8:50000".parse().unwrap();
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .remote(script)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let increment = |remote: SocketAddr| {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .remote(remote)
+                .dispatch()
+        };
+
+        assert_eq!(increment(script).status(), Status::Ok);
+
+        let mut limited_response = increment(script);
+
+        assert_eq!(limited_response.status(), Status::TooManyRequests);
+        assert_eq!(
+            limited_response.headers().get_one("Retry-After"),
+            Some("30")
+        );
+        assert!(limited_response
+            .body_string()
+            .unwrap()
+            .contains("Too many requests"));
+        assert_eq!(increment(neighbor).status(), Status::Ok);
+    }
+}
@@ -0,0 +1,228 @@
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::lock;
+use crate::negotiation::{Body, OptionalBody};
+use crate::ratelimit::Throttle;
+use crate::routes::{create, increment, Annotation};
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::validation::Validate;
+use crate::visibility;
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+const DEFAULT_REACTIONS: [&str; 3] = ["👍", "❤️", "🎉"];
+const MAX_REACTIONS: usize = 16;
+const MAX_REACTION_LENGTH: usize = 32;
+
+/// Reaction bundles: a fixed, ordered set of reactions, each backed by a regular counter.
+#[derive(Default)]
+pub struct Bundles(Mutex<HashMap<Uuid, Vec<(String, Uuid)>>>);
+
+#[derive(Deserialize, Default)]
+pub struct NewBundle {
+    reactions: Option<Vec<String>>,
+}
+
+impl Validate for NewBundle {}
+
+#[derive(Deserialize)]
+pub struct Reaction {
+    reaction: String,
+}
+
+impl Validate for Reaction {}
+
+#[derive(Serialize)]
+pub struct Bundle {
+    id: Uuid,
+    reactions: Vec<Total>,
+}
+
+#[derive(Serialize)]
+struct Total {
+    reaction: String,
+    count: u32,
+    counter: Uuid,
+}
+
+impl Bundles {
+    fn view(&self, id: Uuid, store: &Store) -> Option<Bundle> {
+        let bundles = lock(&self.0);
+        let reactions = bundles.get(&id)?;
+        let counters = store.read();
+
+        Some(Bundle {
+            id,
+            reactions: reactions
+                .iter()
+                .map(|(reaction, counter)| Total {
+                    reaction: reaction.clone(),
+                    count: counters.get(counter).map_or(0, |counter| counter.value),
+                    counter: *counter,
+                })
+                .collect(),
+        })
+    }
+}
+
+fn parse_reactions(reactions: Option<Vec<String>>) -> Result<Vec<String>, ApiError> {
+    let reactions: Vec<String> = match reactions {
+        Some(reactions) => reactions
+            .into_iter()
+            .map(|reaction| reaction.trim().to_string())
+            .collect(),
+        None => DEFAULT_REACTIONS.iter().map(ToString::to_string).collect(),
+    };
+    let valid = !reactions.is_empty()
+        && reactions.len() <= MAX_REACTIONS
+        && reactions.iter().enumerate().all(|(index, reaction)| {
+            !reaction.is_empty()
+                && reaction.len() <= MAX_REACTION_LENGTH
+                && !reactions[..index].contains(reaction)
+        });
+
+    if valid {
+        Ok(reactions)
+    } else {
+        Err(error(
+            Status::BadRequest,
+            "Reactions must be 1 to 16 distinct, non-empty strings.",
+        ))
+    }
+}
+
+#[post("/", data = "<new_bundle>")]
+pub fn create_bundle(
+    new_bundle: OptionalBody<NewBundle>,
+    bundles: State<Bundles>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Bundle>, ApiError> {
+    dry_run.refuse()?;
+
+    let new_bundle = new_bundle.into_inner().unwrap_or_default();
+    let reactions = parse_reactions(new_bundle.reactions)?;
+    let id = Uuid::new_v4();
+    let mut counters = Vec::with_capacity(reactions.len());
+    let mut members = Vec::with_capacity(reactions.len());
+
+    for reaction in reactions {
+        let mut counter = Counter::new(Uuid::new_v4());
+
+        counter.name = Some(reaction.clone());
+        counter.tags.insert("reaction".to_string());
+        writer.check_scope(&counter)?;
+        counters.push((reaction, counter));
+    }
+
+    for (reaction, counter) in counters {
+        members.push((reaction, create(counter, None, &store)?.id));
+    }
+
+    lock(&bundles.0).insert(id, members);
+
+    Ok(Json(
+        bundles.view(id, &store).expect("bundle was just created"),
+    ))
+}
+
+#[get("/<id>")]
+pub fn get_bundle(
+    id: String,
+    bundles: State<Bundles>,
+    store: TenantStore,
+) -> Result<Json<Bundle>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    bundles
+        .view(parsed_uuid, &store)
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[put("/<id>/react", data = "<reaction>")]
+pub fn react(
+    id: String,
+    reaction: Body<Reaction>,
+    bundles: State<Bundles>,
+    store: TenantStore,
+    _throttle: Throttle,
+) -> Result<Json<Bundle>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let counter = {
+        let bundles = lock(&bundles.0);
+        let members = bundles.get(&parsed_uuid).ok_or_else(not_found_error)?;
+
+        members
+            .iter()
+            .find(|(name, _)| *name == reaction.reaction)
+            .map(|(_, counter)| *counter)
+            .ok_or_else(|| error(Status::BadRequest, "Unknown reaction."))?
+    };
+
+    visibility::check_beacon(counter, &store)?;
+    increment(counter, &[], Annotation::default(), &store)?;
+
+    bundles
+        .view(parsed_uuid, &store)
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::rocket;
+
+    #[test]
+    fn react_to_bundle() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/reactions")
+            .header(ContentType::JSON)
+            .body(r#"{ "reactions": ["👍", "🎉"] }"#)
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+
+        let bundle: serde_json::Value =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let id = bundle["id"].as_str().unwrap();
+
+        for reaction in &["🎉", "🎉", "👍"] {
+            let response = client
+                .put(format!("/reactions/{}/react", id))
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "reaction": "{}" }}"#, reaction))
+                .dispatch();
+
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let unknown_response = client
+            .put(format!("/reactions/{}/react", id))
+            .header(ContentType::JSON)
+            .body(r#"{ "reaction": "🙈" }"#)
+            .dispatch();
+
+        assert_eq!(unknown_response.status(), Status::BadRequest);
+
+        let mut response = client.get(format!("/reactions/{}", id)).dispatch();
+        let bundle: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(bundle["reactions"][0]["reaction"], "👍");
+        assert_eq!(bundle["reactions"][0]["count"], 1);
+        assert_eq!(bundle["reactions"][1]["count"], 2);
+    }
+}
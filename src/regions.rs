@@ -0,0 +1,838 @@
+//! Multi-region replication. With `region = "eu"`, `region_peers = { us = "https://us.example.com" }`
+//! and a `replication_secret` shared by every region, instances in different regions all take
+//! writes and send each other what changed every `replication_interval` seconds (5 by default),
+//! as batches signed like `X-Signature` requests under the secret.
+//!
+//! Counters merge without conflicts, as PN-counters. Each region only keeps running totals of
+//! what it added to and took from every counter, which never go down, and batches carry those
+//! totals rather than the changes, so merging keeps the larger of each and batches may arrive
+//! late, twice or out of order. A counter's value is what every region added less what they all
+//! took, kept between zero and the largest value once that sum is known rather than at every
+//! step, so that it does not depend on the order batches arrive in. A local change that a
+//! clamped value swallows is undone by the next `replication` run. `GET /counter/<id>/regions`
+//! lists every region's totals. Batches pass on what a region heard from the others too, so
+//! regions that cannot reach each other directly still agree. Reads answer from what the region
+//! has heard so far, which may be a few intervals behind the others; there is no strong read,
+//! as peers only push batches and cannot be asked for their totals.
+//!
+//! Only plain counters replicate, whose value nothing but changes to it moves: counters with
+//! bounds, a billing cycle, a burst pool, decay or distinct items stay in their region, as
+//! their values cannot be summed up from what each region did. Sets, resets and rollbacks of
+//! plain counters replicate as the change they make to the value, and frozen or archived
+//! counters still take changes merged from elsewhere.
+//!
+//! A restarted instance starts over as a new incarnation of its region, told apart by a random
+//! epoch, and gets the counters it had back from its peers. Only the default tenant's counter
+//! values replicate: names, settings and deletions stay in the region they were made in, and a
+//! counter first heard of in a batch is created bare. The totals of deleted counters, and of
+//! counters that stopped replicating, are dropped by the `replication` job; a deleted counter
+//! comes back if another region changes it again.
+
+use rocket::data::Data;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+use rocket_contrib::json::Json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::counter::Bounds;
+use crate::history::{Entry, Operation};
+use crate::jobs::{Jobs, Run};
+use crate::lock;
+use crate::signing;
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::{error, not_found_error, parse_id, ApiError, Counter, V1};
+
+const DEFAULT_INTERVAL: u64 = 5;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Contributions per batch, so that catching up a restarted peer takes a few rounds rather than
+/// one huge request.
+const MAX_BATCH: usize = 10_000;
+const MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// One incarnation of a region.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Origin {
+    region: String,
+    epoch: u64,
+}
+
+/// What one origin added to and took from a counter.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    up: u64,
+    down: u64,
+}
+
+impl Totals {
+    fn add(&mut self, delta: i64) {
+        if delta > 0 {
+            self.up += delta as u64;
+        } else {
+            self.down += (-delta) as u64;
+        }
+    }
+
+    /// Keeps the larger of each total.
+    fn merge(&mut self, other: Totals) {
+        self.up = self.up.max(other.up);
+        self.down = self.down.max(other.down);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Contribution {
+    counter: Uuid,
+    region: String,
+    epoch: u64,
+    #[serde(flatten)]
+    totals: Totals,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Batch {
+    /// The sender.
+    region: String,
+    epoch: u64,
+    contributions: Vec<Contribution>,
+}
+
+/// What a peer answers a batch with.
+#[derive(Serialize, Deserialize)]
+pub struct Receipt {
+    region: String,
+    epoch: u64,
+}
+
+struct Peer {
+    region: String,
+    url: String,
+}
+
+#[derive(Default)]
+struct Outbox {
+    /// Contributions changed since they were last sent.
+    dirty: HashSet<(Uuid, Origin)>,
+    /// The peer's epoch when it was last heard from, to tell when it restarted.
+    epoch: Option<u64>,
+}
+
+#[derive(Default)]
+struct Ledger {
+    totals: HashMap<Uuid, HashMap<Origin, Totals>>,
+    /// By peer region.
+    outboxes: HashMap<String, Outbox>,
+    /// Counters whose value a local change moved away from what their totals sum up to.
+    unsettled: HashSet<Uuid>,
+}
+
+/// Whether the counter's value is the sum of the changes made to it, so that it can replicate.
+fn replicates(counter: &Counter) -> bool {
+    counter.bounds == Bounds::default()
+        && counter.cycle.is_none()
+        && counter.burst.is_none()
+        && counter.decay.is_none()
+        && counter.distinct.is_none()
+}
+
+/// The value that the totals of every origin add up to, kept within what a value can be.
+fn value(origins: &HashMap<Origin, Totals>) -> u32 {
+    let net: i128 = origins
+        .values()
+        .map(|totals| i128::from(totals.up) - i128::from(totals.down))
+        .sum();
+
+    net.max(0).min(i128::from(u32::MAX)) as u32
+}
+
+impl Ledger {
+    /// The totals of `counter`, starting with its current `value` as this origin's for counters
+    /// that had one before they were first changed or merged here.
+    fn totals_of(
+        &mut self,
+        counter: Uuid,
+        value: u32,
+        origin: &Origin,
+    ) -> &mut HashMap<Origin, Totals> {
+        self.totals.entry(counter).or_insert_with(|| {
+            let mut origins = HashMap::new();
+
+            if value > 0 {
+                let mut totals = Totals::default();
+
+                totals.add(i64::from(value));
+                origins.insert(origin.clone(), totals);
+            }
+
+            origins
+        })
+    }
+
+    fn forget(&mut self, counter: &Uuid) {
+        self.totals.remove(counter);
+        self.unsettled.remove(counter);
+    }
+
+    /// Queues a contribution for every peer but `except`, which it came from.
+    fn mark(&mut self, counter: Uuid, origin: &Origin, except: Option<&str>) {
+        for (region, outbox) in &mut self.outboxes {
+            if Some(region.as_str()) != except {
+                outbox.dirty.insert((counter, origin.clone()));
+            }
+        }
+    }
+
+    /// Notes the epoch `region` is at, queueing everything for it again once it has restarted
+    /// with none of it.
+    fn heard_from(&mut self, region: &str, epoch: u64) {
+        let restarted = match self.outboxes.get_mut(region) {
+            Some(outbox) => outbox
+                .epoch
+                .replace(epoch)
+                .map_or(false, |known| known != epoch),
+            None => false,
+        };
+
+        if restarted {
+            let totals = &self.totals;
+            let outbox = self.outboxes.get_mut(region).expect("peer has an outbox");
+
+            for (counter, origins) in totals {
+                for origin in origins.keys() {
+                    outbox.dirty.insert((*counter, origin.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Cheaply cloneable handle to this region's replication state.
+#[derive(Clone)]
+pub struct Regions {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    origin: Origin,
+    secret: Vec<u8>,
+    peers: Vec<Peer>,
+    interval: Duration,
+    /// Taken with the shard of the counter at hand locked, when both are needed.
+    ledger: Mutex<Ledger>,
+}
+
+impl Regions {
+    /// Replication, if `region` and `replication_secret` are set.
+    pub fn from_config(config: &Config) -> Option<Regions> {
+        let region = config.get_str("region").ok()?.to_string();
+        let secret = config
+            .get_str("replication_secret")
+            .ok()?
+            .as_bytes()
+            .to_vec();
+        let peers: Vec<Peer> = config
+            .get_table("region_peers")
+            .map(|peers| {
+                peers
+                    .iter()
+                    .filter(|(name, _)| **name != region)
+                    .filter_map(|(name, url)| {
+                        Some(Peer {
+                            region: name.clone(),
+                            url: url.as_str()?.trim_end_matches('/').to_string(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut epoch = [0; 8];
+
+        epoch.copy_from_slice(&Uuid::new_v4().as_bytes()[..8]);
+
+        let ledger = Ledger {
+            totals: HashMap::new(),
+            outboxes: peers
+                .iter()
+                .map(|peer| (peer.region.clone(), Outbox::default()))
+                .collect(),
+            unsettled: HashSet::new(),
+        };
+        let shared = Shared {
+            origin: Origin {
+                region,
+                epoch: u64::from_le_bytes(epoch),
+            },
+            secret,
+            peers,
+            interval: Duration::from_secs(
+                config
+                    .get_int("replication_interval")
+                    .ok()
+                    .filter(|interval| *interval > 0)
+                    .map_or(DEFAULT_INTERVAL, |interval| interval as u64),
+            ),
+            ledger: Mutex::new(ledger),
+        };
+
+        Some(Regions {
+            shared: Arc::new(shared),
+        })
+    }
+
+    /// Adds the changes made to `store` from now on to this region's totals.
+    pub fn track(&self, store: &Store) {
+        let regions = self.clone();
+
+        store.on_change(move |counter, delta| regions.record(counter, delta));
+    }
+
+    /// Called by the store in the order of the changes, with no other change to the counter made
+    /// or merged meanwhile.
+    fn record(&self, counter: &Counter, delta: i64) {
+        if !replicates(counter) {
+            return;
+        }
+
+        let origin = &self.shared.origin;
+        let mut ledger = lock(&self.shared.ledger);
+        let before = (i64::from(counter.value) - delta) as u32;
+        let origins = ledger.totals_of(counter.id, before, origin);
+
+        origins.entry(origin.clone()).or_default().add(delta);
+
+        let settled = value(origins) == counter.value;
+
+        ledger.mark(counter.id, origin, None);
+
+        if !settled {
+            ledger.unsettled.insert(counter.id);
+        }
+    }
+
+    /// Merges the batch's totals into the known ones and sets the counters of `store` to what
+    /// they add up to. Contributions of counters there is no room for, or that do not
+    /// replicate here, are left out, to be merged once a later batch brings them again.
+    fn merge(&self, batch: Batch, store: &Store) {
+        let Batch {
+            region,
+            epoch,
+            contributions,
+        } = batch;
+
+        lock(&self.shared.ledger).heard_from(&region, epoch);
+
+        for contribution in contributions {
+            let id = contribution.counter;
+            let received = contribution.totals;
+            let origin = Origin {
+                region: contribution.region,
+                epoch: contribution.epoch,
+            };
+
+            if origin == self.shared.origin {
+                continue;
+            }
+
+            let _ = store.merge(id, |counters| {
+                if !counters.get(&id).map_or(true, replicates) {
+                    return;
+                }
+
+                let mut ledger = lock(&self.shared.ledger);
+                let current = counters.get(&id).map_or(0, |counter| counter.value);
+                let origins = ledger.totals_of(id, current, &self.shared.origin);
+                let totals = origins.entry(origin.clone()).or_default();
+                let known = *totals;
+
+                totals.merge(received);
+
+                if *totals == known {
+                    return;
+                }
+
+                let value = value(origins);
+                let counter = counters.entry(id).or_insert_with(|| Counter::new(id));
+
+                if counter.value != value {
+                    let moved = i64::from(value) - i64::from(counter.value);
+
+                    counter.set_value(value);
+                    store
+                        .history()
+                        .record(id, Entry::new(Operation::Merge, moved, value, None));
+                }
+
+                ledger.unsettled.remove(&id);
+                ledger.mark(id, &origin, Some(&region));
+            });
+        }
+    }
+
+    /// Sets counters that a local change moved away from the sum of their totals back to it,
+    /// and drops the totals of counters that were deleted or stopped replicating.
+    fn settle(&self, store: &Store) {
+        let (unsettled, known): (Vec<Uuid>, Vec<Uuid>) = {
+            let mut ledger = lock(&self.shared.ledger);
+
+            (
+                ledger.unsettled.drain().collect(),
+                ledger.totals.keys().cloned().collect(),
+            )
+        };
+
+        for id in unsettled.into_iter().filter(|id| store.contains(*id)) {
+            let _ = store.merge(id, |counters| {
+                let ledger = lock(&self.shared.ledger);
+                let value = match (counters.get_mut(&id), ledger.totals.get(&id)) {
+                    (Some(counter), Some(origins)) if replicates(counter) => {
+                        let value = value(origins);
+
+                        if counter.value == value {
+                            return;
+                        }
+
+                        let moved = i64::from(value) - i64::from(counter.value);
+
+                        counter.set_value(value);
+                        (moved, value)
+                    }
+                    _ => return,
+                };
+
+                store
+                    .history()
+                    .record(id, Entry::new(Operation::Merge, value.0, value.1, None));
+            });
+        }
+
+        let gone: Vec<Uuid> = known
+            .into_iter()
+            .filter(|id| !store.get(*id).map_or(false, |counter| replicates(&counter)))
+            .collect();
+
+        if !gone.is_empty() {
+            let mut ledger = lock(&self.shared.ledger);
+
+            for id in &gone {
+                ledger.forget(id);
+            }
+        }
+    }
+
+    /// Sends every peer what changed since it was last sent, as the `replication` job, after
+    /// settling the counters of `store`. Peers that cannot be reached get it again with the
+    /// next batch.
+    pub fn replicate(&self, jobs: &Jobs, store: &Store) {
+        if self.shared.peers.is_empty() {
+            return;
+        }
+
+        let regions = self.clone();
+        let store = store.clone();
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("Failed to build the replication client");
+        let mut failing = HashSet::new();
+
+        jobs.every("replication", self.shared.interval, move || {
+            let mut errors = Vec::new();
+
+            regions.settle(&store);
+
+            for peer in &regions.shared.peers {
+                match regions.send(&client, peer) {
+                    Ok(()) => {
+                        if failing.remove(&peer.region) {
+                            println!("Replicating to {} again", peer.region);
+                        }
+                    }
+                    Err(error) => {
+                        if failing.insert(peer.region.clone()) {
+                            eprintln!("Could not replicate to {}: {}", peer.region, error);
+                        }
+
+                        errors.push(format!("{}: {}", peer.region, error));
+                    }
+                }
+            }
+
+            if errors.is_empty() {
+                Run::Done
+            } else {
+                Run::Failed(errors.join(", "))
+            }
+        });
+    }
+
+    /// Sends one batch, which may be empty, to learn whether the peer restarted.
+    fn send(&self, client: &reqwest::Client, peer: &Peer) -> Result<(), String> {
+        let (keys, batch) = self.take(&peer.region);
+        let result = self.post(client, peer, &batch);
+        let mut ledger = lock(&self.shared.ledger);
+
+        match result {
+            Ok(receipt) => {
+                ledger.heard_from(&peer.region, receipt.epoch);
+                Ok(())
+            }
+            Err(error) => {
+                if let Some(outbox) = ledger.outboxes.get_mut(&peer.region) {
+                    outbox.dirty.extend(keys);
+                }
+
+                Err(error)
+            }
+        }
+    }
+
+    /// Up to `MAX_BATCH` of the contributions queued for `region`, taken off its queue.
+    fn take(&self, region: &str) -> (Vec<(Uuid, Origin)>, Batch) {
+        let mut ledger = lock(&self.shared.ledger);
+        let keys: Vec<(Uuid, Origin)> = match ledger.outboxes.get_mut(region) {
+            Some(outbox) => {
+                let keys: Vec<(Uuid, Origin)> =
+                    outbox.dirty.iter().take(MAX_BATCH).cloned().collect();
+
+                for key in &keys {
+                    outbox.dirty.remove(key);
+                }
+
+                keys
+            }
+            None => Vec::new(),
+        };
+        let contributions = keys
+            .iter()
+            .filter_map(|(counter, origin)| {
+                let totals = *ledger.totals.get(counter)?.get(origin)?;
+
+                Some(Contribution {
+                    counter: *counter,
+                    region: origin.region.clone(),
+                    epoch: origin.epoch,
+                    totals,
+                })
+            })
+            .collect();
+        let origin = &self.shared.origin;
+
+        (
+            keys,
+            Batch {
+                region: origin.region.clone(),
+                epoch: origin.epoch,
+                contributions,
+            },
+        )
+    }
+
+    fn post(
+        &self,
+        client: &reqwest::Client,
+        peer: &Peer,
+        batch: &Batch,
+    ) -> Result<Receipt, String> {
+        let body = serde_json::to_vec(batch).map_err(|error| error.to_string())?;
+        let mut response = client
+            .post(&format!("{}{}/replication/batch", peer.url, V1))
+            .header("Content-Type", "application/json")
+            .header("X-Signature", signing::sign(&self.shared.secret, &body))
+            .body(body)
+            .send()
+            .map_err(|error| error.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("the peer answered {}", response.status()));
+        }
+
+        response.json().map_err(|error| error.to_string())
+    }
+
+    /// Every region's totals for a counter, the incarnations of each summed.
+    fn totals(&self, counter: Uuid) -> BTreeMap<String, Totals> {
+        let ledger = lock(&self.shared.ledger);
+        let mut regions: BTreeMap<String, Totals> = BTreeMap::new();
+
+        for (origin, totals) in ledger.totals.get(&counter).into_iter().flatten() {
+            let sum = regions.entry(origin.region.clone()).or_default();
+
+            sum.up += totals.up;
+            sum.down += totals.down;
+        }
+
+        regions
+    }
+}
+
+/// The `X-Signature` header of a batch.
+pub struct Signature(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Signature {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Signature, ()> {
+        Outcome::Success(Signature(
+            request.headers().get_one("X-Signature").map(String::from),
+        ))
+    }
+}
+
+fn not_configured() -> ApiError {
+    error(Status::NotFound, "Replication is not configured.")
+}
+
+/// Where peers send their batches, answered with this region's epoch.
+#[post("/batch", data = "<batch>")]
+pub fn receive(
+    batch: Data,
+    signature: Signature,
+    regions: Option<State<Regions>>,
+    store: State<Store>,
+) -> Result<Json<Receipt>, ApiError> {
+    let regions = regions.ok_or_else(not_configured)?;
+    let mut body = Vec::new();
+
+    batch
+        .open()
+        .take(MAX_BYTES)
+        .read_to_end(&mut body)
+        .map_err(|_| error(Status::BadRequest, "Could not read the request."))?;
+
+    let signed = signature.0.map_or(false, |header| {
+        signing::verify_header(
+            &regions.shared.secret,
+            &header,
+            &body,
+            signing::DEFAULT_TOLERANCE,
+        )
+    });
+
+    if !signed {
+        return Err(error(
+            Status::Unauthorized,
+            "Batch signature is missing or invalid.",
+        ));
+    }
+
+    let batch: Batch = serde_json::from_slice(&body)
+        .map_err(|_| error(Status::BadRequest, "Could not parse the batch."))?;
+
+    regions.merge(batch, &store);
+
+    let origin = &regions.shared.origin;
+
+    Ok(Json(Receipt {
+        region: origin.region.clone(),
+        epoch: origin.epoch,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct RegionTotals {
+    up: u64,
+    down: u64,
+    net: i64,
+}
+
+#[derive(Serialize)]
+pub struct Contributions {
+    /// This instance's region.
+    region: String,
+    value: u32,
+    regions: BTreeMap<String, RegionTotals>,
+}
+
+/// What every region added to and took from a counter since replication started.
+#[get("/<id>/regions")]
+pub fn get_regions(
+    id: String,
+    regions: Option<State<Regions>>,
+    store: TenantStore,
+) -> Result<Json<Contributions>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let regions = regions.ok_or_else(not_configured)?;
+
+    if !store.tenant().is_empty() {
+        return Err(error(
+            Status::NotFound,
+            "Only the default tenant's counters replicate.",
+        ));
+    }
+
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    Ok(Json(Contributions {
+        region: regions.shared.origin.region.clone(),
+        value: counter.value,
+        regions: regions
+            .totals(parsed_uuid)
+            .into_iter()
+            .map(|(region, totals)| {
+                let net = totals.up as i64 - totals.down as i64;
+
+                (
+                    region,
+                    RegionTotals {
+                        up: totals.up,
+                        down: totals.down,
+                        net,
+                    },
+                )
+            })
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn merge_batches_from_other_regions() {
+        let config = Config::build(Environment::Development)
+            .extra("region", "eu")
+            .extra("replication_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .dispatch();
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .dispatch();
+
+        let elsewhere = uuid::Uuid::new_v4();
+        let batch = format!(
+            r#"{{ "region": "us", "epoch": 7, "contributions": [
+                {{ "counter": "{}", "region": "us", "epoch": 7, "up": 5, "down": 1 }},
+                {{ "counter": "{}", "region": "ap", "epoch": 3, "up": 3, "down": 0 }}
+            ] }}"#,
+            counter.id, elsewhere
+        );
+        let send = |signature: String| {
+            client
+                .post("/replication/batch")
+                .header(ContentType::JSON)
+                .header(Header::new("X-Signature", signature))
+                .body(&batch)
+                .dispatch()
+                .status()
+        };
+        let value = |id: uuid::Uuid| {
+            let mut response = client.get(format!("/counter/{}", id)).dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            counter.value
+        };
+
+        assert_eq!(
+            send(crate::signing::sign(b"wrong", batch.as_bytes())),
+            Status::Unauthorized
+        );
+        assert_eq!(value(counter.id), 2);
+
+        // Batches carry totals, so the same one twice counts once.
+        for _ in 0..2 {
+            assert_eq!(
+                send(crate::signing::sign(b"s3cret", batch.as_bytes())),
+                Status::Ok
+            );
+        }
+
+        assert_eq!(value(counter.id), 6);
+        assert_eq!(value(elsewhere), 3);
+
+        let mut response = client
+            .get(format!("/counter/{}/regions", counter.id))
+            .dispatch();
+        let regions: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(regions["region"], "eu");
+        assert_eq!(regions["regions"]["eu"]["net"], 2);
+        assert_eq!(regions["regions"]["us"]["up"], 5);
+        assert_eq!(regions["regions"]["us"]["net"], 4);
+    }
+
+    #[test]
+    fn merge_regions_regardless_of_order() {
+        let config = Config::build(Environment::Development)
+            .extra("region", "eu")
+            .extra("replication_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let create = |body: &str| {
+            let mut response = client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            counter.id
+        };
+        let send = |counter: uuid::Uuid, up: u64, down: u64| {
+            let batch = format!(
+                r#"{{ "region": "us", "epoch": 7, "contributions": [
+                    {{ "counter": "{}", "region": "us", "epoch": 7, "up": {}, "down": {} }}
+                ] }}"#,
+                counter, up, down
+            );
+
+            client
+                .post("/replication/batch")
+                .header(ContentType::JSON)
+                .header(Header::new(
+                    "X-Signature",
+                    crate::signing::sign(b"s3cret", batch.as_bytes()),
+                ))
+                .body(batch)
+                .dispatch()
+                .status()
+        };
+        let value = |id: uuid::Uuid| {
+            let mut response = client.get(format!("/counter/{}", id)).dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            counter.value
+        };
+        let (first, second) = (create("{}"), create("{}"));
+
+        // The value is clamped once the totals are summed up, not at every batch.
+        assert_eq!(send(first, 0, 5), Status::Ok);
+        assert_eq!(send(first, 5, 5), Status::Ok);
+        assert_eq!(send(second, 5, 5), Status::Ok);
+        assert_eq!(send(second, 0, 5), Status::Ok);
+        assert_eq!(value(first), 0);
+        assert_eq!(value(second), 0);
+
+        // Counters with bounds stay in their region.
+        let bounded = create(r#"{ "bounds": { "max": 10 } }"#);
+
+        assert_eq!(send(bounded, 3, 0), Status::Ok);
+        assert_eq!(value(bounded), 0);
+
+        let mut response = client
+            .get(format!("/counter/{}/regions", bounded))
+            .dispatch();
+        let regions: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert!(regions["regions"].as_object().unwrap().is_empty());
+    }
+}
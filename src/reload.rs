@@ -0,0 +1,144 @@
+//! Reloading the configuration without a restart, which would lose every counter. `POST
+//! /admin/reload` reads API keys, rate limits and CORS origins again, from the same places as
+//! at launch: Rocket.toml, overridden by `ROCKET_*` environment variables. Everything else
+//! keeps the value it launched with. Settings from a `--config` file were turned into
+//! environment variables at launch, so they stay as they were.
+
+use rocket::config::{Environment, Value};
+use rocket::http::Status;
+use rocket::{Config, State};
+use rocket_contrib::json::JsonValue;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::auth::{ApiKeys, Writer};
+use crate::cli;
+use crate::cors::Cors;
+use crate::dry_run::DryRun;
+use crate::negotiation::Unused;
+use crate::ratelimit::RateLimits;
+use crate::{error, ApiError};
+
+const FILE_NAME: &str = "Rocket.toml";
+
+/// Rocket.toml in the working directory or the nearest directory above it, as Rocket finds it.
+fn find_file() -> Option<PathBuf> {
+    let directory = env::current_dir().ok()?;
+
+    directory
+        .ancestors()
+        .map(|directory| directory.join(FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// An environment variable's value, which Rocket reads as a TOML value or else as a string.
+fn parse_variable(raw: String) -> Value {
+    format!("value = {}", raw)
+        .parse::<Value>()
+        .ok()
+        .and_then(|document| document.get("value").cloned())
+        .unwrap_or(Value::String(raw))
+}
+
+/// The configuration as it would be read at launch now.
+fn read_config() -> Result<Config, String> {
+    let environment = Environment::active().map_err(|error| error.to_string())?;
+    let mut settings = match find_file() {
+        Some(path) => {
+            let document = fs::read_to_string(&path)
+                .map_err(|error| format!("Could not read {}: {}", path.display(), error))?
+                .parse::<Value>()
+                .map_err(|error| format!("Could not parse {}: {}", path.display(), error))?;
+
+            cli::settings(&document, environment)
+        }
+        None => Default::default(),
+    };
+
+    for (variable, raw) in env::vars() {
+        if variable.starts_with("ROCKET_") && variable != "ROCKET_ENV" {
+            let key = variable["ROCKET_".len()..].to_lowercase();
+
+            settings.insert(key, parse_variable(raw));
+        }
+    }
+
+    settings
+        .into_iter()
+        .fold(Config::build(environment), |config, (key, value)| {
+            config.extra(&key, value)
+        })
+        .finalize()
+        .map_err(|error| error.to_string())
+}
+
+#[post("/reload", data = "<_body>")]
+pub fn reload(
+    api_keys: State<ApiKeys>,
+    rate_limits: State<RateLimits>,
+    cors: State<Cors>,
+    dry_run: DryRun,
+    writer: Writer,
+    _body: Unused,
+) -> Result<JsonValue, ApiError> {
+    dry_run.refuse()?;
+    writer.check_admin()?;
+
+    let config = read_config().map_err(|reason| error(Status::InternalServerError, &reason))?;
+
+    // First, as the only part that can be invalid, so that a bad file changes nothing.
+    cors.reload(&config)
+        .map_err(|reason| error(Status::InternalServerError, &reason))?;
+    api_keys.reload(&config);
+    rate_limits.reload(&config);
+
+    Ok(json!({
+        "status": "ok",
+        "reloaded": ["api_keys", "rate_limits", "cors_origins"]
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket};
+
+    #[test]
+    fn reload_configuration() {
+        let mut key = BTreeMap::new();
+        let mut api_keys = BTreeMap::new();
+
+        key.insert("name".to_string(), Value::String("ops".to_string()));
+        key.insert("role".to_string(), Value::String("admin".to_string()));
+        api_keys.insert("a".to_string(), Value::Table(key));
+
+        let config = Config::build(Environment::Development)
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let create = || {
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(create(), Status::Unauthorized);
+
+        let response = client
+            .post("/admin/reload")
+            .header(Header::new("X-Api-Key", "a"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        // Rocket.toml configures no API keys, so writes are open once it is read again.
+        assert_eq!(create(), Status::Ok);
+    }
+}
@@ -0,0 +1,291 @@
+//! Read replicas. With `follow = "http://leader:8000"`, an instance keeps a copy of the
+//! leader's counters, refreshed every `follow_interval` seconds, and serves reads from it.
+//! Writes are redirected to the leader with 307 Temporary Redirect, and reads that count, such
+//! as hits, are answered with 503. Polls are conditional on the listing's `ETag`, so the leader
+//! answers them with an empty 304 while nothing changes.
+//!
+//! Only the default tenant's counters are copied. History, time series, webhooks and schedules
+//! stay with the leader.
+
+use rocket::data::Data;
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::{Config, Outcome, State};
+use std::time::Duration;
+
+use crate::jobs::{Jobs, Run};
+use crate::store::Store;
+use crate::{Counter, V1};
+
+/// Where writes are sent instead.
+pub const READ_ONLY_PATH: &str = "/read-only";
+
+const DEFAULT_INTERVAL: u64 = 1;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The instance this one follows.
+#[derive(Clone)]
+pub struct Leader {
+    url: String,
+    interval: Duration,
+    /// Sent as `X-Api-Key`, for leaders that require one.
+    api_key: Option<String>,
+}
+
+impl Leader {
+    pub fn from_config(config: &Config) -> Option<Leader> {
+        let url = config.get_str("follow").ok()?;
+
+        Some(Leader {
+            url: url.trim_end_matches('/').to_string(),
+            interval: Duration::from_secs(
+                config
+                    .get_int("follow_interval")
+                    .ok()
+                    .filter(|interval| *interval > 0)
+                    .map_or(DEFAULT_INTERVAL, |interval| interval as u64),
+            ),
+            api_key: config.get_str("follow_api_key").ok().map(String::from),
+        })
+    }
+
+    /// Copies the leader's counters into `store` every interval, as the `replica` job.
+    pub fn follow(&self, store: &Store, jobs: &Jobs) {
+        let leader = self.clone();
+        let store = store.clone();
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .expect("Failed to build the replica client");
+        let mut etag = None;
+        let mut failing = false;
+
+        jobs.every("replica", self.interval, move || {
+            match leader.poll(&client, &mut etag, &store) {
+                Ok(()) => {
+                    if failing {
+                        println!("Following {} again", leader.url);
+                        failing = false;
+                    }
+
+                    Run::Done
+                }
+                Err(error) => {
+                    if !failing {
+                        eprintln!("Could not follow {}: {}", leader.url, error);
+                        failing = true;
+                    }
+
+                    Run::Failed(error)
+                }
+            }
+        });
+    }
+
+    fn poll(
+        &self,
+        client: &reqwest::Client,
+        etag: &mut Option<String>,
+        store: &Store,
+    ) -> Result<(), String> {
+        let mut request = client.get(&format!("{}{}/counter?include_archived=true", self.url, V1));
+
+        if let Some(etag) = etag.as_ref() {
+            request = request.header("If-None-Match", etag.as_str());
+        }
+
+        if let Some(api_key) = &self.api_key {
+            request = request.header("X-Api-Key", api_key.as_str());
+        }
+
+        let mut response = request.send().map_err(|error| error.to_string())?;
+
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(());
+        }
+
+        if !response.status().is_success() {
+            return Err(format!("the leader answered {}", response.status()));
+        }
+
+        let latest = response
+            .headers()
+            .get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        let counters: Vec<Counter> = response.json().map_err(|error| error.to_string())?;
+
+        store.restore(
+            counters
+                .into_iter()
+                .map(|counter| (counter.id, counter))
+                .collect(),
+        );
+        *etag = latest;
+        Ok(())
+    }
+}
+
+/// The path and query of a write that was sent to `READ_ONLY_PATH`.
+struct Redirected(Option<String>);
+
+/// Sends writes to `READ_ONLY_PATH` when following a leader.
+pub fn redirect_writes(request: &mut Request, _: &Data) {
+    let write = match request.method() {
+        Method::Get | Method::Head | Method::Options => false,
+        _ => true,
+    };
+
+    if !write || !is_replica(request) {
+        return;
+    }
+
+    let uri = request.uri().to_string();
+
+    request.local_cache(|| Redirected(Some(uri)));
+    request.set_method(Method::Get);
+    request.set_uri(Origin::parse(READ_ONLY_PATH).unwrap());
+}
+
+/// Guard that only lets redirected writes through, so that asking for `READ_ONLY_PATH` directly
+/// finds nothing.
+pub struct Write(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Write {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Write, ()> {
+        match &request.local_cache(|| Redirected(None)).0 {
+            Some(uri) => Outcome::Success(Write(uri.clone())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Whether the instance follows a leader, for guards of reads that change counters.
+pub fn is_replica(request: &Request) -> bool {
+    request.guard::<State<Leader>>().succeeded().is_some()
+}
+
+pub struct ToLeader(String);
+
+impl<'r> Responder<'r> for ToLeader {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(
+            json!({
+                "status": "error",
+                "reason": "This instance is a read-only replica."
+            })
+            .respond_to(request)?,
+        )
+        .status(Status::TemporaryRedirect)
+        .raw_header("Location", self.0)
+        .ok()
+    }
+}
+
+#[get("/")]
+pub fn read_only(write: Write, leader: State<Leader>) -> ToLeader {
+    ToLeader(format!("{}{}", leader.url, write.0))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn follow_leader() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let leader = format!("http://{}", listener.local_addr().unwrap());
+        let mut counter = Counter::new(uuid::Uuid::new_v4());
+        let (sender, requests) = mpsc::channel();
+
+        counter.set_value(3);
+
+        let body = serde_json::to_string(&vec![&counter]).unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nETag: \"1\"\r\n\
+                         Content-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            sender
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .unwrap();
+        });
+
+        let config = Config::build(Environment::Development)
+            .extra("follow", leader.clone())
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(request.starts_with("GET /v1/counter?include_archived=true "));
+
+        let mut followed = false;
+
+        for _ in 0..50 {
+            if client
+                .get(format!("/counter/{}", counter.id))
+                .dispatch()
+                .status()
+                == Status::Ok
+            {
+                followed = true;
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert!(followed);
+
+        let response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        let location = format!("{}/counter/{}/increment", leader, counter.id);
+
+        assert_eq!(response.status(), Status::TemporaryRedirect);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some(location.as_str())
+        );
+
+        let response = client
+            .get(format!("/counter/{}/hit", counter.id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+    }
+}
@@ -0,0 +1,94 @@
+//! An id for every request, so that a user's report can be matched to the logs. A client's
+//! own `X-Request-Id` is kept when it looks sane; otherwise one is generated. Either way it is
+//! echoed back in `X-Request-Id` and included in error bodies.
+
+use rocket::http::{ContentType, StatusClass};
+use rocket::request::Request;
+use rocket::response::Response;
+use std::io::Cursor;
+use uuid::Uuid;
+
+const HEADER: &str = "X-Request-Id";
+const MAX_LENGTH: usize = 128;
+
+struct RequestId(String);
+
+fn valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// The request's id.
+pub fn of<'r>(request: &'r Request) -> &'r str {
+    &request
+        .local_cache(|| {
+            RequestId(
+                request
+                    .headers()
+                    .get_one(HEADER)
+                    .filter(|id| valid(id))
+                    .map_or_else(|| Uuid::new_v4().to_simple().to_string(), String::from),
+            )
+        })
+        .0
+}
+
+/// Echoes the id back, and adds it to JSON error bodies as `request_id`.
+pub fn annotate_response(request: &Request, response: &mut Response) {
+    let id = of(request);
+
+    response.set_raw_header(HEADER, id.to_string());
+
+    let failed = match response.status().class() {
+        StatusClass::ClientError | StatusClass::ServerError => true,
+        _ => false,
+    };
+
+    if !failed || response.content_type() != Some(ContentType::JSON) {
+        return;
+    }
+
+    let body = match response.body_bytes() {
+        Some(body) => body,
+        None => return,
+    };
+    let annotated = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(serde_json::Value::Object(mut error)) => {
+            error.insert("request_id".to_string(), id.into());
+            serde_json::to_vec(&error).ok()
+        }
+        _ => None,
+    };
+
+    response.set_sized_body(Cursor::new(annotated.unwrap_or(body)));
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use crate::rocket;
+
+    #[test]
+    fn echo_request_ids() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let response = client.get("/counter").dispatch();
+        let generated = response.headers().get_one("X-Request-Id").unwrap();
+
+        assert_eq!(generated.len(), 32);
+
+        let mut error_response = client
+            .get("/counter/not-an-id")
+            .header(Header::new("X-Request-Id", "support-ticket-42"))
+            .dispatch();
+
+        assert_eq!(
+            error_response.headers().get_one("X-Request-Id"),
+            Some("support-ticket-42")
+        );
+        assert!(error_response
+            .body_string()
+            .unwrap()
+            .contains(r#""request_id":"support-ticket-42""#));
+    }
+}
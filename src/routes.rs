@@ -0,0 +1,2269 @@
+//! The routes under `/counter` that create, read and change counters one at a time or list
+//! them, with the bodies and queries they take, and the admin routes that report on the store.
+
+use rocket::http::Status;
+use rocket::request::{self, FormItems, FromRequest, LenientForm, Request};
+use rocket::response::{self, status, Responder, Response};
+use rocket::{Outcome, State};
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+use crate::auth::{Admin, Caller, Reader, WriteTokens, Writer};
+use crate::conditional::Conditional;
+use crate::counter::{default_step, Bounds, Counter};
+use crate::cycle::{Burst, Cycle, Period};
+use crate::decay::Decay;
+use crate::deferred::{Deferred, Reply};
+use crate::deprecation::{Deprecations, Report};
+use crate::distinct::Distinct;
+use crate::features::{Feature, Features};
+use crate::history::{Entry, Filter, Operation, Page, Rate};
+use crate::hit::{Hit, Visitor};
+use crate::listing::{Listing, Listings};
+use crate::negotiation::{Body, OptionalBody, Unused};
+use crate::ratelimit::Throttle;
+use crate::store::{ContentionReport, Store};
+use crate::templates::Templates;
+use crate::tenancy::TenantStore;
+use crate::timeseries::{Rollup, Sample, Window};
+use crate::validation::{Validate, Violations};
+use crate::visibility::Visibility;
+use crate::{aliases, error, history, not_found_error, parse_id, visibility, ApiError};
+
+/// Optional body of mutating requests. Increments and decrements may also say when they
+/// happened, in milliseconds since the Unix epoch, for clients that queue changes while offline
+/// and send them later: the history entry then carries that time instead of the arrival time.
+#[derive(Deserialize, Default)]
+pub struct Annotation {
+    pub(crate) reason: Option<String>,
+    pub(crate) occurred_at: Option<u64>,
+}
+
+impl Validate for Annotation {
+    fn validate(&self, violations: &mut Violations) {
+        violations.reason(&self.reason);
+    }
+}
+
+impl Annotation {
+    /// The annotation of an increment or decrement. Fails with 400 if it happened in the
+    /// future.
+    fn of_change(annotation: OptionalBody<Annotation>) -> Result<Annotation, ApiError> {
+        let annotation = annotation.into_inner().unwrap_or_default();
+
+        match annotation.occurred_at {
+            Some(occurred_at) if occurred_at > history::timestamp() => Err(error(
+                Status::BadRequest,
+                "occurred_at cannot be in the future.",
+            )),
+            _ => Ok(annotation),
+        }
+    }
+}
+
+pub fn reason(annotation: OptionalBody<Annotation>) -> Option<String> {
+    annotation
+        .into_inner()
+        .and_then(|annotation| annotation.reason)
+}
+
+#[derive(Deserialize, Default)]
+pub struct NewCounter {
+    /// Defaults to a random id, or one derived from `key`.
+    pub(crate) id: Option<String>,
+    /// Name to address the counter by in paths, as `key:<key>`, instead of its id.
+    pub(crate) key: Option<String>,
+    /// Starting value, 0 by default.
+    pub(crate) value: Option<u32>,
+    pub(crate) reason: Option<String>,
+    pub(crate) name: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) tags: Option<Vec<String>>,
+    pub(crate) bounds: Option<Bounds>,
+    pub(crate) cycle: Option<NewCycle>,
+    /// Size of the burst pool, which requires a cycle and an upper bound at least as large.
+    pub(crate) burst: Option<u32>,
+    pub(crate) decay: Option<NewDecay>,
+    /// Count distinct items instead of steps.
+    pub(crate) distinct: Option<bool>,
+    pub(crate) visibility: Option<Visibility>,
+}
+
+impl Validate for NewCounter {
+    fn validate(&self, violations: &mut Violations) {
+        violations.reason(&self.reason);
+        violations.key(&self.key);
+        violations.name(&self.name);
+        violations.description(&self.description);
+        violations.tags(&self.tags);
+        violations.check(
+            self.decay
+                .as_ref()
+                .map_or(true, |decay| decay.half_life > 0),
+            "decay.half_life",
+            "must be at least 1",
+        );
+    }
+}
+
+/// A new counter, with its write token if it has one. This is the only time the token is shown.
+#[derive(Serialize)]
+pub struct Created {
+    #[serde(flatten)]
+    pub(crate) counter: Counter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) write_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NewDecay {
+    /// Seconds for the score to halve in.
+    pub(crate) half_life: u64,
+}
+
+#[derive(Deserialize)]
+pub struct NewCycle {
+    pub(crate) period: Period,
+    /// Milliseconds since the Unix epoch, defaults to now.
+    pub(crate) anchor: Option<u64>,
+}
+
+/// Fields to change; an empty `name` or `description` clears it, and `bounds` replaces both
+/// limits at once so `{}` removes them.
+#[derive(Deserialize)]
+pub struct CounterPatch {
+    pub(crate) reason: Option<String>,
+    pub(crate) name: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) tags: Option<Vec<String>>,
+    pub(crate) step: Option<u32>,
+    pub(crate) bounds: Option<Bounds>,
+}
+
+impl Validate for CounterPatch {
+    fn validate(&self, violations: &mut Violations) {
+        violations.reason(&self.reason);
+        violations.name(&self.name);
+        violations.description(&self.description);
+        violations.tags(&self.tags);
+        violations.positive("step", self.step);
+    }
+}
+
+/// Everything `PUT /counter/<id>` sets. Omitted fields are reset, except the value, which is
+/// kept when the counter exists.
+#[derive(Deserialize)]
+pub struct DesiredCounter {
+    pub(crate) reason: Option<String>,
+    pub(crate) value: Option<u32>,
+    pub(crate) name: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) tags: Option<Vec<String>>,
+    pub(crate) step: Option<u32>,
+    pub(crate) bounds: Option<Bounds>,
+    pub(crate) visibility: Option<Visibility>,
+}
+
+impl Validate for DesiredCounter {
+    fn validate(&self, violations: &mut Violations) {
+        violations.reason(&self.reason);
+        violations.name(&self.name);
+        violations.description(&self.description);
+        violations.tags(&self.tags);
+        violations.positive("step", self.step);
+    }
+}
+
+pub fn non_empty(text: String) -> Option<String> {
+    let trimmed = text.trim();
+
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed.to_string())
+    }
+}
+
+pub const MAX_TAG_LENGTH: usize = 64;
+
+pub fn parse_tags(tags: Vec<String>) -> Result<BTreeSet<String>, ApiError> {
+    tags.into_iter()
+        .map(|tag| {
+            let tag = tag.trim();
+
+            if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+                Err(error(
+                    Status::BadRequest,
+                    "Tags must be between 1 and 64 characters long.",
+                ))
+            } else {
+                Ok(tag.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Every `tag` query parameter of a request; counters must carry all of them to match.
+pub struct TagFilter(pub(crate) Vec<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for TagFilter {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<TagFilter, ()> {
+        let tags = request
+            .uri()
+            .query()
+            .map(|query| {
+                FormItems::from(query)
+                    .filter(|item| item.key.as_str() == "tag")
+                    .map(|item| item.value.url_decode_lossy())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Outcome::Success(TagFilter(tags))
+    }
+}
+
+impl TagFilter {
+    pub(crate) fn matches(&self, counter: &Counter) -> bool {
+        self.0.iter().all(|tag| counter.tags.contains(tag))
+    }
+}
+
+#[derive(FromForm)]
+pub struct ListQuery {
+    pub(crate) include_archived: Option<bool>,
+    pub(crate) min_value: Option<u32>,
+    pub(crate) max_value: Option<u32>,
+    pub(crate) sort: Option<String>,
+    pub(crate) order: Option<String>,
+    pub(crate) offset: Option<usize>,
+    pub(crate) limit: Option<usize>,
+    /// Only counters owned by the caller.
+    pub(crate) mine: Option<bool>,
+}
+
+impl ListQuery {
+    fn matches(&self, counter: &Counter) -> bool {
+        (self.include_archived.unwrap_or(false) || !counter.archived)
+            && self.min_value.map_or(true, |min| counter.value >= min)
+            && self.max_value.map_or(true, |max| counter.value <= max)
+    }
+
+    fn sort(&self, counters: &mut [Counter]) -> Result<(), ApiError> {
+        match self.sort.as_deref() {
+            None | Some("id") => counters.sort_by_key(|counter| counter.id),
+            Some("value") => counters.sort_by_key(|counter| (counter.value, counter.id)),
+            Some("created_at") => counters.sort_by_key(|counter| (counter.created_at, counter.id)),
+            Some("name") => counters.sort_by(|a, b| (&a.name, a.id).cmp(&(&b.name, b.id))),
+            Some("score") => counters.sort_by(|a, b| {
+                let score = |counter: &Counter| counter.decay.map_or(0.0, |decay| decay.score);
+
+                score(a)
+                    .partial_cmp(&score(b))
+                    .unwrap_or(Ordering::Equal)
+                    .then(a.id.cmp(&b.id))
+            }),
+            Some(_) => {
+                return Err(error(
+                    Status::BadRequest,
+                    "Sort must be one of id, value, created_at, name or score.",
+                ))
+            }
+        }
+
+        match self.order.as_deref() {
+            None | Some("asc") => Ok(()),
+            Some("desc") => {
+                counters.reverse();
+                Ok(())
+            }
+            Some(_) => Err(error(Status::BadRequest, "Order must be asc or desc.")),
+        }
+    }
+
+    /// Whether the query asks for nothing but the default listing, which is cached.
+    fn is_default(&self) -> bool {
+        !self.include_archived.unwrap_or(false)
+            && self.min_value.is_none()
+            && self.max_value.is_none()
+            && self.sort.as_ref().map_or(true, |sort| sort == "id")
+            && self.order.as_ref().map_or(true, |order| order == "asc")
+            && self.offset.unwrap_or(0) == 0
+            && self.limit.is_none()
+            && !self.mine.unwrap_or(false)
+    }
+}
+
+/// A page of a listing, with the size of the whole listing in `X-Total-Count`.
+pub struct Paginated<T> {
+    pub(crate) items: Vec<T>,
+    pub(crate) total: usize,
+    pub(crate) last_modified: Option<u64>,
+}
+
+impl<T> Paginated<T> {
+    fn new(
+        items: Vec<T>,
+        offset: Option<usize>,
+        limit: Option<usize>,
+        last_modified: Option<u64>,
+    ) -> Paginated<T> {
+        let total = items.len();
+        let items = items
+            .into_iter()
+            .skip(offset.unwrap_or(0))
+            .take(limit.unwrap_or(usize::max_value()))
+            .collect();
+
+        Paginated {
+            items,
+            total,
+            last_modified,
+        }
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r> for Paginated<T> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let page = Conditional::new(self.items, self.last_modified);
+
+        Response::build_from(page.respond_to(request)?)
+            .raw_header("X-Total-Count", self.total.to_string())
+            .ok()
+    }
+}
+
+/// Either the cached listing of every counter or a listing filtered, sorted or paged by query.
+pub enum CounterList {
+    Cached(Listing),
+    Queried(Paginated<Counter>),
+}
+
+impl<'r> Responder<'r> for CounterList {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            CounterList::Cached(listing) => listing.respond_to(request),
+            CounterList::Queried(page) => page.respond_to(request),
+        }
+    }
+}
+
+// Counter routes
+
+#[get("/?<query..>")]
+pub fn get_all_counters(
+    query: LenientForm<ListQuery>,
+    tags: TagFilter,
+    caller: Caller,
+    reader: Option<Reader>,
+    store: TenantStore,
+    listings: State<Listings>,
+) -> Result<CounterList, ApiError> {
+    // The cached listing is the one without private counters.
+    if query.is_default() && tags.0.is_empty() && reader.is_none() {
+        return Ok(CounterList::Cached(listings.get(&store)));
+    }
+
+    let owner = match (query.mine, caller.subject()) {
+        (Some(true), None) => {
+            return Err(error(
+                Status::Unauthorized,
+                "Listing your counters requires a bearer token.",
+            ))
+        }
+        (Some(true), subject) => subject,
+        _ => None,
+    };
+    let mut counters: Vec<Counter> = store
+        .read()
+        .values()
+        .filter(|counter| query.matches(counter) && tags.matches(counter))
+        .filter(|counter| counter.visibility.is_listed_for(&reader))
+        .filter(|counter| owner.map_or(true, |owner| counter.owner.as_deref() == Some(owner)))
+        .cloned()
+        .collect();
+
+    let last_modified = counters.iter().map(|counter| counter.updated_at).max();
+
+    query.sort(&mut counters)?;
+    Ok(CounterList::Queried(Paginated::new(
+        counters,
+        query.offset,
+        query.limit,
+        last_modified,
+    )))
+}
+
+#[derive(Serialize)]
+pub struct Statistics {
+    pub(crate) count: usize,
+    pub(crate) sum: u64,
+    pub(crate) mean: Option<f64>,
+    pub(crate) min: Option<u32>,
+    pub(crate) max: Option<u32>,
+}
+
+pub const MAX_TOP: usize = 100;
+
+/// `GET /counter/top?n=10&tag=game`, the `n` highest counters that carry every tag, from an
+/// index kept in value order. Archived counters are left out, as are private ones for anonymous
+/// callers.
+#[get("/top?<n>")]
+pub fn get_top(
+    n: Option<usize>,
+    tags: TagFilter,
+    reader: Option<Reader>,
+    store: TenantStore,
+) -> Result<Json<Vec<Counter>>, ApiError> {
+    let n = n.unwrap_or(10);
+
+    if n == 0 || n > MAX_TOP {
+        return Err(error(
+            Status::BadRequest,
+            "Between 1 and 100 counters can be asked for.",
+        ));
+    }
+
+    let mut counters: Vec<Counter> = store
+        .ranking()
+        .top(n, &tags.0, reader.is_some())
+        .into_iter()
+        .filter_map(|id| store.get(id))
+        .collect();
+
+    // Values may have changed, or cycles rolled over, since the ids were looked up.
+    counters.sort_by_key(|counter| (Reverse(counter.value), counter.id));
+    Ok(Json(counters))
+}
+
+#[get("/stats?<include_archived>")]
+pub fn get_statistics(
+    include_archived: Option<bool>,
+    tags: TagFilter,
+    reader: Option<Reader>,
+    store: TenantStore,
+) -> Json<Statistics> {
+    let include_archived = include_archived.unwrap_or(false);
+    let hashmap = store.read();
+    let values: Vec<u32> = hashmap
+        .values()
+        .filter(|counter| include_archived || !counter.archived)
+        .filter(|counter| tags.matches(counter))
+        .filter(|counter| counter.visibility.is_listed_for(&reader))
+        .map(|counter| counter.value)
+        .collect();
+    let sum: u64 = values.iter().map(|value| u64::from(*value)).sum();
+
+    Json(Statistics {
+        count: values.len(),
+        sum,
+        mean: if values.is_empty() {
+            None
+        } else {
+            Some(sum as f64 / values.len() as f64)
+        },
+        min: values.iter().min().copied(),
+        max: values.iter().max().copied(),
+    })
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    /// Milliseconds since the Unix epoch, when the counters were copied.
+    pub(crate) taken_at: u64,
+    /// Version of the store the counters are as of. Any later change increases it.
+    pub(crate) version: u64,
+    pub(crate) counters: Vec<Counter>,
+}
+
+/// Every counter as of one instant, archived ones included, ordered by id. Every part of the
+/// store is locked while it is copied, so no write is seen half done, as it could be across
+/// pages of the listing.
+#[get("/snapshot")]
+pub fn get_snapshot(reader: Option<Reader>, store: TenantStore) -> Json<Snapshot> {
+    let (hashmap, version) = store.snapshot();
+    let taken_at = history::timestamp();
+    let mut counters: Vec<Counter> = hashmap
+        .into_iter()
+        .map(|(_, counter)| counter)
+        .filter(|counter| counter.visibility.is_listed_for(&reader))
+        .collect();
+
+    counters.sort_by_key(|counter| counter.id);
+
+    Json(Snapshot {
+        taken_at,
+        version,
+        counters,
+    })
+}
+
+/// Case-insensitive substring search over names and descriptions. Counters whose name
+/// starts with the query come first, then other name matches, then description matches.
+#[get("/search?<q>&<include_archived>&<limit>")]
+pub fn search_counters(
+    q: String,
+    include_archived: Option<bool>,
+    limit: Option<usize>,
+    reader: Option<Reader>,
+    store: TenantStore,
+) -> Json<Vec<Counter>> {
+    let query = q.trim().to_lowercase();
+    let include_archived = include_archived.unwrap_or(false);
+    let hashmap = store.read();
+    let mut matches: Vec<(u8, &Counter)> = hashmap
+        .values()
+        .filter(|counter| include_archived || !counter.archived)
+        .filter(|counter| counter.visibility.is_listed_for(&reader))
+        .filter_map(|counter| {
+            let name = counter.name.as_ref().map(|name| name.to_lowercase());
+            let description = counter
+                .description
+                .as_ref()
+                .map(|description| description.to_lowercase());
+
+            match (name, description) {
+                (Some(ref name), _) if name.starts_with(&query) => Some((0, counter)),
+                (Some(ref name), _) if name.contains(&query) => Some((1, counter)),
+                (_, Some(ref description)) if description.contains(&query) => Some((2, counter)),
+                _ => None,
+            }
+        })
+        .collect();
+
+    matches
+        .sort_by(|(a_rank, a), (b_rank, b)| (a_rank, &a.name, a.id).cmp(&(b_rank, &b.name, b.id)));
+
+    Json(
+        matches
+            .into_iter()
+            .take(limit.unwrap_or(20))
+            .map(|(_, counter)| counter.clone())
+            .collect(),
+    )
+}
+
+pub const MAX_LOOKUP_IDS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct Lookup {
+    pub(crate) ids: Vec<String>,
+}
+
+impl Validate for Lookup {}
+
+/// The requested counters in the order they were asked for. Unknown IDs are left out, as are
+/// private counters for anonymous callers.
+#[post("/lookup", data = "<lookup>")]
+pub fn lookup_counters(
+    lookup: Body<Lookup>,
+    reader: Option<Reader>,
+    store: TenantStore,
+) -> Result<Json<Vec<Counter>>, ApiError> {
+    if lookup.ids.len() > MAX_LOOKUP_IDS {
+        return Err(error(
+            Status::BadRequest,
+            "At most 100 counters can be looked up at once.",
+        ));
+    }
+
+    let mut ids: Vec<Uuid> = Vec::with_capacity(lookup.ids.len());
+
+    for id in &lookup.ids {
+        let id = Uuid::parse_str(id.trim())
+            .map_err(|_| error(Status::BadRequest, "Invalid counter id."))?;
+
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+
+    Ok(Json(
+        ids.into_iter()
+            .filter_map(|id| store.get(id))
+            .filter(|counter| counter.visibility.is_listed_for(&reader))
+            .collect(),
+    ))
+}
+
+/// With `?template=<name>`, the counter takes the template's step, bounds, tags and cycle; the
+/// body may then add tags, but not set bounds or a cycle of its own.
+#[post("/?<template>", data = "<new_counter>")]
+pub fn create_counter(
+    new_counter: OptionalBody<NewCounter>,
+    template: Option<String>,
+    templates: State<Templates>,
+    store: TenantStore,
+    write_tokens: State<WriteTokens>,
+    writer: Writer,
+) -> Result<Json<Created>, ApiError> {
+    let template = match template {
+        Some(name) => Some(templates.get(store.tenant(), &name)?),
+        None => None,
+    };
+    let new_counter = new_counter.into_inner().unwrap_or_default();
+    let reason = new_counter.reason;
+    let id = match (&new_counter.id, &new_counter.key) {
+        (Some(_), Some(_)) => {
+            return Err(error(
+                Status::BadRequest,
+                "A counter is created with an id or a key, not both.",
+            ))
+        }
+        (Some(id), None) => parse_id(id)?,
+        (None, Some(key)) => aliases::key_id(key),
+        (None, None) => Uuid::new_v4(),
+    };
+    let mut counter = Counter::new(id);
+
+    counter.key = new_counter.key;
+    counter.owner = writer.caller.subject().map(String::from);
+    counter.write_token = write_tokens.issue();
+    counter.name = new_counter.name.and_then(non_empty);
+    counter.description = new_counter.description.and_then(non_empty);
+
+    if let Some(tags) = new_counter.tags {
+        counter.tags = parse_tags(tags)?;
+    }
+
+    if let Some(template) = template {
+        if new_counter.bounds.is_some() || new_counter.cycle.is_some() {
+            return Err(error(
+                Status::BadRequest,
+                "Counters created from a template take its bounds and cycle.",
+            ));
+        }
+
+        template.apply(&mut counter);
+    }
+
+    if let Some(bounds) = new_counter.bounds {
+        bounds.validate()?;
+        counter.bounds = bounds;
+    }
+
+    if let Some(cycle) = new_counter.cycle {
+        let now = history::timestamp();
+
+        counter.cycle = Some(
+            Cycle::new(cycle.period, cycle.anchor.unwrap_or(now), now).ok_or_else(|| {
+                error(
+                    Status::BadRequest,
+                    "Cycle anchor must not be in the future.",
+                )
+            })?,
+        );
+    }
+
+    if let Some(pool) = new_counter.burst {
+        match (&counter.cycle, counter.bounds.max) {
+            (Some(_), Some(max)) if pool <= max => counter.burst = Some(Burst::new(pool)),
+            _ => {
+                return Err(error(
+                    Status::BadRequest,
+                    "A burst pool needs a cycle and an upper bound no smaller than the pool.",
+                ))
+            }
+        }
+    }
+
+    if let Some(decay) = new_counter.decay {
+        counter.decay = Some(Decay::new(decay.half_life, counter.created_at));
+    }
+
+    if new_counter.distinct == Some(true) {
+        counter.distinct = Some(Distinct::default());
+    }
+
+    counter.visibility = new_counter.visibility.unwrap_or_default();
+
+    if let Some(value) = new_counter.value {
+        if counter.distinct.is_some() {
+            return Err(error(
+                Status::BadRequest,
+                "Distinct-count counters start empty.",
+            ));
+        }
+
+        if value < counter.bounds.min.unwrap_or(0) || value > counter.ceiling() {
+            return Err(error(
+                Status::BadRequest,
+                "Value must be within the counter's bounds.",
+            ));
+        }
+
+        counter.set_value(value);
+        counter.min_seen = value;
+    }
+
+    writer.check_scope(&counter)?;
+    create(counter, reason, &store).map(|counter| {
+        Json(Created {
+            write_token: counter.write_token.clone(),
+            counter,
+        })
+    })
+}
+
+/// Adds `counter` to the store, failing with 409 if one with its id already exists.
+pub fn create(
+    counter: Counter,
+    reason: Option<String>,
+    store: &Store,
+) -> Result<Counter, ApiError> {
+    let id = counter.id;
+
+    store.write_or_create(id, |hashmap| {
+        if hashmap.contains_key(&id) {
+            return Err(error(
+                Status::Conflict,
+                "A counter with this id already exists.",
+            ));
+        }
+
+        hashmap.insert(id, counter.clone());
+        store
+            .history()
+            .record(id, Entry::new(Operation::Create, 0, counter.value, reason));
+        Ok(counter)
+    })?
+}
+
+#[get("/<id>")]
+pub fn get_counter(id: String, store: TenantStore) -> Result<Conditional<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    store
+        .get(parsed_uuid)
+        .map(|counter| {
+            let last_modified = counter.updated_at;
+
+            Conditional::new(counter, Some(last_modified))
+        })
+        .ok_or_else(not_found_error)
+}
+
+/// Deletes the counter along with its history. Owners may delete their own counters, and
+/// only admins those without an owner.
+#[delete("/<id>")]
+pub fn delete_counter(
+    id: String,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    if counter.owner.is_none() {
+        writer.check_admin()?;
+    }
+
+    store
+        .remove(parsed_uuid)
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+/// Number of steps to change a counter by in one request, one unless given.
+pub fn steps(steps: Option<u32>) -> Result<u32, ApiError> {
+    match steps.unwrap_or(1) {
+        0 => Err(error(Status::BadRequest, "Steps must be positive.")),
+        steps => Ok(steps),
+    }
+}
+
+/// Adds the counter's step, or `steps` of them at once.
+#[put("/<id>/increment?<steps>", data = "<annotation>")]
+pub fn increment_counter(
+    id: String,
+    steps: Option<u32>,
+    annotation: OptionalBody<Annotation>,
+    store: TenantStore,
+    deferred: State<Deferred>,
+    features: State<Features>,
+    writer: Writer,
+) -> Result<Reply, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let steps = self::steps(steps)?;
+
+    writer.check(parsed_uuid, &store)?;
+    features.check_auto_create(parsed_uuid, &store)?;
+
+    let annotation = Annotation::of_change(annotation)?;
+    let store = store.inner().clone();
+
+    Ok(deferred.run("increment", move || {
+        increment_by(parsed_uuid, steps, &[], annotation, &store)
+    }))
+}
+
+#[get("/<id>/hit?<pixel>&<by>")]
+pub fn hit_counter(
+    id: String,
+    pixel: Option<bool>,
+    by: Option<String>,
+    visitor: Visitor,
+    store: TenantStore,
+    features: State<Features>,
+    _throttle: Throttle,
+) -> Result<Hit, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    // Counters with a write token or an owner only take changes from writers, which a beacon
+    // cannot be. Maintenance mode is left to `Throttle`.
+    let guarded = store.get(parsed_uuid).map_or(false, |counter| {
+        counter.write_token.is_some() || counter.owner.is_some()
+    });
+    let counted = !guarded
+        && features.check_auto_create(parsed_uuid, &store).is_ok()
+        && visibility::check_beacon(parsed_uuid, &store).is_ok();
+
+    if visitor.may_be_counted() && counted {
+        let labels = by.map(|by| visitor.labels(&by)).unwrap_or_default();
+
+        // A beacon has nowhere to show an error, so hits on read-only or non-public counters
+        // are dropped.
+        let _ = increment(parsed_uuid, &labels, Annotation::default(), &store);
+    }
+
+    if pixel.unwrap_or(true) {
+        Ok(Hit::Pixel)
+    } else {
+        Ok(Hit::Empty)
+    }
+}
+
+pub fn increment(
+    id: Uuid,
+    labels: &[String],
+    annotation: Annotation,
+    store: &Store,
+) -> Result<Counter, ApiError> {
+    increment_by(id, 1, labels, annotation, store)
+}
+
+pub fn increment_by(
+    id: Uuid,
+    steps: u32,
+    labels: &[String],
+    annotation: Annotation,
+    store: &Store,
+) -> Result<Counter, ApiError> {
+    store.write_or_create(id, |hashmap| {
+        let counter = hashmap.entry(id).or_insert_with(|| Counter::new(id));
+
+        counter.ensure_mutable()?;
+        counter.ensure_steppable()?;
+
+        let delta = counter.step_up_by(steps);
+
+        // At the ceiling there is nothing to record.
+        if delta == 0 {
+            return Ok(counter.clone());
+        }
+
+        counter.score(i64::from(delta), annotation.occurred_at);
+
+        for label in labels {
+            *counter.breakdown.entry(label.clone()).or_insert(0) += delta;
+        }
+
+        store.history().record(
+            id,
+            Entry::new(
+                Operation::Increment,
+                i64::from(delta),
+                counter.value,
+                annotation.reason,
+            )
+            .occurred_at(annotation.occurred_at),
+        );
+        Ok(counter.clone())
+    })?
+}
+
+/// Removes the counter's step, or `steps` of them at once.
+#[put("/<id>/decrement?<steps>", data = "<annotation>")]
+pub fn decrement_counter(
+    id: String,
+    steps: Option<u32>,
+    annotation: OptionalBody<Annotation>,
+    store: TenantStore,
+    deferred: State<Deferred>,
+    features: State<Features>,
+    writer: Writer,
+) -> Result<Reply, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let steps = self::steps(steps)?;
+
+    writer.check(parsed_uuid, &store)?;
+    features.check_auto_create(parsed_uuid, &store)?;
+
+    let annotation = Annotation::of_change(annotation)?;
+    let store = store.inner().clone();
+
+    Ok(deferred.run("decrement", move || {
+        decrement_by(parsed_uuid, steps, annotation, &store)
+    }))
+}
+
+pub fn decrement(id: Uuid, annotation: Annotation, store: &Store) -> Result<Counter, ApiError> {
+    decrement_by(id, 1, annotation, store)
+}
+
+pub fn decrement_by(
+    id: Uuid,
+    steps: u32,
+    annotation: Annotation,
+    store: &Store,
+) -> Result<Counter, ApiError> {
+    store.write_or_create(id, |hashmap| {
+        let counter = hashmap.entry(id).or_insert_with(|| Counter::new(id));
+
+        counter.ensure_mutable()?;
+        counter.ensure_steppable()?;
+
+        let delta = counter.step_down_by(steps);
+
+        // Nor at the floor.
+        if delta == 0 {
+            return Ok(counter.clone());
+        }
+
+        counter.score(-i64::from(delta), annotation.occurred_at);
+
+        store.history().record(
+            id,
+            Entry::new(
+                Operation::Decrement,
+                -i64::from(delta),
+                counter.value,
+                annotation.reason,
+            )
+            .occurred_at(annotation.occurred_at),
+        );
+        Ok(counter.clone())
+    })?
+}
+
+/// A block of values a step apart, `start` included and `end` not.
+#[derive(Serialize, Deserialize)]
+pub struct Range {
+    pub(crate) start: u32,
+    pub(crate) end: u32,
+}
+
+/// Reserves the next `count` values for the caller by adding as many steps to the counter at
+/// once, for use as a distributed id generator. All of them or none are reserved.
+#[post("/<id>/next?<count>", data = "<_body>")]
+pub fn next_range(
+    id: String,
+    count: Option<u32>,
+    store: TenantStore,
+    features: State<Features>,
+    writer: Writer,
+    _body: Unused,
+) -> Result<Json<Range>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let count = count.unwrap_or(1);
+
+    if count == 0 {
+        return Err(error(Status::BadRequest, "Count must be positive."));
+    }
+
+    writer.check(parsed_uuid, &store)?;
+    features.check_auto_create(parsed_uuid, &store)?;
+
+    store.write_or_create(parsed_uuid, |hashmap| {
+        let counter = hashmap
+            .entry(parsed_uuid)
+            .or_insert_with(|| Counter::new(parsed_uuid));
+
+        counter.ensure_mutable()?;
+        counter.ensure_steppable()?;
+
+        let start = counter.value;
+
+        // Values only go up from within the bounds, so only the ceiling can get in the way.
+        counter
+            .step
+            .checked_mul(count)
+            .and_then(|delta| start.checked_add(delta))
+            .filter(|end| *end <= counter.ceiling())
+            .ok_or_else(|| error(Status::Conflict, "Not enough values are left."))?;
+
+        let delta = counter.step_up_by(count);
+        let end = counter.value;
+
+        counter.score(i64::from(delta), None);
+        store.history().record(
+            parsed_uuid,
+            Entry::new(Operation::Increment, i64::from(delta), end, None),
+        );
+        Ok(Json(Range { start, end }))
+    })?
+}
+
+/// Reverts the newest change of value that has not been undone yet, as the history recorded
+/// it, so undoing again goes further back. The change is reverted by its delta, ignoring bounds.
+#[post("/<id>/undo", data = "<annotation>")]
+pub fn undo_counter(
+    id: String,
+    annotation: OptionalBody<Annotation>,
+    store: TenantStore,
+    features: State<Features>,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    features.require(Feature::History)?;
+    writer.check(parsed_uuid, &store)?;
+
+    let reason = reason(annotation);
+
+    store.write(parsed_uuid, |hashmap| {
+        let counter = hashmap.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+
+        counter.ensure_mutable()?;
+        counter.ensure_steppable()?;
+
+        let change = store
+            .history()
+            .last_change(&parsed_uuid)
+            .ok_or_else(|| error(Status::Conflict, "There is nothing to undo."))?;
+        let value = (i64::from(counter.value) - change.delta)
+            .max(0)
+            .min(i64::from(u32::max_value())) as u32;
+        let delta = i64::from(value) - i64::from(counter.value);
+
+        counter.set_value(value);
+        counter.score(delta, None);
+        store.history().record(
+            parsed_uuid,
+            Entry::new(Operation::Undo, delta, value, reason),
+        );
+        Ok(Json(counter.clone()))
+    })
+}
+
+#[put("/<id>/archive", data = "<annotation>")]
+pub fn archive_counter(
+    id: String,
+    annotation: OptionalBody<Annotation>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    update_counter(
+        &id,
+        reason(annotation),
+        &writer,
+        Operation::Archive,
+        &store,
+        |counter| counter.archived = true,
+    )
+}
+
+#[put("/<id>/unarchive", data = "<annotation>")]
+pub fn unarchive_counter(
+    id: String,
+    annotation: OptionalBody<Annotation>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    update_counter(
+        &id,
+        reason(annotation),
+        &writer,
+        Operation::Unarchive,
+        &store,
+        |counter| counter.archived = false,
+    )
+}
+
+#[put("/<id>/freeze", data = "<annotation>")]
+pub fn freeze_counter(
+    id: String,
+    annotation: OptionalBody<Annotation>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    update_counter(
+        &id,
+        reason(annotation),
+        &writer,
+        Operation::Freeze,
+        &store,
+        |counter| counter.frozen = true,
+    )
+}
+
+#[put("/<id>/unfreeze", data = "<annotation>")]
+pub fn unfreeze_counter(
+    id: String,
+    annotation: OptionalBody<Annotation>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    update_counter(
+        &id,
+        reason(annotation),
+        &writer,
+        Operation::Unfreeze,
+        &store,
+        |counter| counter.frozen = false,
+    )
+}
+
+pub fn update_counter<F>(
+    id: &str,
+    reason: Option<String>,
+    writer: &Writer,
+    operation: Operation,
+    store: &Store,
+    f: F,
+) -> Result<Json<Counter>, ApiError>
+where
+    F: FnOnce(&mut Counter),
+{
+    let parsed_uuid = parse_id(id)?;
+
+    writer.check(parsed_uuid, store)?;
+    store
+        .write(parsed_uuid, |hashmap| {
+            hashmap.get_mut(&parsed_uuid).map(|counter| {
+                f(counter);
+                counter.updated_at = history::timestamp();
+                store
+                    .history()
+                    .record(parsed_uuid, Entry::new(operation, 0, counter.value, reason));
+                counter.clone()
+            })
+        })
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[derive(FromForm)]
+pub struct HistoryQuery {
+    pub(crate) operation: Option<String>,
+    pub(crate) from: Option<u64>,
+    pub(crate) to: Option<u64>,
+    pub(crate) order: Option<String>,
+    pub(crate) offset: Option<usize>,
+    pub(crate) limit: Option<usize>,
+}
+
+#[patch("/<id>", data = "<patch>")]
+pub fn patch_counter(
+    id: String,
+    patch: Body<CounterPatch>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    let patch = patch.into_inner();
+    let tags = match patch.tags {
+        Some(tags) => Some(parse_tags(tags)?),
+        None => None,
+    };
+
+    if let Some(bounds) = patch.bounds {
+        bounds.validate()?;
+    }
+
+    let name = patch.name;
+    let description = patch.description;
+    let step = patch.step;
+    let bounds = patch.bounds;
+
+    if let Some(name) = &name {
+        if let Some(mut renamed) = store.get(parse_id(&id)?) {
+            renamed.name = non_empty(name.clone());
+            writer.check_scope(&renamed)?;
+        }
+    }
+
+    update_counter(
+        &id,
+        patch.reason,
+        &writer,
+        Operation::Update,
+        &store,
+        |counter| {
+            if let Some(name) = name {
+                counter.name = non_empty(name);
+            }
+
+            if let Some(description) = description {
+                counter.description = non_empty(description);
+            }
+
+            if let Some(tags) = tags {
+                counter.tags = tags;
+            }
+
+            if let Some(step) = step {
+                counter.step = step;
+            }
+
+            if let Some(bounds) = bounds {
+                counter.bounds = bounds;
+            }
+        },
+    )
+}
+
+/// Creates the counter with the given id, or replaces its metadata and, if given, its value,
+/// for provisioning counters idempotently. Answers with 201 when the counter was created.
+#[put("/<id>", data = "<desired>")]
+pub fn upsert_counter(
+    id: String,
+    desired: Body<DesiredCounter>,
+    store: TenantStore,
+    write_tokens: State<WriteTokens>,
+    writer: Writer,
+) -> Result<status::Custom<Json<Created>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let DesiredCounter {
+        reason,
+        value,
+        name,
+        description,
+        tags,
+        step,
+        bounds,
+        visibility,
+    } = desired.into_inner();
+    let tags = parse_tags(tags.unwrap_or_default())?;
+    let bounds = bounds.unwrap_or_default();
+    let step = step.unwrap_or_else(default_step);
+
+    bounds.validate()?;
+
+    // A new counter is checked against the caller's prefix once it has its name.
+    if store.get(parsed_uuid).is_some() {
+        writer.check(parsed_uuid, &store)?;
+    }
+
+    let apply = |counter: &mut Counter| {
+        counter.name = name.and_then(non_empty);
+        counter.description = description.and_then(non_empty);
+        counter.tags = tags;
+        counter.step = step;
+        counter.bounds = bounds;
+        counter.visibility = visibility.unwrap_or_default();
+    };
+    let check_value = |counter: &Counter, value: u32| {
+        if value < counter.bounds.min.unwrap_or(0) || value > counter.ceiling() {
+            Err(error(
+                Status::BadRequest,
+                "Value must be within the counter's bounds.",
+            ))
+        } else {
+            Ok(())
+        }
+    };
+
+    store.write_or_create(parsed_uuid, |hashmap| {
+        if let Some(counter) = hashmap.get_mut(&parsed_uuid) {
+            let mut updated = counter.clone();
+
+            apply(&mut updated);
+            writer.check_scope(&updated)?;
+
+            if let Some(value) = value.filter(|value| *value != updated.value) {
+                updated.ensure_mutable()?;
+                updated.ensure_steppable()?;
+                check_value(&updated, value)?;
+                updated.set_value(value);
+            }
+
+            updated.updated_at = history::timestamp();
+            store.history().record(
+                parsed_uuid,
+                Entry::new(
+                    Operation::Update,
+                    i64::from(updated.value) - i64::from(counter.value),
+                    updated.value,
+                    reason,
+                ),
+            );
+            *counter = updated.clone();
+
+            return Ok(status::Custom(
+                Status::Ok,
+                Json(Created {
+                    counter: updated,
+                    write_token: None,
+                }),
+            ));
+        }
+
+        let mut counter = Counter::new(parsed_uuid);
+
+        counter.owner = writer.caller.subject().map(String::from);
+        counter.write_token = write_tokens.issue();
+        apply(&mut counter);
+        writer.check_scope(&counter)?;
+
+        if let Some(value) = value {
+            check_value(&counter, value)?;
+            counter.set_value(value);
+            counter.min_seen = value;
+        }
+
+        hashmap.insert(parsed_uuid, counter.clone());
+        store.history().record(
+            parsed_uuid,
+            Entry::new(Operation::Create, 0, counter.value, reason),
+        );
+
+        Ok(status::Custom(
+            Status::Created,
+            Json(Created {
+                write_token: counter.write_token.clone(),
+                counter,
+            }),
+        ))
+    })?
+}
+
+#[get("/<id>/history?<query..>")]
+pub fn get_history(
+    id: String,
+    query: LenientForm<HistoryQuery>,
+    store: TenantStore,
+    features: State<Features>,
+) -> Result<Json<Page>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    features.require(Feature::History)?;
+
+    let operations = match &query.operation {
+        Some(operation) => Some(
+            operation
+                .split(',')
+                .map(|name| Operation::parse(name.trim()))
+                .collect::<Option<Vec<Operation>>>()
+                .ok_or_else(|| error(Status::BadRequest, "Unknown operation."))?,
+        ),
+        None => None,
+    };
+    let newest_first = match query.order.as_deref() {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return Err(error(Status::BadRequest, "Order must be asc or desc.")),
+    };
+    let filter = Filter {
+        operations,
+        from: query.from,
+        to: query.to,
+        newest_first,
+    };
+
+    store
+        .history()
+        .page(
+            &parsed_uuid,
+            &filter,
+            query.offset.unwrap_or(0),
+            query.limit.unwrap_or(50),
+        )
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[get("/<id>/rate?<window>")]
+pub fn get_rate(
+    id: String,
+    window: Option<u64>,
+    store: TenantStore,
+    features: State<Features>,
+) -> Result<Json<Rate>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    features.require(Feature::History)?;
+
+    store
+        .history()
+        .rate(&parsed_uuid, window.unwrap_or(300))
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[derive(Serialize)]
+pub struct Delta {
+    pub(crate) since: u64,
+    pub(crate) delta: i64,
+}
+
+/// `GET /counter/<id>/delta?since=<ms>`, how much the counter changed from `since` until now.
+/// Summed from the history while it reaches back that far, and otherwise taken from the last
+/// time-series sample before `since`; 404 when neither does.
+#[get("/<id>/delta?<since>")]
+pub fn get_delta(id: String, since: u64, store: TenantStore) -> Result<Json<Delta>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+    let delta = change_since(&counter, since, &store).ok_or_else(|| {
+        error(
+            Status::NotFound,
+            "Neither the history nor the time series reaches back that far.",
+        )
+    })?;
+
+    Ok(Json(Delta { since, delta }))
+}
+
+/// How much `counter` changed from `since` until now, summed from the history while it reaches
+/// back that far, and otherwise taken from the last time-series sample before `since`.
+pub fn change_since(counter: &Counter, since: u64, store: &Store) -> Option<i64> {
+    store.history().delta_since(&counter.id, since).or_else(|| {
+        store
+            .timeseries()
+            .at(&counter.id, since)
+            .map(|sample| i64::from(counter.value) - i64::from(sample.value))
+    })
+}
+
+#[get("/<id>/timeseries?<from>&<to>&<resolution>")]
+pub fn get_timeseries(
+    id: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    resolution: Option<u64>,
+    store: TenantStore,
+) -> Result<Json<Vec<Sample>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    if store.timeseries().interval().is_none() {
+        return Err(error(Status::NotFound, "Time-series sampling is disabled."));
+    }
+
+    store
+        .timeseries()
+        .query(&parsed_uuid, from, to, resolution)
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+/// Hourly (`1h`, the default) or daily (`1d`) rollups of the counter's time series, which are
+/// kept for longer than its samples.
+#[get("/<id>/rollup?<window>&<from>&<to>")]
+pub fn get_rollup(
+    id: String,
+    window: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    store: TenantStore,
+) -> Result<Json<Vec<Rollup>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    if store.timeseries().interval().is_none() {
+        return Err(error(Status::NotFound, "Time-series sampling is disabled."));
+    }
+
+    let window = match window {
+        Some(window) => Window::parse(&window)
+            .ok_or_else(|| error(Status::BadRequest, "Window must be 1h or 1d."))?,
+        None => Window::Hour,
+    };
+
+    store
+        .timeseries()
+        .rollups(&parsed_uuid, window, from, to)
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+// Admin routes
+
+#[get("/contention?<limit>")]
+pub fn get_contention(
+    limit: Option<usize>,
+    store: State<Store>,
+    _admin: Admin,
+) -> Json<Vec<ContentionReport>> {
+    Json(store.contention(limit.unwrap_or(10)))
+}
+
+/// Calls to deprecated routes, by route and client.
+#[get("/deprecations")]
+pub fn get_deprecations(deprecations: State<Deprecations>, _admin: Admin) -> Json<Vec<Report>> {
+    Json(deprecations.report())
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn list_counters() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        client.post("/counter").header(ContentType::JSON).dispatch();
+        client.post("/counter").header(ContentType::JSON).dispatch();
+
+        let mut response = client.get("/counter").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let body_string = response.body_string().unwrap();
+        let counters: Vec<Counter> = serde_json::from_str(&body_string).unwrap();
+
+        assert_eq!(counters.len(), 2)
+    }
+
+    #[test]
+    fn paginate_counters() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        for _ in 0..5 {
+            client.post("/counter").header(ContentType::JSON).dispatch();
+        }
+
+        let mut response = client.get("/counter?offset=3&limit=10").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-Total-Count"), Some("5"));
+
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 2);
+    }
+
+    #[test]
+    fn sort_and_filter_counters() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        for increments in &[3, 1, 2] {
+            let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+            let counter: Counter =
+                serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+            for _ in 0..*increments {
+                client
+                    .put(format!("/counter/{}/increment", counter.id))
+                    .header(ContentType::JSON)
+                    .dispatch();
+            }
+        }
+
+        let mut response = client
+            .get("/counter?sort=value&order=desc&min_value=2")
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let values: Vec<u32> = counters.iter().map(|counter| counter.value).collect();
+
+        assert_eq!(values, vec![3, 2]);
+
+        let response = client.get("/counter?sort=color").dispatch();
+
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn search_counters_by_name() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        for body in &[
+            r#"{ "name": "API requests" }"#,
+            r#"{ "name": "Signups", "description": "Accounts created via the API" }"#,
+            r#"{ "name": "Failed API calls" }"#,
+            r#"{ "name": "Downloads" }"#,
+        ] {
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+
+        let mut response = client.get("/counter/search?q=api").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let names: Vec<String> = counters
+            .into_iter()
+            .filter_map(|counter| counter.name)
+            .collect();
+
+        assert_eq!(names, vec!["API requests", "Failed API calls", "Signups"]);
+    }
+
+    #[test]
+    fn lookup_counters_by_id() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut ids = Vec::new();
+
+        for _ in 0..3 {
+            let mut response = client.post("/counter").header(ContentType::JSON).dispatch();
+            let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            ids.push(counter.id);
+        }
+
+        let mut response = client
+            .post("/counter/lookup")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "ids": ["{}", "{}", "{}"] }}"#,
+                ids[2],
+                ids[0],
+                uuid::Uuid::new_v4()
+            ))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let found: Vec<_> = counters.iter().map(|counter| counter.id).collect();
+
+        assert_eq!(found, vec![ids[2], ids[0]]);
+
+        let invalid_response = client
+            .post("/counter/lookup")
+            .header(ContentType::JSON)
+            .body(r#"{ "ids": ["not-an-id"] }"#)
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn read_consistent_snapshot() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let mut response = client.get("/counter/snapshot").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let snapshot: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let counters = snapshot["counters"].as_array().unwrap();
+
+        assert!(snapshot["version"].as_u64().unwrap() > 0);
+        assert!(snapshot["taken_at"].as_u64().unwrap() >= counter.created_at);
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0]["value"], 1);
+    }
+
+    #[test]
+    fn aggregate_statistics() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        for increments in &[4, 0, 2] {
+            let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+            let counter: Counter =
+                serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+            for _ in 0..*increments {
+                client
+                    .put(format!("/counter/{}/increment", counter.id))
+                    .header(ContentType::JSON)
+                    .dispatch();
+            }
+        }
+
+        let mut response = client.get("/counter/stats").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let stats: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(stats["count"], 3);
+        assert_eq!(stats["sum"], 6);
+        assert_eq!(stats["mean"], 2.0);
+        assert_eq!(stats["min"], 0);
+        assert_eq!(stats["max"], 4);
+    }
+
+    #[test]
+    fn create_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let body_as_string = response.body_string().unwrap();
+        let counter: Counter = serde_json::from_str(&body_as_string).unwrap();
+
+        assert_eq!(counter.value, 0);
+    }
+
+    #[test]
+    fn create_counter_with_id_and_value() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let id = uuid::Uuid::new_v4();
+        let body = format!(r#"{{ "id": "{}", "value": 42, "name": "Seeded" }}"#, id);
+        let create = |body: &str| {
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+        let mut response = create(&body);
+        let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(counter.id, id);
+        assert_eq!(counter.value, 42);
+        assert_eq!(counter.name, Some("Seeded".to_string()));
+        assert_eq!(create(&body).status(), Status::Conflict);
+        assert_eq!(
+            create(r#"{ "value": 5, "bounds": { "max": 3 } }"#).status(),
+            Status::BadRequest
+        );
+        assert_eq!(create(r#"{ "id": "xyz123" }"#).status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn upsert_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let id = uuid::Uuid::new_v4();
+        let put = |body: &str| {
+            client
+                .put(format!("/counter/{}", id))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+        let body = r#"{ "name": "Deploys", "tags": ["ops"], "value": 7 }"#;
+        let mut created_response = put(body);
+        let created: Counter =
+            serde_json::from_str(&created_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(created_response.status(), Status::Created);
+        assert_eq!(created.id, id);
+        assert_eq!(created.value, 7);
+        assert_eq!(put(body).status(), Status::Ok);
+
+        let mut replaced_response = put(r#"{ "name": "Releases" }"#);
+        let replaced: Counter =
+            serde_json::from_str(&replaced_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(replaced_response.status(), Status::Ok);
+        assert_eq!(replaced.name, Some("Releases".to_string()));
+        assert!(replaced.tags.is_empty());
+        assert_eq!(replaced.value, 7);
+        assert_eq!(
+            put(r#"{ "value": 5, "bounds": { "max": 3 } }"#).status(),
+            Status::BadRequest
+        );
+    }
+
+    #[test]
+    fn filter_counters_by_tag() {
+        let client = Client::new(rocket()).expect("Init failed");
+
+        client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "tags": ["prod", "api"] }"#)
+            .dispatch();
+        client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "tags": ["prod"] }"#)
+            .dispatch();
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let untagged: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let patch_response = client
+            .patch(format!("/counter/{}", untagged.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "tags": ["api", "prod"] }"#)
+            .dispatch();
+
+        assert_eq!(patch_response.status(), Status::Ok);
+
+        let mut response = client.get("/counter?tag=prod&tag=api").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 2);
+
+        let mut response = client.get("/counter?tag=prod").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 3);
+    }
+
+    #[test]
+    fn create_and_get_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut post_response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+        assert_eq!(post_response.status(), Status::Ok);
+
+        match post_response.body_string() {
+            Some(content) => {
+                let counter: Counter = serde_json::from_str(&content).unwrap();
+                let get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+
+                assert_eq!(get_response.status(), Status::Ok);
+            }
+            None => panic!("Invalid body"),
+        };
+    }
+
+    #[test]
+    fn create_and_increment_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+
+        match create_response.body_string() {
+            Some(create_response_content) => {
+                let counter: Counter = serde_json::from_str(&create_response_content).unwrap();
+                let mut increment_response = client
+                    .put(format!("/counter/{}/increment", counter.id))
+                    .header(ContentType::JSON)
+                    .dispatch();
+
+                assert_eq!(increment_response.status(), Status::Ok);
+
+                match increment_response.body_string() {
+                    Some(increment_response_content) => {
+                        let counter_with_increment: Counter =
+                            serde_json::from_str(&increment_response_content).unwrap();
+
+                        assert_eq!(counter_with_increment.value, 1);
+                    }
+                    None => panic!("Invalid body"),
+                };
+            }
+            None => panic!("Invalid body"),
+        };
+    }
+
+    #[test]
+    fn address_counters_by_key() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "key": "repo:owner/name" }"#)
+            .dispatch();
+        let created: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(created.key.as_ref().unwrap(), "repo:owner/name");
+
+        let mut increment_response = client
+            .put("/counter/key:repo%3Aowner%2Fname/increment")
+            .header(ContentType::JSON)
+            .dispatch();
+        let incremented: Counter =
+            serde_json::from_str(&increment_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(incremented.id, created.id);
+        assert_eq!(incremented.value, 1);
+
+        let mut new_key_response = client
+            .put("/counter/key:signups%20today/increment")
+            .header(ContentType::JSON)
+            .dispatch();
+        let auto_created: Counter =
+            serde_json::from_str(&new_key_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(auto_created.value, 1);
+        assert_eq!(
+            client
+                .get(format!("/counter/{}", auto_created.id))
+                .dispatch()
+                .status(),
+            Status::Ok
+        );
+
+        let invalid_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "key": "{}" }}"#, "k".repeat(257)))
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn allocate_id_range() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let id = uuid::Uuid::new_v4();
+        let next = |count: u32| {
+            let mut response = client
+                .post(format!("/counter/{}/next?count={}", id, count))
+                .dispatch();
+
+            serde_json::from_str::<super::Range>(&response.body_string().unwrap()).unwrap()
+        };
+
+        let first = next(100);
+        let second = next(10);
+
+        assert_eq!((first.start, first.end), (0, 100));
+        assert_eq!((second.start, second.end), (100, 110));
+
+        let mut response = client.get(format!("/counter/{}", id)).dispatch();
+        let counter: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 110);
+
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let bounded: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .patch(format!("/counter/{}", bounded.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "step": 5, "bounds": { "max": 20 } }"#)
+            .dispatch();
+        let mut stepped_response = client
+            .post(format!("/counter/{}/next?count=3", bounded.id))
+            .dispatch();
+        let stepped: super::Range =
+            serde_json::from_str(&stepped_response.body_string().unwrap()).unwrap();
+
+        assert_eq!((stepped.start, stepped.end), (0, 15));
+
+        let beyond_response = client
+            .post(format!("/counter/{}/next?count=2", bounded.id))
+            .dispatch();
+
+        assert_eq!(beyond_response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn annotate_mutation_with_reason() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "reason": "manual correction after outage" }"#)
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Ok);
+
+        let mut response = client
+            .get(format!("/counter/{}/history", counter.id))
+            .dispatch();
+        let page: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(page["entries"][0].get("reason"), None);
+        assert_eq!(
+            page["entries"][1]["reason"],
+            "manual correction after outage"
+        );
+    }
+
+    #[test]
+    fn backdate_mutation() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let an_hour_ago = crate::history::timestamp() - 3_600_000;
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "occurred_at": {} }}"#, an_hour_ago))
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Ok);
+
+        let future_response = client
+            .put(format!("/counter/{}/decrement", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "occurred_at": {} }}"#,
+                crate::history::timestamp() + 3_600_000
+            ))
+            .dispatch();
+
+        assert_eq!(future_response.status(), Status::BadRequest);
+
+        let mut response = client
+            .get(format!("/counter/{}/history?order=desc", counter.id))
+            .dispatch();
+        let page: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["entries"][0]["operation"], "create");
+        assert_eq!(page["entries"][1]["operation"], "increment");
+        assert_eq!(page["entries"][1]["timestamp"], an_hour_ago);
+        assert_eq!(page["entries"][1]["value"], 1);
+    }
+
+    #[test]
+    fn archive_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let archive_response = client
+            .put(format!("/counter/{}/archive", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(archive_response.status(), Status::Ok);
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Conflict);
+
+        let get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+
+        assert_eq!(get_response.status(), Status::Ok);
+
+        let mut list_response = client.get("/counter").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 0);
+
+        let mut list_response = client.get("/counter?include_archived=true").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 1);
+    }
+
+    #[test]
+    fn undo_changes() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for action in &["increment", "increment", "decrement"] {
+            client
+                .put(format!("/counter/{}/{}", counter.id, action))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let undo = || {
+            let mut response = client
+                .post(format!("/counter/{}/undo", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+
+            (response.status(), response.body_string().unwrap())
+        };
+
+        for expected in &[2, 1, 0] {
+            let (status, body) = undo();
+            let undone: Counter = serde_json::from_str(&body).unwrap();
+
+            assert_eq!(status, Status::Ok);
+            assert_eq!(undone.value, *expected);
+        }
+
+        assert_eq!(undo().0, Status::Conflict);
+
+        let mut history_response = client
+            .get(format!("/counter/{}/history?operation=undo", counter.id))
+            .dispatch();
+        let history: serde_json::Value =
+            serde_json::from_str(&history_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(history["total"], 3);
+    }
+
+    #[test]
+    fn delta_since_timestamp() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        for action in &["increment", "increment", "increment", "decrement"] {
+            client
+                .put(format!("/counter/{}/{}", counter.id, action))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let delta = |since: u64| -> serde_json::Value {
+            let mut response = client
+                .get(format!("/counter/{}/delta?since={}", counter.id, since))
+                .dispatch();
+
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+
+        assert_eq!(delta(0)["delta"], 2);
+        assert_eq!(delta(counter.created_at)["delta"], 2);
+        assert_eq!(delta(crate::history::timestamp() + 60_000)["delta"], 0);
+    }
+
+    #[test]
+    fn freeze_counter() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/freeze", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Locked);
+
+        client
+            .put(format!("/counter/{}/unfreeze", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn delete_counters() {
+        let mut api_keys = BTreeMap::new();
+        let mut admin = BTreeMap::new();
+
+        admin.insert("name".to_string(), Value::String("ops".to_string()));
+        admin.insert("role".to_string(), Value::String("admin".to_string()));
+        api_keys.insert("w".to_string(), Value::String("deploy bot".to_string()));
+        api_keys.insert("a".to_string(), Value::Table(admin));
+
+        let config = Config::build(Environment::Development)
+            .extra("jwt_secret", "s3cret")
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let bearer = |subject: &str| {
+            let claims = serde_json::json!({ "sub": subject, "exp": 4_102_444_800u64 });
+            let token =
+                jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, b"s3cret").unwrap();
+
+            Header::new("Authorization", format!("Bearer {}", token))
+        };
+        let create = |caller: Header<'static>| {
+            let mut response = client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .header(caller)
+                .dispatch();
+
+            serde_json::from_str::<Counter>(&response.body_string().unwrap()).unwrap()
+        };
+        let delete = |counter: &Counter, caller: Header<'static>| {
+            client
+                .delete(format!("/counter/{}", counter.id))
+                .header(caller)
+                .dispatch()
+                .status()
+        };
+        let owned = create(bearer("alice"));
+
+        assert_eq!(delete(&owned, bearer("bob")), Status::Forbidden);
+        assert_eq!(delete(&owned, bearer("alice")), Status::Ok);
+        assert_eq!(delete(&owned, bearer("alice")), Status::NotFound);
+        assert_eq!(
+            client
+                .get(format!("/counter/{}", owned.id))
+                .dispatch()
+                .status(),
+            Status::NotFound
+        );
+
+        let unowned = create(Header::new("X-Api-Key", "w"));
+
+        assert_eq!(unowned.owner, None);
+        assert_eq!(
+            delete(&unowned, Header::new("X-Api-Key", "w")),
+            Status::Forbidden
+        );
+        assert_eq!(delete(&unowned, Header::new("X-Api-Key", "a")), Status::Ok);
+    }
+
+    #[test]
+    fn step_several_times_at_once() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .patch(format!("/counter/{}", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "step": 2, "bounds": { "max": 7 } }"#)
+            .dispatch();
+
+        let step = |direction: &str, steps: u32| {
+            let mut response = client
+                .put(format!(
+                    "/counter/{}/{}?steps={}",
+                    counter.id, direction, steps
+                ))
+                .dispatch();
+            let body = response.body_string().unwrap();
+
+            match response.status() {
+                Status::Ok => Ok(serde_json::from_str::<Counter>(&body).unwrap().value),
+                status => Err(status),
+            }
+        };
+
+        assert_eq!(step("increment", 3), Ok(6));
+        assert_eq!(step("increment", 2), Ok(7));
+        assert_eq!(step("decrement", 0), Err(Status::BadRequest));
+        assert_eq!(step("decrement", 10), Ok(0));
+    }
+}
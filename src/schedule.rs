@@ -0,0 +1,373 @@
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::calendar::{self, MILLIS_PER_DAY};
+use crate::dry_run::DryRun;
+use crate::history::{self, Entry, Operation};
+use crate::lock;
+use crate::negotiation::Body;
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::validation::{Validate, Violations};
+use crate::{error, not_found_error, parse_id, ApiError};
+
+const MAX_SCHEDULES: usize = 100;
+const MAX_TIMES: u32 = 10_000;
+/// A century ahead, in milliseconds.
+const MAX_LEAD: u64 = 36_525 * MILLIS_PER_DAY;
+/// Longest the scheduler sleeps before checking whether it is still needed.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Increment,
+    Decrement,
+    Reset,
+}
+
+/// When to run, as milliseconds since the Unix epoch or a UTC `YYYY-MM-DDTHH:MMZ` time.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum When {
+    Timestamp(u64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+pub struct NewSchedule {
+    operation: Action,
+    at: When,
+    /// How many steps to increment or decrement by, 1 by default.
+    times: Option<u32>,
+    reason: Option<String>,
+}
+
+impl Validate for NewSchedule {
+    fn validate(&self, violations: &mut Violations) {
+        violations.positive("times", self.times);
+        violations.reason(&self.reason);
+    }
+}
+
+/// A single operation that will run on a counter at a given time.
+#[derive(Serialize, Clone)]
+pub struct Schedule {
+    id: Uuid,
+    counter: Uuid,
+    operation: Action,
+    times: u32,
+    /// Milliseconds since the Unix epoch.
+    at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Store of the counter's tenant.
+    #[serde(skip)]
+    store: Store,
+}
+
+/// Pending schedules, run by a background thread as they fall due. A schedule whose counter
+/// is gone, archived or frozen by then is dropped without running.
+#[derive(Default)]
+pub struct Schedules {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    pending: Mutex<HashMap<Uuid, Schedule>>,
+    changed: Condvar,
+}
+
+impl Schedules {
+    pub fn pending(&self) -> usize {
+        lock(&self.shared.pending).len()
+    }
+
+    /// How long the most overdue schedule has been waiting to run, in milliseconds.
+    pub fn lag(&self, now: u64) -> u64 {
+        lock(&self.shared.pending)
+            .values()
+            .map(|schedule| now.saturating_sub(schedule.at))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Starts running schedules as they fall due. The thread stops once the schedules are
+    /// dropped.
+    pub fn spawn_runner(&self) {
+        let shared = Arc::downgrade(&self.shared);
+
+        thread::spawn(move || run(&shared));
+    }
+}
+
+fn run(shared: &Weak<Shared>) {
+    while let Some(shared) = shared.upgrade() {
+        let now = history::timestamp();
+        let due: Vec<Schedule> = {
+            let mut pending = lock(&shared.pending);
+            let due_ids: Vec<Uuid> = pending
+                .values()
+                .filter(|schedule| schedule.at <= now)
+                .map(|schedule| schedule.id)
+                .collect();
+
+            due_ids.iter().filter_map(|id| pending.remove(id)).collect()
+        };
+
+        for schedule in &due {
+            apply(schedule, &schedule.store);
+        }
+
+        let pending = lock(&shared.pending);
+        let sleep = pending
+            .values()
+            .map(|schedule| Duration::from_millis(schedule.at.saturating_sub(now)))
+            .min()
+            .unwrap_or(MAX_SLEEP)
+            .min(MAX_SLEEP);
+
+        if due.is_empty() {
+            let _ = shared.changed.wait_timeout(pending, sleep);
+        }
+    }
+}
+
+fn apply(schedule: &Schedule, store: &Store) {
+    store.write(schedule.counter, |counters| {
+        let counter = match counters.get_mut(&schedule.counter) {
+            Some(counter) => counter,
+            None => return,
+        };
+
+        if counter.ensure_mutable().is_err() {
+            return;
+        }
+
+        let before = counter.value;
+        let operation = match schedule.operation {
+            Action::Increment => {
+                for _ in 0..schedule.times {
+                    counter.step_up();
+                }
+
+                Operation::Increment
+            }
+            Action::Decrement => {
+                for _ in 0..schedule.times {
+                    counter.step_down();
+                }
+
+                Operation::Decrement
+            }
+            Action::Reset => {
+                counter.set_value(counter.bounds.min.unwrap_or(0));
+                Operation::Reset
+            }
+        };
+
+        store.history().record(
+            counter.id,
+            Entry::new(
+                operation,
+                i64::from(counter.value) - i64::from(before),
+                counter.value,
+                schedule.reason.clone(),
+            ),
+        );
+    })
+}
+
+#[post("/<id>/schedule", data = "<new_schedule>")]
+pub fn create_schedule(
+    id: String,
+    new_schedule: Body<NewSchedule>,
+    schedules: State<Schedules>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Schedule>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let new_schedule = new_schedule.into_inner();
+    let at = match new_schedule.at {
+        When::Timestamp(at) => Some(at),
+        When::Text(text) => calendar::parse_datetime(&text),
+    }
+    .ok_or_else(|| error(Status::BadRequest, "Time must look like 2025-01-01T00:00Z."))?;
+    let now = history::timestamp();
+    let times = new_schedule.times.unwrap_or(1);
+
+    if at <= now || at > now + MAX_LEAD {
+        return Err(error(
+            Status::BadRequest,
+            "Time must be in the future, at most a century ahead.",
+        ));
+    }
+
+    if times == 0 || times > MAX_TIMES {
+        return Err(error(
+            Status::BadRequest,
+            "Times must be between 1 and 10000.",
+        ));
+    }
+
+    store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    let mut pending = lock(&schedules.shared.pending);
+
+    if pending
+        .values()
+        .filter(|schedule| schedule.counter == parsed_uuid)
+        .count()
+        >= MAX_SCHEDULES
+    {
+        return Err(error(
+            Status::Conflict,
+            "A counter can have at most 100 pending schedules.",
+        ));
+    }
+
+    let schedule = Schedule {
+        id: Uuid::new_v4(),
+        counter: parsed_uuid,
+        operation: new_schedule.operation,
+        times: if new_schedule.operation == Action::Reset {
+            1
+        } else {
+            times
+        },
+        at,
+        reason: new_schedule.reason,
+        store: store.inner().clone(),
+    };
+
+    pending.insert(schedule.id, schedule.clone());
+    schedules.shared.changed.notify_all();
+    Ok(Json(schedule))
+}
+
+/// Pending schedules of the counter, soonest first.
+#[get("/<id>/schedule")]
+pub fn list_schedules(
+    id: String,
+    schedules: State<Schedules>,
+) -> Result<Json<Vec<Schedule>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let mut listed: Vec<Schedule> = lock(&schedules.shared.pending)
+        .values()
+        .filter(|schedule| schedule.counter == parsed_uuid)
+        .cloned()
+        .collect();
+
+    listed.sort_by_key(|schedule| (schedule.at, schedule.id));
+    Ok(Json(listed))
+}
+
+#[delete("/<id>/schedule/<schedule_id>")]
+pub fn cancel_schedule(
+    id: String,
+    schedule_id: String,
+    schedules: State<Schedules>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Schedule>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let schedule_id = parse_id(&schedule_id)?;
+
+    writer.check_admin()?;
+    writer.check(parsed_uuid, &store)?;
+
+    let mut pending = lock(&schedules.shared.pending);
+
+    match pending.get(&schedule_id) {
+        Some(schedule) if schedule.counter == parsed_uuid => {}
+        _ => return Err(not_found_error()),
+    }
+
+    pending
+        .remove(&schedule_id)
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn run_scheduled_operation() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let soon = crate::history::timestamp() + 200;
+        let schedule_response = client
+            .post(format!("/counter/{}/schedule", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "operation": "increment", "at": {}, "times": 3 }}"#,
+                soon
+            ))
+            .dispatch();
+
+        assert_eq!(schedule_response.status(), Status::Ok);
+
+        let mut later_response = client
+            .post(format!("/counter/{}/schedule", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "operation": "reset", "at": "2099-01-01T12:30Z" }"#)
+            .dispatch();
+        let later: serde_json::Value =
+            serde_json::from_str(&later_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(later["at"], 4_070_953_800_000u64);
+
+        thread::sleep(Duration::from_millis(500));
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let incremented: Counter =
+            serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(incremented.value, 3);
+
+        let mut list_response = client
+            .get(format!("/counter/{}/schedule", counter.id))
+            .dispatch();
+        let pending: Vec<serde_json::Value> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0]["operation"], "reset");
+
+        let cancel_response = client
+            .delete(format!(
+                "/counter/{}/schedule/{}",
+                counter.id,
+                later["id"].as_str().unwrap()
+            ))
+            .dispatch();
+
+        assert_eq!(cancel_response.status(), Status::Ok);
+    }
+}
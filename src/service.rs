@@ -0,0 +1,137 @@
+//! `CounterService`, the counters without the `caas` binary around them, for Rust applications
+//! that embed them. It keeps the counters with their history and time series, configured from
+//! the same keys as `Rocket.toml`, and runs the background jobs that sample and save them.
+//! `mount` adds the HTTP API onto a Rocket instance of the application's own, sharing the same
+//! counters, so that changes made either way show up in both.
+
+use rocket::{Config, Rocket};
+use uuid::Uuid;
+
+use crate::jobs::Jobs;
+use crate::routes::{create, decrement, increment, non_empty, Annotation};
+use crate::storage::Storage;
+use crate::store::Store;
+use crate::{api, ApiError, Counter};
+
+/// Cheaply cloneable handle to embedded counters, shared by the application and the HTTP API
+/// once it is mounted.
+#[derive(Clone)]
+pub struct CounterService {
+    store: Store,
+    jobs: Jobs,
+    storage: Storage,
+}
+
+impl CounterService {
+    /// Fails if the `storage` setting is invalid or its file cannot be loaded.
+    pub fn from_config(config: &Config) -> Result<CounterService, String> {
+        let jobs = Jobs::from_config(config);
+        let store = Store::from_config(config);
+        let storage = Storage::from_config(config)?;
+
+        storage.attach(&store, &jobs, config)?;
+        store.schedule_sampler(&jobs, "timeseries");
+        Ok(CounterService {
+            store,
+            jobs,
+            storage,
+        })
+    }
+
+    /// Writes the counters to the `storage` file now, if there is one, rather than waiting for
+    /// the next `storage_interval`. For applications shutting down.
+    pub fn flush(&self) -> Result<(), String> {
+        self.storage.flush(&self.store)
+    }
+
+    /// A new counter with a random id, starting from zero.
+    pub fn create(&self, name: Option<String>) -> Result<Counter, ApiError> {
+        let mut counter = Counter::new(Uuid::new_v4());
+
+        counter.name = name.and_then(non_empty);
+        create(counter, None, &self.store)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Counter> {
+        self.store.get(id)
+    }
+
+    /// Every counter, in no particular order.
+    pub fn list(&self) -> Vec<Counter> {
+        self.store
+            .read()
+            .into_iter()
+            .map(|(_, counter)| counter)
+            .collect()
+    }
+
+    /// Steps the counter up, creating it first if it does not exist.
+    pub fn increment(&self, id: Uuid) -> Result<Counter, ApiError> {
+        increment(id, &[], Annotation::default(), &self.store)
+    }
+
+    /// Steps the counter down, creating it first if it does not exist.
+    pub fn decrement(&self, id: Uuid) -> Result<Counter, ApiError> {
+        decrement(id, Annotation::default(), &self.store)
+    }
+
+    /// Mounts the HTTP API onto `rocket`, at `/` and `/v1` as `caas` serves it, with its
+    /// fairings and error catchers. Its configuration is read from `rocket`'s.
+    pub fn mount(&self, rocket: Rocket) -> Rocket {
+        api(rocket
+            .manage(self.jobs.clone())
+            .manage(self.store.clone())
+            .manage(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::Status;
+    use rocket::local::Client;
+
+    use crate::{rocket, CounterService};
+
+    #[test]
+    fn embed_counter_service() {
+        let config = Config::build(Environment::Development).finalize().unwrap();
+        let service = CounterService::from_config(&config).unwrap();
+        let counter = service.create(Some("Embedded".to_string())).unwrap();
+
+        service.increment(counter.id()).unwrap();
+
+        let client = Client::new(service.mount(rocket::custom(config))).expect("Init failed");
+        let response = client
+            .put(format!("/v1/counter/{}/increment", counter.id()))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(service.get(counter.id()).unwrap().value(), 2);
+        assert_eq!(service.list().len(), 1);
+        assert_eq!(service.list()[0].name(), Some("Embedded"));
+    }
+
+    #[test]
+    fn flush_counters_on_shutdown() {
+        let file = std::env::temp_dir().join(format!("caas-{}.json", uuid::Uuid::new_v4()));
+        let config = || {
+            Config::build(Environment::Development)
+                .extra("storage", format!("file:{}", file.display()))
+                .extra("storage_interval", 3600)
+                .finalize()
+                .unwrap()
+        };
+        let service = CounterService::from_config(&config()).unwrap();
+        let counter = service.create(Some("Flushed".to_string())).unwrap();
+
+        service.increment(counter.id()).unwrap();
+        service.flush().unwrap();
+
+        let restarted = CounterService::from_config(&config()).unwrap();
+
+        assert_eq!(restarted.get(counter.id()).unwrap().value(), 1);
+
+        let _ = std::fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,59 @@
+//! Graceful shutdown of `caas` on SIGTERM or SIGINT, so that a redeploy loses no change it has
+//! answered. From the signal on, writes are refused with 503 as in maintenance mode, though
+//! without saving the mode to `maintenance_file`. Requests already past that check get a moment
+//! to finish, the counters are written to the `storage` file one last time, and the process
+//! exits. Rocket 0.4 cannot stop accepting connections, so reads are served until the end.
+
+use rocket::Rocket;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::maintenance::Maintenance;
+use crate::service::CounterService;
+
+/// How long writes that got past the check before the signal have to finish.
+const GRACE: Duration = Duration::from_secs(1);
+/// How often to look for a signal.
+const POLL: Duration = Duration::from_millis(100);
+
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+/// Handles SIGTERM and SIGINT for the service that `rocket` manages.
+pub fn listen(rocket: &Rocket) {
+    let (service, maintenance) = match (
+        rocket.state::<CounterService>(),
+        rocket.state::<Maintenance>(),
+    ) {
+        (Some(service), Some(maintenance)) => (service.clone(), maintenance.clone()),
+        _ => return,
+    };
+
+    // The handler only sets a flag, which is about all that is safe in one.
+    unsafe {
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        while !SIGNALLED.load(Ordering::SeqCst) {
+            thread::sleep(POLL);
+        }
+
+        println!("Shutting down");
+        maintenance.drain();
+        thread::sleep(GRACE);
+
+        if let Err(reason) = service.flush() {
+            eprintln!("{}", reason);
+            process::exit(1);
+        }
+
+        process::exit(0);
+    });
+}
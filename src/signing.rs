@@ -0,0 +1,318 @@
+use hmac::{Hmac, Mac};
+use rocket::http::Method;
+use rocket::request::Request;
+use rocket::{Config, State};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::history;
+use crate::lock;
+
+/// Seconds a signature's timestamp may be off from the server's clock by default.
+pub const DEFAULT_TOLERANCE: u64 = 300;
+/// Upper bounds of the clock skew histogram buckets, in seconds.
+pub const SKEW_BUCKETS: [u64; 7] = [1, 5, 30, 60, 300, 900, 3600];
+/// Why signed requests are rejected: no usable header, a timestamp outside the tolerance, or
+/// a signature that does not match.
+pub const REJECTIONS: [&str; 3] = ["missing", "stale", "invalid"];
+
+/// Verification of signed requests for server-to-server integrations, enabled by
+/// `signing_secret`. Requests that change counters must then carry
+/// `X-Signature: t=<unix seconds>,v1=<hex>`, where the hex is the HMAC-SHA256 of
+/// `<unix seconds>.<body>` under the secret. Requests older or newer than `signing_tolerance`
+/// seconds are rejected, so that a captured request cannot be replayed later. How far off
+/// clients' clocks are, and why requests were rejected, is observed for `/metrics`.
+#[derive(Default)]
+pub struct Signing {
+    secret: Option<Vec<u8>>,
+    tolerance: u64,
+    observed: Mutex<Observations>,
+}
+
+/// Clock skew of signed requests, whether accepted or not, and rejections by reason.
+#[derive(Clone, Default)]
+pub struct Observations {
+    pub rejections: BTreeMap<&'static str, u64>,
+    pub skew_buckets: [u64; SKEW_BUCKETS.len()],
+    pub skew_count: u64,
+    pub skew_sum: u64,
+}
+
+impl Signing {
+    pub fn from_config(config: &Config) -> Signing {
+        Signing {
+            secret: config
+                .get_str("signing_secret")
+                .ok()
+                .map(|secret| secret.as_bytes().to_vec()),
+            tolerance: config
+                .get_int("signing_tolerance")
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .map_or(DEFAULT_TOLERANCE, |seconds| seconds as u64),
+            observed: Mutex::default(),
+        }
+    }
+
+    pub fn observed(&self) -> Observations {
+        lock(&self.observed).clone()
+    }
+
+    fn reject(&self, reason: &'static str) -> bool {
+        *lock(&self.observed).rejections.entry(reason).or_insert(0) += 1;
+        false
+    }
+
+    fn observe_skew(&self, skew: u64) {
+        let mut observed = lock(&self.observed);
+
+        for (bucket, bound) in observed.skew_buckets.iter_mut().zip(SKEW_BUCKETS.iter()) {
+            if skew <= *bound {
+                *bucket += 1;
+            }
+        }
+
+        observed.skew_count += 1;
+        observed.skew_sum += skew;
+    }
+
+    /// Checks the signature header of a request that changes counters. The body is checked
+    /// once it has been read, which every route does for methods that carry one, except for
+    /// `DELETE` requests: they are signed with an empty body, as for `GET`, since routes never
+    /// read one.
+    pub fn check_header(&self, request: &Request) -> bool {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return true,
+        };
+        let (timestamp, mac) = match request
+            .headers()
+            .get_one("X-Signature")
+            .and_then(parse_header)
+        {
+            Some(signature) => signature,
+            None => return self.reject("missing"),
+        };
+        let skew = skew(timestamp);
+
+        self.observe_skew(skew);
+
+        if skew > self.tolerance {
+            return self.reject("stale");
+        }
+
+        if request.method() == Method::Delete || !request.method().supports_payload() {
+            return verify(secret, timestamp, b"", &mac) || self.reject("invalid");
+        }
+
+        request.local_cache(|| Pending(Some((secret.clone(), timestamp, mac))));
+        true
+    }
+}
+
+/// A signature whose header checked out, waiting for the body to be read.
+struct Pending(Option<(Vec<u8>, u64, Vec<u8>)>);
+
+/// Whether `body` matches the request's signature, if a signature is waiting for it.
+pub fn verify_body(request: &Request, body: &[u8]) -> bool {
+    let verified = match &request.local_cache(|| Pending(None)).0 {
+        Some((secret, timestamp, mac)) => verify(secret, *timestamp, body, mac),
+        None => true,
+    };
+
+    if !verified {
+        if let Some(signing) = request.guard::<State<Signing>>().succeeded() {
+            signing.reject("invalid");
+        }
+    }
+
+    verified
+}
+
+/// The `X-Signature` header of a request this instance sends with `body`, signed under `secret`
+/// the same way as the requests it verifies.
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let timestamp = history::timestamp() / 1000;
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes keys of any length");
+
+    mac.input(format!("{}.", timestamp).as_bytes());
+    mac.input(body);
+
+    format!("t={},v1={}", timestamp, encode_hex(&mac.result().code()))
+}
+
+/// Whether `header` signs `body` under `secret` within `tolerance` seconds of now, for requests
+/// whose whole body is at hand.
+pub fn verify_header(secret: &[u8], header: &str, body: &[u8], tolerance: u64) -> bool {
+    match parse_header(header) {
+        Some((timestamp, mac)) => {
+            fresh(timestamp, tolerance) && verify(secret, timestamp, body, &mac)
+        }
+        None => false,
+    }
+}
+
+fn fresh(timestamp: u64, tolerance: u64) -> bool {
+    skew(timestamp) <= tolerance
+}
+
+/// Seconds between `timestamp` and the server's clock, either way.
+fn skew(timestamp: u64) -> u64 {
+    let now = history::timestamp() / 1000;
+
+    if now > timestamp {
+        now - timestamp
+    } else {
+        timestamp - now
+    }
+}
+
+fn parse_header(header: &str) -> Option<(u64, Vec<u8>)> {
+    let mut timestamp = None;
+    let mut mac = None;
+
+    for part in header.split(',') {
+        let mut pair = part.trim().splitn(2, '=');
+
+        match (pair.next(), pair.next()) {
+            (Some("t"), Some(value)) => timestamp = value.parse().ok(),
+            (Some("v1"), Some(value)) => mac = decode_hex(value),
+            _ => {}
+        }
+    }
+
+    Some((timestamp?, mac?))
+}
+
+/// `bytes` in lowercase hexadecimal, two digits each.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn verify(secret: &[u8], timestamp: u64, body: &[u8], expected: &[u8]) -> bool {
+    let mut mac = match Hmac::<Sha256>::new_varkey(secret) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+
+    mac.input(format!("{}.", timestamp).as_bytes());
+    mac.input(body);
+    // Compares in constant time.
+    mac.verify(expected).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use super::encode_hex;
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn verify_request_signatures() {
+        use hmac::{Hmac, Mac};
+
+        let config = Config::build(Environment::Development)
+            .extra("signing_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let sign = |timestamp: u64, body: &str| {
+            let mut mac = Hmac::<sha2::Sha256>::new_varkey(b"s3cret").unwrap();
+
+            mac.input(format!("{}.{}", timestamp, body).as_bytes());
+
+            Header::new(
+                "X-Signature",
+                format!("t={},v1={}", timestamp, encode_hex(&mac.result().code())),
+            )
+        };
+        let now = crate::history::timestamp() / 1000;
+        let body = r#"{ "name": "Signed" }"#;
+        let create = |signature: Header<'static>, body: &str| {
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .header(signature)
+                .body(body)
+                .dispatch()
+        };
+
+        assert_eq!(create(sign(now, body), body).status(), Status::Ok);
+
+        let mut tampered_response = create(sign(now, body), r#"{ "name": "Tampered" }"#);
+
+        assert_eq!(tampered_response.status(), Status::Unauthorized);
+        assert!(tampered_response
+            .body_string()
+            .unwrap()
+            .contains("does not match the body"));
+        assert_eq!(
+            create(sign(now - 3600, body), body).status(),
+            Status::Unauthorized
+        );
+
+        let unsigned_response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+        assert_eq!(unsigned_response.status(), Status::Unauthorized);
+
+        let mut create_response = create(sign(now, body), body);
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let next = |signature: Header<'static>| {
+            client
+                .post(format!("/counter/{}/next", counter.id))
+                .header(signature)
+                .dispatch()
+                .status()
+        };
+
+        // The route reads no body, yet the signature is still checked against the empty one.
+        assert_eq!(
+            next(Header::new(
+                "X-Signature",
+                format!("t={},v1={}", now, "00".repeat(32))
+            )),
+            Status::Unauthorized
+        );
+        assert_eq!(next(sign(now, "")), Status::Ok);
+
+        let exposition = client.get("/metrics").dispatch().body_string().unwrap();
+
+        assert!(exposition.contains("request_signature_rejections_total{reason=\"missing\"} 1\n"));
+        assert!(exposition.contains("request_signature_rejections_total{reason=\"stale\"} 1\n"));
+        assert!(exposition.contains("request_signature_rejections_total{reason=\"invalid\"} 2\n"));
+        assert!(exposition.contains("request_signature_skew_seconds_count 6\n"));
+
+        let reload = |signature: Option<Header<'static>>| {
+            let mut request = client.post("/admin/reload");
+
+            if let Some(signature) = signature {
+                request = request.header(signature);
+            }
+
+            request.dispatch().status()
+        };
+
+        assert_eq!(reload(None), Status::Unauthorized);
+        assert_eq!(reload(Some(sign(now, ""))), Status::Ok);
+    }
+}
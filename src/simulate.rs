@@ -0,0 +1,203 @@
+//! `POST /counter/<id>/simulate`, for trying out a counter's automations: threshold webhooks,
+//! milestone hooks, alerts and notification channels. The operations are applied one by one to
+//! a copy of the counter, which is clamped at its bounds as real increments and decrements are,
+//! and each trigger is checked against every value on the way, starting from the state it is
+//! in now. Nothing is stored or delivered, and the triggers' own state is left as it was.
+
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use uuid::Uuid;
+
+use crate::alerts::Alerts;
+use crate::auth::Writer;
+use crate::negotiation::Body;
+use crate::notify::Notifiers;
+use crate::tenancy::TenantStore;
+use crate::transaction::Action;
+use crate::validation::{Validate, Violations};
+use crate::webhooks::Webhooks;
+use crate::{error, not_found_error, parse_id, ApiError};
+
+/// Most operations that one simulation can hold.
+const MAX_OPERATIONS: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct Operation {
+    operation: Action,
+    /// Defaults to 1.
+    steps: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct Simulation {
+    operations: Vec<Operation>,
+}
+
+impl Validate for Simulation {
+    fn validate(&self, violations: &mut Violations) {
+        for (index, operation) in self.operations.iter().enumerate() {
+            violations.positive(&format!("operations[{}].steps", index), operation.steps);
+        }
+    }
+}
+
+/// A trigger that would fire.
+#[derive(Serialize)]
+pub struct Fired {
+    /// Index of the operation that makes it fire.
+    pub operation: usize,
+    /// `webhook`, `milestone_hook`, `alert` or `channel`.
+    pub trigger: &'static str,
+    pub id: Uuid,
+    /// The counter's value at that point.
+    pub value: u32,
+}
+
+#[derive(Serialize)]
+pub struct Outcome {
+    /// The counter's value after each operation.
+    values: Vec<u32>,
+    /// In the order they would fire.
+    fired: Vec<Fired>,
+}
+
+/// `POST /counter/<id>/simulate` with `{ "operations": [{ "operation": "increment",
+/// "steps": 3 }, { "operation": "decrement" }] }`. Alerts are reported even if they would be
+/// held back by the minimum interval between emails, and idle alerts, which depend on time
+/// passing rather than on values, never are.
+#[post("/<id>/simulate", data = "<simulation>")]
+pub fn simulate(
+    id: String,
+    simulation: Body<Simulation>,
+    store: TenantStore,
+    webhooks: State<Webhooks>,
+    alerts: State<Alerts>,
+    notifiers: State<Notifiers>,
+    writer: Writer,
+) -> Result<Json<Outcome>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let Simulation { operations } = simulation.into_inner();
+
+    if operations.is_empty() || operations.len() > MAX_OPERATIONS {
+        return Err(error(
+            Status::BadRequest,
+            &format!(
+                "A simulation takes between 1 and {} operations.",
+                MAX_OPERATIONS
+            ),
+        ));
+    }
+
+    // The triggers' conditions and recipients are only shown to those who may change the
+    // counter.
+    writer.check(parsed_uuid, &store)?;
+
+    let mut counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    counter.ensure_mutable()?;
+    counter.ensure_steppable()?;
+
+    let values: Vec<u32> = operations
+        .iter()
+        .map(|operation| {
+            let steps = operation.steps.unwrap_or(1);
+
+            match operation.operation {
+                Action::Increment => counter.step_up_by(steps),
+                Action::Decrement => counter.step_down_by(steps),
+            };
+            counter.value
+        })
+        .collect();
+
+    let mut fired = webhooks.simulate(parsed_uuid, &values);
+
+    fired.extend(alerts.simulate(parsed_uuid, &values));
+    fired.extend(notifiers.simulate(parsed_uuid, &values));
+    fired.sort_by_key(|fired| fired.operation);
+
+    Ok(Json(Outcome { values, fired }))
+}
+
+/// Runs `fires` for each of `values` in turn, collecting a `Fired` for each time it returns
+/// true.
+pub fn walk<F>(trigger: &'static str, id: Uuid, values: &[u32], mut fires: F) -> Vec<Fired>
+where
+    F: FnMut(u32) -> bool,
+{
+    values
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| fires(**value))
+        .map(|(operation, value)| Fired {
+            operation,
+            trigger,
+            id,
+            value: *value,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn simulate_triggers() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let mut webhook_response = client
+            .post(format!("/counter/{}/webhooks", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "url": "http://127.0.0.1:9/hook", "condition": "value >= 2" }"#)
+            .dispatch();
+        let webhook: serde_json::Value =
+            serde_json::from_str(&webhook_response.body_string().unwrap()).unwrap();
+        let simulate = |body: &str| {
+            client
+                .post(format!("/counter/{}/simulate", counter.id))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+
+        let mut simulate_response = simulate(
+            r#"{ "operations": [
+                { "operation": "increment", "steps": 3 },
+                { "operation": "decrement", "steps": 2 },
+                { "operation": "increment" }
+            ] }"#,
+        );
+        let outcome: serde_json::Value =
+            serde_json::from_str(&simulate_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(simulate_response.status(), Status::Ok);
+        assert_eq!(outcome["values"], serde_json::json!([3, 1, 2]));
+        assert_eq!(outcome["fired"].as_array().unwrap().len(), 2);
+        assert_eq!(outcome["fired"][0]["trigger"], "webhook");
+        assert_eq!(outcome["fired"][0]["id"], webhook["id"]);
+        assert_eq!(outcome["fired"][0]["operation"], 0);
+        assert_eq!(outcome["fired"][1]["operation"], 2);
+        assert_eq!(outcome["fired"][1]["value"], 2);
+
+        let mut get_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+        let unchanged: Counter =
+            serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(unchanged.value, 0);
+        assert_eq!(
+            simulate(r#"{ "operations": [] }"#).status(),
+            Status::BadRequest
+        );
+        assert_eq!(
+            simulate(r#"{ "operations": [{ "operation": "increment", "steps": 0 }] }"#).status(),
+            Status::UnprocessableEntity
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! `POST /integrations/slack`, the endpoint of a Slack slash command such as `/count inc
+//! deploys`. Slack signs every request with the app's signing secret, configured as
+//! `slack_signing_secret`, and that signature stands in for an API key: the command can change
+//! any counter that has neither an owner nor a write token.
+
+use hmac::{Hmac, Mac};
+use rocket::data::Data;
+use rocket::http::Status;
+use rocket::request::{self, FormItems, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+use rocket_contrib::json::{Json, JsonValue};
+use sha2::Sha256;
+use std::io::Read;
+use uuid::Uuid;
+
+use crate::features::{Feature, Features};
+use crate::history;
+use crate::ratelimit::Throttle;
+use crate::routes::{create, decrement, increment, Annotation};
+use crate::signing::decode_hex;
+use crate::tenancy::TenantStore;
+use crate::{error, ApiError, Counter};
+
+/// Slash command payloads are a few hundred bytes.
+const MAX_BYTES: u64 = 16 * 1024;
+/// Seconds a request's timestamp may be off from the server's clock, as Slack recommends.
+const TOLERANCE: u64 = 300;
+const USAGE: &str = "Usage: `/count inc <counter>`, `/count dec <counter>` or `/count get \
+                     <counter>`, where the counter is a name, an alias or an id.";
+
+#[derive(Default)]
+pub struct SlackSecret(Option<Vec<u8>>);
+
+impl SlackSecret {
+    pub fn from_config(config: &Config) -> SlackSecret {
+        SlackSecret(
+            config
+                .get_str("slack_signing_secret")
+                .ok()
+                .map(|secret| secret.as_bytes().to_vec()),
+        )
+    }
+}
+
+/// The headers Slack signs its requests with.
+pub struct Signature {
+    timestamp: Option<u64>,
+    mac: Option<Vec<u8>>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Signature {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Signature, ()> {
+        let headers = request.headers();
+
+        Outcome::Success(Signature {
+            timestamp: headers
+                .get_one("X-Slack-Request-Timestamp")
+                .and_then(|timestamp| timestamp.parse().ok()),
+            mac: headers
+                .get_one("X-Slack-Signature")
+                .filter(|signature| signature.starts_with("v0="))
+                .and_then(|signature| decode_hex(&signature["v0=".len()..])),
+        })
+    }
+}
+
+impl Signature {
+    /// Whether `body` was signed with `secret` within the last few minutes.
+    fn verify(&self, secret: &[u8], body: &[u8]) -> bool {
+        let (timestamp, expected) = match (self.timestamp, &self.mac) {
+            (Some(timestamp), Some(expected)) => (timestamp, expected),
+            _ => return false,
+        };
+        let now = history::timestamp() / 1000;
+        let skew = if now > timestamp {
+            now - timestamp
+        } else {
+            timestamp - now
+        };
+        let mut mac = match Hmac::<Sha256>::new_varkey(secret) {
+            Ok(mac) => mac,
+            Err(_) => return false,
+        };
+
+        mac.input(format!("v0:{}:", timestamp).as_bytes());
+        mac.input(body);
+        skew <= TOLERANCE && mac.verify(expected).is_ok()
+    }
+}
+
+/// A reply only the caller sees.
+fn ephemeral(text: &str) -> Json<JsonValue> {
+    Json(json!({ "response_type": "ephemeral", "text": text }))
+}
+
+fn label(counter: &Counter) -> String {
+    match &counter.name {
+        Some(name) => format!("*{}*", name),
+        None => format!("`{}`", counter.id),
+    }
+}
+
+/// The counter `target` refers to by id, alias or exact name. A name that several counters
+/// share refers to none of them.
+fn find(target: &str, store: &TenantStore) -> Result<Option<Counter>, String> {
+    if let Ok(id) = Uuid::parse_str(target) {
+        return Ok(store.get(id));
+    }
+
+    if let Some(id) = store.resolve(target) {
+        return Ok(store.get(id));
+    }
+
+    let mut named = store
+        .read()
+        .into_iter()
+        .map(|(_, counter)| counter)
+        .filter(|counter| counter.name.as_deref() == Some(target))
+        .collect::<Vec<Counter>>();
+
+    match named.len() {
+        0 => Ok(None),
+        1 => Ok(named.pop()),
+        _ => Err(format!("Several counters are named {}; use an id.", target)),
+    }
+}
+
+fn run(text: &str, user: &str, store: &TenantStore, features: &Features) -> Result<String, String> {
+    let mut words = text.split_whitespace();
+    let (action, target) = match (words.next(), words.next(), words.next()) {
+        (Some(action), Some(target), None) => (action, target),
+        _ => return Err(USAGE.to_string()),
+    };
+    let reason = |user: &str| Annotation {
+        reason: Some(format!("Slack: @{}", user)),
+        occurred_at: None,
+    };
+    let message = |error: ApiError| error.1["reason"].as_str().unwrap_or("").to_string();
+    let counter = find(target, store)?;
+
+    if let Some(counter) = &counter {
+        if counter.owner.is_some() || counter.write_token.is_some() {
+            return Err(format!(
+                "{} can only be changed through the API.",
+                label(counter)
+            ));
+        }
+    }
+
+    let counter = match (action, counter) {
+        ("get", Some(counter)) => counter,
+        ("inc", Some(counter)) | ("increment", Some(counter)) => {
+            increment(counter.id, &[], reason(user), store).map_err(message)?
+        }
+        ("inc", None) | ("increment", None) if features.enabled(Feature::AutoCreate) => {
+            let mut counter = Counter::new(Uuid::new_v4());
+
+            counter.name = Some(target.to_string());
+
+            let counter = create(counter, reason(user).reason, store).map_err(message)?;
+
+            increment(counter.id, &[], reason(user), store).map_err(message)?
+        }
+        ("dec", Some(counter)) | ("decrement", Some(counter)) => {
+            decrement(counter.id, reason(user), store).map_err(message)?
+        }
+        ("get", None)
+        | ("inc", None)
+        | ("increment", None)
+        | ("dec", None)
+        | ("decrement", None) => return Err(format!("There is no counter {}.", target)),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    Ok(format!("{} is at {}.", label(&counter), counter.value))
+}
+
+/// Answers with 401 when the signature does not check out, and otherwise with 200 whatever
+/// happened, as Slack only shows the replies to those. Changes are answered in the channel,
+/// everything else only to the caller.
+#[post("/slack", data = "<payload>")]
+pub fn command(
+    payload: Data,
+    signature: Signature,
+    secret: State<SlackSecret>,
+    store: TenantStore,
+    features: State<Features>,
+    _throttle: Throttle,
+) -> Result<Json<JsonValue>, ApiError> {
+    let secret = secret
+        .0
+        .as_ref()
+        .ok_or_else(|| error(Status::NotFound, "The Slack integration is not configured."))?;
+    let mut body = Vec::new();
+
+    payload
+        .open()
+        .take(MAX_BYTES)
+        .read_to_end(&mut body)
+        .map_err(|_| error(Status::BadRequest, "Could not read the request."))?;
+
+    if !signature.verify(secret, &body) {
+        return Err(error(
+            Status::Unauthorized,
+            "Slack signature is missing, stale or invalid.",
+        ));
+    }
+
+    let body = String::from_utf8_lossy(&body);
+    let mut text = String::new();
+    let mut user = String::new();
+
+    for item in FormItems::from(&*body) {
+        match item.key.as_str() {
+            "text" => text = item.value.url_decode_lossy(),
+            "user_name" => user = item.value.url_decode_lossy(),
+            _ => {}
+        }
+    }
+
+    let reads = text.trim().starts_with("get ");
+
+    Ok(match run(&text, &user, &store, &features) {
+        Ok(reply) if !reads => Json(json!({ "response_type": "in_channel", "text": reply })),
+        Ok(reply) => ephemeral(&reply),
+        Err(reason) => ephemeral(&reason),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::signing::encode_hex;
+    use crate::{build, rocket};
+
+    #[test]
+    fn run_slack_commands() {
+        use hmac::{Hmac, Mac};
+
+        let config = Config::build(Environment::Development)
+            .extra("slack_signing_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let now = crate::history::timestamp() / 1000;
+        let command = |timestamp: u64, text: &str| {
+            let body = format!(
+                "team_domain=example&user_name=ada&command=%2Fcount&text={}",
+                text
+            );
+            let mut mac = Hmac::<sha2::Sha256>::new_varkey(b"s3cret").unwrap();
+
+            mac.input(format!("v0:{}:{}", timestamp, body).as_bytes());
+
+            let signature = encode_hex(&mac.result().code());
+
+            client
+                .post("/integrations/slack")
+                .header(ContentType::Form)
+                .header(Header::new(
+                    "X-Slack-Request-Timestamp",
+                    timestamp.to_string(),
+                ))
+                .header(Header::new(
+                    "X-Slack-Signature",
+                    format!("v0={}", signature),
+                ))
+                .body(body)
+                .dispatch()
+        };
+        let reply = |mut response: rocket::local::LocalResponse| -> serde_json::Value {
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+
+        let created = reply(command(now, "inc+deploys"));
+
+        assert_eq!(created["response_type"], "in_channel");
+        assert_eq!(created["text"], "*deploys* is at 1.");
+        assert_eq!(
+            reply(command(now, "inc+deploys"))["text"],
+            "*deploys* is at 2."
+        );
+        assert_eq!(
+            reply(command(now, "dec+deploys"))["text"],
+            "*deploys* is at 1."
+        );
+
+        let shown = reply(command(now, "get+deploys"));
+
+        assert_eq!(shown["response_type"], "ephemeral");
+        assert_eq!(shown["text"], "*deploys* is at 1.");
+        assert_eq!(
+            reply(command(now, "get+releases"))["text"],
+            "There is no counter releases."
+        );
+        assert!(reply(command(now, ""))["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("Usage:"));
+        assert_eq!(
+            command(now - 3600, "inc+deploys").status(),
+            Status::Unauthorized
+        );
+
+        let unsigned_response = client
+            .post("/integrations/slack")
+            .header(ContentType::Form)
+            .body("text=inc+deploys")
+            .dispatch();
+
+        assert_eq!(unsigned_response.status(), Status::Unauthorized);
+
+        let unconfigured = Client::new(rocket()).expect("Init failed");
+
+        assert_eq!(
+            unconfigured
+                .post("/integrations/slack")
+                .header(ContentType::Form)
+                .body("text=inc+deploys")
+                .dispatch()
+                .status(),
+            Status::NotFound
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use rocket::http::Status;
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::history::{self, Entry, Operation};
+use crate::lock;
+use crate::negotiation::{Body, OptionalBody};
+use crate::routes::{non_empty, reason, Annotation};
+use crate::store::CounterMap;
+use crate::tenancy::TenantStore;
+use crate::validation::Validate;
+use crate::{error, not_found_error, ApiError};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Named copies of every counter, kept in memory so the store can be rolled back to them.
+#[derive(Default)]
+pub struct Snapshots {
+    /// Keyed by tenant and name, as each tenant has a store of its own.
+    saved: Mutex<BTreeMap<(String, String), Snapshot>>,
+    /// Most snapshots each tenant can keep, as `max_snapshots` sets. Unlimited unless set.
+    max: Option<usize>,
+}
+
+struct Snapshot {
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    counters: CounterMap,
+}
+
+#[derive(Deserialize)]
+pub struct NewSnapshot {
+    name: String,
+}
+
+impl Validate for NewSnapshot {}
+
+#[derive(Serialize)]
+pub struct Summary {
+    name: String,
+    created_at: u64,
+    counters: usize,
+}
+
+/// How a single counter differs between two states. `from` or `to` is missing when the
+/// counter did not exist on that side.
+#[derive(Serialize)]
+pub struct Change {
+    id: Uuid,
+    from: Option<u32>,
+    to: Option<u32>,
+    delta: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct Diff {
+    created: Vec<Change>,
+    deleted: Vec<Change>,
+    changed: Vec<Change>,
+}
+
+impl Summary {
+    fn new(name: &str, snapshot: &Snapshot) -> Summary {
+        Summary {
+            name: name.to_string(),
+            created_at: snapshot.created_at,
+            counters: snapshot.counters.len(),
+        }
+    }
+}
+
+impl Snapshots {
+    pub fn from_config(config: &Config) -> Snapshots {
+        Snapshots {
+            max: config
+                .get_int("max_snapshots")
+                .ok()
+                .map(|max| max.max(0) as usize),
+            ..Default::default()
+        }
+    }
+
+    /// When the most recent snapshot of any tenant was taken.
+    pub fn newest(&self) -> Option<u64> {
+        lock(&self.saved)
+            .values()
+            .map(|snapshot| snapshot.created_at)
+            .max()
+    }
+}
+
+/// Counters that were created, deleted or changed value between `before` and `after`,
+/// ordered by id.
+fn changes(before: &CounterMap, after: &CounterMap) -> Vec<Change> {
+    let mut ids: Vec<Uuid> = before.keys().chain(after.keys()).cloned().collect();
+
+    ids.sort();
+    ids.dedup();
+    ids.into_iter()
+        .filter_map(|id| {
+            let from = before.get(&id).map(|counter| counter.value);
+            let to = after.get(&id).map(|counter| counter.value);
+
+            if from == to {
+                return None;
+            }
+
+            Some(Change {
+                id,
+                from,
+                to,
+                delta: i64::from(to.unwrap_or(0)) - i64::from(from.unwrap_or(0)),
+            })
+        })
+        .collect()
+}
+
+#[get("/snapshots")]
+pub fn list_snapshots(snapshots: State<Snapshots>, store: TenantStore) -> Json<Vec<Summary>> {
+    Json(
+        lock(&snapshots.saved)
+            .iter()
+            .filter(|((tenant, _), _)| tenant == store.tenant())
+            .map(|((_, name), snapshot)| Summary::new(name, snapshot))
+            .collect(),
+    )
+}
+
+#[post("/snapshots", data = "<new_snapshot>")]
+pub fn create_snapshot(
+    new_snapshot: Body<NewSnapshot>,
+    snapshots: State<Snapshots>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Summary>, ApiError> {
+    dry_run.refuse()?;
+
+    writer.check_admin()?;
+
+    let name = match non_empty(new_snapshot.into_inner().name) {
+        Some(ref name) if name.len() <= MAX_NAME_LENGTH => name.clone(),
+        _ => {
+            return Err(error(
+                Status::BadRequest,
+                "Snapshot names must be between 1 and 64 characters long.",
+            ))
+        }
+    };
+    let key = (store.tenant().to_string(), name);
+    let limit = snapshots.max;
+    let mut snapshots = lock(&snapshots.saved);
+
+    if snapshots.contains_key(&key) {
+        return Err(error(Status::Conflict, "Snapshot already exists."));
+    }
+
+    let kept = snapshots
+        .keys()
+        .filter(|(tenant, _)| *tenant == key.0)
+        .count();
+
+    if limit.map_or(false, |max| kept >= max) {
+        return Err(error(
+            Status::InsufficientStorage,
+            "Snapshot limit reached.",
+        ));
+    }
+
+    let snapshot = Snapshot {
+        created_at: history::timestamp(),
+        counters: store.read(),
+    };
+    let summary = Summary::new(&key.1, &snapshot);
+
+    snapshots.insert(key, snapshot);
+    Ok(Json(summary))
+}
+
+/// Replaces every counter with its state in the snapshot. Each counter whose value changes
+/// gets a `rollback` history entry with the delta, so the rolled-back counts stay auditable.
+#[post("/snapshots/<name>/rollback", data = "<annotation>")]
+pub fn rollback(
+    name: String,
+    annotation: OptionalBody<Annotation>,
+    snapshots: State<Snapshots>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Vec<Change>>, ApiError> {
+    writer.check_admin()?;
+
+    let counters = lock(&snapshots.saved)
+        .get(&(store.tenant().to_string(), name))
+        .map(|snapshot| snapshot.counters.clone())
+        .ok_or_else(not_found_error)?;
+    let reason = reason(annotation);
+    let replaced = store.restore(counters.clone());
+    let changes = changes(&replaced, &counters);
+
+    for change in &changes {
+        store.history().record(
+            change.id,
+            Entry::new(
+                Operation::Rollback,
+                change.delta,
+                change.to.unwrap_or(0),
+                reason.clone(),
+            ),
+        );
+    }
+
+    Ok(Json(changes))
+}
+
+/// What happened between snapshots `a` and `b`: counters created, deleted, and whose value
+/// changed, each with its delta.
+#[get("/snapshots/<a>/diff/<b>")]
+pub fn diff(
+    a: String,
+    b: String,
+    snapshots: State<Snapshots>,
+    store: TenantStore,
+) -> Result<Json<Diff>, ApiError> {
+    let snapshots = lock(&snapshots.saved);
+    let tenant = store.tenant().to_string();
+    let (before, after) = match (
+        snapshots.get(&(tenant.clone(), a)),
+        snapshots.get(&(tenant, b)),
+    ) {
+        (Some(before), Some(after)) => (before, after),
+        _ => return Err(not_found_error()),
+    };
+    let mut diff = Diff::default();
+
+    for change in changes(&before.counters, &after.counters) {
+        match (change.from, change.to) {
+            (None, _) => diff.created.push(change),
+            (_, None) => diff.deleted.push(change),
+            _ => diff.changed.push(change),
+        }
+    }
+
+    Ok(Json(diff))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn rollback_to_snapshot() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let increment = || {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        };
+
+        increment();
+
+        let snapshot_response = client
+            .post("/admin/snapshots")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "before-launch" }"#)
+            .dispatch();
+
+        assert_eq!(snapshot_response.status(), Status::Ok);
+
+        increment();
+        increment();
+
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let later: Counter = serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        let mut rollback_response = client
+            .post("/admin/snapshots/before-launch/rollback")
+            .header(ContentType::JSON)
+            .body(r#"{ "reason": "bad deploy" }"#)
+            .dispatch();
+
+        assert_eq!(rollback_response.status(), Status::Ok);
+
+        let changes: serde_json::Value =
+            serde_json::from_str(&rollback_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(changes.as_array().unwrap().len(), 2);
+
+        let mut response = client
+            .get(format!("/counter/{}", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        let restored: Counter = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(restored.value, 1);
+
+        let mut history_response = client
+            .get(format!(
+                "/counter/{}/history?operation=rollback",
+                counter.id
+            ))
+            .header(ContentType::JSON)
+            .dispatch();
+        let history: serde_json::Value =
+            serde_json::from_str(&history_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(history["entries"][0]["delta"], -2);
+        assert_eq!(history["entries"][0]["reason"], "bad deploy");
+
+        let later_response = client
+            .get(format!("/counter/{}", later.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(later_response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let create = || -> Counter {
+            let mut response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+        let snapshot = |name: &str| {
+            client
+                .post("/admin/snapshots")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "name": "{}" }}"#, name))
+                .dispatch();
+        };
+        let changed = create();
+        let unchanged = create();
+
+        snapshot("a");
+
+        for _ in 0..3 {
+            client
+                .put(format!("/counter/{}/increment", changed.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let created = create();
+
+        snapshot("b");
+
+        let mut response = client
+            .get("/admin/snapshots/a/diff/b")
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let diff: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(diff["created"][0]["id"], created.id.to_string());
+        assert_eq!(diff["deleted"].as_array().unwrap().len(), 0);
+        assert_eq!(diff["changed"].as_array().unwrap().len(), 1);
+        assert_eq!(diff["changed"][0]["id"], changed.id.to_string());
+        assert_eq!(diff["changed"][0]["delta"], 3);
+        assert_ne!(diff["changed"][0]["id"], unchanged.id.to_string());
+
+        let missing_response = client
+            .get("/admin/snapshots/a/diff/missing")
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(missing_response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn limit_snapshots() {
+        let config = Config::build(Environment::Development)
+            .extra("max_snapshots", 1)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let snapshot = |name: &str| {
+            client
+                .post("/admin/snapshots")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "name": "{}" }}"#, name))
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(snapshot("a"), Status::Ok);
+        assert_eq!(snapshot("b"), Status::InsufficientStorage);
+    }
+}
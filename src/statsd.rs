@@ -0,0 +1,418 @@
+//! statsd in both directions. Counter packets are ingested over UDP, for emitters sending too
+//! many increments to spend an HTTP request on each, on `statsd_port` next to the HTTP one. And
+//! for deployments without Prometheus, service metrics are emitted to a statsd or DogStatsD
+//! agent at `statsd_sink`.
+//!
+//! Each ingested line of a packet looks like `signups:1|c`, optionally with a sample rate as in
+//! `signups:1|c|@0.1`. It adds that many steps to the counter named `signups`, or removes
+//! them if negative, creating the counter if no counter has that name. Other metric types
+//! and malformed lines are dropped, as statsd has no way to report errors back, and so are
+//! lines that an anonymous HTTP write in their place would be refused for; see `Ingest`.
+
+use rocket::http::StatusClass;
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::{Config, Rocket, State};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use uuid::Uuid;
+
+use crate::audit::Changes;
+use crate::auth::{self, ApiKeys, Jwt};
+use crate::firewall::IpRules;
+use crate::history::{Entry, Operation};
+use crate::lock;
+use crate::maintenance::Maintenance;
+use crate::metrics;
+use crate::replica::Leader;
+use crate::store::Store;
+use crate::Counter;
+
+/// Largest UDP payload.
+const MAX_PACKET: usize = 65_535;
+/// Most steps a single line can add or remove, after scaling by the sample rate.
+const MAX_STEPS: i64 = 10_000;
+
+struct Sample<'a> {
+    name: &'a str,
+    steps: i64,
+}
+
+fn parse(line: &str) -> Option<Sample> {
+    let mut fields = line.trim().split('|');
+    let mut metric = fields.next()?.rsplitn(2, ':');
+    let value: f64 = metric.next()?.parse().ok()?;
+    let name = metric.next()?.trim();
+
+    if name.is_empty() || fields.next()? != "c" {
+        return None;
+    }
+
+    let rate = match fields.next() {
+        Some(rate) if rate.starts_with('@') => rate[1..].parse().ok()?,
+        Some(_) => return None,
+        None => 1.0,
+    };
+
+    if !(rate > 0.0 && rate <= 1.0 && value.is_finite()) {
+        return None;
+    }
+
+    Some(Sample {
+        name,
+        steps: ((value / rate).round() as i64)
+            .max(-MAX_STEPS)
+            .min(MAX_STEPS),
+    })
+}
+
+/// Where ingested lines go, and what they are checked against. Lines are anonymous writes, so
+/// they are dropped as HTTP ones would be refused: while API keys or JWTs are configured, in
+/// maintenance mode, on read replicas and from addresses the write `ip_rules` block. Nor do
+/// they change counters that have a write token or an owner.
+pub struct Ingest {
+    store: Store,
+    api_keys: ApiKeys,
+    jwt: Jwt,
+    maintenance: Maintenance,
+    ip_rules: IpRules,
+    replica: bool,
+    /// Counter ids by name, so that a name is only looked up among every counter once, and
+    /// held while a line is applied, so that two lines never create two counters of one name.
+    names: Mutex<HashMap<String, Uuid>>,
+}
+
+impl Ingest {
+    /// With the store and the settings that `rocket` manages.
+    pub fn from_rocket(rocket: &Rocket) -> Option<Ingest> {
+        Some(Ingest {
+            store: rocket.state::<Store>()?.clone(),
+            api_keys: rocket.state::<ApiKeys>().cloned().unwrap_or_default(),
+            jwt: rocket.state::<Jwt>().cloned().unwrap_or_default(),
+            maintenance: rocket.state::<Maintenance>().cloned().unwrap_or_default(),
+            ip_rules: rocket.state::<IpRules>().cloned().unwrap_or_default(),
+            replica: rocket.state::<Leader>().is_some(),
+            names: Mutex::default(),
+        })
+    }
+
+    /// Applies every counter line in `packet`, sent from `from`.
+    pub fn ingest(&self, packet: &[u8], from: Option<IpAddr>) {
+        let refused = self.replica
+            || auth::secured(&self.api_keys, &self.jwt)
+            || self.maintenance.window().is_some()
+            || !self.ip_rules.allows_write(from);
+
+        if refused {
+            return;
+        }
+
+        let packet = String::from_utf8_lossy(packet);
+
+        for sample in packet.lines().filter_map(parse) {
+            self.apply(&sample);
+        }
+    }
+
+    /// The counter named `name`, the oldest if there are several.
+    fn named(&self, name: &str, names: &HashMap<String, Uuid>) -> Option<Uuid> {
+        let is_named =
+            |counter: &Counter| !counter.archived && counter.name.as_deref() == Some(name);
+        let known = names.get(name).filter(|id| {
+            self.store
+                .get(**id)
+                .map_or(false, |counter| is_named(&counter))
+        });
+
+        if let Some(id) = known {
+            return Some(*id);
+        }
+
+        self.store
+            .read()
+            .values()
+            .filter(|counter| is_named(counter))
+            .min_by_key(|counter| (counter.created_at, counter.id))
+            .map(|counter| counter.id)
+    }
+
+    fn apply(&self, sample: &Sample) {
+        let mut names = lock(&self.names);
+        let id = self.named(sample.name, &names).unwrap_or_else(Uuid::new_v4);
+        let store = &self.store;
+
+        // Over quota there is nowhere to put a new counter, and nobody to tell.
+        let created = store.write_or_create(id, |counters| {
+            let counter = counters.entry(id).or_insert_with(|| {
+                let mut counter = Counter::new(id);
+
+                counter.name = Some(sample.name.to_string());
+                counter
+            });
+            let writable = counter.write_token.is_none()
+                && counter.owner.is_none()
+                && counter.ensure_mutable().is_ok()
+                && counter.ensure_steppable().is_ok();
+
+            if !writable {
+                return;
+            }
+
+            let steps = sample.steps.abs() as u32;
+            let (operation, delta) = if sample.steps >= 0 {
+                (Operation::Increment, i64::from(counter.step_up_by(steps)))
+            } else {
+                (
+                    Operation::Decrement,
+                    -i64::from(counter.step_down_by(steps)),
+                )
+            };
+
+            if delta != 0 {
+                store
+                    .history()
+                    .record(id, Entry::new(operation, delta, counter.value, None));
+            }
+        });
+
+        if created.is_ok() {
+            names.insert(sample.name.to_string(), id);
+        }
+    }
+}
+
+/// Starts listening for packets on `address:port`.
+pub fn serve(address: String, port: u16, ingest: Ingest) {
+    let socket = match UdpSocket::bind((address.as_str(), port)) {
+        Ok(socket) => socket,
+        Err(error) => {
+            eprintln!(
+                "Failed to listen for statsd packets on port {}: {}",
+                port, error
+            );
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        let mut buffer = vec![0; MAX_PACKET];
+
+        while let Ok((length, from)) = socket.recv_from(&mut buffer) {
+            ingest.ingest(&buffer[..length], Some(from.ip()));
+        }
+    });
+}
+
+const DEFAULT_PREFIX: &str = "caas";
+
+/// Where service metrics are sent, configured as `statsd_sink = "127.0.0.1:8125"` or with the
+/// `ROCKET_STATSD_SINK` environment variable. With `statsd_tags = true`, metrics carry
+/// DogStatsD tags; plain statsd gets the tag values appended to the metric name instead.
+pub struct Sink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    prefix: String,
+    tags: bool,
+}
+
+impl Sink {
+    pub fn from_config(config: &Config) -> Option<Sink> {
+        let sink = config.get_str("statsd_sink").ok()?;
+        let target = match sink.to_socket_addrs().map(|mut addresses| addresses.next()) {
+            Ok(Some(target)) => target,
+            _ => {
+                eprintln!("Not emitting statsd metrics to {}: unknown address", sink);
+                return None;
+            }
+        };
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = match UdpSocket::bind(local) {
+            Ok(socket) => socket,
+            Err(error) => {
+                eprintln!("Not emitting statsd metrics: {}", error);
+                return None;
+            }
+        };
+
+        Some(Sink {
+            socket,
+            target,
+            prefix: config
+                .get_str("statsd_prefix")
+                .unwrap_or(DEFAULT_PREFIX)
+                .to_string(),
+            tags: config.get_bool("statsd_tags").unwrap_or(false),
+        })
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+        let line = if self.tags {
+            let tags: Vec<String> = tags
+                .iter()
+                .map(|(key, value)| format!("{}:{}", key, value))
+                .collect();
+
+            format!(
+                "{}.{}:{}|{}|#{}",
+                self.prefix,
+                name,
+                value,
+                kind,
+                tags.join(",")
+            )
+        } else {
+            let mut path = format!("{}.{}", self.prefix, name);
+
+            for (_, value) in tags {
+                path.push('.');
+                path.push_str(value);
+            }
+
+            format!("{}:{}|{}", path, value, kind)
+        };
+
+        // Metrics are best effort; a missing agent must not fail requests.
+        let _ = self.socket.send_to(line.as_bytes(), self.target);
+    }
+}
+
+/// Emits the request's count, duration, whether it failed and whether it changed a counter.
+pub fn emit(request: &Request, response: &mut Response) {
+    let sink = match request.guard::<State<Sink>>().succeeded() {
+        Some(sink) => sink,
+        None => return,
+    };
+    let handler = request
+        .route()
+        .and_then(|route| route.name)
+        .unwrap_or("unmatched");
+    let method = request.method().to_string();
+    let status = response.status().code.to_string();
+
+    sink.send(
+        "requests",
+        "1",
+        "c",
+        &[
+            ("handler", handler),
+            ("method", &method),
+            ("status", &status),
+        ],
+    );
+
+    if response.status().class() == StatusClass::ServerError {
+        sink.send(
+            "errors",
+            "1",
+            "c",
+            &[("handler", handler), ("status", &status)],
+        );
+    }
+
+    if let Some(elapsed) = metrics::elapsed(request) {
+        let milliseconds = format!("{:.3}", elapsed.as_secs_f64() * 1000.0);
+
+        sink.send(
+            "request_duration",
+            &milliseconds,
+            "ms",
+            &[("handler", handler)],
+        );
+    }
+
+    if request.local_cache(|| Changes(false)).0 && response.status().class() == StatusClass::Success
+    {
+        sink.send("counter_mutations", "1", "c", &[("handler", handler)]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::ContentType;
+    use rocket::local::Client;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn ingest_statsd_packets() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let ingest = super::Ingest::from_rocket(client.rocket()).unwrap();
+
+        ingest.ingest(b"signups:3|c\nsignups:1|c|@0.5\nlatency:320|ms\n", None);
+        ingest.ingest(b"signups:-1|c", None);
+
+        let mut response = client.get("/counter/search?q=signups").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].value, 4);
+
+        let mut response = client.get("/counter/search?q=latency").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert!(counters.is_empty());
+
+        // Dropped in maintenance mode, as HTTP writes would be refused.
+        client
+            .post("/admin/maintenance")
+            .header(ContentType::JSON)
+            .body(r#"{ "enabled": true }"#)
+            .dispatch();
+        ingest.ingest(b"signups:5|c", None);
+
+        let mut response = client.get("/counter/search?q=signups").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters[0].value, 4);
+    }
+
+    #[test]
+    fn emit_statsd_metrics() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        agent
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let config = Config::build(Environment::Development)
+            .extra("statsd_sink", agent.local_addr().unwrap().to_string())
+            .extra("statsd_tags", true)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+
+        client.post("/counter").header(ContentType::JSON).dispatch();
+
+        let mut lines = Vec::new();
+        let mut buffer = [0; 1024];
+
+        while lines.len() < 3 {
+            let length = agent.recv(&mut buffer).unwrap();
+
+            lines.push(String::from_utf8_lossy(&buffer[..length]).into_owned());
+        }
+
+        assert_eq!(
+            lines[0],
+            "caas.requests:1|c|#handler:create_counter,method:POST,status:200"
+        );
+        assert!(lines[1].starts_with("caas.request_duration:"));
+        assert!(lines[1].ends_with("|ms|#handler:create_counter"));
+        assert_eq!(
+            lines[2],
+            "caas.counter_mutations:1|c|#handler:create_counter"
+        );
+    }
+}
@@ -0,0 +1,629 @@
+//! Where the default tenant's counters are kept between restarts, set with `storage` or
+//! `caas --storage`. `"memory"`, the default, keeps them in memory only. With
+//! `"file:/var/lib/caas/counters.json"` they are loaded from that file at launch and written
+//! back to it every `storage_interval` seconds once they have changed, as the `storage` job, and
+//! once more when `caas` shuts down on SIGTERM or SIGINT. Changes made since the last write are
+//! lost if the instance stops any other way. Only the counters are kept: their history, time
+//! series and other tenants' counters start over.
+//!
+//! However many changes are made in between, each write saves every counter once, so busy
+//! counters cost no more to keep than quiet ones. With `storage_flush_after = 1000`, the
+//! counters are also written as soon as their values have changed that many times since the
+//! last write, rather than waiting for the interval, to bound how many changes can be lost.
+//!
+//! Every counter in the file is checked at launch: that it can be read, appears only once and
+//! is within its bounds. The service refuses to start with a report of those that are not,
+//! unless `storage_repair = true`, which drops them instead, keeping the file as it was next to
+//! it with a `.corrupt` suffix.
+//!
+//! The file carries the version of its format, and one written in an older format is migrated
+//! up when it is loaded and saved in the current one. Every change to the format comes with a
+//! migration up to it and one back down. `caas --migrate-status` shows the file's version and
+//! the migrations it is missing, and `caas --migrate-to <version>` rewrites it in another
+//! version, such as for going back to an older `caas`. A file from a newer `caas` is refused.
+//!
+//! Other backends, such as `sqlite:`, are not available, and are refused at launch and by
+//! `caas --validate-config`, as is file storage in an instance that replicates between regions,
+//! which gets its counters back from its peers instead.
+
+use rocket::Config;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::jobs::{Jobs, Run};
+use crate::store::{CounterMap, Store};
+use crate::Counter;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+/// Version of the file format written, that of the last migration.
+const VERSION: u64 = 2;
+
+/// A change to the file format, from the version before `version` to it and back.
+struct Migration {
+    version: u64,
+    description: &'static str,
+    up: fn(Value) -> Result<Value, String>,
+    down: fn(Value) -> Result<Value, String>,
+}
+
+/// In order. Version 1 is the bare array of counters that files started out as.
+const MIGRATIONS: [Migration; 1] = [Migration {
+    version: 2,
+    description: "Keep the counters in an object along with the format version",
+    up: |counters| Ok(serde_json::json!({ "version": 2, "counters": counters })),
+    down: |mut document| Ok(document["counters"].take()),
+}];
+
+#[derive(Clone)]
+pub enum Storage {
+    Memory,
+    File(PathBuf),
+}
+
+/// A counter as written to the file, with the tokens that its API form leaves out.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    #[serde(flatten)]
+    counter: Counter,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    write_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hook_token: Option<String>,
+}
+
+impl Storage {
+    /// Parses a `storage` setting, such as `file:counters.json`.
+    pub fn parse(storage: &str) -> Result<Storage, String> {
+        const FILE: &str = "file:";
+
+        if storage == "memory" {
+            Ok(Storage::Memory)
+        } else if storage.starts_with(FILE) && storage.len() > FILE.len() {
+            Ok(Storage::File(PathBuf::from(&storage[FILE.len()..])))
+        } else {
+            Err(format!(
+                "Unsupported storage {}: must be memory or file:<path>",
+                storage
+            ))
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Storage, String> {
+        let storage = match config.get_str("storage") {
+            Ok(storage) => Storage::parse(storage)?,
+            Err(_) => Storage::Memory,
+        };
+
+        if let (Storage::File(_), Ok(_)) = (&storage, config.get_str("region")) {
+            return Err("File storage cannot be used with region replication".into());
+        }
+
+        Ok(storage)
+    }
+
+    /// Fills `store` with the saved counters and keeps saving them, every `storage_interval`
+    /// seconds from `config`. Fails if the file exists but cannot be read, or has counters that
+    /// fail the integrity check and `storage_repair` is off, rather than overwriting it.
+    pub fn attach(&self, store: &Store, jobs: &Jobs, config: &Config) -> Result<(), String> {
+        let file = match self {
+            Storage::Memory => return Ok(()),
+            Storage::File(file) => file.clone(),
+        };
+
+        if file.exists() {
+            let repair = config.get_bool("storage_repair").unwrap_or(false);
+
+            store.restore(load(&file, repair)?);
+        }
+
+        let interval = config
+            .get_int("storage_interval")
+            .ok()
+            .filter(|interval| *interval > 0)
+            .map_or(DEFAULT_INTERVAL, |interval| {
+                Duration::from_secs(interval as u64)
+            });
+        let changes = Arc::new(AtomicU64::new(0));
+
+        let threshold = config
+            .get_int("storage_flush_after")
+            .ok()
+            .filter(|threshold| *threshold > 0);
+
+        if let Some(threshold) = threshold {
+            let changes = Arc::clone(&changes);
+            let waker = jobs.waker("storage");
+
+            // Only the change that reaches the threshold wakes the job, which starts counting
+            // again when it writes.
+            store.on_change(move |_, _| {
+                if changes.fetch_add(1, Ordering::SeqCst) + 1 == threshold as u64 {
+                    waker.wake();
+                }
+            });
+        }
+
+        let store = store.clone();
+        let mut saved = store.version();
+
+        jobs.every("storage", interval, move || {
+            changes.store(0, Ordering::SeqCst);
+
+            let version = store.version();
+
+            if version != saved {
+                match save(&file, store.read()) {
+                    Ok(()) => saved = version,
+                    Err(reason) => eprintln!("Could not save the counters: {}", reason),
+                }
+            }
+
+            Run::Done
+        });
+
+        Ok(())
+    }
+
+    /// Writes the counters in `store` to the file now, for shutting down.
+    pub fn flush(&self, store: &Store) -> Result<(), String> {
+        match self {
+            Storage::Memory => Ok(()),
+            Storage::File(file) => save(file, store.read())
+                .map_err(|reason| format!("Could not save the counters: {}", reason)),
+        }
+    }
+
+    /// The format version of the file and the migrations it is missing, for
+    /// `caas --migrate-status`.
+    pub fn migration_status(&self) -> Result<String, String> {
+        let file = self.file()?;
+        let version = version(&read(file)?)?;
+        let pending: Vec<String> = MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > version)
+            .map(|migration| format!("  {}: {}", migration.version, migration.description))
+            .collect();
+
+        if version > VERSION {
+            Ok(format!(
+                "{} is at version {}, newer than this caas, which goes up to {}.",
+                file.display(),
+                version,
+                VERSION
+            ))
+        } else if pending.is_empty() {
+            Ok(format!(
+                "{} is up to date at version {}.",
+                file.display(),
+                version
+            ))
+        } else {
+            Ok(format!(
+                "{} is at version {} of {}. Pending migrations:\n{}",
+                file.display(),
+                version,
+                VERSION,
+                pending.join("\n")
+            ))
+        }
+    }
+
+    /// Rewrites the file in format `version`, migrating it up or down, for
+    /// `caas --migrate-to`.
+    pub fn migrate_to(&self, version: u64) -> Result<String, String> {
+        let file = self.file()?;
+        let document = read(file)?;
+        let from = self::version(&document)?;
+
+        write(file, &migrate(document, version)?)?;
+        Ok(format!(
+            "Migrated {} from version {} to {}.",
+            file.display(),
+            from,
+            version
+        ))
+    }
+
+    fn file(&self) -> Result<&Path, String> {
+        match self {
+            Storage::Memory => Err("Counters are kept in memory, with no file to migrate".into()),
+            Storage::File(file) => Ok(file),
+        }
+    }
+}
+
+fn read(file: &Path) -> Result<Value, String> {
+    fs::read_to_string(file)
+        .map_err(|reason| reason.to_string())
+        .and_then(|saved| serde_json::from_str(&saved).map_err(|reason| reason.to_string()))
+        .map_err(|reason| {
+            format!(
+                "Could not load counters from {}: {}",
+                file.display(),
+                reason
+            )
+        })
+}
+
+/// The format version of a file's contents.
+fn version(document: &Value) -> Result<u64, String> {
+    if document.is_array() {
+        Ok(1)
+    } else {
+        document["version"]
+            .as_u64()
+            .filter(|version| *version > 0)
+            .ok_or_else(|| "The counters file has no format version".to_string())
+    }
+}
+
+/// `document` in format `to`, through the migrations up or down between its version and that.
+fn migrate(mut document: Value, to: u64) -> Result<Value, String> {
+    let from = version(&document)?;
+
+    for version in &[from, to] {
+        if *version > VERSION {
+            return Err(format!(
+                "Version {} of the counters file is newer than this caas, which goes up to {}",
+                version, VERSION
+            ));
+        }
+    }
+
+    if to == 0 {
+        return Err("Versions of the counters file start from 1".into());
+    }
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > from)
+    {
+        if migration.version <= to {
+            document = (migration.up)(document)?;
+        }
+    }
+
+    for migration in MIGRATIONS
+        .iter()
+        .rev()
+        .filter(|migration| migration.version > to)
+    {
+        if migration.version <= from {
+            document = (migration.down)(document)?;
+        }
+    }
+
+    Ok(document)
+}
+
+fn load(file: &Path, repair: bool) -> Result<CounterMap, String> {
+    let entries = match migrate(read(file)?, VERSION)?["counters"].take() {
+        Value::Array(entries) => entries,
+        _ => return Err(format!("{} has no counters", file.display())),
+    };
+    let mut counters = CounterMap::new();
+    let mut damage = Vec::new();
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        match check(entry, &counters) {
+            Ok(counter) => {
+                counters.insert(counter.id, counter);
+            }
+            Err(reason) => damage.push(format!("entry {}: {}", index + 1, reason)),
+        }
+    }
+
+    if damage.is_empty() {
+        return Ok(counters);
+    }
+
+    let report = format!(
+        "{} of the counters in {} failed the integrity check:\n  {}",
+        damage.len(),
+        file.display(),
+        damage.join("\n  ")
+    );
+
+    if !repair {
+        return Err(format!(
+            "{}\nSet storage_repair = true to start without them.",
+            report
+        ));
+    }
+
+    let mut corrupt = file.as_os_str().to_owned();
+
+    corrupt.push(".corrupt");
+    fs::copy(file, &corrupt)
+        .map_err(|reason| format!("Could not keep a copy of {}: {}", file.display(), reason))?;
+    save(file, counters.clone())
+        .map_err(|reason| format!("Could not save the counters: {}", reason))?;
+    eprintln!(
+        "{}\nDropped them, keeping the file as it was in {}.",
+        report,
+        Path::new(&corrupt).display()
+    );
+    Ok(counters)
+}
+
+/// The counter in `entry`, unless it cannot be read, is already in `counters` or is outside
+/// its bounds.
+fn check(entry: Value, counters: &CounterMap) -> Result<Counter, String> {
+    let stored: Stored = serde_json::from_value(entry).map_err(|reason| reason.to_string())?;
+    let mut counter = stored.counter;
+
+    if counters.contains_key(&counter.id) {
+        return Err(format!("counter {} appears more than once", counter.id));
+    }
+
+    if counter.value < counter.bounds.min.unwrap_or(0) || counter.value > counter.ceiling() {
+        return Err(format!(
+            "counter {} is at {}, outside its bounds",
+            counter.id, counter.value
+        ));
+    }
+
+    counter.write_token = stored.write_token;
+    counter.hook_token = stored.hook_token;
+    Ok(counter)
+}
+
+/// Writes the counters to a file next to `file` first, then moves it over `file`, so that an
+/// instance stopped halfway leaves the last complete copy.
+fn save(file: &Path, counters: CounterMap) -> Result<(), String> {
+    let stored: Vec<Stored> = counters
+        .into_iter()
+        .map(|(_, counter)| Stored {
+            write_token: counter.write_token.clone(),
+            hook_token: counter.hook_token.clone(),
+            counter,
+        })
+        .collect();
+
+    write(
+        file,
+        &serde_json::json!({ "version": VERSION, "counters": stored }),
+    )
+}
+
+fn write(file: &Path, document: &Value) -> Result<(), String> {
+    let mut partial = file.as_os_str().to_owned();
+
+    partial.push(".partial");
+
+    serde_json::to_vec(document)
+        .map_err(|reason| reason.to_string())
+        .and_then(|saved| fs::write(&partial, saved).map_err(|reason| reason.to_string()))
+        .and_then(|_| fs::rename(&partial, file).map_err(|reason| reason.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::error::LaunchErrorKind;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Storage;
+    use crate::jobs::Jobs;
+    use crate::store::Store;
+    use crate::{build, rocket, Counter, CounterService};
+
+    #[test]
+    fn flush_counters_after_enough_changes() {
+        let file = std::env::temp_dir().join(format!("caas-{}.json", uuid::Uuid::new_v4()));
+        let config = Config::build(Environment::Development)
+            .extra("storage", format!("file:{}", file.display()))
+            .extra("storage_interval", 3600)
+            .extra("storage_flush_after", 3)
+            .finalize()
+            .unwrap();
+        let service = CounterService::from_config(&config).unwrap();
+        let counter = service.create(None).unwrap();
+
+        service.increment(counter.id()).unwrap();
+        service.increment(counter.id()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+
+        assert!(!file.exists());
+
+        service.increment(counter.id()).unwrap();
+
+        for _ in 0..50 {
+            if file.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(
+            CounterService::from_config(&config)
+                .unwrap()
+                .get(counter.id())
+                .unwrap()
+                .value(),
+            3
+        );
+
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn check_stored_counters() {
+        let file = std::env::temp_dir().join(format!("caas-{}.json", uuid::Uuid::new_v4()));
+        let config = |repair: bool| {
+            Config::build(Environment::Development)
+                .extra("storage", format!("file:{}", file.display()))
+                .extra("storage_interval", 3600)
+                .extra("storage_repair", repair)
+                .finalize()
+                .unwrap()
+        };
+        let service = CounterService::from_config(&config(false)).unwrap();
+        let counter = service.create(Some("Sound".to_string())).unwrap();
+
+        service.flush().unwrap();
+
+        let mut saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        let entries = saved["counters"].as_array_mut().unwrap();
+        let mut out_of_bounds = entries[0].clone();
+
+        out_of_bounds["id"] = serde_json::json!(uuid::Uuid::new_v4());
+        out_of_bounds["value"] = serde_json::json!(5);
+        out_of_bounds["bounds"] = serde_json::json!({ "max": 3 });
+        entries.push(serde_json::json!({ "id": "not a counter" }));
+        entries.push(entries[0].clone());
+        entries.push(out_of_bounds);
+        std::fs::write(&file, serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let refusing = config(false);
+        let storage = Storage::from_config(&refusing).unwrap();
+        let report = storage
+            .attach(
+                &Store::from_config(&refusing),
+                &Jobs::from_config(&refusing),
+                &refusing,
+            )
+            .unwrap_err();
+
+        assert!(report.contains("3 of the counters"));
+        assert!(report.contains("entry 2:"));
+        assert!(report.contains("appears more than once"));
+        assert!(report.contains("outside its bounds"));
+
+        let failed = match Client::new(build(rocket::custom(config(false)))) {
+            Err(error) => match error.kind() {
+                LaunchErrorKind::FailedFairings(failed) => failed.clone(),
+                _ => vec![],
+            },
+            Ok(_) => vec![],
+        };
+
+        assert_eq!(failed, vec!["Storage"]);
+
+        let repaired = CounterService::from_config(&config(true)).unwrap();
+        let mut corrupt = file.as_os_str().to_owned();
+
+        corrupt.push(".corrupt");
+
+        assert_eq!(repaired.list().len(), 1);
+        assert_eq!(repaired.get(counter.id()).unwrap().name(), Some("Sound"));
+        assert_eq!(
+            CounterService::from_config(&config(false))
+                .unwrap()
+                .list()
+                .len(),
+            1
+        );
+        assert!(std::fs::read_to_string(&corrupt)
+            .unwrap()
+            .contains("not a counter"));
+
+        let _ = std::fs::remove_file(&file);
+        let _ = std::fs::remove_file(&corrupt);
+    }
+
+    #[test]
+    fn migrate_storage_file() {
+        let file = std::env::temp_dir().join(format!("caas-{}.json", uuid::Uuid::new_v4()));
+        let config = Config::build(Environment::Development)
+            .extra("storage", format!("file:{}", file.display()))
+            .extra("storage_interval", 3600)
+            .finalize()
+            .unwrap();
+        let storage = Storage::from_config(&config).unwrap();
+        let service = CounterService::from_config(&config).unwrap();
+        let counter = service.create(Some("Migrated".to_string())).unwrap();
+        let saved = || -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap()
+        };
+
+        service.flush().unwrap();
+
+        assert_eq!(saved()["version"], 2);
+        assert!(storage
+            .migration_status()
+            .unwrap()
+            .contains("up to date at version 2"));
+
+        storage.migrate_to(1).unwrap();
+
+        assert!(saved().is_array());
+        assert!(storage
+            .migration_status()
+            .unwrap()
+            .contains("version 1 of 2. Pending migrations:\n  2: "));
+        assert_eq!(
+            CounterService::from_config(&config)
+                .unwrap()
+                .get(counter.id())
+                .unwrap()
+                .name(),
+            Some("Migrated")
+        );
+
+        std::fs::write(&file, r#"{ "version": 3, "counters": [] }"#).unwrap();
+
+        assert!(storage
+            .attach(
+                &Store::from_config(&config),
+                &Jobs::from_config(&config),
+                &config
+            )
+            .unwrap_err()
+            .contains("newer than this caas"));
+        assert!(storage.migrate_to(2).is_err());
+        assert!(Storage::parse("memory").unwrap().migrate_to(1).is_err());
+
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn keep_counters_in_file() {
+        let file = std::env::temp_dir().join(format!("caas-{}.json", uuid::Uuid::new_v4()));
+        let config = || {
+            Config::build(Environment::Development)
+                .extra("storage", format!("file:{}", file.display()))
+                .extra("storage_interval", 1)
+                .extra("write_tokens", true)
+                .finalize()
+                .unwrap()
+        };
+        let client = Client::new(build(rocket::custom(config()))).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Kept", "value": 3 }"#)
+            .dispatch();
+        let created: serde_json::Value =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let id = created["id"].as_str().unwrap();
+        let write_token = created["write_token"].as_str().unwrap().to_string();
+
+        thread::sleep(Duration::from_millis(1500));
+
+        let restarted = Client::new(build(rocket::custom(config()))).expect("Init failed");
+        let mut read_response = restarted.get(format!("/counter/{}", id)).dispatch();
+        let counter: Counter = serde_json::from_str(&read_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 3);
+        assert_eq!(counter.name, Some("Kept".to_string()));
+
+        let increment_response = restarted
+            .put(format!("/counter/{}/increment", id))
+            .header(Header::new("X-Write-Token", write_token))
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Ok);
+        assert!(Storage::parse("sqlite:counters.db").is_err());
+
+        let _ = std::fs::remove_file(&file);
+    }
+}
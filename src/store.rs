@@ -0,0 +1,1107 @@
+use rocket::Config;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::activity::Activity;
+use crate::history::{self, Entry, History, Operation};
+use crate::jobs::{Jobs, Run};
+use crate::lock;
+use crate::milestones::Milestones;
+use crate::ranking::Ranking;
+use crate::telemetry;
+use crate::timeseries::Timeseries;
+use crate::watch::Watchers;
+use crate::Counter;
+
+pub type CounterMap = HashMap<Uuid, Counter>;
+
+/// Number of independently locked parts the counters are split into.
+const SHARDS: usize = 64;
+
+/// The counters, split by id so that writes to unrelated counters do not wait on each other.
+/// Code that locks more than one shard takes them in index order, and takes the `publishing` of
+/// every shard it needs before any `state`.
+struct Shards(Vec<Shard>);
+
+impl Default for Shards {
+    fn default() -> Shards {
+        Shards((0..SHARDS).map(|_| Shard::default()).collect())
+    }
+}
+
+/// Everything kept per counter is kept with its shard, so that neither reads nor writes take a
+/// lock shared by every counter while the shard is locked.
+#[derive(Default)]
+struct Shard {
+    state: Mutex<ShardState>,
+    /// Writers queued on or holding the lock of each counter, taken without `state` held.
+    contention: Mutex<HashMap<Uuid, Contention>>,
+    /// Taken by writers before `state` and held until their change is published, with `state`
+    /// already released, so that changes to each counter are published and reported to the
+    /// change hook in the order they were made. Readers do not wait for it.
+    publishing: Mutex<()>,
+}
+
+#[derive(Default)]
+struct ShardState {
+    counters: CounterMap,
+    /// When each counter was last used, by the store's clock, for LRU eviction.
+    last_used: HashMap<Uuid, u64>,
+}
+
+impl Shards {
+    fn index(&self, id: Uuid) -> usize {
+        let mut hasher = DefaultHasher::new();
+
+        id.hash(&mut hasher);
+        hasher.finish() as usize % self.0.len()
+    }
+
+    fn of(&self, id: Uuid) -> &Shard {
+        &self.0[self.index(id)]
+    }
+
+    fn lock_all(&self) -> Vec<MutexGuard<ShardState>> {
+        self.0.iter().map(|shard| lock(&shard.state)).collect()
+    }
+
+    fn publishing_all(&self) -> Vec<MutexGuard<()>> {
+        self.0.iter().map(|shard| lock(&shard.publishing)).collect()
+    }
+}
+
+/// Told about the amount of every change made here to the value of a counter.
+type ChangeHook = Box<dyn Fn(&Counter, i64) + Send + Sync>;
+
+/// Where a change comes from, so that changes merged from other regions are not sent back.
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    Local,
+    Merged,
+}
+
+/// Cheaply cloneable handle to the counter state, shared by the routes and background threads.
+#[derive(Clone, Default)]
+pub struct Store {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    counters: Shards,
+    /// Held while making room for and creating a counter, so that the quota holds, and while
+    /// giving one an alias, so that no two counters get the same alias.
+    creating: Mutex<()>,
+    quota: Quota,
+    /// The tenant's counter quota from `usage_quotas`, refused with no eviction.
+    counter_quota: Mutex<Option<usize>>,
+    history: History,
+    timeseries: Timeseries,
+    clock: AtomicU64,
+    /// Bumped on every change to any counter, while the changed counter's shard is locked.
+    version: AtomicU64,
+    watchers: Watchers,
+    ranking: Ranking,
+    milestones: Milestones,
+    activity: Activity,
+    on_change: RwLock<Vec<ChangeHook>>,
+}
+
+/// Upper bound on the number of counters and what to do once it is reached.
+#[derive(Default, Clone, Copy)]
+pub struct Quota {
+    pub max_counters: Option<usize>,
+    pub eviction: EvictionPolicy,
+}
+
+#[derive(Clone, Copy)]
+pub enum EvictionPolicy {
+    /// Refuse to create new counters.
+    Reject,
+    /// Drop the least recently used counter to make room.
+    Lru,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> EvictionPolicy {
+        EvictionPolicy::Reject
+    }
+}
+
+impl Quota {
+    pub fn from_config(config: &Config) -> Quota {
+        Quota {
+            max_counters: config.get_int("max_counters").ok().map(|max| max as usize),
+            eviction: match config.get_str("eviction_policy") {
+                Ok("lru") => EvictionPolicy::Lru,
+                _ => EvictionPolicy::Reject,
+            },
+        }
+    }
+}
+
+pub enum QuotaExceeded {
+    /// `max_counters`, what the instance has room for.
+    Capacity,
+    /// The counters the tenant pays for.
+    Plan,
+}
+
+pub struct AliasTaken;
+
+#[derive(Default)]
+struct Contention {
+    acquisitions: u64,
+    total_wait: Duration,
+    max_wait: Duration,
+    writers: usize,
+    peak_writers: usize,
+}
+
+#[derive(Serialize)]
+pub struct ContentionReport {
+    pub id: Uuid,
+    pub acquisitions: u64,
+    pub total_wait_us: u64,
+    mean_wait_us: u64,
+    max_wait_us: u64,
+    pub current_writers: usize,
+    peak_concurrent_writers: usize,
+}
+
+impl Store {
+    pub fn from_config(config: &Config) -> Store {
+        Store {
+            shared: Arc::new(Shared {
+                quota: Quota::from_config(config),
+                history: History::from_config(config),
+                timeseries: Timeseries::from_config(config),
+                milestones: Milestones::from_config(config),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// A detached copy of the counters and their history, for dry runs. Changes to it do not
+    /// reach this store or its watchers.
+    pub fn scratch(&self) -> Store {
+        let scratch = Store {
+            shared: Arc::new(Shared {
+                quota: self.shared.quota,
+                counter_quota: Mutex::new(*lock(&self.shared.counter_quota)),
+                history: self.shared.history.copy(),
+                milestones: self.shared.milestones.copy(),
+                activity: self.shared.activity.copy(),
+                ..Default::default()
+            }),
+        };
+
+        scratch.restore(self.read());
+        scratch
+    }
+
+    /// Refuses to create counters beyond `max`, whatever the eviction policy.
+    pub fn limit_counters(&self, max: Option<usize>) {
+        *lock(&self.shared.counter_quota) = max;
+    }
+
+    /// Runs `hook` with the counter, as changed, and amount of every change made here to a
+    /// counter's value from now on, for replication and saving. Deleting a counter is not a
+    /// change to its value. The counter's shard is no longer locked by then.
+    pub fn on_change<F: Fn(&Counter, i64) + Send + Sync + 'static>(&self, hook: F) {
+        self.shared
+            .on_change
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Box::new(hook));
+    }
+
+    pub fn history(&self) -> &History {
+        &self.shared.history
+    }
+
+    pub fn timeseries(&self) -> &Timeseries {
+        &self.shared.timeseries
+    }
+
+    /// Every counter as it changes, from now on. Changes to each counter arrive in the order
+    /// they were made.
+    pub fn subscribe(&self) -> Receiver<Counter> {
+        self.shared.watchers.subscribe()
+    }
+
+    /// Counters by value, highest first.
+    pub fn ranking(&self) -> &Ranking {
+        &self.shared.ranking
+    }
+
+    pub fn milestones(&self) -> &Milestones {
+        &self.shared.milestones
+    }
+
+    /// Recent changes by counter.
+    pub fn activity(&self) -> &Activity {
+        &self.shared.activity
+    }
+
+    /// Passes a changed counter on to the watchers, the ranking and the milestones. Called with
+    /// the counter's shard unlocked, but its `publishing` held.
+    fn publish(&self, counter: &Counter) {
+        self.shared.ranking.update(counter);
+        self.shared.milestones.update(counter);
+        self.shared.watchers.publish(counter);
+    }
+
+    /// Records every counter's value at the configured time-series interval, as job `name`.
+    /// The job finishes once the store is dropped.
+    pub fn schedule_sampler(&self, jobs: &Jobs, name: &str) {
+        let interval = match self.shared.timeseries.interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+        let shared = Arc::downgrade(&self.shared);
+
+        jobs.every(name, interval, move || match shared.upgrade() {
+            Some(shared) => {
+                Store { shared }.sample();
+                Run::Done
+            }
+            None => Run::Finished,
+        });
+    }
+
+    fn sample(&self) {
+        let values: Vec<(Uuid, u32)> = self
+            .read()
+            .values()
+            .map(|counter| (counter.id, counter.value))
+            .collect();
+
+        self.shared.timeseries.record(&values, history::timestamp());
+    }
+
+    /// A copy of every counter, with billing cycles that have ended rolled over first. Every
+    /// shard is locked at once, so the copy is consistent.
+    pub fn read(&self) -> CounterMap {
+        self.snapshot().0
+    }
+
+    /// Like `read`, along with the version the copy is of.
+    pub fn snapshot(&self) -> (CounterMap, u64) {
+        telemetry::in_span("store.read", || {
+            let mut shards = self.shared.counters.lock_all();
+            let now = history::timestamp();
+            let mut copy = CounterMap::new();
+            let mut rolled = Vec::new();
+
+            for shard in shards.iter_mut() {
+                for counter in shard.counters.values_mut() {
+                    if self.roll_over(counter, now) {
+                        rolled.push(counter.id);
+                    }
+
+                    copy.insert(counter.id, counter.clone());
+                }
+            }
+
+            let version = self.version();
+
+            drop(shards);
+            rolled.into_iter().for_each(|id| self.republish(id));
+
+            (copy, version)
+        })
+    }
+
+    /// Changes whenever any counter does, so that anything derived from the counters can tell
+    /// whether it is still current.
+    pub fn version(&self) -> u64 {
+        self.shared.version.load(Ordering::SeqCst)
+    }
+
+    pub fn count(&self) -> usize {
+        self.shared
+            .counters
+            .0
+            .iter()
+            .map(|shard| lock(&shard.state).counters.len())
+            .sum()
+    }
+
+    /// The id of every counter, in no particular order, for going through them one at a time
+    /// without copying them all.
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.shared
+            .counters
+            .0
+            .iter()
+            .flat_map(|shard| {
+                lock(&shard.state)
+                    .counters
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<Uuid>>()
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, id: Uuid) -> bool {
+        lock(&self.shared.counters.of(id).state)
+            .counters
+            .contains_key(&id)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Counter> {
+        telemetry::in_span("store.get", || {
+            let shard = self.shared.counters.of(id);
+            let mut state = lock(&shard.state);
+            let mut rolled = false;
+            let counter = state.counters.get_mut(&id).map(|counter| {
+                rolled = self.roll_over(counter, history::timestamp());
+                counter.clone()
+            });
+
+            if counter.is_some() {
+                self.touch(&mut state, id);
+            }
+
+            drop(state);
+
+            if rolled {
+                self.republish(id);
+            }
+
+            counter
+        })
+    }
+
+    /// Runs a mutation of a single counter under the lock of its shard, recording how long the
+    /// caller waited for the lock and how many writers were queued on the same counter. `f`
+    /// gets the shard, which holds the counter if it exists, and must not touch other
+    /// counters.
+    pub fn write<T, F>(&self, id: Uuid, f: F) -> T
+    where
+        F: FnOnce(&mut CounterMap) -> T,
+    {
+        telemetry::in_span("store.write", || self.locked(id, Source::Local, f))
+    }
+
+    /// Like `write`, but for mutations that may insert `id`. Room is made for the new counter
+    /// according to the quota before `f` runs.
+    pub fn write_or_create<T, F>(&self, id: Uuid, f: F) -> Result<T, QuotaExceeded>
+    where
+        F: FnOnce(&mut CounterMap) -> T,
+    {
+        telemetry::in_span("store.write", || self.creating(id, Source::Local, f))
+    }
+
+    /// Like `write_or_create`, for changes merged from other regions, which are not reported to
+    /// the change hook.
+    pub fn merge<T, F>(&self, id: Uuid, f: F) -> Result<T, QuotaExceeded>
+    where
+        F: FnOnce(&mut CounterMap) -> T,
+    {
+        telemetry::in_span("store.merge", || self.creating(id, Source::Merged, f))
+    }
+
+    fn creating<T, F>(&self, id: Uuid, source: Source, f: F) -> Result<T, QuotaExceeded>
+    where
+        F: FnOnce(&mut CounterMap) -> T,
+    {
+        if self.contains(id) {
+            return Ok(self.locked(id, source, f));
+        }
+
+        let _creating = lock(&self.shared.creating);
+
+        self.make_room(id)?;
+        Ok(self.locked(id, source, f))
+    }
+
+    /// Runs a mutation of several counters as one, with the shards of all of them locked. `f`
+    /// gets copies of the counters in the order of `ids`, which must not repeat, and its
+    /// changes are kept only if it returns `Ok`. Returns `None`, without running `f`, if any of
+    /// the counters does not exist.
+    pub fn write_all<T, E, F>(&self, ids: &[Uuid], f: F) -> Option<Result<T, E>>
+    where
+        F: FnOnce(&mut [Counter]) -> Result<T, E>,
+    {
+        telemetry::in_span("store.write", || {
+            let shards = &self.shared.counters;
+            let mut indexes: Vec<usize> = ids.iter().map(|id| shards.index(*id)).collect();
+
+            indexes.sort();
+            indexes.dedup();
+
+            let _publishing: Vec<MutexGuard<()>> = indexes
+                .iter()
+                .map(|index| lock(&shards.0[*index].publishing))
+                .collect();
+            let mut locked: HashMap<usize, MutexGuard<ShardState>> = indexes
+                .iter()
+                .map(|index| (*index, lock(&shards.0[*index].state)))
+                .collect();
+            let now = history::timestamp();
+            let mut counters = Vec::with_capacity(ids.len());
+            let mut rolled = Vec::new();
+
+            for id in ids {
+                let counter = locked.get_mut(&shards.index(*id))?.counters.get_mut(id)?;
+
+                if self.roll_over(counter, now) {
+                    rolled.push(counter.clone());
+                }
+
+                counters.push(counter.clone());
+            }
+
+            let result = f(&mut counters);
+            let mut changes = Vec::new();
+
+            if result.is_ok() {
+                for counter in counters {
+                    let id = counter.id;
+                    let shard = locked
+                        .get_mut(&shards.index(id))
+                        .expect("shard of every counter is locked");
+
+                    if shard.counters.get(&id) != Some(&counter) {
+                        let before = shard.counters.insert(id, counter.clone());
+
+                        changes.push((before, counter));
+                        self.changed();
+                    }
+
+                    self.touch(shard, id);
+                }
+            }
+
+            drop(locked);
+            rolled.iter().for_each(|counter| self.publish(counter));
+
+            for (before, after) in changes {
+                self.publish(&after);
+                self.record_activity(before.as_ref(), &after);
+                self.report_change(before.as_ref(), &after);
+            }
+
+            Some(result)
+        })
+    }
+
+    /// The counter that has `alias`, if any.
+    pub fn resolve(&self, alias: &str) -> Option<Uuid> {
+        self.shared.counters.0.iter().find_map(|shard| {
+            lock(&shard.state)
+                .counters
+                .values()
+                .find(|counter| counter.aliases.contains(alias))
+                .map(|counter| counter.id)
+        })
+    }
+
+    /// Gives counter `id` another name to be found by, unless another counter has it already.
+    /// Returns the counter, or `None` if it does not exist.
+    pub fn add_alias(&self, id: Uuid, alias: &str) -> Result<Option<Counter>, AliasTaken> {
+        let _creating = lock(&self.shared.creating);
+
+        match self.resolve(alias) {
+            Some(owner) if owner != id => return Err(AliasTaken),
+            _ => (),
+        }
+
+        Ok(self.write(id, |hashmap| {
+            hashmap.get_mut(&id).map(|counter| {
+                counter.aliases.insert(alias.to_string());
+                counter.clone()
+            })
+        }))
+    }
+
+    /// Replaces every counter at once, returning the counters that were replaced.
+    pub fn restore(&self, counters: CounterMap) -> CounterMap {
+        let _publishing = self.shared.counters.publishing_all();
+        let mut shards = self.shared.counters.lock_all();
+        let mut replaced = CounterMap::new();
+        let mut last_used = HashMap::new();
+
+        for shard in shards.iter_mut() {
+            replaced.extend(shard.counters.drain());
+            last_used.extend(shard.last_used.drain());
+        }
+
+        let changed: Vec<Counter> = counters
+            .values()
+            .filter(|counter| replaced.get(&counter.id) != Some(counter))
+            .cloned()
+            .collect();
+        let removed: Vec<Uuid> = replaced
+            .keys()
+            .filter(|id| !counters.contains_key(id))
+            .cloned()
+            .collect();
+
+        for (id, counter) in counters {
+            let shard = &mut shards[self.shared.counters.index(id)];
+            let tick = last_used.get(&id).cloned().unwrap_or_else(|| self.tick());
+
+            shard.counters.insert(id, counter);
+            shard.last_used.insert(id, tick);
+        }
+
+        self.changed();
+        drop(shards);
+        changed.iter().for_each(|counter| self.publish(counter));
+
+        for id in removed {
+            self.shared.ranking.remove(id);
+            self.shared.milestones.forget(&id);
+            self.shared.activity.forget(&id);
+        }
+
+        replaced
+    }
+
+    /// Removes counter `id`, returning it if it existed. Its history, time series, milestones
+    /// and activity go with it.
+    pub fn remove(&self, id: Uuid) -> Option<Counter> {
+        let removed = self.write(id, |counters| counters.remove(&id))?;
+
+        self.shared.history.forget(&id);
+        self.shared.timeseries.forget(&id);
+        self.forget_contention(id);
+        self.record_removal(&removed);
+        Some(removed)
+    }
+
+    /// Removes every counter that `matches` in one pass, with all shards locked, returning the
+    /// removed counters. Their history, time series, milestones and activity go with them.
+    pub fn remove_where<F>(&self, matches: F) -> Vec<Counter>
+    where
+        F: Fn(&Counter) -> bool,
+    {
+        let _publishing = self.shared.counters.publishing_all();
+        let mut shards = self.shared.counters.lock_all();
+        let mut removed = Vec::new();
+
+        for shard in shards.iter_mut() {
+            let ids: Vec<Uuid> = shard
+                .counters
+                .values()
+                .filter(|counter| matches(counter))
+                .map(|counter| counter.id)
+                .collect();
+
+            for id in &ids {
+                shard.last_used.remove(id);
+            }
+
+            removed.extend(ids.iter().filter_map(|id| shard.counters.remove(id)));
+        }
+
+        if !removed.is_empty() {
+            self.changed();
+        }
+
+        drop(shards);
+
+        for counter in &removed {
+            self.shared.ranking.remove(counter.id);
+            self.shared.history.forget(&counter.id);
+            self.shared.timeseries.forget(&counter.id);
+            self.shared.milestones.forget(&counter.id);
+            self.shared.activity.forget(&counter.id);
+            self.forget_contention(counter.id);
+            self.record_removal(counter);
+        }
+
+        removed
+    }
+
+    /// Counters ordered by total time spent waiting for the lock, most contended first.
+    pub fn contention(&self, limit: usize) -> Vec<ContentionReport> {
+        let mut reports: Vec<ContentionReport> = Vec::new();
+
+        for shard in &self.shared.counters.0 {
+            reports.extend(lock(&shard.contention).iter().map(|(id, entry)| {
+                let total_wait_us = entry.total_wait.as_micros() as u64;
+
+                ContentionReport {
+                    id: *id,
+                    acquisitions: entry.acquisitions,
+                    total_wait_us,
+                    mean_wait_us: total_wait_us / entry.acquisitions.max(1),
+                    max_wait_us: entry.max_wait.as_micros() as u64,
+                    current_writers: entry.writers,
+                    peak_concurrent_writers: entry.peak_writers,
+                }
+            }));
+        }
+
+        reports.sort_by(|a, b| b.total_wait_us.cmp(&a.total_wait_us));
+        reports.truncate(limit);
+        reports
+    }
+
+    fn locked<T, F>(&self, id: Uuid, source: Source, f: F) -> T
+    where
+        F: FnOnce(&mut CounterMap) -> T,
+    {
+        let shard = self.shared.counters.of(id);
+        let mut writing = self.enter(id);
+
+        let started = Instant::now();
+        let _publishing = lock(&shard.publishing);
+        let mut state = lock(&shard.state);
+
+        writing.waited = Some(started.elapsed());
+
+        let rolled = state.counters.get_mut(&id).map_or(false, |counter| {
+            self.roll_over(counter, history::timestamp())
+        });
+        let before = state.counters.get(&id).cloned();
+
+        writing.exists = before.is_some();
+
+        let result = f(&mut state.counters);
+        let after = state.counters.get(&id).cloned();
+
+        writing.exists = after.is_some();
+
+        if before != after {
+            self.changed();
+        }
+
+        match after {
+            Some(_) => self.touch(&mut state, id),
+            None => {
+                state.last_used.remove(&id);
+            }
+        }
+
+        drop(state);
+        drop(writing);
+
+        match after {
+            Some(counter) => {
+                if before.as_ref() != Some(&counter) {
+                    self.publish(&counter);
+                    self.record_activity(before.as_ref(), &counter);
+
+                    if source == Source::Local {
+                        self.report_change(before.as_ref(), &counter);
+                    }
+                } else if rolled {
+                    self.publish(&counter);
+                }
+            }
+            None if before.is_some() => {
+                self.shared.ranking.remove(id);
+                self.shared.milestones.forget(&id);
+                self.shared.activity.forget(&id);
+            }
+            None => {}
+        }
+
+        result
+    }
+
+    /// Notes a removed counter in the change feed, its history being gone.
+    fn record_removal(&self, counter: &Counter) {
+        self.shared.history.feed().record(
+            counter.id,
+            Entry::new(Operation::Delete, -i64::from(counter.value), 0, None),
+        );
+    }
+
+    /// Notes a change to the value of a counter that existed before.
+    fn record_activity(&self, before: Option<&Counter>, after: &Counter) {
+        if let Some(before) = before.filter(|before| before.value != after.value) {
+            self.shared.activity.record(
+                after.id,
+                i64::from(after.value) - i64::from(before.value),
+                history::timestamp(),
+            );
+        }
+    }
+
+    /// Tells the change hook, if any, about a change made here to the value of a counter, new
+    /// counters starting from zero.
+    fn report_change(&self, before: Option<&Counter>, after: &Counter) {
+        let delta = i64::from(after.value) - before.map_or(0, |before| i64::from(before.value));
+
+        if delta != 0 {
+            let hooks = self
+                .shared
+                .on_change
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            for hook in hooks.iter() {
+                hook(after, delta);
+            }
+        }
+    }
+
+    /// Publishes counter `id` as it is now, after a reader rolled it over without holding
+    /// `publishing`.
+    fn republish(&self, id: Uuid) {
+        let shard = self.shared.counters.of(id);
+        let _publishing = lock(&shard.publishing);
+        let counter = lock(&shard.state).counters.get(&id).cloned();
+
+        if let Some(counter) = counter {
+            self.publish(&counter);
+        }
+    }
+
+    /// Rolls the counter over if its billing cycle has ended, returning whether it did, in which
+    /// case it is to be published once its shard is released.
+    fn roll_over(&self, counter: &mut Counter, now: u64) -> bool {
+        counter.fade(now);
+
+        match counter.roll_over(now) {
+            Some(total) => {
+                self.shared.history.record(
+                    counter.id,
+                    Entry::new(Operation::Reset, -i64::from(total), 0, None),
+                );
+                self.changed();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Called with the shard of every changed counter still locked.
+    fn changed(&self) {
+        self.shared.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn tick(&self) -> u64 {
+        self.shared.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Notes counter `id` as just used, in its shard's `state`.
+    fn touch(&self, state: &mut ShardState, id: Uuid) {
+        let tick = self.tick();
+
+        state.last_used.insert(id, tick);
+    }
+
+    /// Makes room for counter `id` according to the quota. Called with `creating` held and
+    /// no shard locked.
+    fn make_room(&self, id: Uuid) -> Result<(), QuotaExceeded> {
+        if let Some(max) = *lock(&self.shared.counter_quota) {
+            if self.count() >= max {
+                return Err(QuotaExceeded::Plan);
+            }
+        }
+
+        let max = match self.shared.quota.max_counters {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let mut count = self.count();
+
+        if count < max {
+            return Ok(());
+        }
+
+        match self.shared.quota.eviction {
+            EvictionPolicy::Reject => Err(QuotaExceeded::Capacity),
+            EvictionPolicy::Lru => {
+                while count >= max {
+                    let oldest = self
+                        .shared
+                        .counters
+                        .0
+                        .iter()
+                        .filter_map(|shard| {
+                            lock(&shard.state)
+                                .last_used
+                                .iter()
+                                .filter(|(candidate, _)| **candidate != id)
+                                .min_by_key(|(_, tick)| **tick)
+                                .map(|(id, tick)| (*id, *tick))
+                        })
+                        .min_by_key(|(_, tick)| *tick)
+                        .map(|(id, _)| id)
+                        .ok_or(QuotaExceeded::Capacity)?;
+
+                    let mut state = lock(&self.shared.counters.of(oldest).state);
+                    let evicted = state.counters.remove(&oldest);
+
+                    state.last_used.remove(&oldest);
+
+                    if evicted.is_some() {
+                        self.changed();
+                    }
+
+                    drop(state);
+                    self.shared.ranking.remove(oldest);
+                    self.shared.history.forget(&oldest);
+                    self.shared.timeseries.forget(&oldest);
+                    self.shared.milestones.forget(&oldest);
+                    self.shared.activity.forget(&oldest);
+                    self.forget_contention(oldest);
+
+                    if let Some(evicted) = &evicted {
+                        self.record_removal(evicted);
+                    }
+
+                    count = self.count();
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts a writer on counter `id` until the returned guard is dropped.
+    fn enter(&self, id: Uuid) -> Writing {
+        let mut contention = lock(&self.shared.counters.of(id).contention);
+        let entry = contention.entry(id).or_default();
+
+        entry.writers += 1;
+        entry.peak_writers = entry.peak_writers.max(entry.writers);
+
+        Writing {
+            store: self,
+            id,
+            waited: None,
+            exists: false,
+        }
+    }
+
+    /// Drops the contention of a removed counter, unless writers are still queued on it.
+    fn forget_contention(&self, id: Uuid) {
+        let mut contention = lock(&self.shared.counters.of(id).contention);
+
+        if contention
+            .get(&id)
+            .map_or(false, |entry| entry.writers == 0)
+        {
+            contention.remove(&id);
+        }
+    }
+}
+
+/// A writer queued on or holding a counter's lock. Dropping it, even while unwinding from a
+/// panicking write, takes the writer off the counter's contention, which is only kept while
+/// writers are queued or the counter exists.
+struct Writing<'a> {
+    store: &'a Store,
+    id: Uuid,
+    /// How long the writer waited for the lock, once it has it.
+    waited: Option<Duration>,
+    exists: bool,
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        let mut contention = lock(&self.store.shared.counters.of(self.id).contention);
+        let idle = match contention.get_mut(&self.id) {
+            Some(entry) => {
+                entry.writers -= 1;
+
+                if let Some(waited) = self.waited {
+                    entry.acquisitions += 1;
+                    entry.total_wait += waited;
+                    entry.max_wait = entry.max_wait.max(waited);
+                }
+
+                entry.writers == 0
+            }
+            None => false,
+        };
+
+        if idle && !self.exists {
+            contention.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Store;
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn survive_panicking_writers() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let store = client.rocket().state::<Store>().unwrap().clone();
+        let id = counter.id;
+        let panicked = thread::spawn(move || store.write(id, |_| panic!("writer failed")))
+            .join()
+            .is_err();
+
+        assert!(panicked);
+
+        let increment_response = client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(increment_response.status(), Status::Ok);
+        assert_eq!(client.get("/counter").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn run_change_hooks_with_the_counter_unlocked() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let store = client.rocket().state::<Store>().unwrap().clone();
+        let reader = store.clone();
+        let (sender, receiver) = mpsc::channel();
+
+        store.on_change(move |changed, _| {
+            let _ = sender.send(reader.get(changed.id).map(|counter| counter.value));
+        });
+        let id = counter.id;
+
+        thread::spawn(move || {
+            store.write(id, |counters| counters.get_mut(&id).unwrap().value += 1)
+        });
+
+        // The hook reading its own counter would never return with the counter still locked.
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Ok(Some(counter.value + 1))
+        );
+    }
+
+    #[test]
+    fn report_contention() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let mut response = client.get("/admin/contention").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let body_string = response.body_string().unwrap();
+        let reports: Vec<serde_json::Value> = serde_json::from_str(&body_string).unwrap();
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0]["acquisitions"], 2);
+        assert_eq!(reports[0]["current_writers"], 0);
+
+        let store = client.rocket().state::<Store>().unwrap();
+
+        store.write(uuid::Uuid::new_v4(), |_| ());
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            store.write(counter.id, |_| panic!("write failed"))
+        }));
+
+        assert!(panicked.is_err());
+
+        let reports = store.contention(usize::max_value());
+
+        // Writes to counters that do not exist are not tracked, and a panicking writer leaves.
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].current_writers, 0);
+
+        store.remove_where(|removed| removed.id == counter.id);
+
+        assert!(store.contention(usize::max_value()).is_empty());
+    }
+
+    #[test]
+    fn evict_least_recently_used_counter() {
+        let config = Config::build(Environment::Development)
+            .extra("max_counters", 2i64)
+            .extra("eviction_policy", "lru")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+
+        let mut first_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let first: Counter = serde_json::from_str(&first_response.body_string().unwrap()).unwrap();
+        let mut second_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let second: Counter =
+            serde_json::from_str(&second_response.body_string().unwrap()).unwrap();
+
+        client.get(format!("/counter/{}", first.id)).dispatch();
+        client.post("/counter").header(ContentType::JSON).dispatch();
+
+        let first_response = client.get(format!("/counter/{}", first.id)).dispatch();
+        let second_response = client.get(format!("/counter/{}", second.id)).dispatch();
+
+        assert_eq!(first_response.status(), Status::Ok);
+        assert_eq!(second_response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn reject_counters_over_quota() {
+        let config = Config::build(Environment::Development)
+            .extra("max_counters", 1i64)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+
+        let first_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let second_response = client.post("/counter").header(ContentType::JSON).dispatch();
+
+        assert_eq!(first_response.status(), Status::Ok);
+        assert_eq!(second_response.status(), Status::InsufficientStorage);
+    }
+
+    #[test]
+    fn publish_counter_changes() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let changes = client.rocket().state::<Store>().unwrap().subscribe();
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        client
+            .put(format!("/counter/{}/freeze", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let timeout = Duration::from_secs(1);
+        let created = changes.recv_timeout(timeout).unwrap();
+        let incremented = changes.recv_timeout(timeout).unwrap();
+        let frozen = changes.recv_timeout(timeout).unwrap();
+
+        assert_eq!((created.id, created.value), (counter.id, 0));
+        assert_eq!(incremented.value, 1);
+        assert!(frozen.frozen);
+        assert!(changes.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+//! Distributed tracing in the OpenTelemetry data model. Every request gets a server span, a
+//! child of the caller's span when it sends a W3C `traceparent` header, and store operations
+//! get internal spans below it. Spans are exported as OTLP/HTTP JSON to `otlp_endpoint`, such
+//! as `http://collector:4318/v1/traces`. Off unless set.
+//!
+//! Rocket handles each request on a single thread, so the trace of the request being handled
+//! is kept in a thread-local, as OpenTelemetry SDKs do, and store operations need no handle
+//! to it. Work handed to another thread carries the trace along with `context`; spans it
+//! records after the request has been answered, such as those of a deferred request answered
+//! with 202 Accepted, are dropped.
+
+use rocket::data::Data;
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::{Config, State};
+use std::cell::RefCell;
+use std::mem;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::lock;
+use crate::signing::encode_hex;
+
+const DEFAULT_SERVICE_NAME: &str = "counter-as-a-service";
+/// Most spans sent to the collector at once, unless a single trace has more.
+const MAX_BATCH: usize = 512;
+
+/// OTLP span kinds.
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+/// OTLP status code for errors.
+const STATUS_ERROR: u8 = 2;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<Attribute>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<SpanStatus>,
+}
+
+#[derive(Serialize)]
+struct Attribute {
+    key: &'static str,
+    value: AttributeValue,
+}
+
+#[derive(Serialize)]
+enum AttributeValue {
+    #[serde(rename = "stringValue")]
+    String(String),
+    #[serde(rename = "intValue")]
+    Int(String),
+}
+
+#[derive(Serialize)]
+struct SpanStatus {
+    code: u8,
+}
+
+/// The request being traced on this thread. Clones share the spans recorded below it.
+#[derive(Clone)]
+struct Trace {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    started: u64,
+    spans: Arc<Mutex<Vec<Span>>>,
+}
+
+/// The trace of the request being handled, for work that runs on another thread.
+pub struct Context(Option<Trace>);
+
+/// Captures the trace of the request being handled on this thread, if any.
+pub fn context() -> Context {
+    Context(CURRENT.with(|current| current.borrow().clone()))
+}
+
+impl Context {
+    /// Runs `f` with spans it records going to the captured trace.
+    pub fn enter<T, F: FnOnce() -> T>(self, f: F) -> T {
+        let previous = CURRENT.with(|current| mem::replace(&mut *current.borrow_mut(), self.0));
+        let result = f();
+
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        result
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Trace>> = RefCell::new(None);
+}
+
+/// Sends the spans of finished traces to the collector. `None` when tracing is off.
+pub struct Tracer {
+    exporter: Option<Mutex<Sender<Vec<Span>>>>,
+}
+
+impl Tracer {
+    pub fn from_config(config: &Config) -> Tracer {
+        let endpoint = match config.get_str("otlp_endpoint") {
+            Ok(endpoint) => endpoint.to_string(),
+            Err(_) => return Tracer { exporter: None },
+        };
+        let service_name = config
+            .get_str("service_name")
+            .unwrap_or(DEFAULT_SERVICE_NAME)
+            .to_string();
+        let (sender, traces) = mpsc::channel();
+
+        thread::spawn(move || export(&endpoint, &service_name, &traces));
+        Tracer {
+            exporter: Some(Mutex::new(sender)),
+        }
+    }
+}
+
+/// Posts traces as they finish, batching whatever has queued up meanwhile. A trace is never
+/// split across posts.
+fn export(endpoint: &str, service_name: &str, traces: &mpsc::Receiver<Vec<Span>>) {
+    let client = reqwest::Client::new();
+
+    while let Ok(mut batch) = traces.recv() {
+        while batch.len() < MAX_BATCH {
+            match traces.try_recv() {
+                Ok(trace) => batch.extend(trace),
+                Err(_) => break,
+            }
+        }
+
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": service_name }
+                    }]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": DEFAULT_SERVICE_NAME },
+                    "spans": batch
+                }]
+            }]
+        });
+        let sent = client
+            .post(endpoint)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send();
+
+        if let Err(error) = sent {
+            eprintln!("Could not export {} spans: {}", batch.len(), error);
+        }
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn random_span_id() -> String {
+    encode_hex(&Uuid::new_v4().as_bytes()[..8])
+}
+
+/// Trace id, parent span id and whether the caller sampled the trace, from
+/// `00-<trace id>-<span id>-<flags>`.
+fn parse_traceparent(header: &str) -> Option<(String, String, bool)> {
+    let parts: Vec<&str> = header.trim().split('-').collect();
+    let is_hex =
+        |part: &str, length| part.len() == length && part.chars().all(|c| c.is_ascii_hexdigit());
+
+    match parts.as_slice() {
+        [version, trace_id, span_id, flags]
+            if is_hex(version, 2)
+                && *version != "ff"
+                && is_hex(trace_id, 32)
+                && is_hex(span_id, 16)
+                && is_hex(flags, 2)
+                && trace_id.chars().any(|c| c != '0')
+                && span_id.chars().any(|c| c != '0') =>
+        {
+            let sampled = u8::from_str_radix(flags, 16).map_or(false, |flags| flags & 1 == 1);
+
+            Some((trace_id.to_lowercase(), span_id.to_lowercase(), sampled))
+        }
+        _ => None,
+    }
+}
+
+/// Starts the request's server span, unless tracing is off or the caller did not sample it.
+pub fn start(request: &mut Request, _: &Data) {
+    let enabled = request
+        .guard::<State<Tracer>>()
+        .succeeded()
+        .map_or(false, |tracer| tracer.exporter.is_some());
+    let trace = match request
+        .headers()
+        .get_one("traceparent")
+        .and_then(parse_traceparent)
+    {
+        Some((_, _, false)) => None,
+        Some((trace_id, parent, true)) => Some((trace_id, Some(parent))),
+        None => Some((encode_hex(Uuid::new_v4().as_bytes()), None)),
+    };
+
+    CURRENT.with(|current| {
+        *current.borrow_mut() = match trace {
+            Some((trace_id, parent_span_id)) if enabled => Some(Trace {
+                trace_id,
+                span_id: random_span_id(),
+                parent_span_id,
+                started: now_nanos(),
+                spans: Arc::default(),
+            }),
+            _ => None,
+        }
+    });
+}
+
+/// Ends the request's server span and exports it with the spans below it, in one message.
+pub fn finish(request: &Request, response: &mut Response) {
+    let trace = match CURRENT.with(|current| current.borrow_mut().take()) {
+        Some(trace) => trace,
+        None => return,
+    };
+    let exporter = match request.guard::<State<Tracer>>().succeeded() {
+        Some(tracer) => match &tracer.inner().exporter {
+            Some(exporter) => lock(exporter).clone(),
+            None => return,
+        },
+        None => return,
+    };
+    let status = response.status().code;
+    let route = request.route().map(|route| route.uri.path().to_string());
+    let mut attributes = vec![
+        Attribute {
+            key: "http.method",
+            value: AttributeValue::String(request.method().to_string()),
+        },
+        Attribute {
+            key: "http.target",
+            value: AttributeValue::String(request.uri().to_string()),
+        },
+        Attribute {
+            key: "http.status_code",
+            value: AttributeValue::Int(status.to_string()),
+        },
+    ];
+
+    if let Some(route) = &route {
+        attributes.push(Attribute {
+            key: "http.route",
+            value: AttributeValue::String(route.clone()),
+        });
+    }
+
+    let server = Span {
+        trace_id: trace.trace_id,
+        span_id: trace.span_id,
+        parent_span_id: trace.parent_span_id,
+        name: match &route {
+            Some(route) => format!("{} {}", request.method(), route),
+            None => format!("HTTP {}", request.method()),
+        },
+        kind: KIND_SERVER,
+        start_time_unix_nano: trace.started.to_string(),
+        end_time_unix_nano: now_nanos().to_string(),
+        attributes,
+        status: if status >= 500 {
+            Some(SpanStatus { code: STATUS_ERROR })
+        } else {
+            None
+        },
+    };
+
+    let mut spans = mem::take(&mut *lock(&trace.spans));
+
+    spans.push(server);
+    let _ = exporter.send(spans);
+}
+
+/// Runs `f` in an internal span named `name` below the request being handled, if it is traced.
+pub fn in_span<T, F: FnOnce() -> T>(name: &'static str, f: F) -> T {
+    let traced = CURRENT.with(|current| current.borrow().is_some());
+
+    if !traced {
+        return f();
+    }
+
+    let started = now_nanos();
+    let result = f();
+    let ended = now_nanos();
+
+    CURRENT.with(|current| {
+        if let Some(trace) = current.borrow_mut().as_mut() {
+            let span = Span {
+                trace_id: trace.trace_id.clone(),
+                span_id: random_span_id(),
+                parent_span_id: Some(trace.span_id.clone()),
+                name: name.to_string(),
+                kind: KIND_INTERNAL,
+                start_time_unix_nano: started.to_string(),
+                end_time_unix_nano: ended.to_string(),
+                attributes: Vec::new(),
+                status: None,
+            };
+
+            lock(&trace.spans).push(span);
+        }
+    });
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{build, rocket};
+
+    #[test]
+    fn export_traces() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (sender, exports) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            sender
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .unwrap();
+        });
+
+        let config = Config::build(Environment::Development)
+            .extra("otlp_endpoint", endpoint)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+
+        client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(Header::new(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .dispatch();
+
+        let export = exports.recv_timeout(Duration::from_secs(5)).unwrap();
+        let body = &export[export.find("\r\n\r\n").unwrap() + 4..];
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let spans = json["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let server = spans.iter().find(|span| span["kind"] == 2).unwrap();
+
+        assert_eq!(server["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(server["parentSpanId"], "00f067aa0ba902b7");
+        assert!(server["name"]
+            .as_str()
+            .unwrap()
+            .starts_with("POST /counter"));
+        assert!(spans
+            .iter()
+            .any(|span| span["name"] == "store.write" && span["parentSpanId"] == server["spanId"]));
+    }
+
+    #[test]
+    fn export_deferred_spans() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (sender, exports) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            sender
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .unwrap();
+        });
+
+        let mut budgets = BTreeMap::new();
+
+        budgets.insert("increment".to_string(), Value::Integer(5000));
+
+        let config = Config::build(Environment::Development)
+            .extra("otlp_endpoint", endpoint)
+            .extra("latency_budgets", Value::Table(budgets))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let response = client
+            .put(format!("/counter/{}/increment", uuid::Uuid::new_v4()))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let export = exports.recv_timeout(Duration::from_secs(5)).unwrap();
+        let body = &export[export.find("\r\n\r\n").unwrap() + 4..];
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let spans = json["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let server = spans.iter().find(|span| span["kind"] == 2).unwrap();
+
+        // The increment ran on the blocking pool, yet its store span is part of the trace.
+        assert!(spans
+            .iter()
+            .any(|span| span["name"] == "store.write" && span["parentSpanId"] == server["spanId"]));
+    }
+}
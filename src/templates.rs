@@ -0,0 +1,222 @@
+//! Named presets for new counters, so that a team's conventions are kept by the server rather
+//! than by every client. `POST /counter?template=daily-metric` creates a counter with the
+//! template's step, bounds, tags and reset cycle.
+
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use crate::auth::Writer;
+use crate::counter::{default_step, Bounds};
+use crate::cycle::{Cycle, Period};
+use crate::dry_run::DryRun;
+use crate::lock;
+use crate::negotiation::Body;
+use crate::routes::parse_tags;
+use crate::tenancy::TenantStore;
+use crate::validation::{Validate, Violations};
+use crate::{error, not_found_error, ApiError, Counter};
+
+const MAX_NAME_LENGTH: usize = 64;
+/// Most templates that one tenant can have.
+const MAX_TEMPLATES: usize = 100;
+
+/// Keyed by tenant and name, like snapshots.
+#[derive(Default)]
+pub struct Templates(Mutex<BTreeMap<(String, String), Template>>);
+
+#[derive(Serialize, Clone)]
+pub struct Template {
+    name: String,
+    step: u32,
+    #[serde(skip_serializing_if = "is_unbounded")]
+    bounds: Bounds,
+    tags: BTreeSet<String>,
+    /// How often counters made from the template reset, counting from their creation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cycle: Option<Period>,
+}
+
+#[derive(Deserialize)]
+pub struct NewTemplate {
+    step: Option<u32>,
+    bounds: Option<Bounds>,
+    tags: Option<Vec<String>>,
+    cycle: Option<Period>,
+}
+
+impl Validate for NewTemplate {
+    fn validate(&self, violations: &mut Violations) {
+        violations.positive("step", self.step);
+        violations.tags(&self.tags);
+    }
+}
+
+fn is_unbounded(bounds: &Bounds) -> bool {
+    *bounds == Bounds::default()
+}
+
+impl Templates {
+    pub fn get(&self, tenant: &str, name: &str) -> Result<Template, ApiError> {
+        lock(&self.0)
+            .get(&(tenant.to_string(), name.to_string()))
+            .cloned()
+            .ok_or_else(|| error(Status::NotFound, "Template was not found."))
+    }
+}
+
+impl Template {
+    /// Gives a new counter the template's settings. Its tags are added to any the counter has.
+    pub fn apply(&self, counter: &mut Counter) {
+        counter.step = self.step;
+        counter.bounds = self.bounds;
+        counter.tags.extend(self.tags.iter().cloned());
+        counter.cycle = self
+            .cycle
+            .and_then(|period| Cycle::new(period, counter.created_at, counter.created_at));
+    }
+}
+
+#[get("/templates")]
+pub fn list_templates(templates: State<Templates>, store: TenantStore) -> Json<Vec<Template>> {
+    Json(
+        lock(&templates.0)
+            .iter()
+            .filter(|((tenant, _), _)| tenant == store.tenant())
+            .map(|(_, template)| template.clone())
+            .collect(),
+    )
+}
+
+/// `PUT /admin/templates/<name>` with `{ "step": 1, "bounds": { "max": 1000 }, "tags":
+/// ["metrics"], "cycle": "monthly" }`, every field optional. Replaces any template of that name;
+/// counters already created from it keep their settings.
+#[put("/templates/<name>", data = "<new_template>")]
+pub fn put_template(
+    name: String,
+    new_template: Body<NewTemplate>,
+    templates: State<Templates>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Template>, ApiError> {
+    dry_run.refuse()?;
+
+    writer.check_admin()?;
+
+    if name.trim().is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(error(
+            Status::BadRequest,
+            "Template names must be between 1 and 64 characters long.",
+        ));
+    }
+
+    let NewTemplate {
+        step,
+        bounds,
+        tags,
+        cycle,
+    } = new_template.into_inner();
+
+    let bounds = bounds.unwrap_or_default();
+
+    bounds.validate()?;
+
+    let template = Template {
+        name: name.clone(),
+        step: step.unwrap_or_else(default_step),
+        bounds,
+        tags: parse_tags(tags.unwrap_or_default())?,
+        cycle,
+    };
+    let key = (store.tenant().to_string(), name);
+    let mut templates = lock(&templates.0);
+    let count = templates
+        .keys()
+        .filter(|(tenant, _)| tenant == store.tenant())
+        .count();
+
+    if count >= MAX_TEMPLATES && !templates.contains_key(&key) {
+        return Err(error(
+            Status::InsufficientStorage,
+            "Template limit reached.",
+        ));
+    }
+
+    templates.insert(key, template.clone());
+    Ok(Json(template))
+}
+
+#[delete("/templates/<name>")]
+pub fn delete_template(
+    name: String,
+    templates: State<Templates>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Template>, ApiError> {
+    dry_run.refuse()?;
+
+    writer.check_admin()?;
+
+    lock(&templates.0)
+        .remove(&(store.tenant().to_string(), name))
+        .map(Json)
+        .ok_or_else(not_found_error)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn create_counter_from_template() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let template_response = client
+            .put("/admin/templates/daily-metric")
+            .header(ContentType::JSON)
+            .body(r#"{ "step": 5, "bounds": { "max": 100 }, "tags": ["metrics"], "cycle": "monthly" }"#)
+            .dispatch();
+
+        assert_eq!(template_response.status(), Status::Ok);
+
+        let mut create_response = client
+            .post("/counter?template=daily-metric")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Signups", "tags": ["growth"] }"#)
+            .dispatch();
+
+        assert_eq!(create_response.status(), Status::Ok);
+
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.step, 5);
+        assert_eq!(counter.bounds.max, Some(100));
+        assert!(counter.cycle.is_some());
+        assert_eq!(
+            counter.tags.into_iter().collect::<Vec<_>>(),
+            vec!["growth", "metrics"]
+        );
+
+        let conflicting_response = client
+            .post("/counter?template=daily-metric")
+            .header(ContentType::JSON)
+            .body(r#"{ "bounds": { "max": 1000 } }"#)
+            .dispatch();
+
+        assert_eq!(conflicting_response.status(), Status::BadRequest);
+
+        let missing_response = client
+            .post("/counter?template=weekly-metric")
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert_eq!(missing_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,467 @@
+//! Tenants, each with counters of its own. With `tenancy = "path"`, requests under
+//! `/t/<tenant>` are served from that tenant's store, as in `/t/acme/v1/counter`. With
+//! `tenancy = "api_key"`, the tenant is the name of the request's API key. Everything else goes
+//! to the default tenant, which is the only one without tenancy.
+//!
+//! In path mode, once API keys or JWTs are configured, only keys and tokens bound to a tenant,
+//! and admins bound to none, may use it; others get 404 as if it did not exist. A tenant is
+//! created by the first write of such a caller, or on first use if it is listed in `tenants`.
+//! Requests never create any other, so that requests to made-up tenants cost nothing.
+//!
+//! Every tenant has its own quota, history and listings. Webhooks, schedules and time-series
+//! sampling work for every tenant. The MQTT, event bus, WebSocket and statsd integrations
+//! cover the default tenant only, so the service refuses to launch with any of them and
+//! tenancy both configured rather than leave the other tenants out.
+
+use rocket::data::Data;
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Config, Outcome, State};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::Mutex;
+
+use crate::auth::{self, ApiKeys, TenantAccess};
+use crate::dry_run;
+use crate::lock;
+use crate::store::Store;
+
+const PREFIX: &str = "/t/";
+const MAX_NAME_LENGTH: usize = 64;
+const DEFAULT_MAX_TENANTS: usize = 1000;
+/// Settings of the integrations that only cover the default tenant.
+const DEFAULT_TENANT_ONLY: [&str; 5] = [
+    "mqtt_broker",
+    "kafka_brokers",
+    "nats_server",
+    "websocket_port",
+    "statsd_port",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Off,
+    Path,
+    ApiKey,
+}
+
+type Hook = Box<dyn Fn(&str, &Store) + Send + Sync>;
+
+pub struct Tenants {
+    mode: Mode,
+    max_tenants: usize,
+    /// Tenants in path mode that anyone allowed to use them may create.
+    configured: HashSet<String>,
+    config: Config,
+    default: Store,
+    stores: Mutex<HashMap<String, Store>>,
+    hooks: Mutex<Vec<Hook>>,
+}
+
+impl Tenants {
+    pub fn from_config(config: &Config, default: Store) -> Tenants {
+        Tenants {
+            mode: match config.get_str("tenancy") {
+                Ok("path") => Mode::Path,
+                Ok("api_key") => Mode::ApiKey,
+                _ => Mode::Off,
+            },
+            max_tenants: config
+                .get_int("max_tenants")
+                .ok()
+                .filter(|max| *max >= 0)
+                .map_or(DEFAULT_MAX_TENANTS, |max| max as usize),
+            configured: config
+                .get_slice("tenants")
+                .map(|tenants| {
+                    tenants
+                        .iter()
+                        .filter_map(|tenant| tenant.as_str())
+                        .filter(|tenant| valid_name(tenant))
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            config: config.clone(),
+            default,
+            stores: Mutex::default(),
+            hooks: Mutex::default(),
+        }
+    }
+
+    /// Runs `hook` with the name and store of every tenant created from now on, for background
+    /// work that the default store gets set up with at launch.
+    pub fn on_create<F: Fn(&str, &Store) + Send + Sync + 'static>(&self, hook: F) {
+        lock(&self.hooks).push(Box::new(hook));
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.mode != Mode::Off
+    }
+
+    /// Fails with tenancy on if an integration that only covers the default tenant is
+    /// configured.
+    pub fn check_integrations(&self) -> Result<(), String> {
+        let configured = DEFAULT_TENANT_ONLY
+            .iter()
+            .find(|key| self.config.get_extra(key).is_ok());
+
+        match configured {
+            Some(key) if self.enabled() => Err(format!(
+                "{} cannot be used with tenancy, as it only covers the default tenant",
+                key
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// How many tenants besides the default one may have stores.
+    pub fn max_tenants(&self) -> usize {
+        self.max_tenants
+    }
+
+    /// The store of a tenant named outside of a request, such as one a counter is moved to.
+    /// `None` for names that cannot be routed to, for tenants that do not exist yet unless
+    /// `create` is set or they are configured, and once there are too many tenants.
+    pub fn get(&self, tenant: &str, create: bool) -> Option<Store> {
+        if self.mode == Mode::Path && !tenant.is_empty() && !valid_name(tenant) {
+            return None;
+        }
+
+        if create || self.mode != Mode::Path || self.configured.contains(tenant) {
+            self.store(tenant)
+        } else {
+            self.existing(tenant)
+        }
+    }
+
+    /// The tenant's store, if it has one yet.
+    fn existing(&self, tenant: &str) -> Option<Store> {
+        if tenant.is_empty() {
+            return Some(self.default.clone());
+        }
+
+        lock(&self.stores).get(tenant).cloned()
+    }
+
+    /// Every tenant with a store so far, with its name, the default one first.
+    pub fn stores(&self) -> Vec<(String, Store)> {
+        let mut stores: Vec<(String, Store)> = lock(&self.stores)
+            .iter()
+            .map(|(tenant, store)| (tenant.clone(), store.clone()))
+            .collect();
+
+        stores.sort_by(|a, b| a.0.cmp(&b.0));
+        stores.insert(0, (String::new(), self.default.clone()));
+        stores
+    }
+
+    /// The tenant's store, created on first use. `None` once there are too many tenants.
+    fn store(&self, tenant: &str) -> Option<Store> {
+        if tenant.is_empty() {
+            return Some(self.default.clone());
+        }
+
+        let mut stores = lock(&self.stores);
+
+        if let Some(store) = stores.get(tenant) {
+            return Some(store.clone());
+        }
+
+        if stores.len() >= self.max_tenants {
+            return None;
+        }
+
+        let store = Store::from_config(&self.config);
+
+        for hook in lock(&self.hooks).iter() {
+            hook(tenant, &store);
+        }
+
+        stores.insert(tenant.to_string(), store.clone());
+        Some(store)
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Name of the tenant a path-mode request was routed to.
+struct Routed(String);
+
+/// Strips the `/t/<tenant>` prefix off requests in path mode, so that the same routes serve
+/// every tenant.
+pub fn route(request: &mut Request, _: &Data) {
+    let path_mode = request
+        .guard::<State<Tenants>>()
+        .succeeded()
+        .map_or(false, |tenants| tenants.mode == Mode::Path);
+
+    if !path_mode {
+        return;
+    }
+
+    let path = request.uri().path().to_string();
+
+    if !path.starts_with(PREFIX) {
+        return;
+    }
+
+    let rest = &path[PREFIX.len()..];
+    let (name, rest) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+
+    if !valid_name(name) {
+        return;
+    }
+
+    let uri = match request.uri().query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_string(),
+    };
+
+    if let Ok(uri) = Origin::parse_owned(uri) {
+        request.local_cache(|| Routed(name.to_string()));
+        request.set_uri(uri);
+    }
+}
+
+/// Name of the request's tenant, empty for the default one. Only known once the request has
+/// been routed.
+pub fn of(request: &Request) -> String {
+    let tenants = match request.guard::<State<Tenants>>().succeeded() {
+        Some(tenants) => tenants.inner(),
+        None => return String::new(),
+    };
+
+    match tenants.mode {
+        Mode::Off => String::new(),
+        Mode::Path => request.local_cache(|| Routed(String::new())).0.clone(),
+        Mode::ApiKey => request
+            .headers()
+            .get_one("X-Api-Key")
+            .and_then(|given| {
+                let api_keys = request.guard::<State<ApiKeys>>().succeeded()?;
+
+                api_keys.name(given)
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// The store of the request's tenant, or a copy of it for dry runs. Fails with 404 for a
+/// tenant beyond `max_tenants`, and in path mode for tenants that the caller may not use or
+/// that do not exist and are not created by the request.
+pub struct TenantStore {
+    tenant: String,
+    store: Store,
+}
+
+impl TenantStore {
+    /// Name of the tenant, empty for the default one.
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    pub fn inner(&self) -> &Store {
+        &self.store
+    }
+}
+
+impl Deref for TenantStore {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        &self.store
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for TenantStore {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<TenantStore, ()> {
+        let tenants = match request.guard::<State<Tenants>>().succeeded() {
+            Some(tenants) => tenants.inner(),
+            None => {
+                return request.guard::<State<Store>>().map(|store| TenantStore {
+                    tenant: String::new(),
+                    store: dry_run::store(request, store.inner().clone()),
+                })
+            }
+        };
+        let tenant = of(request);
+
+        let store = match tenants.mode {
+            Mode::Path if !tenant.is_empty() => match auth::tenant_access(request, &tenant) {
+                TenantAccess::Denied => None,
+                access => {
+                    let write = request.method().supports_payload();
+
+                    tenants.get(&tenant, access == TenantAccess::Bound && write)
+                }
+            },
+            _ => tenants.store(&tenant),
+        };
+
+        match store {
+            Some(store) => Outcome::Success(TenantStore {
+                tenant,
+                store: dry_run::store(request, store),
+            }),
+            None => Outcome::Failure((Status::NotFound, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::error::LaunchErrorKind;
+    use rocket::http::{ContentType, Header, Method, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use super::Tenants;
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn isolate_tenants() {
+        let config = Config::build(Environment::Development)
+            .extra("tenancy", "path")
+            .extra("tenants", vec!["acme"])
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client
+            .post("/t/acme/counter")
+            .header(ContentType::JSON)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let list = |path: &str| {
+            let mut response = client.get(path).dispatch();
+            let counters: Vec<Counter> =
+                serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            counters.len()
+        };
+
+        assert_eq!(list("/t/acme/counter"), 1);
+        assert_eq!(list("/counter"), 0);
+        assert_eq!(
+            client.post("/t/other/counter").dispatch().status(),
+            Status::NotFound
+        );
+        assert_eq!(
+            client
+                .get(format!("/counter/{}", counter.id))
+                .dispatch()
+                .status(),
+            Status::NotFound
+        );
+        assert_eq!(
+            client
+                .get(format!("/t/acme/counter/{}", counter.id))
+                .dispatch()
+                .status(),
+            Status::Ok
+        );
+
+        // Integrations that only cover the default tenant are refused.
+        let config = Config::build(Environment::Development)
+            .extra("tenancy", "path")
+            .extra("statsd_port", 0)
+            .finalize()
+            .unwrap();
+
+        let failed = match Client::new(build(rocket::custom(config))) {
+            Err(error) => match error.kind() {
+                LaunchErrorKind::FailedFairings(failed) => failed.clone(),
+                _ => vec![],
+            },
+            Ok(_) => vec![],
+        };
+
+        assert_eq!(failed, vec!["Tenants"]);
+    }
+
+    #[test]
+    fn bind_tenants_to_credentials() {
+        let mut acme = BTreeMap::new();
+        let mut globex = BTreeMap::new();
+        let mut admin = BTreeMap::new();
+        let mut api_keys = BTreeMap::new();
+
+        acme.insert("name".to_string(), Value::String("acme bot".to_string()));
+        acme.insert("tenant".to_string(), Value::String("acme".to_string()));
+        globex.insert("name".to_string(), Value::String("globex bot".to_string()));
+        globex.insert("tenant".to_string(), Value::String("globex".to_string()));
+        admin.insert("name".to_string(), Value::String("ops".to_string()));
+        admin.insert("role".to_string(), Value::String("admin".to_string()));
+        api_keys.insert("a".to_string(), Value::Table(acme));
+        api_keys.insert("g".to_string(), Value::Table(globex));
+        api_keys.insert("root".to_string(), Value::Table(admin));
+
+        let config = Config::build(Environment::Development)
+            .extra("tenancy", "path")
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let status = |method: Method, path: &str, key: Option<&'static str>| {
+            let mut request = client.req(method, path).header(ContentType::JSON);
+
+            if let Some(key) = key {
+                request = request.header(Header::new("X-Api-Key", key));
+            }
+
+            request.dispatch().status()
+        };
+
+        // Reads do not create tenants, and keys are only let into their own.
+        assert_eq!(
+            status(Method::Get, "/t/acme/counter", Some("a")),
+            Status::NotFound
+        );
+        assert_eq!(
+            status(Method::Post, "/t/acme/counter", Some("g")),
+            Status::NotFound
+        );
+        assert_eq!(
+            status(Method::Post, "/t/acme/counter", Some("a")),
+            Status::Ok
+        );
+        assert_eq!(
+            status(Method::Get, "/t/acme/counter", Some("a")),
+            Status::Ok
+        );
+        assert_eq!(
+            status(Method::Get, "/t/acme/counter", Some("g")),
+            Status::NotFound
+        );
+        assert_eq!(
+            status(Method::Get, "/t/acme/counter", None),
+            Status::NotFound
+        );
+        assert_eq!(
+            status(Method::Get, "/t/acme/counter", Some("root")),
+            Status::Ok
+        );
+        assert_eq!(
+            status(Method::Get, "/t/made-up/counter", Some("root")),
+            Status::NotFound
+        );
+
+        let tenants = client.rocket().state::<Tenants>().unwrap();
+
+        assert_eq!(tenants.stores().len(), 2);
+    }
+}
@@ -0,0 +1,388 @@
+use rocket::Config;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::lock;
+
+/// A week of samples at one-minute intervals.
+const DEFAULT_RETENTION: usize = 10_080;
+/// A month of hourly rollups and a year of daily ones.
+const DEFAULT_HOURLY_RETENTION: usize = 720;
+const DEFAULT_DAILY_RETENTION: usize = 365;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Sample {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub value: u32,
+}
+
+/// How a counter's samples changed over an hour or a day, starting at a whole hour or day in
+/// UTC.
+#[derive(Serialize, Clone, Copy)]
+pub struct Rollup {
+    /// Milliseconds since the Unix epoch.
+    pub start: u64,
+    /// The last value sampled in the window.
+    pub value: u32,
+    pub min: u32,
+    pub max: u32,
+    /// Total of the increases between samples, which is what was counted unless the counter
+    /// was also decremented or reset.
+    pub sum: u64,
+    /// Net change since the last sample before the window.
+    pub delta: i64,
+}
+
+#[derive(Clone, Copy)]
+pub enum Window {
+    Hour,
+    Day,
+}
+
+impl Window {
+    /// `1h` or `1d`.
+    pub fn parse(text: &str) -> Option<Window> {
+        match text {
+            "1h" => Some(Window::Hour),
+            "1d" => Some(Window::Day),
+            _ => None,
+        }
+    }
+
+    fn millis(self) -> u64 {
+        match self {
+            Window::Hour => 60 * 60 * 1000,
+            Window::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Rollups {
+    hourly: VecDeque<Rollup>,
+    daily: VecDeque<Rollup>,
+}
+
+impl Rollups {
+    fn of(&self, window: Window) -> &VecDeque<Rollup> {
+        match window {
+            Window::Hour => &self.hourly,
+            Window::Day => &self.daily,
+        }
+    }
+}
+
+/// Counter values sampled at a fixed interval, keeping at most `retention` samples per counter.
+/// Sampling is disabled unless `timeseries_interval` (in seconds) is configured. Samples are
+/// also rolled up into hours and days, which are kept for longer, as `rollup_retention`.
+pub struct Timeseries {
+    samples: Mutex<HashMap<Uuid, VecDeque<Sample>>>,
+    rollups: Mutex<HashMap<Uuid, Rollups>>,
+    interval: Option<Duration>,
+    retention: usize,
+    hourly_retention: usize,
+    daily_retention: usize,
+    /// When the last sample was taken, zero before the first.
+    last_sampled: AtomicU64,
+}
+
+impl Default for Timeseries {
+    fn default() -> Timeseries {
+        Timeseries {
+            samples: Mutex::new(HashMap::new()),
+            rollups: Mutex::new(HashMap::new()),
+            interval: None,
+            retention: DEFAULT_RETENTION,
+            hourly_retention: DEFAULT_HOURLY_RETENTION,
+            daily_retention: DEFAULT_DAILY_RETENTION,
+            last_sampled: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Adds a sample, which changed the value by `change` since the one before, to the rollup of
+/// its window, starting a new one if needed.
+fn roll_up(
+    rollups: &mut VecDeque<Rollup>,
+    window: Window,
+    retention: usize,
+    sample: Sample,
+    change: i64,
+) {
+    let start = sample.timestamp - sample.timestamp % window.millis();
+
+    match rollups.back_mut() {
+        Some(rollup) if rollup.start == start => {
+            rollup.value = sample.value;
+            rollup.min = rollup.min.min(sample.value);
+            rollup.max = rollup.max.max(sample.value);
+            rollup.sum += change.max(0) as u64;
+            rollup.delta += change;
+        }
+        _ => rollups.push_back(Rollup {
+            start,
+            value: sample.value,
+            min: sample.value,
+            max: sample.value,
+            sum: change.max(0) as u64,
+            delta: change,
+        }),
+    }
+
+    while rollups.len() > retention {
+        rollups.pop_front();
+    }
+}
+
+impl Timeseries {
+    pub fn from_config(config: &Config) -> Timeseries {
+        let rollup_retention = |window: &str, default: usize| {
+            config
+                .get_table("rollup_retention")
+                .ok()
+                .and_then(|table| table.get(window).and_then(|value| value.as_integer()))
+                .filter(|retention| *retention > 0)
+                .map_or(default, |retention| retention as usize)
+        };
+
+        Timeseries {
+            samples: Mutex::new(HashMap::new()),
+            rollups: Mutex::new(HashMap::new()),
+            interval: config
+                .get_int("timeseries_interval")
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .map(|seconds| Duration::from_secs(seconds as u64)),
+            retention: config
+                .get_int("timeseries_retention")
+                .map(|retention| retention as usize)
+                .unwrap_or(DEFAULT_RETENTION),
+            hourly_retention: rollup_retention("hourly", DEFAULT_HOURLY_RETENTION),
+            daily_retention: rollup_retention("daily", DEFAULT_DAILY_RETENTION),
+            last_sampled: AtomicU64::new(0),
+        }
+    }
+
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Milliseconds since the Unix epoch of the last sample, if any was taken yet.
+    pub fn last_sampled(&self) -> Option<u64> {
+        match self.last_sampled.load(Ordering::Relaxed) {
+            0 => None,
+            timestamp => Some(timestamp),
+        }
+    }
+
+    pub fn record(&self, values: &[(Uuid, u32)], timestamp: u64) {
+        let mut samples = lock(&self.samples);
+        let mut rollups = lock(&self.rollups);
+
+        self.last_sampled.store(timestamp, Ordering::Relaxed);
+
+        for (id, value) in values {
+            let series = samples.entry(*id).or_default();
+            let rollups = rollups.entry(*id).or_default();
+            let sample = Sample {
+                timestamp,
+                value: *value,
+            };
+            let change = series
+                .back()
+                .map_or(0, |last| i64::from(*value) - i64::from(last.value));
+
+            roll_up(
+                &mut rollups.hourly,
+                Window::Hour,
+                self.hourly_retention,
+                sample,
+                change,
+            );
+            roll_up(
+                &mut rollups.daily,
+                Window::Day,
+                self.daily_retention,
+                sample,
+                change,
+            );
+            series.push_back(sample);
+
+            while series.len() > self.retention {
+                series.pop_front();
+            }
+        }
+    }
+
+    pub fn forget(&self, id: &Uuid) {
+        lock(&self.samples).remove(id);
+        lock(&self.rollups).remove(id);
+    }
+
+    /// Rollups by `window` that start between `from` and `to` (inclusive, in milliseconds),
+    /// oldest first.
+    pub fn rollups(
+        &self,
+        id: &Uuid,
+        window: Window,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Option<Vec<Rollup>> {
+        let rollups = lock(&self.rollups);
+
+        Some(
+            rollups
+                .get(id)?
+                .of(window)
+                .iter()
+                .filter(|rollup| {
+                    from.map_or(true, |from| rollup.start >= from)
+                        && to.map_or(true, |to| rollup.start <= to)
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// The last sample taken at or before `timestamp`, in milliseconds.
+    pub fn at(&self, id: &Uuid, timestamp: u64) -> Option<Sample> {
+        let samples = lock(&self.samples);
+
+        samples
+            .get(id)?
+            .iter()
+            .rev()
+            .find(|sample| sample.timestamp <= timestamp)
+            .cloned()
+    }
+
+    /// Samples between `from` and `to` (inclusive, in milliseconds). With a `resolution` in
+    /// seconds, samples are downsampled to the last value seen in each bucket of that size.
+    pub fn query(
+        &self,
+        id: &Uuid,
+        from: Option<u64>,
+        to: Option<u64>,
+        resolution: Option<u64>,
+    ) -> Option<Vec<Sample>> {
+        let samples = lock(&self.samples);
+        let series = samples.get(id)?;
+        let in_range = series.iter().filter(|sample| {
+            from.map_or(true, |from| sample.timestamp >= from)
+                && to.map_or(true, |to| sample.timestamp <= to)
+        });
+
+        let bucket_size = match resolution {
+            Some(resolution) if resolution > 0 => resolution * 1000,
+            _ => return Some(in_range.cloned().collect()),
+        };
+        let mut buckets: Vec<Sample> = Vec::new();
+
+        for sample in in_range {
+            let bucket = Sample {
+                timestamp: sample.timestamp - sample.timestamp % bucket_size,
+                value: sample.value,
+            };
+
+            match buckets.last_mut() {
+                Some(last) if last.timestamp == bucket.timestamp => *last = bucket,
+                _ => buckets.push(bucket),
+            }
+        }
+
+        Some(buckets)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::store::Store;
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn sample_timeseries() {
+        let config = Config::build(Environment::Development)
+            .extra("timeseries_interval", 1i64)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        thread::sleep(Duration::from_millis(1500));
+
+        let mut response = client
+            .get(format!("/counter/{}/timeseries?resolution=60", counter.id))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let samples: Vec<serde_json::Value> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0]["value"], 1);
+    }
+
+    #[test]
+    fn roll_up_timeseries() {
+        let config = Config::build(Environment::Development)
+            .extra("timeseries_interval", 3600i64)
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let store = client.rocket().state::<Store>().unwrap();
+        let hour = 60 * 60 * 1000;
+
+        for (timestamp, value) in &[(0, 5), (hour / 2, 8), (hour, 6), (24 * hour, 10)] {
+            store
+                .timeseries()
+                .record(&[(counter.id, *value)], *timestamp);
+        }
+
+        let mut hourly_response = client
+            .get(format!("/counter/{}/rollup", counter.id))
+            .dispatch();
+        let hourly: Vec<serde_json::Value> =
+            serde_json::from_str(&hourly_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(hourly.len(), 3);
+        assert_eq!(hourly[0]["sum"], 3);
+        assert_eq!(hourly[0]["max"], 8);
+        assert_eq!(hourly[1]["delta"], -2);
+
+        let mut daily_response = client
+            .get(format!("/counter/{}/rollup?window=1d", counter.id))
+            .dispatch();
+        let daily: Vec<serde_json::Value> =
+            serde_json::from_str(&daily_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily[0]["value"], 6);
+        assert_eq!(daily[1]["delta"], 4);
+
+        let invalid_response = client
+            .get(format!("/counter/{}/rollup?window=1w", counter.id))
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+    }
+}
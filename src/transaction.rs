@@ -0,0 +1,224 @@
+//! `POST /transaction`, for changes that only make sense together, such as taking an item out
+//! of stock and counting it as sold. Every operation is checked against its counter's bounds
+//! before any is applied, and either all of them are applied or none is.
+
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::history::{Entry, Operation};
+use crate::negotiation::Body;
+use crate::tenancy::TenantStore;
+use crate::validation::{Validate, Violations};
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+/// Most operations that one transaction can hold.
+const MAX_OPERATIONS: usize = 100;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Increment,
+    Decrement,
+}
+
+#[derive(Deserialize)]
+pub struct Change {
+    id: String,
+    operation: Action,
+    /// Defaults to the counter's step.
+    by: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct Transaction {
+    operations: Vec<Change>,
+    reason: Option<String>,
+}
+
+impl Validate for Transaction {
+    fn validate(&self, violations: &mut Violations) {
+        violations.reason(&self.reason);
+
+        for (index, change) in self.operations.iter().enumerate() {
+            violations.positive(&format!("operations[{}].by", index), change.by);
+        }
+    }
+}
+
+/// The value `counter` would have after `operation`, unless that is past one of its bounds.
+fn apply(counter: &Counter, operation: Action, by: u32) -> Option<u32> {
+    match operation {
+        Action::Increment => counter
+            .value
+            .checked_add(by)
+            .filter(|value| *value <= counter.ceiling()),
+        Action::Decrement => counter
+            .value
+            .checked_sub(by)
+            .filter(|value| *value >= counter.bounds.min.unwrap_or(0)),
+    }
+}
+
+/// `POST /transaction` with `{ "operations": [{ "id": "<id>", "operation": "decrement" },
+/// { "id": "<id>", "operation": "increment", "by": 2 }] }`. Unlike single increments and
+/// decrements, an operation that would cross a bound is not clamped but fails the transaction
+/// with 409. Answers with the counters as they are afterwards, in the order first mentioned.
+#[post("/", data = "<transaction>")]
+pub fn transaction(
+    transaction: Body<Transaction>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Vec<Counter>>, ApiError> {
+    let Transaction { operations, reason } = transaction.into_inner();
+
+    if operations.is_empty() || operations.len() > MAX_OPERATIONS {
+        return Err(error(
+            Status::BadRequest,
+            &format!(
+                "A transaction takes between 1 and {} operations.",
+                MAX_OPERATIONS
+            ),
+        ));
+    }
+
+    let mut ids: Vec<Uuid> = Vec::new();
+    let mut steps = Vec::with_capacity(operations.len());
+
+    for change in operations {
+        let id = parse_id(&change.id)?;
+        let position = match ids.iter().position(|known| *known == id) {
+            Some(position) => position,
+            None => {
+                writer.check(id, &store)?;
+                ids.push(id);
+                ids.len() - 1
+            }
+        };
+
+        steps.push((position, change.operation, change.by));
+    }
+
+    let result = store.write_all(&ids, |counters| {
+        let mut entries = Vec::with_capacity(steps.len());
+
+        for (number, (position, operation, by)) in steps.iter().enumerate() {
+            let counter = &mut counters[*position];
+
+            counter.ensure_mutable()?;
+            counter.ensure_steppable()?;
+
+            let value =
+                apply(counter, *operation, by.unwrap_or(counter.step)).ok_or_else(|| {
+                    error(
+                        Status::Conflict,
+                        &format!(
+                            "Operation {} would take counter {} past its bounds.",
+                            number + 1,
+                            counter.id
+                        ),
+                    )
+                })?;
+            let delta = i64::from(value) - i64::from(counter.value);
+
+            counter.set_value(value);
+            counter.score(delta, None);
+            entries.push((
+                counter.id,
+                match operation {
+                    Action::Increment => Operation::Increment,
+                    Action::Decrement => Operation::Decrement,
+                },
+                delta,
+                value,
+            ));
+        }
+
+        // Recorded only once every operation has succeeded, so a failed transaction leaves no
+        // trace in the history.
+        for (id, operation, delta, value) in entries {
+            store
+                .history()
+                .record(id, Entry::new(operation, delta, value, reason.clone()));
+        }
+
+        Ok(counters.to_vec())
+    });
+
+    result.ok_or_else(not_found_error)?.map(Json)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn apply_transaction() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let create = |body: &str| -> Counter {
+            let mut response = client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch();
+
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+        let stock = create(r#"{ "name": "Stock" }"#);
+        let sold = create(r#"{ "name": "Sold", "bounds": { "max": 3 } }"#);
+
+        client
+            .put(format!("/counter/{}/increment", stock.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        let transaction = |by: u32| {
+            client
+                .post("/transaction")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{ "operations": [
+                        {{ "id": "{}", "operation": "decrement", "by": {} }},
+                        {{ "id": "{}", "operation": "increment", "by": {} }}
+                    ] }}"#,
+                    stock.id, by, sold.id, by
+                ))
+                .dispatch()
+        };
+
+        assert_eq!(transaction(2).status(), Status::Conflict);
+
+        let mut response = transaction(1);
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let counters: Vec<Counter> =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters[0].value, 0);
+        assert_eq!(counters[1].value, 1);
+
+        let mut history_response = client
+            .get(format!("/counter/{}/history", sold.id))
+            .dispatch();
+        let history: serde_json::Value =
+            serde_json::from_str(&history_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(history["total"], 2);
+
+        let missing_response = client
+            .post("/transaction")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "operations": [{{ "id": "{}", "operation": "increment" }}] }}"#,
+                uuid::Uuid::new_v4()
+            ))
+            .dispatch();
+
+        assert_eq!(missing_response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,399 @@
+//! `POST /counter/<id>/transfer`, handing a counter to another owner or, with tenancy, moving it
+//! to another tenant, for when teams reorganize. Only the counter's owner or an admin can
+//! transfer it. A counter moved to another tenant takes its history along and keeps its id, so
+//! links to it only need the new tenant; its time series starts over.
+//!
+//! `POST /counter/transfer?tag=payments` hands every counter that carries every `tag` to another
+//! owner at once, or takes them away from their owners, for when a whole team's counters change
+//! hands. Only admins can, and `dry_run=true` shows which counters it would change.
+
+use rocket::http::Status;
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Deserializer};
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::dry_run::DryRun;
+use crate::history::{self, Entry, Operation};
+use crate::negotiation::Body;
+use crate::routes::TagFilter;
+use crate::store::Store;
+use crate::tenancy::{TenantStore, Tenants};
+use crate::validation::{Validate, Violations};
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+#[derive(Deserialize)]
+pub struct Transfer {
+    /// Subject of the new owner's JWTs.
+    owner: Option<String>,
+    /// Name of the tenant to move to, empty for the default one.
+    tenant: Option<String>,
+    reason: Option<String>,
+}
+
+impl Validate for Transfer {
+    fn validate(&self, violations: &mut Violations) {
+        violations.check(
+            self.owner.as_ref().map_or(true, |owner| !owner.is_empty()),
+            "owner",
+            "must not be empty",
+        );
+        violations.reason(&self.reason);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BulkTransfer {
+    /// Subject of the new owner's JWTs, or `null` to leave the counters without an owner.
+    #[serde(default, deserialize_with = "given")]
+    owner: Option<Option<String>>,
+    reason: Option<String>,
+}
+
+impl Validate for BulkTransfer {
+    fn validate(&self, violations: &mut Violations) {
+        violations.check(
+            self.owner
+                .as_ref()
+                .map_or(true, |owner| owner != &Some(String::new())),
+            "owner",
+            "must not be empty",
+        );
+        violations.reason(&self.reason);
+    }
+}
+
+/// Tells an `owner` of `null` apart from none at all.
+fn given<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize)]
+pub struct Transferred {
+    transferred: usize,
+    ids: Vec<Uuid>,
+}
+
+/// Moves `counter` from `from` to `to` with its history, unless `to` has a counter with the
+/// same id or one of its aliases.
+fn move_counter(counter: Counter, from: &Store, to: &Store) -> Result<Counter, ApiError> {
+    let id = counter.id;
+    let taken = to.get(id).is_some()
+        || counter
+            .aliases
+            .iter()
+            .any(|alias| to.resolve(alias).is_some());
+
+    if taken {
+        return Err(error(
+            Status::Conflict,
+            "The tenant already has this counter or one of its aliases.",
+        ));
+    }
+
+    to.write_or_create(id, |hashmap| hashmap.insert(id, counter.clone()))?;
+
+    for entry in from.history().entries(&id) {
+        to.history().record(id, entry);
+    }
+
+    from.remove_where(|counter| counter.id == id);
+    Ok(counter)
+}
+
+/// `{ "owner": "bob" }` to give the counter to bob, `{ "tenant": "acme" }` to move it to the
+/// acme tenant, or both at once.
+#[post("/<id>/transfer", data = "<transfer>")]
+pub fn transfer(
+    id: String,
+    transfer: Body<Transfer>,
+    store: TenantStore,
+    tenants: State<Tenants>,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Counter>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let mut counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+
+    writer.check_owner(&counter)?;
+
+    let Transfer {
+        owner,
+        tenant,
+        reason,
+    } = transfer.into_inner();
+
+    if owner.is_none() && tenant.is_none() {
+        return Err(error(
+            Status::BadRequest,
+            "Give an owner, a tenant or both to transfer the counter to.",
+        ));
+    }
+
+    let target = match tenant {
+        Some(tenant) if tenant != store.tenant() => {
+            dry_run.refuse()?;
+
+            if !tenants.enabled() {
+                return Err(error(
+                    Status::BadRequest,
+                    "Tenancy is off, so counters cannot move between tenants.",
+                ));
+            }
+
+            writer.check_tenant(&tenant)?;
+
+            Some(
+                tenants
+                    .get(&tenant, writer.is_bound_to(&tenant))
+                    .ok_or_else(|| error(Status::NotFound, "No such tenant."))?,
+            )
+        }
+        _ => None,
+    };
+
+    if let Some(owner) = owner {
+        counter.owner = Some(owner);
+    }
+
+    counter.updated_at = history::timestamp();
+
+    let entry = Entry::new(Operation::Transfer, 0, counter.value, reason);
+
+    match target {
+        Some(target) => {
+            let counter = move_counter(counter, &store, &target)?;
+
+            target.history().record(parsed_uuid, entry);
+            Ok(Json(counter))
+        }
+        None => store
+            .write(parsed_uuid, |hashmap| {
+                hashmap.get_mut(&parsed_uuid).map(|stored| {
+                    stored.owner = counter.owner;
+                    stored.updated_at = counter.updated_at;
+                    store.history().record(parsed_uuid, entry);
+                    stored.clone()
+                })
+            })
+            .map(Json)
+            .ok_or_else(not_found_error),
+    }
+}
+
+/// `{ "owner": "bob" }` to give every counter with the tags to bob, or `{ "owner": null }` to
+/// take them away from their owners. Counters that already have the owner are left as they are.
+#[post("/transfer", data = "<transfer>")]
+pub(crate) fn transfer_all(
+    tags: TagFilter,
+    transfer: Body<BulkTransfer>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Transferred>, ApiError> {
+    writer.check_admin()?;
+
+    if tags.0.is_empty() {
+        return Err(error(
+            Status::BadRequest,
+            "Give a tag to choose the counters to transfer.",
+        ));
+    }
+
+    let BulkTransfer { owner, reason } = transfer.into_inner();
+    let owner = owner.ok_or_else(|| {
+        error(
+            Status::BadRequest,
+            "Give an owner, or null to take the counters from their owners.",
+        )
+    })?;
+    let mut ids: Vec<Uuid> = store
+        .ids()
+        .into_iter()
+        .filter_map(|id| {
+            store.write(id, |hashmap| {
+                let counter = hashmap
+                    .get_mut(&id)
+                    .filter(|counter| tags.matches(counter) && counter.owner != owner)?;
+
+                counter.owner = owner.clone();
+                counter.updated_at = history::timestamp();
+                store.history().record(
+                    id,
+                    Entry::new(Operation::Transfer, 0, counter.value, reason.clone()),
+                );
+                Some(id)
+            })
+        })
+        .collect();
+
+    ids.sort();
+    Ok(Json(Transferred {
+        transferred: ids.len(),
+        ids,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn transfer_counters() {
+        let config = Config::build(Environment::Development)
+            .extra("jwt_secret", "s3cret")
+            .extra("tenancy", "path")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let token = |subject: &str| {
+            let claims = serde_json::json!({
+                "sub": subject,
+                "tenant": "acme",
+                "exp": 4_102_444_800u64
+            });
+            let token =
+                jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, b"s3cret").unwrap();
+
+            Header::new("Authorization", format!("Bearer {}", token))
+        };
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(token("alice"))
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let transfer = |subject: &str, body: &str| {
+            client
+                .post(format!("/counter/{}/transfer", counter.id))
+                .header(ContentType::JSON)
+                .header(token(subject))
+                .body(body)
+                .dispatch()
+        };
+
+        assert_eq!(
+            transfer("bob", r#"{ "owner": "bob" }"#).status(),
+            Status::Forbidden
+        );
+        assert_eq!(transfer("alice", "{}").status(), Status::BadRequest);
+
+        let mut owner_response = transfer("alice", r#"{ "owner": "bob" }"#);
+        let transferred: Counter =
+            serde_json::from_str(&owner_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(owner_response.status(), Status::Ok);
+        assert_eq!(transferred.owner, Some("bob".to_string()));
+        assert_eq!(
+            transfer("alice", r#"{ "tenant": "acme" }"#).status(),
+            Status::Forbidden
+        );
+
+        let tenant_response = transfer("bob", r#"{ "tenant": "acme" }"#);
+
+        assert_eq!(tenant_response.status(), Status::Ok);
+
+        let old_response = client.get(format!("/counter/{}", counter.id)).dispatch();
+
+        assert_eq!(old_response.status(), Status::NotFound);
+
+        let mut history_response = client
+            .get(format!("/t/acme/counter/{}/history", counter.id))
+            .header(token("bob"))
+            .dispatch();
+        let history = history_response.body_string().unwrap();
+
+        assert_eq!(history_response.status(), Status::Ok);
+        assert!(history.contains(r#""operation":"create""#));
+        assert!(history.contains(r#""operation":"transfer""#));
+    }
+
+    #[test]
+    fn transfer_counters_in_bulk() {
+        let config = Config::build(Environment::Development)
+            .extra("jwt_secret", "s3cret")
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let token = |subject: &str, scope: &str| {
+            let claims =
+                serde_json::json!({ "sub": subject, "scope": scope, "exp": 4_102_444_800u64 });
+            let token =
+                jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, b"s3cret").unwrap();
+
+            Header::new("Authorization", format!("Bearer {}", token))
+        };
+
+        for tags in &[r#"["payments"]"#, r#"["payments", "eu"]"#, r#"["search"]"#] {
+            client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .header(token("alice", ""))
+                .body(format!(r#"{{ "tags": {} }}"#, tags))
+                .dispatch();
+        }
+
+        let transfer = |query: &str, body: &str, scope: &str| {
+            let mut response = client
+                .post(format!("/counter/transfer?{}", query))
+                .header(ContentType::JSON)
+                .header(token("root", scope))
+                .body(body)
+                .dispatch();
+            let body = response.body_string().unwrap_or_default();
+
+            (
+                response.status(),
+                serde_json::from_str(&body).unwrap_or_default(),
+            )
+        };
+        let owners = || {
+            let mut response = client
+                .get("/counter")
+                .header(token("root", "admin"))
+                .dispatch();
+            let counters: Vec<Counter> =
+                serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+            counters
+                .into_iter()
+                .filter(|counter| counter.owner == Some("bob".to_string()))
+                .count()
+        };
+        let to_bob = r#"{ "owner": "bob" }"#;
+
+        assert_eq!(transfer("tag=payments", to_bob, "").0, Status::Forbidden);
+        assert_eq!(transfer("", to_bob, "admin").0, Status::BadRequest);
+        assert_eq!(
+            transfer("tag=payments", "{}", "admin").0,
+            Status::BadRequest
+        );
+
+        let (status, preview): (Status, serde_json::Value) =
+            transfer("tag=payments&dry_run=true", to_bob, "admin");
+
+        assert_eq!(status, Status::Ok);
+        assert_eq!(preview["transferred"], 2);
+        assert_eq!(owners(), 0);
+
+        let (_, transferred): (Status, serde_json::Value) =
+            transfer("tag=payments", to_bob, "admin");
+
+        assert_eq!(transferred["transferred"], 2);
+        assert_eq!(owners(), 2);
+
+        let (_, disowned): (Status, serde_json::Value) =
+            transfer("tag=payments&tag=eu", r#"{ "owner": null }"#, "admin");
+
+        assert_eq!(disowned["transferred"], 1);
+        assert_eq!(owners(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+use rocket::response::content::Html;
+
+/// Lists every counter with buttons for the common operations, all through the public API.
+/// Values are kept live by polling the listing, which the browser revalidates with its `ETag`,
+/// so an idle page costs the server an empty 304 every couple of seconds. The API key, if the
+/// deployment needs one, is kept in local storage and sent as `X-Api-Key`. Resetting puts the
+/// counter back with its own metadata and its lowest value, and deleting is `DELETE`.
+const PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Counters</title>
+<style>
+  body { margin: 2rem auto; max-width: 60rem; padding: 0 1rem; font-family: system-ui, -apple-system, "Segoe UI", Roboto, sans-serif; color: #222; }
+  header, form { display: flex; gap: 0.5rem; align-items: center; flex-wrap: wrap; }
+  header { justify-content: space-between; }
+  table { width: 100%; border-collapse: collapse; margin-top: 1rem; }
+  th, td { text-align: left; padding: 0.5rem; border-bottom: 1px solid #ddd; }
+  td.value { font-size: 1.5rem; font-variant-numeric: tabular-nums; }
+  td.id { font-family: ui-monospace, monospace; font-size: 0.8rem; color: #666; }
+  button { cursor: pointer; }
+  #error { color: #b00020; min-height: 1.5em; }
+</style>
+</head>
+<body>
+<header>
+  <h1>Counters</h1>
+  <label>API key <input id="api-key" type="password" autocomplete="off"></label>
+</header>
+<form id="create">
+  <input id="name" placeholder="Name (optional)">
+  <button type="submit">Create counter</button>
+</form>
+<p id="error"></p>
+<table>
+  <thead><tr><th>Name</th><th>Value</th><th>Id</th><th></th></tr></thead>
+  <tbody id="counters"></tbody>
+</table>
+<script>
+  (function () {
+    var api = "/v1/counter";
+    var apiKey = document.getElementById("api-key");
+    var rows = document.getElementById("counters");
+    var errors = document.getElementById("error");
+
+    apiKey.value = localStorage.getItem("caas-api-key") || "";
+    apiKey.addEventListener("change", function () {
+      localStorage.setItem("caas-api-key", apiKey.value);
+    });
+
+    function request(method, path, body) {
+      var headers = { Accept: "application/json" };
+
+      if (apiKey.value) {
+        headers["X-Api-Key"] = apiKey.value;
+      }
+
+      if (body !== undefined) {
+        headers["Content-Type"] = "application/json";
+      }
+
+      return fetch(api + path, {
+        method: method,
+        headers: headers,
+        body: body === undefined ? undefined : JSON.stringify(body),
+        cache: "no-cache"
+      }).then(function (response) {
+        return response.json().then(function (json) {
+          if (!response.ok) {
+            throw new Error(json.reason || response.statusText);
+          }
+
+          return json;
+        });
+      });
+    }
+
+    function report(error) {
+      errors.textContent = error ? error.message : "";
+    }
+
+    function button(label, action) {
+      var element = document.createElement("button");
+
+      element.type = "button";
+      element.textContent = label;
+      element.addEventListener("click", function () {
+        action().then(refresh).then(function () { report(null); }, report);
+      });
+      return element;
+    }
+
+    function row(counter) {
+      var tr = document.createElement("tr");
+      var cells = [counter.name || "", counter.value, counter.id];
+      var classes = ["", "value", "id"];
+      var actions = document.createElement("td");
+
+      cells.forEach(function (text, index) {
+        var td = document.createElement("td");
+
+        td.className = classes[index];
+        td.textContent = text;
+        tr.appendChild(td);
+      });
+
+      actions.appendChild(button("+1", function () {
+        return request("PUT", "/" + counter.id + "/increment");
+      }));
+      actions.appendChild(button("−1", function () {
+        return request("PUT", "/" + counter.id + "/decrement");
+      }));
+      actions.appendChild(button("Reset", function () {
+        return confirm("Reset this counter?")
+          ? request("PUT", "/" + counter.id, {
+              value: counter.bounds.min || 0,
+              name: counter.name,
+              description: counter.description,
+              tags: counter.tags,
+              step: counter.step,
+              bounds: counter.bounds,
+              visibility: counter.visibility,
+              reason: "Reset from the admin page"
+            })
+          : Promise.resolve();
+      }));
+      actions.appendChild(button("Delete", function () {
+        return confirm("Delete this counter and its history?")
+          ? request("DELETE", "/" + counter.id)
+          : Promise.resolve();
+      }));
+      tr.appendChild(actions);
+      return tr;
+    }
+
+    function refresh() {
+      return request("GET", "").then(function (counters) {
+        rows.textContent = "";
+        counters.forEach(function (counter) {
+          rows.appendChild(row(counter));
+        });
+      });
+    }
+
+    document.getElementById("create").addEventListener("submit", function (event) {
+      var name = document.getElementById("name");
+
+      event.preventDefault();
+      request("POST", "", name.value ? { name: name.value } : {})
+        .then(function () { name.value = ""; })
+        .then(refresh)
+        .then(function () { report(null); }, report);
+    });
+
+    refresh().catch(report);
+    setInterval(function () { refresh().catch(report); }, 2000);
+  })();
+</script>
+</body>
+</html>
+"#;
+
+/// `GET /ui`, a page for managing counters without writing requests by hand.
+#[get("/")]
+pub fn page() -> Html<&'static str> {
+    Html(PAGE)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn serve_admin_ui() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut response = client.get("/ui").dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert!(response.body_string().unwrap().contains("/v1/counter"));
+
+        // What the page's reset button sends: the counter as listed, at its lowest value.
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Visits", "tags": ["web"] }"#)
+            .dispatch();
+        let counter: serde_json::Value =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let id = counter["id"].as_str().unwrap();
+
+        client
+            .patch(format!("/v1/counter/{}", id))
+            .header(ContentType::JSON)
+            .body(r#"{ "bounds": { "min": 2 } }"#)
+            .dispatch();
+        client
+            .put(format!("/v1/counter/{}/increment?steps=5", id))
+            .dispatch();
+
+        let mut list_response = client.get("/v1/counter").dispatch();
+        let listed: Vec<serde_json::Value> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+        let listed = &listed[0];
+        let reset = serde_json::json!({
+            "value": listed["bounds"]["min"],
+            "name": listed["name"],
+            "description": listed["description"],
+            "tags": listed["tags"],
+            "step": listed["step"],
+            "bounds": listed["bounds"],
+            "reason": "Reset from the admin page"
+        });
+        let reset_response = client
+            .put(format!("/v1/counter/{}", id))
+            .header(ContentType::JSON)
+            .body(reset.to_string())
+            .dispatch();
+
+        assert_eq!(reset_response.status(), Status::Ok);
+
+        let mut get_response = client.get(format!("/v1/counter/{}", id)).dispatch();
+        let reset: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(reset.value, 2);
+        assert_eq!(reset.name, Some("Visits".to_string()));
+        assert!(reset.tags.contains("web"));
+
+        let delete_response = client.delete(format!("/v1/counter/{}", id)).dispatch();
+
+        assert_eq!(delete_response.status(), Status::Ok);
+    }
+}
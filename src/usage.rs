@@ -0,0 +1,432 @@
+//! Usage metering, for billing tenants. Every request is counted against its tenant and, when it
+//! carries a configured API key, against the key, by calendar month in UTC. `GET /admin/usage`
+//! reports the counts of this month and the last one with each tenant's counters and quotas.
+//!
+//! Quotas are configured as
+//! `usage_quotas = { requests = 1000000, counters = 500, tenants = { acme = { requests = 5000000 } } }`,
+//! the top-level ones for every tenant and those under `tenants` for one, by name, with the
+//! default tenant named `""`. Requests beyond the monthly quota are answered with 429 and
+//! `Retry-After` until the month is over, and creating counters beyond the counter quota with
+//! 403. Health checks and metrics scrapes are not counted.
+
+use rocket::data::Data;
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::{Config, Outcome, State};
+use rocket_contrib::json::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::{Admin, ApiKeys};
+use crate::calendar::{self, MILLIS_PER_DAY};
+use crate::history;
+use crate::lock;
+use crate::store::Store;
+use crate::tenancy::{self, Tenants};
+use crate::V1;
+
+/// Where requests over quota are sent instead.
+pub const EXCEEDED_PATH: &str = "/quota-exceeded";
+
+const UNMETERED: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+#[derive(Clone, Copy, Default)]
+struct Quota {
+    /// Per calendar month.
+    requests: Option<u64>,
+    counters: Option<usize>,
+}
+
+impl Quota {
+    fn from_table(table: &rocket::config::Table) -> Quota {
+        let limit = |name| {
+            table
+                .get(name)
+                .and_then(|limit| limit.as_integer())
+                .filter(|limit| *limit >= 0)
+        };
+
+        Quota {
+            requests: limit("requests").map(|limit| limit as u64),
+            counters: limit("counters").map(|limit| limit as usize),
+        }
+    }
+
+    /// This quota with the limits that `other` sets instead.
+    fn or(self, other: Quota) -> Quota {
+        Quota {
+            requests: other.requests.or(self.requests),
+            counters: other.counters.or(self.counters),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct Period {
+    /// Milliseconds since the Unix epoch, from the first of the month to the first of the next.
+    from: u64,
+    to: u64,
+    requests: u64,
+    /// Requests refused for being over quota, not counted in `requests`.
+    refused: u64,
+    by_api_key: BTreeMap<String, u64>,
+}
+
+impl Period {
+    /// The calendar month `now` is in.
+    fn of(now: u64) -> Period {
+        let (year, month, _) = calendar::civil_from_days(now / MILLIS_PER_DAY);
+        let from = calendar::days_from_civil(year, month, 1) * MILLIS_PER_DAY;
+
+        Period {
+            from,
+            to: calendar::add_months(from, 1),
+            ..Period::default()
+        }
+    }
+}
+
+struct Meter {
+    current: Period,
+    /// The month before the current one, if the tenant made requests then.
+    previous: Option<Period>,
+}
+
+impl Meter {
+    /// Starts a new period once `now` is past the current one.
+    fn roll(&mut self, now: u64) {
+        if now < self.current.to {
+            return;
+        }
+
+        let next = Period::of(now);
+        let ended = std::mem::replace(&mut self.current, next);
+
+        self.previous = Some(ended).filter(|ended| ended.to == self.current.from);
+    }
+}
+
+/// Cheaply cloneable handle to the quotas and every tenant's meter.
+#[derive(Clone)]
+pub struct Usage {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    quota: Quota,
+    tenants: HashMap<String, Quota>,
+    meters: Mutex<HashMap<String, Meter>>,
+}
+
+impl Usage {
+    pub fn from_config(config: &Config) -> Usage {
+        let table = config.get_table("usage_quotas").ok();
+        let shared = Shared {
+            quota: table.map(Quota::from_table).unwrap_or_default(),
+            tenants: table
+                .and_then(|table| table.get("tenants"))
+                .and_then(|tenants| tenants.as_table())
+                .map(|tenants| {
+                    tenants
+                        .iter()
+                        .filter_map(|(tenant, quota)| {
+                            Some((tenant.clone(), Quota::from_table(quota.as_table()?)))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            meters: Mutex::default(),
+        };
+
+        Usage {
+            shared: Arc::new(shared),
+        }
+    }
+
+    fn quota(&self, tenant: &str) -> Quota {
+        let shared = &self.shared;
+
+        shared
+            .tenants
+            .get(tenant)
+            .map_or(shared.quota, |quota| shared.quota.or(*quota))
+    }
+
+    /// Holds the tenant's store to its counter quota.
+    pub fn apply(&self, tenant: &str, store: &Store) {
+        store.limit_counters(self.quota(tenant).counters);
+    }
+
+    /// Counts a request of `tenant` made with the API key named `key`, or returns the seconds
+    /// until the tenant may make requests again if it is over quota. Requests of tenants beyond
+    /// the first `max_tenants` are not counted, as they are turned away anyway.
+    fn count(
+        &self,
+        tenant: &str,
+        key: Option<String>,
+        max_tenants: usize,
+        now: u64,
+    ) -> Result<(), u64> {
+        let mut meters = lock(&self.shared.meters);
+
+        if !meters.contains_key(tenant) && meters.len() > max_tenants {
+            return Ok(());
+        }
+
+        let meter = meters.entry(tenant.to_string()).or_insert_with(|| Meter {
+            current: Period::of(now),
+            previous: None,
+        });
+
+        meter.roll(now);
+
+        let period = &mut meter.current;
+
+        if self
+            .quota(tenant)
+            .requests
+            .map_or(false, |quota| period.requests >= quota)
+        {
+            period.refused += 1;
+            return Err((period.to.saturating_sub(now) + 999) / 1000);
+        }
+
+        period.requests += 1;
+
+        if let Some(key) = key {
+            *period.by_api_key.entry(key).or_insert(0) += 1;
+        }
+
+        Ok(())
+    }
+}
+
+fn metered(path: &str) -> bool {
+    let path = if path.starts_with(V1) {
+        &path[V1.len()..]
+    } else {
+        path
+    };
+
+    !UNMETERED.iter().any(|prefix| {
+        path.starts_with(prefix)
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    })
+}
+
+/// Seconds until the month is over, for requests refused for being over quota.
+struct Refused(Option<u64>);
+
+/// Counts the request against its tenant's quota, sending it to `EXCEEDED_PATH` once the quota
+/// is used up. Runs after tenancy routing, which tells the tenant.
+pub fn meter(request: &mut Request, _: &Data) {
+    let refused = match (
+        request.guard::<State<Usage>>().succeeded(),
+        request.guard::<State<Tenants>>().succeeded(),
+    ) {
+        (Some(usage), Some(tenants)) if metered(request.uri().path()) => {
+            let key = request.headers().get_one("X-Api-Key").and_then(|given| {
+                let api_keys = request.guard::<State<ApiKeys>>().succeeded()?;
+
+                api_keys.name(given)
+            });
+
+            usage
+                .count(
+                    &tenancy::of(request),
+                    key,
+                    tenants.max_tenants(),
+                    history::timestamp(),
+                )
+                .err()
+        }
+        _ => None,
+    };
+
+    if let Some(seconds) = refused {
+        request.local_cache(|| Refused(Some(seconds)));
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(EXCEEDED_PATH).unwrap());
+    }
+}
+
+/// Guard that only lets requests over quota through, so that asking for `EXCEEDED_PATH`
+/// directly finds nothing.
+pub struct OverQuota(u64);
+
+impl<'a, 'r> FromRequest<'a, 'r> for OverQuota {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<OverQuota, ()> {
+        match request.local_cache(|| Refused(None)).0 {
+            Some(seconds) => Outcome::Success(OverQuota(seconds)),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for OverQuota {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = json!({
+            "status": "error",
+            "reason": "The monthly request quota is used up."
+        });
+
+        Response::build_from(body.respond_to(request)?)
+            .status(Status::TooManyRequests)
+            .raw_header("Retry-After", self.0.max(1).to_string())
+            .ok()
+    }
+}
+
+#[get("/")]
+pub fn exceeded(over_quota: OverQuota) -> OverQuota {
+    over_quota
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    /// Empty for the default tenant.
+    tenant: String,
+    counters: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counter_quota: Option<usize>,
+    period: Period,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous: Option<Period>,
+}
+
+/// Every tenant's requests this month and the last, and counters, with their quotas.
+#[get("/usage")]
+pub fn get_usage(usage: State<Usage>, tenants: State<Tenants>, _admin: Admin) -> Json<Vec<Report>> {
+    let now = history::timestamp();
+    let mut meters = lock(&usage.shared.meters);
+    let mut stores = tenants.stores();
+
+    for tenant in meters.keys() {
+        if !stores.iter().any(|(name, _)| name == tenant) {
+            stores.push((tenant.clone(), Store::default()));
+        }
+    }
+
+    Json(
+        stores
+            .into_iter()
+            .map(|(tenant, store)| {
+                let quota = usage.quota(&tenant);
+                let (period, previous) = match meters.get_mut(&tenant) {
+                    Some(meter) => {
+                        meter.roll(now);
+                        (meter.current.clone(), meter.previous.clone())
+                    }
+                    None => (Period::of(now), None),
+                };
+
+                Report {
+                    counters: store.count(),
+                    request_quota: quota.requests,
+                    counter_quota: quota.counters,
+                    period,
+                    previous,
+                    tenant,
+                }
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket};
+
+    #[test]
+    fn meter_usage_per_tenant() {
+        let mut admin = BTreeMap::new();
+        let mut writer = BTreeMap::new();
+        let mut api_keys = BTreeMap::new();
+        let mut acme = BTreeMap::new();
+        let mut tenants = BTreeMap::new();
+        let mut quotas = BTreeMap::new();
+
+        admin.insert("name".to_string(), Value::String("ops".to_string()));
+        admin.insert("role".to_string(), Value::String("admin".to_string()));
+        api_keys.insert("a".to_string(), Value::Table(admin));
+        writer.insert("name".to_string(), Value::String("deploy bot".to_string()));
+        writer.insert("tenant".to_string(), Value::String("acme".to_string()));
+        api_keys.insert("w".to_string(), Value::Table(writer));
+        acme.insert("counters".to_string(), Value::Integer(2));
+        tenants.insert("acme".to_string(), Value::Table(acme));
+        quotas.insert("requests".to_string(), Value::Integer(4));
+        quotas.insert("counters".to_string(), Value::Integer(1));
+        quotas.insert("tenants".to_string(), Value::Table(tenants));
+
+        let config = Config::build(Environment::Development)
+            .extra("tenancy", "path")
+            .extra("api_keys", Value::Table(api_keys))
+            .extra("usage_quotas", Value::Table(quotas))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let create = || {
+            client
+                .post("/t/acme/counter")
+                .header(ContentType::JSON)
+                .header(Header::new("X-Api-Key", "w"))
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(create(), Status::Ok);
+        assert_eq!(create(), Status::Ok);
+        assert_eq!(create(), Status::Forbidden);
+        assert_eq!(
+            client
+                .get("/t/acme/counter")
+                .header(Header::new("X-Api-Key", "w"))
+                .dispatch()
+                .status(),
+            Status::Ok
+        );
+
+        let refused = client
+            .get("/t/acme/counter")
+            .header(Header::new("X-Api-Key", "w"))
+            .dispatch();
+
+        assert_eq!(refused.status(), Status::TooManyRequests);
+        assert!(refused.headers().get_one("Retry-After").is_some());
+
+        let mut response = client
+            .get("/admin/usage")
+            .header(Header::new("X-Api-Key", "a"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let reports: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let acme = reports
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|report| report["tenant"] == "acme")
+            .unwrap();
+
+        assert_eq!(acme["counters"], 2);
+        assert_eq!(acme["counter_quota"], 2);
+        assert_eq!(acme["request_quota"], 4);
+        assert_eq!(acme["period"]["requests"], 4);
+        assert_eq!(acme["period"]["refused"], 1);
+        assert_eq!(acme["period"]["by_api_key"]["deploy bot"], 4);
+        assert_eq!(reports[0]["counter_quota"], 1);
+    }
+}
@@ -0,0 +1,179 @@
+//! Checks on request bodies beyond their shape, run as they are read, so that routes only see
+//! bodies that make sense. A body that fails them is answered with 422 and every problem found,
+//! as `{ "errors": [{ "field": "tags[1]", "message": "must be between 1 and 64 characters" }] }`
+//! alongside the usual error fields.
+
+use rocket::request::Request;
+
+use crate::aliases::{self, MAX_KEY_LENGTH};
+use crate::routes::MAX_TAG_LENGTH;
+
+const MAX_NAME_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+const MAX_REASON_LENGTH: usize = 500;
+const MAX_TAGS: usize = 50;
+
+#[derive(Serialize, Clone)]
+pub struct Violation {
+    field: String,
+    message: String,
+}
+
+/// The problems found with a body.
+#[derive(Default)]
+pub struct Violations(Vec<Violation>);
+
+impl Violations {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Notes that `field` is invalid unless `valid` holds.
+    pub fn check(&mut self, valid: bool, field: &str, message: &str) {
+        if !valid {
+            self.0.push(Violation {
+                field: field.to_string(),
+                message: message.to_string(),
+            });
+        }
+    }
+
+    fn length(&mut self, field: &str, text: &Option<String>, max: usize) {
+        if let Some(text) = text {
+            self.check(
+                text.chars().count() <= max,
+                field,
+                &format!("must be at most {} characters", max),
+            );
+        }
+    }
+
+    pub fn name(&mut self, name: &Option<String>) {
+        self.length("name", name, MAX_NAME_LENGTH);
+    }
+
+    pub fn description(&mut self, description: &Option<String>) {
+        self.length("description", description, MAX_DESCRIPTION_LENGTH);
+    }
+
+    pub fn reason(&mut self, reason: &Option<String>) {
+        self.length("reason", reason, MAX_REASON_LENGTH);
+    }
+
+    pub fn key(&mut self, key: &Option<String>) {
+        if let Some(key) = key {
+            self.check(
+                aliases::valid_key(key),
+                "key",
+                &format!(
+                    "must be 1 to {} characters, without control characters",
+                    MAX_KEY_LENGTH
+                ),
+            );
+        }
+    }
+
+    pub fn tags(&mut self, tags: &Option<Vec<String>>) {
+        let tags = match tags {
+            Some(tags) => tags,
+            None => return,
+        };
+
+        self.check(
+            tags.len() <= MAX_TAGS,
+            "tags",
+            &format!("must have at most {} tags", MAX_TAGS),
+        );
+
+        for (index, tag) in tags.iter().enumerate() {
+            let length = tag.trim().chars().count();
+
+            self.check(
+                (1..=MAX_TAG_LENGTH).contains(&length),
+                &format!("tags[{}]", index),
+                &format!("must be between 1 and {} characters", MAX_TAG_LENGTH),
+            );
+        }
+    }
+
+    /// Amounts such as steps, which must be at least 1 when given.
+    pub fn positive(&mut self, field: &str, amount: Option<u32>) {
+        self.check(amount != Some(0), field, "must be at least 1");
+    }
+}
+
+/// Bodies that can be checked once read. The default checks nothing.
+pub trait Validate {
+    fn validate(&self, _violations: &mut Violations) {}
+}
+
+/// What was wrong with the request's body, kept for the 422 catcher.
+struct Invalid(Vec<Violation>);
+
+/// Checks `body`, keeping what is wrong with it for `errors`. Returns whether it is valid.
+pub fn check<T: Validate>(request: &Request, body: &T) -> bool {
+    let mut violations = Violations::default();
+
+    body.validate(&mut violations);
+
+    if violations.is_empty() {
+        return true;
+    }
+
+    request.local_cache(move || Invalid(violations.0));
+    false
+}
+
+/// What was wrong with the request's body, if it was read and found invalid.
+pub fn errors(request: &Request) -> Vec<Violation> {
+    request.local_cache(|| Invalid(Vec::new())).0.clone()
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn validate_request_bodies() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "name": "{}", "tags": [" "] }}"#,
+                "n".repeat(201)
+            ))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+        assert_eq!(body["errors"][0]["field"], "name");
+        assert_eq!(body["errors"][1]["field"], "tags[0]");
+
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let patch_response = client
+            .patch(format!("/counter/{}", counter.id))
+            .header(ContentType::JSON)
+            .body(r#"{ "step": 0 }"#)
+            .dispatch();
+
+        assert_eq!(patch_response.status(), Status::UnprocessableEntity);
+
+        let import_response = client
+            .post("/admin/import.csv")
+            .header(ContentType::CSV)
+            .header(Header::new("Content-Length", "1000000000"))
+            .body("name,value\r\n")
+            .dispatch();
+
+        assert_eq!(import_response.status(), Status::PayloadTooLarge);
+    }
+}
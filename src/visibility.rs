@@ -0,0 +1,154 @@
+//! Who may read and change a counter, chosen when it is created with `"visibility"`. Public hit
+//! counters are open to everyone, while business counters can be kept to callers with an API
+//! key or bearer token.
+
+use rocket::http::Status;
+use rocket::request::Request;
+use uuid::Uuid;
+
+use crate::auth::Reader;
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::{error, ApiError, V1};
+
+/// Paths whose next segment is a counter id, with or without the `/v1` prefix.
+const COUNTER_PATHS: [&str; 2] = ["/counter/", "/display/"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Anyone may read the counter, and browsers may count on it with hits and reactions.
+    Public,
+    /// Anyone may read the counter, but only writers may change it.
+    PublicRead,
+    /// Only callers with an API key or a bearer token may read the counter, and only writers
+    /// may change it.
+    Private,
+}
+
+impl Default for Visibility {
+    fn default() -> Visibility {
+        Visibility::Public
+    }
+}
+
+impl Visibility {
+    pub fn is_public(&self) -> bool {
+        *self == Visibility::Public
+    }
+
+    /// Whether a caller, identified or not, may see the counter in listings.
+    pub fn is_listed_for(self, reader: &Option<Reader>) -> bool {
+        self != Visibility::Private || reader.is_some()
+    }
+}
+
+/// For the routes that browsers call directly: fails with 403 unless counter `id` is public or
+/// does not exist yet.
+pub fn check_beacon(id: Uuid, store: &Store) -> Result<(), ApiError> {
+    match store.get(id) {
+        Some(ref counter) if !counter.visibility.is_public() => Err(error(
+            Status::Forbidden,
+            "Counter only takes changes from writers.",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the request is for a private counter by id.
+pub fn is_private(request: &Request) -> bool {
+    let path = request.uri().path();
+    let path = if path.starts_with(V1) {
+        &path[V1.len()..]
+    } else {
+        path
+    };
+    let rest = match COUNTER_PATHS
+        .iter()
+        .find(|prefix| path.starts_with(*prefix))
+    {
+        Some(prefix) => &path[prefix.len()..],
+        None => return false,
+    };
+    let id = match Uuid::parse_str(rest.split('/').next().unwrap_or("")) {
+        Ok(id) => id,
+        Err(_) => return false,
+    };
+
+    request
+        .guard::<TenantStore>()
+        .succeeded()
+        .and_then(|store| store.get(id))
+        .map_or(false, |counter| counter.visibility == Visibility::Private)
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn hide_private_counters() {
+        let mut api_keys = BTreeMap::new();
+
+        api_keys.insert("s3cret".to_string(), Value::String("finance".to_string()));
+
+        let config = Config::build(Environment::Development)
+            .extra("api_keys", Value::Table(api_keys))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+        let key = || Header::new("X-Api-Key", "s3cret");
+        let create = |visibility: &str| -> Counter {
+            let mut response = client
+                .post("/counter")
+                .header(ContentType::JSON)
+                .header(key())
+                .body(format!(r#"{{ "visibility": "{}" }}"#, visibility))
+                .dispatch();
+
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+        let private = create("private");
+        let public_read = create("public_read");
+
+        let anonymous_response = client.get(format!("/counter/{}", private.id)).dispatch();
+
+        assert_eq!(anonymous_response.status(), Status::Unauthorized);
+
+        let read_response = client
+            .get(format!("/counter/{}", private.id))
+            .header(key())
+            .dispatch();
+
+        assert_eq!(read_response.status(), Status::Ok);
+
+        let mut list_response = client.get("/counter").dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].id, public_read.id);
+
+        let mut list_response = client.get("/counter").header(key()).dispatch();
+        let counters: Vec<Counter> =
+            serde_json::from_str(&list_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counters.len(), 2);
+
+        client
+            .get(format!("/counter/{}/hit", public_read.id))
+            .dispatch();
+
+        let mut get_response = client
+            .get(format!("/counter/{}", public_read.id))
+            .dispatch();
+        let counter: Counter = serde_json::from_str(&get_response.body_string().unwrap()).unwrap();
+
+        assert_eq!(counter.value, 0);
+    }
+}
@@ -0,0 +1,26 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use crate::lock;
+use crate::Counter;
+
+/// Fan-out of counter changes to everyone watching the store. Each subscriber gets every
+/// changed counter and picks out the ones it cares about; subscribers that went away are
+/// dropped on the next change.
+#[derive(Default)]
+pub struct Watchers {
+    subscribers: Mutex<Vec<Sender<Counter>>>,
+}
+
+impl Watchers {
+    pub fn subscribe(&self) -> Receiver<Counter> {
+        let (sender, receiver) = mpsc::channel();
+
+        lock(&self.subscribers).push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, counter: &Counter) {
+        lock(&self.subscribers).retain(|subscriber| subscriber.send(counter.clone()).is_ok());
+    }
+}
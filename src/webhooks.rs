@@ -0,0 +1,791 @@
+use rocket::http::Status;
+use rocket::{Config, State};
+use rocket_contrib::json::{Json, JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use uuid::Uuid;
+
+use crate::auth::Writer;
+use crate::deliveries::{Deliveries, Kind};
+use crate::dry_run::DryRun;
+use crate::features::{Feature, Features};
+use crate::history;
+use crate::lock;
+use crate::negotiation::Body;
+use crate::notify::{Condition, Event, Notifier, Notifiers};
+use crate::simulate::{self, Fired};
+use crate::store::Store;
+use crate::tenancy::TenantStore;
+use crate::validation::{Validate, Violations};
+use crate::{error, not_found_error, parse_id, ApiError, Counter};
+
+const MAX_WEBHOOKS: usize = 20;
+const MAX_MILESTONE_HOOKS: usize = 5;
+/// Lowest milestone when announcing powers of ten.
+const FIRST_ROUND_NUMBER: u32 = 100;
+
+#[derive(Serialize, Clone)]
+pub struct Webhook {
+    id: Uuid,
+    url: String,
+    condition: Condition,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    /// Whether the condition held at the last change, so that it fires only on becoming true.
+    #[serde(skip)]
+    holds: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    condition: String,
+}
+
+impl Validate for NewWebhook {}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Platform {
+    Slack,
+    Discord,
+}
+
+impl Platform {
+    /// The platform an incoming-webhook URL belongs to, going by its host.
+    fn of(url: &reqwest::Url) -> Option<Platform> {
+        match url.host_str()? {
+            "hooks.slack.com" => Some(Platform::Slack),
+            "discord.com" | "discordapp.com" => Some(Platform::Discord),
+            _ => None,
+        }
+    }
+
+    /// Markup around bold text.
+    fn bold(self) -> &'static str {
+        match self {
+            Platform::Slack => "*",
+            Platform::Discord => "**",
+        }
+    }
+
+    fn message(self, text: &str) -> JsonValue {
+        match self {
+            Platform::Slack => json!({ "text": text }),
+            Platform::Discord => json!({ "content": text }),
+        }
+    }
+}
+
+/// A chat channel's incoming webhook that is posted to as the counter passes milestones:
+/// every multiple of `every`, or without it every power of ten from 100 on. Each milestone is
+/// announced once, even if the value drops below it and passes it again.
+#[derive(Serialize, Clone)]
+pub struct MilestoneHook {
+    id: Uuid,
+    url: String,
+    platform: Platform,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    every: Option<u32>,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    /// Highest milestone announced, or passed before the hook was added.
+    #[serde(skip)]
+    announced: u32,
+}
+
+#[derive(Deserialize)]
+pub struct NewMilestoneHook {
+    url: String,
+    /// Needed when the URL is not a Slack or Discord one.
+    platform: Option<Platform>,
+    every: Option<u32>,
+}
+
+impl Validate for NewMilestoneHook {
+    fn validate(&self, violations: &mut Violations) {
+        violations.positive("every", self.every);
+    }
+}
+
+impl MilestoneHook {
+    /// Highest milestone at or below `value`, 0 if none.
+    fn milestone(&self, value: u32) -> u32 {
+        match self.every {
+            Some(every) => value / every * every,
+            None => {
+                let mut milestone = 0;
+                let mut next = FIRST_ROUND_NUMBER;
+
+                while next <= value {
+                    milestone = next;
+                    next = match next.checked_mul(10) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                }
+
+                milestone
+            }
+        }
+    }
+}
+
+/// `1234567` as `1,234,567`.
+fn group_thousands(value: u32) -> String {
+    let digits = value.to_string();
+    let mut grouped = String::new();
+
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index) % 3 == 0 {
+            grouped.push(',');
+        }
+
+        grouped.push(digit);
+    }
+
+    grouped
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    event: &'static str,
+    webhook: Uuid,
+    condition: Condition,
+    counter: &'a Counter,
+}
+
+/// Webhooks per counter. Every change to a counter is checked against its webhooks, and those
+/// whose condition has just become true are posted to through the delivery queue, which
+/// retries them with exponential backoff. Milestone hooks are checked and posted to the same way.
+#[derive(Clone)]
+pub struct Webhooks {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    hooks: Mutex<HashMap<Uuid, Vec<Webhook>>>,
+    milestone_hooks: Mutex<HashMap<Uuid, Vec<MilestoneHook>>>,
+    deliveries: Deliveries,
+}
+
+impl Webhooks {
+    pub fn from_config(config: &Config) -> Webhooks {
+        Webhooks {
+            shared: Arc::new(Shared {
+                hooks: Mutex::default(),
+                milestone_hooks: Mutex::default(),
+                deliveries: Deliveries::from_config(config),
+            }),
+        }
+    }
+
+    /// Every distinct webhook URL.
+    pub fn urls(&self) -> Vec<String> {
+        let mut urls: Vec<String> = lock(&self.shared.hooks)
+            .values()
+            .flatten()
+            .map(|webhook| webhook.url.clone())
+            .collect();
+
+        urls.sort();
+        urls.dedup();
+        urls
+    }
+
+    /// URLs of the webhooks and milestone hooks of `id`.
+    pub fn urls_of(&self, id: Uuid) -> Vec<String> {
+        let mut urls: Vec<String> = lock(&self.shared.hooks)
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .map(|webhook| webhook.url.clone())
+            .collect();
+
+        urls.extend(
+            lock(&self.shared.milestone_hooks)
+                .get(&id)
+                .into_iter()
+                .flatten()
+                .map(|hook| hook.url.clone()),
+        );
+        urls
+    }
+
+    pub fn deliveries(&self) -> &Deliveries {
+        &self.shared.deliveries
+    }
+
+    /// Makes webhook, Slack and Discord notification channels available, sent through the
+    /// delivery queue like webhooks.
+    pub fn register_notifiers(&self, notifiers: &Notifiers) {
+        notifiers.register(WebhookNotifier(self.shared.deliveries.clone()));
+
+        for platform in &[Platform::Slack, Platform::Discord] {
+            notifiers.register(ChatNotifier {
+                platform: *platform,
+                deliveries: self.shared.deliveries.clone(),
+            });
+        }
+    }
+
+    /// Starts checking changes to the counters in `store`. Checking stops with the store.
+    pub fn watch(&self, store: &Store) {
+        let changes = store.subscribe();
+        let webhooks = self.clone();
+
+        thread::spawn(move || {
+            for counter in changes {
+                webhooks.check(&counter);
+            }
+        });
+    }
+
+    /// Which of the webhooks and milestone hooks of `id` would fire if the counter took
+    /// `values` in turn, without delivering anything.
+    pub fn simulate(&self, id: Uuid, values: &[u32]) -> Vec<Fired> {
+        let mut fired = Vec::new();
+
+        for webhook in lock(&self.shared.hooks).get(&id).into_iter().flatten() {
+            let mut holds = webhook.holds;
+
+            fired.extend(simulate::walk("webhook", webhook.id, values, |value| {
+                webhook.condition.became_true(value, &mut holds)
+            }));
+        }
+
+        for hook in lock(&self.shared.milestone_hooks)
+            .get(&id)
+            .into_iter()
+            .flatten()
+        {
+            let mut announced = hook.announced;
+
+            fired.extend(simulate::walk("milestone_hook", hook.id, values, |value| {
+                let milestone = hook.milestone(value);
+                let passed = milestone > announced;
+
+                announced = announced.max(milestone);
+                passed
+            }));
+        }
+        fired
+    }
+
+    fn check(&self, counter: &Counter) {
+        self.check_milestones(counter);
+
+        let mut hooks = lock(&self.shared.hooks);
+        let webhooks = match hooks.get_mut(&counter.id) {
+            Some(webhooks) => webhooks,
+            None => return,
+        };
+
+        for webhook in webhooks.iter_mut() {
+            if webhook
+                .condition
+                .became_true(counter.value, &mut webhook.holds)
+            {
+                self.deliver(webhook, counter);
+            }
+        }
+    }
+
+    fn check_milestones(&self, counter: &Counter) {
+        let mut milestone_hooks = lock(&self.shared.milestone_hooks);
+        let milestone_hooks = match milestone_hooks.get_mut(&counter.id) {
+            Some(milestone_hooks) => milestone_hooks,
+            None => return,
+        };
+
+        for hook in milestone_hooks.iter_mut() {
+            let milestone = hook.milestone(counter.value);
+
+            if milestone <= hook.announced {
+                continue;
+            }
+
+            let bold = hook.platform.bold();
+            let text = format!(
+                ":tada: {} just passed {}!",
+                counter.name.as_ref().map_or_else(
+                    || counter.id.to_string(),
+                    |name| format!("{}{}{}", bold, name, bold)
+                ),
+                group_thousands(milestone)
+            );
+
+            hook.announced = milestone;
+            self.shared.deliveries.enqueue(
+                counter.id,
+                hook.id,
+                Kind::Milestone,
+                hook.url.clone(),
+                serde_json::to_value(hook.platform.message(&text)).unwrap_or_default(),
+            );
+        }
+    }
+
+    fn deliver(&self, webhook: &Webhook, counter: &Counter) {
+        let payload = serde_json::to_value(&Notification {
+            event: "threshold",
+            webhook: webhook.id,
+            condition: webhook.condition,
+            counter,
+        })
+        .unwrap_or_default();
+
+        self.shared.deliveries.enqueue(
+            counter.id,
+            webhook.id,
+            Kind::Threshold,
+            webhook.url.clone(),
+            payload,
+        );
+    }
+}
+
+fn http_url(url: &str) -> Option<reqwest::Url> {
+    reqwest::Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "https" || url.scheme() == "http")
+}
+
+/// Channels that post the event as JSON, as webhooks do.
+struct WebhookNotifier(Deliveries);
+
+impl Notifier for WebhookNotifier {
+    fn kind(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn check_target(&self, target: &str) -> Result<(), String> {
+        http_url(target)
+            .map(|_| ())
+            .ok_or_else(|| "URL must be http or https.".to_string())
+    }
+
+    fn send(&self, target: &str, event: &Event) -> Result<(), String> {
+        let payload = serde_json::to_value(event).map_err(|reason| reason.to_string())?;
+
+        self.0.enqueue(
+            event.counter.id,
+            event.channel,
+            Kind::Channel,
+            target.to_string(),
+            payload,
+        );
+        Ok(())
+    }
+}
+
+/// Channels that post the event in a sentence to a chat's incoming webhook.
+struct ChatNotifier {
+    platform: Platform,
+    deliveries: Deliveries,
+}
+
+impl Notifier for ChatNotifier {
+    fn kind(&self) -> &'static str {
+        match self.platform {
+            Platform::Slack => "slack",
+            Platform::Discord => "discord",
+        }
+    }
+
+    fn check_target(&self, target: &str) -> Result<(), String> {
+        http_url(target)
+            .map(|_| ())
+            .ok_or_else(|| "URL must be http or https.".to_string())
+    }
+
+    fn send(&self, target: &str, event: &Event) -> Result<(), String> {
+        let payload = serde_json::to_value(self.platform.message(&event.summary()))
+            .map_err(|reason| reason.to_string())?;
+
+        self.deliveries.enqueue(
+            event.counter.id,
+            event.channel,
+            Kind::Channel,
+            target.to_string(),
+            payload,
+        );
+        Ok(())
+    }
+}
+
+#[post("/<id>/webhooks", data = "<new_webhook>")]
+pub fn create_webhook(
+    id: String,
+    new_webhook: Body<NewWebhook>,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    features: State<Features>,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Webhook>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+
+    features.require(Feature::Webhooks)?;
+    writer.check(parsed_uuid, &store)?;
+
+    let condition = Condition::parse(&new_webhook.condition).ok_or_else(|| {
+        error(
+            Status::BadRequest,
+            "Condition must look like `value >= 1000`.",
+        )
+    })?;
+    if http_url(&new_webhook.url).is_none() {
+        return Err(error(Status::BadRequest, "URL must be http or https."));
+    }
+
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+    let mut hooks = lock(&webhooks.shared.hooks);
+    let registered = hooks.entry(parsed_uuid).or_default();
+
+    if registered.len() >= MAX_WEBHOOKS {
+        return Err(error(
+            Status::Conflict,
+            "A counter can have at most 20 webhooks.",
+        ));
+    }
+
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: new_webhook.into_inner().url,
+        condition,
+        created_at: history::timestamp(),
+        holds: condition.matches(counter.value),
+    };
+
+    registered.push(webhook.clone());
+    Ok(Json(webhook))
+}
+
+#[get("/<id>/webhooks")]
+pub fn list_webhooks(
+    id: String,
+    webhooks: State<Webhooks>,
+) -> Result<Json<Vec<Webhook>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+    let hooks = lock(&webhooks.shared.hooks);
+
+    Ok(Json(hooks.get(&parsed_uuid).cloned().unwrap_or_default()))
+}
+
+#[delete("/<id>/webhooks/<webhook_id>")]
+pub fn delete_webhook(
+    id: String,
+    webhook_id: String,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<Webhook>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let webhook_id = parse_id(&webhook_id)?;
+
+    writer.check_admin()?;
+    writer.check(parsed_uuid, &store)?;
+
+    let mut hooks = lock(&webhooks.shared.hooks);
+    let registered = hooks.get_mut(&parsed_uuid).ok_or_else(not_found_error)?;
+    let index = registered
+        .iter()
+        .position(|webhook| webhook.id == webhook_id)
+        .ok_or_else(not_found_error)?;
+
+    Ok(Json(registered.remove(index)))
+}
+
+/// `{ "url": "https://hooks.slack.com/services/...", "every": 1000 }` to celebrate every
+/// thousand in a Slack channel. Discord webhook URLs work the same way.
+#[post("/<id>/milestones", data = "<new_hook>")]
+pub fn create_milestone_hook(
+    id: String,
+    new_hook: Body<NewMilestoneHook>,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    features: State<Features>,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<MilestoneHook>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+
+    features.require(Feature::Webhooks)?;
+    writer.check(parsed_uuid, &store)?;
+
+    let NewMilestoneHook {
+        url,
+        platform,
+        every,
+    } = new_hook.into_inner();
+    let parsed_url =
+        http_url(&url).ok_or_else(|| error(Status::BadRequest, "URL must be http or https."))?;
+    let platform = platform
+        .or_else(|| Platform::of(&parsed_url))
+        .ok_or_else(|| {
+            error(
+                Status::BadRequest,
+                "Platform must be given as slack or discord for this URL.",
+            )
+        })?;
+
+    let counter = store.get(parsed_uuid).ok_or_else(not_found_error)?;
+    let mut milestone_hooks = lock(&webhooks.shared.milestone_hooks);
+    let registered = milestone_hooks.entry(parsed_uuid).or_default();
+
+    if registered.len() >= MAX_MILESTONE_HOOKS {
+        return Err(error(
+            Status::Conflict,
+            &format!(
+                "A counter can have at most {} milestone hooks.",
+                MAX_MILESTONE_HOOKS
+            ),
+        ));
+    }
+
+    let mut hook = MilestoneHook {
+        id: Uuid::new_v4(),
+        url,
+        platform,
+        every,
+        created_at: history::timestamp(),
+        announced: 0,
+    };
+
+    hook.announced = hook.milestone(counter.value);
+    registered.push(hook.clone());
+    Ok(Json(hook))
+}
+
+/// Incoming-webhook URLs let anyone post to the channel, so only those who may change the
+/// counter see them. `/counter/<id>/milestones` itself tells when milestones were reached.
+#[get("/<id>/milestones/hooks")]
+pub fn list_milestone_hooks(
+    id: String,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    writer: Writer,
+) -> Result<Json<Vec<MilestoneHook>>, ApiError> {
+    let parsed_uuid = parse_id(&id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let milestone_hooks = lock(&webhooks.shared.milestone_hooks);
+
+    Ok(Json(
+        milestone_hooks
+            .get(&parsed_uuid)
+            .cloned()
+            .unwrap_or_default(),
+    ))
+}
+
+#[delete("/<id>/milestones/<hook_id>")]
+pub fn delete_milestone_hook(
+    id: String,
+    hook_id: String,
+    webhooks: State<Webhooks>,
+    store: TenantStore,
+    dry_run: DryRun,
+    writer: Writer,
+) -> Result<Json<MilestoneHook>, ApiError> {
+    dry_run.refuse()?;
+
+    let parsed_uuid = parse_id(&id)?;
+    let hook_id = parse_id(&hook_id)?;
+
+    writer.check(parsed_uuid, &store)?;
+
+    let mut milestone_hooks = lock(&webhooks.shared.milestone_hooks);
+    let registered = milestone_hooks
+        .get_mut(&parsed_uuid)
+        .ok_or_else(not_found_error)?;
+    let index = registered
+        .iter()
+        .position(|hook| hook.id == hook_id)
+        .ok_or_else(not_found_error)?;
+
+    Ok(Json(registered.remove(index)))
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::Client;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{rocket, Counter};
+
+    #[test]
+    fn fire_webhook_on_threshold() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client.post("/counter").header(ContentType::JSON).dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/milestone", listener.local_addr().unwrap());
+        let (sender, deliveries) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buffer).unwrap() {
+                    0 => break,
+                    read => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            sender
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .unwrap();
+        });
+
+        let mut webhook_response = client
+            .post(format!("/counter/{}/webhooks", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "url": "{}", "condition": "value >= 2" }}"#,
+                url
+            ))
+            .dispatch();
+
+        assert_eq!(webhook_response.status(), Status::Ok);
+        assert!(webhook_response
+            .body_string()
+            .unwrap()
+            .contains(r#""condition":"value >= 2""#));
+
+        for _ in 0..3 {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let delivery = deliveries.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(delivery.starts_with("POST /milestone "));
+        assert!(delivery.contains(r#""event":"threshold""#));
+        assert!(delivery.contains(r#""value":2"#));
+
+        let invalid_response = client
+            .post(format!("/counter/{}/webhooks", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "url": "{}", "condition": "value ~ 2" }}"#,
+                url
+            ))
+            .dispatch();
+
+        assert_eq!(invalid_response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn announce_milestones_in_chat() {
+        let client = Client::new(rocket()).expect("Init failed");
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .body(r#"{ "name": "Downloads" }"#)
+            .dispatch();
+        let counter: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/api/webhooks/1/abc",
+            listener.local_addr().unwrap()
+        );
+        let (sender, deliveries) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+
+                while !request.ends_with(b"}") {
+                    match stream.read(&mut buffer).unwrap() {
+                        0 => break,
+                        read => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                    .unwrap();
+                sender
+                    .send(String::from_utf8_lossy(&request).into_owned())
+                    .unwrap();
+            }
+        });
+
+        let hook_response = client
+            .post(format!("/counter/{}/milestones", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "url": "{}", "platform": "discord", "every": 2 }}"#,
+                url
+            ))
+            .dispatch();
+
+        assert_eq!(hook_response.status(), Status::Ok);
+
+        for _ in 0..5 {
+            client
+                .put(format!("/counter/{}/increment", counter.id))
+                .header(ContentType::JSON)
+                .dispatch();
+        }
+
+        let first = deliveries.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = deliveries.recv_timeout(Duration::from_secs(5)).unwrap();
+        let mut announced = [first, second];
+
+        announced.sort();
+        assert!(announced[0].contains(r#"{"content":":tada: **Downloads** just passed 2!"}"#));
+        assert!(announced[1].contains(r#"{"content":":tada: **Downloads** just passed 4!"}"#));
+
+        // Dropping below a milestone and passing it again is not news.
+        client
+            .put(format!("/counter/{}/decrement", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        client
+            .put(format!("/counter/{}/decrement", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+        client
+            .put(format!("/counter/{}/increment", counter.id))
+            .header(ContentType::JSON)
+            .dispatch();
+
+        assert!(deliveries.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let unknown_platform_response = client
+            .post(format!("/counter/{}/milestones", counter.id))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "url": "{}" }}"#, url))
+            .dispatch();
+
+        assert_eq!(unknown_platform_response.status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,506 @@
+//! Live counter updates over WebSocket. Rocket cannot upgrade connections, so the socket is
+//! served on a port of its own, `websocket_port`, next to the HTTP one.
+//!
+//! Clients connect to `/v1/ws` and send `{"type":"subscribe","ids":[...]}` or
+//! `{"type":"unsubscribe","ids":[...]}`, as often as they like, to follow up to
+//! `websocket_max_subscriptions` counters (1000 by default) over the one connection. On
+//! subscribing they get the current state of each counter, and from then on a
+//! `{"type":"change","counter":{...}}` message whenever one of them changes.
+//!
+//! Connecting takes what reading over HTTP takes: an address the read `ip_rules` let in, and
+//! an `X-Api-Key` header or bearer token in `Authorization` on the handshake when
+//! `public_reads` is off. Private counters are only sent to connections that carried one. Up to
+//! `websocket_max_connections` connections (100 by default) are served at once, and any more
+//! are closed straight away.
+//!
+//! Changes are fanned out only to the connections following the counter. A client that reads
+//! slower than its counters change gets behind: once 64 of its messages are waiting to go out,
+//! further changes are held back and sent as those go, keeping only the latest state of each
+//! counter. A slow client so costs at most one held change per counter it follows, and never
+//! holds up the others.
+
+use rocket::Rocket;
+use std::collections::{HashMap, HashSet};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use uuid::Uuid;
+use ws::{
+    CloseCode, Frame, Handler, Handshake, Message, OpCode, Request, Response, Sender, Settings,
+};
+
+use crate::auth::{self, ApiKeys, Jwt};
+use crate::features::{Feature, Features};
+use crate::firewall::IpRules;
+use crate::lock;
+use crate::store::Store;
+use crate::visibility::Visibility;
+use crate::{Counter, V1};
+
+const DEFAULT_MAX_SUBSCRIPTIONS: usize = 1000;
+const DEFAULT_MAX_CONNECTIONS: usize = 100;
+/// Messages waiting to go out on one connection before changes are held back.
+const MAX_IN_FLIGHT: usize = 64;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Subscribe { ids: Vec<Uuid> },
+    Unsubscribe { ids: Vec<Uuid> },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Push<'a> {
+    Change { counter: &'a Counter },
+    Error { reason: &'a str },
+}
+
+/// One open connection.
+struct Subscriber {
+    out: Sender,
+    /// Whether the handshake carried an API key or a bearer token, which private counters take.
+    identified: bool,
+    ids: HashSet<Uuid>,
+    /// Messages handed to the connection that have not gone out yet.
+    in_flight: usize,
+    /// Latest changes held back while too many messages are in flight, by counter.
+    held: HashMap<Uuid, Counter>,
+}
+
+impl Subscriber {
+    fn new(out: Sender, identified: bool) -> Subscriber {
+        Subscriber {
+            out,
+            identified,
+            ids: HashSet::new(),
+            in_flight: 0,
+            held: HashMap::new(),
+        }
+    }
+
+    fn send(&mut self, push: &Push) {
+        let text = match serde_json::to_string(push) {
+            Ok(text) => text,
+            Err(_) => return,
+        };
+
+        // A connection that is going away is cleaned up in `on_close`.
+        if self.out.send(text).is_ok() {
+            self.in_flight += 1;
+        }
+    }
+
+    fn may_read(&self, counter: &Counter) -> bool {
+        counter.visibility != Visibility::Private || self.identified
+    }
+
+    /// Sends the change, or holds it back if the client is behind. Changes to counters the
+    /// client may not read, such as ones made private since it subscribed, are left out.
+    fn push(&mut self, counter: &Counter) {
+        if !self.may_read(counter) {
+            return;
+        }
+
+        if self.in_flight >= MAX_IN_FLIGHT || !self.held.is_empty() {
+            self.held.insert(counter.id, counter.clone());
+        } else {
+            self.send(&Push::Change { counter });
+        }
+    }
+
+    /// Notes that a message went out, sending held changes once half the room is free.
+    fn sent(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+
+        if self.in_flight > MAX_IN_FLIGHT / 2 {
+            return;
+        }
+
+        let room = MAX_IN_FLIGHT - self.in_flight;
+        let ids: Vec<Uuid> = self.held.keys().take(room).cloned().collect();
+
+        for id in ids {
+            if let Some(counter) = self.held.remove(&id) {
+                self.send(&Push::Change { counter: &counter });
+            }
+        }
+    }
+}
+
+/// Open connections, and which of them follow each counter.
+#[derive(Default)]
+struct Subscriptions {
+    connections: HashMap<u32, Subscriber>,
+    followers: HashMap<Uuid, HashSet<u32>>,
+}
+
+impl Subscriptions {
+    fn unfollow(&mut self, connection: u32, id: &Uuid) {
+        if let Some(followers) = self.followers.get_mut(id) {
+            followers.remove(&connection);
+
+            if followers.is_empty() {
+                self.followers.remove(id);
+            }
+        }
+    }
+}
+
+struct Hub {
+    subscriptions: Mutex<Subscriptions>,
+    max_subscriptions: usize,
+    api_keys: ApiKeys,
+    jwt: Jwt,
+    ip_rules: IpRules,
+    public_reads: bool,
+}
+
+impl Hub {
+    fn broadcast(&self, counter: &Counter) {
+        let mut guard = lock(&self.subscriptions);
+        let subscriptions = &mut *guard;
+        let followers = match subscriptions.followers.get(&counter.id) {
+            Some(followers) => followers,
+            None => return,
+        };
+
+        for connection in followers {
+            if let Some(subscriber) = subscriptions.connections.get_mut(connection) {
+                subscriber.push(counter);
+            }
+        }
+    }
+}
+
+struct Connection {
+    out: Sender,
+    hub: Arc<Hub>,
+    store: Store,
+    identified: bool,
+}
+
+impl Connection {
+    /// Runs `f` with this connection's subscriber, if it is still open.
+    fn with_subscriber<F: FnOnce(&mut Subscriber)>(&self, f: F) {
+        let mut subscriptions = lock(&self.hub.subscriptions);
+
+        if let Some(subscriber) = subscriptions.connections.get_mut(&self.out.connection_id()) {
+            f(subscriber);
+        }
+    }
+
+    fn subscribe(&self, ids: Vec<Uuid>) {
+        let connection = self.out.connection_id();
+        let max = self.hub.max_subscriptions;
+        let mut guard = lock(&self.hub.subscriptions);
+        let subscriptions = &mut *guard;
+        let subscriber = match subscriptions.connections.get_mut(&connection) {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+        let new: HashSet<Uuid> = ids
+            .into_iter()
+            .filter(|id| !subscriber.ids.contains(id))
+            .collect();
+        let private = new.iter().any(|id| {
+            self.store
+                .get(*id)
+                .map_or(false, |counter| !subscriber.may_read(&counter))
+        });
+
+        if private {
+            subscriber.send(&Push::Error {
+                reason: "Private counters take a valid X-Api-Key header or bearer token.",
+            });
+            return;
+        }
+
+        if subscriber.ids.len() + new.len() > max {
+            subscriber.send(&Push::Error {
+                reason: &format!("At most {} counters can be followed at once.", max),
+            });
+            return;
+        }
+
+        for id in new {
+            subscriptions
+                .followers
+                .entry(id)
+                .or_default()
+                .insert(connection);
+            subscriber.ids.insert(id);
+
+            if let Some(counter) = self.store.get(id) {
+                subscriber.push(&counter);
+            }
+        }
+    }
+
+    fn unsubscribe(&self, ids: &[Uuid]) {
+        let connection = self.out.connection_id();
+        let mut subscriptions = lock(&self.hub.subscriptions);
+
+        if let Some(subscriber) = subscriptions.connections.get_mut(&connection) {
+            for id in ids {
+                subscriber.ids.remove(id);
+                subscriber.held.remove(id);
+            }
+        }
+
+        for id in ids {
+            subscriptions.unfollow(connection, id);
+        }
+    }
+}
+
+impl Handler for Connection {
+    fn on_request(&mut self, request: &Request) -> ws::Result<Response> {
+        if request.resource() != format!("{}/ws", V1) {
+            return Ok(Response::new(404, "Not Found", Vec::new()));
+        }
+
+        let header = |name| {
+            request
+                .header(name)
+                .and_then(|value| str::from_utf8(value).ok())
+        };
+        let hub = &self.hub;
+
+        match auth::authenticate(
+            &hub.api_keys,
+            &hub.jwt,
+            header("X-Api-Key"),
+            header("Authorization"),
+        ) {
+            Ok(identified) if identified || hub.public_reads => {
+                self.identified = identified;
+                Response::from_request(request)
+            }
+            _ => Ok(Response::new(401, "Unauthorized", Vec::new())),
+        }
+    }
+
+    /// Registers the connection, unless its address is not allowed to read.
+    fn on_open(&mut self, handshake: Handshake) -> ws::Result<()> {
+        let ip = handshake.peer_addr.map(|address| address.ip());
+
+        if !self.hub.ip_rules.allows_read(ip) {
+            return self.out.close_with_reason(
+                CloseCode::Policy,
+                "Requests from your network are not allowed here.",
+            );
+        }
+
+        lock(&self.hub.subscriptions).connections.insert(
+            self.out.connection_id(),
+            Subscriber::new(self.out.clone(), self.identified),
+        );
+        Ok(())
+    }
+
+    fn on_message(&mut self, message: Message) -> ws::Result<()> {
+        let command = message
+            .as_text()
+            .ok()
+            .and_then(|text| serde_json::from_str::<Command>(text).ok());
+
+        match command {
+            Some(Command::Subscribe { ids }) => self.subscribe(ids),
+            Some(Command::Unsubscribe { ids }) => self.unsubscribe(&ids),
+            None => self.with_subscriber(|subscriber| {
+                subscriber.send(&Push::Error {
+                    reason: "Expected a subscribe or unsubscribe message.",
+                })
+            }),
+        }
+
+        Ok(())
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> ws::Result<Option<Frame>> {
+        if frame.opcode() == OpCode::Text {
+            self.with_subscriber(Subscriber::sent);
+        }
+
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        let connection = self.out.connection_id();
+        let mut subscriptions = lock(&self.hub.subscriptions);
+
+        if let Some(subscriber) = subscriptions.connections.remove(&connection) {
+            for id in &subscriber.ids {
+                subscriptions.unfollow(connection, id);
+            }
+        }
+    }
+}
+
+/// Starts accepting WebSocket connections on `port` of the configured address in the
+/// background, checking them against the API keys, JWT secret, IP rules and features that
+/// `rocket` manages.
+pub fn serve(rocket: &Rocket, port: u16, store: Store) {
+    let config = rocket.config();
+    let limit = |key, default| {
+        config
+            .get_int(key)
+            .ok()
+            .filter(|max| *max > 0)
+            .map_or(default, |max| max as usize)
+    };
+    let max_connections = limit("websocket_max_connections", DEFAULT_MAX_CONNECTIONS);
+    let hub = Arc::new(Hub {
+        subscriptions: Mutex::default(),
+        max_subscriptions: limit("websocket_max_subscriptions", DEFAULT_MAX_SUBSCRIPTIONS),
+        api_keys: rocket.state::<ApiKeys>().cloned().unwrap_or_default(),
+        jwt: rocket.state::<Jwt>().cloned().unwrap_or_default(),
+        ip_rules: rocket.state::<IpRules>().cloned().unwrap_or_default(),
+        public_reads: rocket
+            .state::<Features>()
+            .map_or(true, |features| features.enabled(Feature::PublicReads)),
+    });
+    let address = config.address.clone();
+    let changes = store.subscribe();
+    let dispatcher = Arc::clone(&hub);
+
+    thread::spawn(move || {
+        for counter in changes {
+            dispatcher.broadcast(&counter);
+        }
+    });
+
+    thread::spawn(move || {
+        // Room in the event queue for every message in flight, so that sending never blocks.
+        let server = ws::Builder::new()
+            .with_settings(Settings {
+                max_connections,
+                queue_size: MAX_IN_FLIGHT * 2,
+                ..Settings::default()
+            })
+            .build(|out| Connection {
+                out,
+                hub: Arc::clone(&hub),
+                store: store.clone(),
+                identified: false,
+            });
+        let listening = server.and_then(|server| server.listen((address.as_str(), port)));
+
+        if let Err(error) = listening {
+            eprintln!("WebSocket server stopped: {}", error);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::config::{Config, Environment, Value};
+    use rocket::http::{ContentType, Header};
+    use rocket::local::Client;
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::store::Store;
+    use crate::{build, rocket, Counter};
+
+    #[test]
+    fn check_websocket_readers() {
+        let serve = |public_reads: bool| {
+            let mut api_keys = BTreeMap::new();
+            let mut features = BTreeMap::new();
+
+            api_keys.insert("s3cret".to_string(), Value::String("finance".to_string()));
+            features.insert("public_reads".to_string(), Value::Boolean(public_reads));
+
+            let config = Config::build(Environment::Development)
+                .address("127.0.0.1")
+                .extra("api_keys", Value::Table(api_keys))
+                .extra("features", Value::Table(features))
+                .finalize()
+                .unwrap();
+            let client = Client::new(build(rocket::custom(config))).expect("Init failed");
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let store = client.rocket().state::<Store>().unwrap().clone();
+
+            super::serve(client.rocket(), port, store);
+            thread::sleep(Duration::from_millis(200));
+            (client, port)
+        };
+        let connect = |port: u16, key: Option<&str>| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let key = key.map_or(String::new(), |key| format!("X-Api-Key: {}\r\n", key));
+            let mut response = Vec::new();
+            let mut byte = [0];
+
+            write!(
+                stream,
+                "GET /v1/ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: 13\r\n{}\r\n",
+                key
+            )
+            .unwrap();
+
+            while !response.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                response.push(byte[0]);
+            }
+
+            (stream, String::from_utf8_lossy(&response).into_owned())
+        };
+        let subscribe = |stream: &mut TcpStream, id: uuid::Uuid| {
+            let command = format!(r#"{{"type":"subscribe","ids":["{}"]}}"#, id);
+            let mut frame = vec![0x81, 0x80 | command.len() as u8, 0, 0, 0, 0];
+            let mut header = [0; 2];
+
+            frame.extend_from_slice(command.as_bytes());
+            stream.write_all(&frame).unwrap();
+            stream.read_exact(&mut header).unwrap();
+
+            let length = match header[1] & 0x7f {
+                126 => {
+                    let mut length = [0; 2];
+
+                    stream.read_exact(&mut length).unwrap();
+                    u16::from_be_bytes(length) as usize
+                }
+                length => length as usize,
+            };
+            let mut payload = vec![0; length];
+
+            stream.read_exact(&mut payload).unwrap();
+            String::from_utf8(payload).unwrap()
+        };
+
+        let (client, port) = serve(true);
+        let mut create_response = client
+            .post("/counter")
+            .header(ContentType::JSON)
+            .header(Header::new("X-Api-Key", "s3cret"))
+            .body(r#"{ "visibility": "private" }"#)
+            .dispatch();
+        let private: Counter =
+            serde_json::from_str(&create_response.body_string().unwrap()).unwrap();
+        let (mut anonymous, response) = connect(port, None);
+
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(subscribe(&mut anonymous, private.id).contains("Private counters"));
+
+        let (mut keyed, _) = connect(port, Some("s3cret"));
+
+        assert!(subscribe(&mut keyed, private.id).contains(r#""type":"change""#));
+
+        // Without public reads, connecting takes a key like reading over HTTP does.
+        let (_client, port) = serve(false);
+
+        assert!(connect(port, None).1.starts_with("HTTP/1.1 401"));
+        assert!(connect(port, Some("s3cret")).1.starts_with("HTTP/1.1 101"));
+    }
+}